@@ -0,0 +1,32 @@
+-- Your SQL goes here
+CREATE TABLE orders (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    symbol VARCHAR(20) NOT NULL,
+    side VARCHAR(4) NOT NULL,
+    price NUMERIC(30, 10) NOT NULL,
+    quantity NUMERIC(30, 10) NOT NULL,
+    filled_quantity NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    status VARCHAR(20) NOT NULL DEFAULT 'open',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_orders_user_id ON orders(user_id);
+-- Open orders are reloaded into the in-memory books at startup
+CREATE INDEX idx_orders_symbol_status ON orders(symbol, status);
+
+CREATE TABLE fills (
+    id SERIAL PRIMARY KEY,
+    symbol VARCHAR(20) NOT NULL,
+    maker_order_id INTEGER NOT NULL REFERENCES orders(id),
+    taker_order_id INTEGER NOT NULL REFERENCES orders(id),
+    maker_user_id INTEGER NOT NULL REFERENCES users(id),
+    taker_user_id INTEGER NOT NULL REFERENCES users(id),
+    taker_side VARCHAR(4) NOT NULL,
+    price NUMERIC(30, 10) NOT NULL,
+    quantity NUMERIC(30, 10) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_fills_symbol_created_at ON fills(symbol, created_at);
+CREATE INDEX idx_fills_maker_order_id ON fills(maker_order_id);
+CREATE INDEX idx_fills_taker_order_id ON fills(taker_order_id);
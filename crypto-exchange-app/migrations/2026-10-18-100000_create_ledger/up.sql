@@ -0,0 +1,85 @@
+-- Your SQL goes here
+-- One account per (user, asset, kind). User accounts are either 'available'
+-- or 'held'; system accounts (user_id NULL) such as 'external' mirror money
+-- entering and leaving the exchange.
+CREATE TABLE ledger_accounts (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id),
+    asset VARCHAR(20) NOT NULL,
+    kind VARCHAR(20) NOT NULL,
+    balance NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT ledger_accounts_user_balance_non_negative CHECK (user_id IS NULL OR balance >= 0)
+);
+CREATE UNIQUE INDEX idx_ledger_accounts_user_asset_kind
+    ON ledger_accounts(user_id, asset, kind) WHERE user_id IS NOT NULL;
+CREATE UNIQUE INDEX idx_ledger_accounts_system_asset_kind
+    ON ledger_accounts(asset, kind) WHERE user_id IS NULL;
+
+CREATE TABLE journal_entries (
+    id SERIAL PRIMARY KEY,
+    kind VARCHAR(30) NOT NULL,
+    reference VARCHAR(100) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_journal_entries_reference ON journal_entries(reference);
+
+CREATE TABLE journal_postings (
+    id SERIAL PRIMARY KEY,
+    entry_id INTEGER NOT NULL REFERENCES journal_entries(id),
+    account_id INTEGER NOT NULL REFERENCES ledger_accounts(id),
+    asset VARCHAR(20) NOT NULL,
+    amount NUMERIC(30, 10) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_journal_postings_entry_id ON journal_postings(entry_id);
+CREATE INDEX idx_journal_postings_account_id ON journal_postings(account_id);
+
+-- The journal is append-only
+CREATE FUNCTION ledger_reject_modification() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'journal records are immutable';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER journal_entries_immutable BEFORE UPDATE OR DELETE ON journal_entries
+    FOR EACH ROW EXECUTE PROCEDURE ledger_reject_modification();
+CREATE TRIGGER journal_postings_immutable BEFORE UPDATE OR DELETE ON journal_postings
+    FOR EACH ROW EXECUTE PROCEDURE ledger_reject_modification();
+
+-- Every entry must balance to zero per asset by the time its transaction commits
+CREATE FUNCTION ledger_check_entry_balanced() RETURNS trigger AS $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM journal_postings
+        WHERE entry_id = NEW.entry_id
+        GROUP BY asset
+        HAVING SUM(amount) <> 0
+    ) THEN
+        RAISE EXCEPTION 'journal entry % does not balance', NEW.entry_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER journal_postings_balanced AFTER INSERT ON journal_postings
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE PROCEDURE ledger_check_entry_balanced();
+
+-- Amount of the order's hold asset that is still reserved in the user's held account
+ALTER TABLE orders
+ADD COLUMN reserved NUMERIC(30, 10) NOT NULL DEFAULT 0;
+
+CREATE TABLE withdrawals (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    asset VARCHAR(20) NOT NULL,
+    amount NUMERIC(30, 10) NOT NULL,
+    address VARCHAR(255) NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_withdrawals_user_id ON withdrawals(user_id);
+CREATE INDEX idx_withdrawals_status ON withdrawals(status);
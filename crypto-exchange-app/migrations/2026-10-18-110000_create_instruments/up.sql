@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE instruments (
+    id SERIAL PRIMARY KEY,
+    symbol VARCHAR(20) NOT NULL UNIQUE,
+    base_asset VARCHAR(20) NOT NULL,
+    quote_asset VARCHAR(20) NOT NULL,
+    price_tick NUMERIC(30, 10) NOT NULL,
+    quantity_step NUMERIC(30, 10) NOT NULL,
+    min_quantity NUMERIC(30, 10) NOT NULL,
+    max_quantity NUMERIC(30, 10) NOT NULL,
+    min_notional NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    status VARCHAR(20) NOT NULL DEFAULT 'active',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT instruments_status_valid CHECK (status IN ('active', 'halted', 'post_only'))
+);
+
+-- The pairs that were previously configured through TRADING_PAIRS
+INSERT INTO instruments (symbol, base_asset, quote_asset, price_tick, quantity_step, min_quantity, max_quantity, min_notional)
+VALUES
+    ('BTC/USDT', 'BTC', 'USDT', 0.01, 0.00001, 0.00001, 1000, 5),
+    ('ETH/USDT', 'ETH', 'USDT', 0.01, 0.0001, 0.0001, 10000, 5);
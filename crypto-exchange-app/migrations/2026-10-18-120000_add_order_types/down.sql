@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE orders
+    DROP COLUMN order_type,
+    DROP COLUMN time_in_force,
+    DROP COLUMN post_only,
+    DROP COLUMN stop_price,
+    DROP COLUMN expires_at,
+    DROP COLUMN triggered_at,
+    DROP COLUMN reject_reason;
+ALTER TABLE orders ALTER COLUMN price SET NOT NULL;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+-- Market and stop-market orders have no limit price
+ALTER TABLE orders ALTER COLUMN price DROP NOT NULL;
+ALTER TABLE orders
+    ADD COLUMN order_type VARCHAR(20) NOT NULL DEFAULT 'limit',
+    ADD COLUMN time_in_force VARCHAR(3) NOT NULL DEFAULT 'gtc',
+    ADD COLUMN post_only BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN stop_price NUMERIC(30, 10),
+    ADD COLUMN expires_at TIMESTAMPTZ,
+    ADD COLUMN triggered_at TIMESTAMPTZ,
+    ADD COLUMN reject_reason VARCHAR(255);
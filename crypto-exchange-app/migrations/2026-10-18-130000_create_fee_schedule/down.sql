@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_fills_taker_user_id_created_at;
+DROP INDEX idx_fills_maker_user_id_created_at;
+ALTER TABLE fills
+    DROP COLUMN maker_fee,
+    DROP COLUMN maker_fee_asset,
+    DROP COLUMN taker_fee,
+    DROP COLUMN taker_fee_asset;
+DROP TABLE user_fee_overrides;
+DROP TABLE fee_tiers;
@@ -0,0 +1,40 @@
+-- Your SQL goes here
+-- Maker and taker rates by 30-day traded volume. A user pays the rates of
+-- the highest tier whose min_volume they have reached.
+CREATE TABLE fee_tiers (
+    id SERIAL PRIMARY KEY,
+    min_volume NUMERIC(30, 10) NOT NULL UNIQUE,
+    maker_rate NUMERIC(10, 6) NOT NULL,
+    taker_rate NUMERIC(10, 6) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT fee_tiers_rates_valid CHECK (maker_rate >= 0 AND maker_rate < 1 AND taker_rate >= 0 AND taker_rate < 1)
+);
+
+INSERT INTO fee_tiers (min_volume, maker_rate, taker_rate)
+VALUES
+    (0, 0.001, 0.002),
+    (50000, 0.0008, 0.0018),
+    (500000, 0.0006, 0.0015),
+    (5000000, 0.0004, 0.0012),
+    (20000000, 0.0002, 0.001);
+
+-- Rates negotiated with individual users, replacing their volume tier
+CREATE TABLE user_fee_overrides (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id),
+    maker_rate NUMERIC(10, 6) NOT NULL,
+    taker_rate NUMERIC(10, 6) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT user_fee_overrides_rates_valid CHECK (maker_rate >= 0 AND maker_rate < 1 AND taker_rate >= 0 AND taker_rate < 1)
+);
+
+-- Each side pays its fee in the asset it receives
+ALTER TABLE fills
+    ADD COLUMN maker_fee NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    ADD COLUMN maker_fee_asset VARCHAR(20) NOT NULL DEFAULT '',
+    ADD COLUMN taker_fee NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    ADD COLUMN taker_fee_asset VARCHAR(20) NOT NULL DEFAULT '';
+
+-- 30-day volume is summed over both sides of a user's fills
+CREATE INDEX idx_fills_maker_user_id_created_at ON fills(maker_user_id, created_at);
+CREATE INDEX idx_fills_taker_user_id_created_at ON fills(taker_user_id, created_at);
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- OHLCV history at 1m, 5m, 1h and 1d resolutions. Exchange pairs such as
+-- BTC/USDT are built from fills; market data snapshots are stored under
+-- the coin and its quote currency, such as BTC/USD, with zero volume.
+CREATE TABLE candles (
+    id SERIAL PRIMARY KEY,
+    symbol VARCHAR(30) NOT NULL,
+    resolution VARCHAR(3) NOT NULL,
+    open_time TIMESTAMPTZ NOT NULL,
+    open NUMERIC(30, 10) NOT NULL,
+    high NUMERIC(30, 10) NOT NULL,
+    low NUMERIC(30, 10) NOT NULL,
+    close NUMERIC(30, 10) NOT NULL,
+    volume NUMERIC(30, 10) NOT NULL DEFAULT 0,
+    trade_count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT candles_resolution_valid CHECK (resolution IN ('1m', '5m', '1h', '1d')),
+    CONSTRAINT candles_symbol_resolution_open_time_key UNIQUE (symbol, resolution, open_time)
+);
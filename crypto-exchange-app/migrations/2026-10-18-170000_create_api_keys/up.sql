@@ -0,0 +1,33 @@
+-- Your SQL goes here
+-- Keys for trading clients that sign requests with HMAC-SHA256. The secret
+-- is kept because signatures are checked by recomputing them. An empty
+-- allowed_ips list accepts requests from any address.
+CREATE TABLE api_keys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    label VARCHAR(100) NOT NULL,
+    key VARCHAR(32) NOT NULL UNIQUE,
+    secret VARCHAR(64) NOT NULL,
+    scopes TEXT[] NOT NULL,
+    allowed_ips TEXT[] NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT api_keys_scopes_valid CHECK (
+        cardinality(scopes) > 0 AND scopes <@ ARRAY['read', 'trade', 'withdraw']::TEXT[]
+    )
+);
+
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
+
+-- Nonces seen within the timestamp window, so a signed request cannot be
+-- replayed
+CREATE TABLE api_key_nonces (
+    api_key_id INTEGER NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    nonce VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (api_key_id, nonce)
+);
+
+CREATE INDEX idx_api_key_nonces_created_at ON api_key_nonces(created_at);
@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT false;
+CREATE INDEX idx_users_is_admin ON users(is_admin);
+
+UPDATE users SET is_admin = true
+WHERE id IN (
+    SELECT user_roles.user_id
+    FROM user_roles
+    JOIN roles ON roles.id = user_roles.role_id
+    WHERE roles.name = 'super_admin'
+);
+
+DROP TABLE user_roles;
+DROP TABLE role_permissions;
+DROP TABLE permissions;
+DROP TABLE roles;
@@ -0,0 +1,77 @@
+-- Your SQL goes here
+-- Staff roles and the permissions they grant, replacing users.is_admin.
+-- Admin routes each check one named permission.
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    description VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE permissions (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(50) NOT NULL UNIQUE,
+    description VARCHAR(255) NOT NULL
+);
+
+CREATE TABLE role_permissions (
+    role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    permission_id INTEGER NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission_id)
+);
+
+CREATE TABLE user_roles (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    granted_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE INDEX idx_user_roles_role_id ON user_roles(role_id);
+
+INSERT INTO roles (name, description)
+VALUES
+    ('support', 'Looks up and edits user accounts'),
+    ('kyc_reviewer', 'Reviews identity verifications and documents'),
+    ('finance', 'Credits deposits, processes withdrawals and manages fees'),
+    ('super_admin', 'Full access, including granting roles');
+
+INSERT INTO permissions (name, description)
+VALUES
+    ('users.read', 'View user accounts'),
+    ('users.manage', 'Create users and edit their details'),
+    ('roles.manage', 'Grant and revoke staff roles'),
+    ('kyc.read', 'View the verification queue and documents'),
+    ('kyc.review', 'Approve or reject verifications'),
+    ('funds.deposit', 'Credit deposits'),
+    ('funds.withdrawals', 'Complete or reject withdrawals'),
+    ('fees.read', 'View the fee schedule and overrides'),
+    ('fees.manage', 'Change fee tiers and overrides'),
+    ('instruments.manage', 'Create, update and delete trading instruments');
+
+INSERT INTO role_permissions (role_id, permission_id)
+SELECT roles.id, permissions.id
+FROM roles
+JOIN permissions ON (roles.name, permissions.name) IN (
+    ('support', 'users.read'),
+    ('support', 'users.manage'),
+    ('support', 'kyc.read'),
+    ('kyc_reviewer', 'users.read'),
+    ('kyc_reviewer', 'kyc.read'),
+    ('kyc_reviewer', 'kyc.review'),
+    ('finance', 'users.read'),
+    ('finance', 'funds.deposit'),
+    ('finance', 'funds.withdrawals'),
+    ('finance', 'fees.read'),
+    ('finance', 'fees.manage')
+) OR roles.name = 'super_admin';
+
+-- Existing admins keep full access
+INSERT INTO user_roles (user_id, role_id)
+SELECT users.id, roles.id
+FROM users, roles
+WHERE users.is_admin AND roles.name = 'super_admin';
+
+DROP INDEX idx_users_is_admin;
+ALTER TABLE users DROP COLUMN is_admin;
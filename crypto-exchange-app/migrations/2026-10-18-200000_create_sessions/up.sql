@@ -0,0 +1,27 @@
+-- One row per login session, which is one refresh token family. expires_at
+-- follows the family's newest refresh token.
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    family_id VARCHAR(36) NOT NULL UNIQUE,
+    ip_address VARCHAR(45),
+    user_agent VARCHAR(512),
+    last_ip_address VARCHAR(45),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_sessions_user_id ON sessions(user_id);
+
+-- Sessions started before this table existed, without client details
+INSERT INTO sessions (user_id, family_id, created_at, last_seen_at, expires_at, revoked_at)
+SELECT user_id,
+       family_id,
+       MIN(created_at),
+       MAX(created_at),
+       MAX(expires_at),
+       CASE WHEN BOOL_AND(revoked_at IS NOT NULL) THEN MAX(revoked_at) END
+FROM refresh_tokens
+GROUP BY user_id, family_id;
@@ -0,0 +1,3 @@
+-- Set when the token confirms a change to a new address, which only
+-- replaces users.email once verified
+ALTER TABLE email_verification_tokens ADD COLUMN email VARCHAR(255);
@@ -0,0 +1,23 @@
+DROP TABLE kyc_status_history;
+
+ALTER TABLE user_verifications
+    ADD COLUMN id_verification_status VARCHAR(50) NOT NULL DEFAULT 'not_submitted',
+    ADD COLUMN id_verified_at TIMESTAMPTZ,
+    DROP CONSTRAINT user_verifications_status_check,
+    ALTER COLUMN verification_status SET DEFAULT 'pending';
+
+UPDATE user_verifications
+SET id_verification_status = CASE verification_status
+        WHEN 'submitted' THEN 'pending_review'
+        WHEN 'in_review' THEN 'pending_review'
+        WHEN 'approved' THEN 'approved'
+        WHEN 'rejected' THEN 'rejected'
+        ELSE 'not_submitted'
+    END,
+    id_verified_at = reviewed_at,
+    verification_status = 'pending';
+
+ALTER TABLE user_verifications
+    DROP COLUMN status_reason,
+    DROP COLUMN submitted_at,
+    DROP COLUMN reviewed_at;
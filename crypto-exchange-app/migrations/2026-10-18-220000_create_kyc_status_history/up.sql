@@ -0,0 +1,48 @@
+-- verification_status becomes the one KYC state, moved only by kyc::transition.
+-- id_verification_status only ever tracked the document review, so existing
+-- rows take their state from it.
+ALTER TABLE user_verifications
+    ADD COLUMN status_reason TEXT,
+    ADD COLUMN submitted_at TIMESTAMPTZ,
+    ADD COLUMN reviewed_at TIMESTAMPTZ;
+
+UPDATE user_verifications
+SET verification_status = CASE id_verification_status
+        WHEN 'pending_review' THEN 'submitted'
+        WHEN 'approved' THEN 'approved'
+        WHEN 'rejected' THEN 'rejected'
+        ELSE 'draft'
+    END,
+    submitted_at = CASE WHEN id_front_path IS NOT NULL THEN updated_at END,
+    reviewed_at = id_verified_at,
+    status_reason = CASE
+        WHEN id_verification_status = 'rejected' THEN 'Your ID document could not be verified'
+    END;
+
+ALTER TABLE user_verifications
+    ALTER COLUMN verification_status SET DEFAULT 'draft',
+    ADD CONSTRAINT user_verifications_status_check CHECK (
+        verification_status IN (
+            'draft', 'submitted', 'in_review', 'needs_more_info', 'approved', 'rejected', 'expired'
+        )
+    ),
+    DROP COLUMN id_verification_status,
+    DROP COLUMN id_verified_at;
+
+-- Every change of a verification's state. from_status is NULL for the row
+-- that records its creation; changed_by is NULL for changes the system made.
+CREATE TABLE kyc_status_history (
+    id SERIAL PRIMARY KEY,
+    verification_id INTEGER NOT NULL REFERENCES user_verifications(id) ON DELETE CASCADE,
+    from_status VARCHAR(50),
+    to_status VARCHAR(50) NOT NULL,
+    reason TEXT,
+    changed_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_kyc_status_history_verification_id ON kyc_status_history(verification_id);
+
+INSERT INTO kyc_status_history (verification_id, from_status, to_status, reason, created_at)
+SELECT id, NULL, verification_status, status_reason, updated_at
+FROM user_verifications;
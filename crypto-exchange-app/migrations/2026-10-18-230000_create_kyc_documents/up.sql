@@ -0,0 +1,45 @@
+-- Every file a user uploads for KYC, one per document type. Uploading a type
+-- again replaces the file and sends it back for review.
+CREATE TABLE kyc_documents (
+    id SERIAL PRIMARY KEY,
+    verification_id INTEGER NOT NULL REFERENCES user_verifications(id) ON DELETE CASCADE,
+    document_type VARCHAR(30) NOT NULL CHECK (
+        document_type IN ('id_front', 'id_back', 'selfie', 'proof_of_address')
+    ),
+    file_path VARCHAR(255) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    review_status VARCHAR(20) NOT NULL DEFAULT 'pending' CHECK (
+        review_status IN ('pending', 'approved', 'rejected')
+    ),
+    reviewer_notes TEXT,
+    reviewed_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    reviewed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (verification_id, document_type)
+);
+
+-- The ID documents uploaded so far become the front of each user's ID, with
+-- the outcome of the verification they were part of
+INSERT INTO kyc_documents (
+    verification_id, document_type, file_path, content_type, review_status, reviewed_at,
+    created_at, updated_at
+)
+SELECT id,
+    'id_front',
+    id_front_path,
+    CASE
+        WHEN id_front_path LIKE '%.pdf' THEN 'application/pdf'
+        WHEN id_front_path LIKE '%.png' THEN 'image/png'
+        ELSE 'image/jpeg'
+    END,
+    CASE verification_status
+        WHEN 'approved' THEN 'approved'
+        WHEN 'rejected' THEN 'rejected'
+        ELSE 'pending'
+    END,
+    CASE WHEN verification_status IN ('approved', 'rejected') THEN reviewed_at END,
+    COALESCE(submitted_at, updated_at),
+    updated_at
+FROM user_verifications
+WHERE id_front_path IS NOT NULL;
@@ -0,0 +1,3 @@
+DROP TABLE kyc_document_access_log;
+
+ALTER TABLE kyc_documents RENAME COLUMN storage_key TO file_path;
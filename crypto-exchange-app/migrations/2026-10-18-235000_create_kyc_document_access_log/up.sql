@@ -0,0 +1,17 @@
+-- Documents are kept in a document store under an opaque key. Rows that still
+-- hold an /uploads/id_documents path point at a plaintext file that is moved
+-- into the store, encrypted, when the server starts.
+ALTER TABLE kyc_documents RENAME COLUMN file_path TO storage_key;
+
+-- Every time a document's contents are served, and to whom
+CREATE TABLE kyc_document_access_log (
+    id SERIAL PRIMARY KEY,
+    document_id INTEGER NOT NULL REFERENCES kyc_documents(id) ON DELETE CASCADE,
+    accessed_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    request_id VARCHAR(64),
+    ip_address VARCHAR(45),
+    user_agent VARCHAR(512),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_kyc_document_access_log_document_id ON kyc_document_access_log(document_id);
@@ -0,0 +1,600 @@
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::mfa;
+use crate::models;
+use crate::schema;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, delete, get, post, web};
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::net::IpAddr;
+
+/// Headers a trading client sends instead of `Authorization`
+pub const KEY_HEADER: &str = "X-API-Key";
+pub const TIMESTAMP_HEADER: &str = "X-API-Timestamp";
+pub const NONCE_HEADER: &str = "X-API-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-API-Signature";
+
+/// How far, in milliseconds, a request's timestamp may be from the server
+/// clock. Nonces only have to be remembered for this long.
+const RECV_WINDOW_MS: i64 = 30_000;
+
+const MAX_NONCE_LENGTH: usize = 64;
+const MAX_LABEL_LENGTH: usize = 100;
+const MAX_ACTIVE_KEYS: i64 = 20;
+
+/// What an API key may be used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// GET requests for account data
+    Read,
+    /// Placing and cancelling orders
+    Trade,
+    /// Requesting withdrawals
+    Withdraw,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Trade => "trade",
+            Scope::Withdraw => "withdraw",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "read" => Some(Scope::Read),
+            "trade" => Some(Scope::Trade),
+            "withdraw" => Some(Scope::Withdraw),
+            _ => None,
+        }
+    }
+}
+
+/// The key a request was signed with, stored in the request extensions by
+/// `verify_signature`
+#[derive(Clone, Debug)]
+pub struct ApiKeyIdentity {
+    pub key_id: i32,
+    pub user_id: i32,
+    scopes: Vec<Scope>,
+}
+
+impl ApiKeyIdentity {
+    /// Returns the key's user if the key was granted the scope
+    pub fn require(&self, scope: Scope) -> Result<i32, AppError> {
+        if self.scopes.contains(&scope) {
+            Ok(self.user_id)
+        } else {
+            Err(AppError::forbidden(format!(
+                "API key does not have the '{}' scope",
+                scope.as_str()
+            )))
+        }
+    }
+}
+
+/// Returns the API key a request was authenticated with, if any
+pub fn identity(req: &HttpRequest) -> Option<ApiKeyIdentity> {
+    req.extensions().get::<ApiKeyIdentity>().cloned()
+}
+
+/// Parses an allow-list entry, either an address or a CIDR range
+fn parse_network(entry: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match entry.trim().split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (entry.trim(), None),
+    };
+    let address = address.parse::<IpAddr>().ok()?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u32>().ok().filter(|p| *p <= max_prefix)?,
+        None => max_prefix,
+    };
+    Some((address, prefix))
+}
+
+fn network_contains((network, prefix): (IpAddr, u32), ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn ip_allowed(allowed_ips: &[String], ip: Option<IpAddr>) -> bool {
+    if allowed_ips.is_empty() {
+        return true;
+    }
+    let Some(ip) = ip else {
+        return false;
+    };
+    allowed_ips
+        .iter()
+        .filter_map(|entry| parse_network(entry))
+        .any(|network| network_contains(network, ip))
+}
+
+/// The bytes a client signs: method, path with query string, timestamp and
+/// nonce on separate lines, followed by the raw body
+fn signing_payload(method: &str, path: &str, timestamp: i64, nonce: &str, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+fn signature_matches(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(payload);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    hex::encode(buffer)
+}
+
+/// A request carrying API key headers, as read by `verify_signature`
+struct SignedRequest {
+    key: String,
+    timestamp: i64,
+    nonce: String,
+    signature: String,
+    method: String,
+    path: String,
+    body: web::Bytes,
+    peer: Option<IpAddr>,
+}
+
+/// Errors that can occur while authenticating a signed request
+enum SignatureError {
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    Database(diesel::result::Error),
+}
+
+impl From<SignatureError> for AppError {
+    fn from(e: SignatureError) -> Self {
+        match e {
+            SignatureError::Unauthorized(message) => AppError::unauthorized(message),
+            SignatureError::Forbidden(message) => AppError::forbidden(message),
+            SignatureError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for SignatureError {
+    fn from(e: diesel::result::Error) -> Self {
+        SignatureError::Database(e)
+    }
+}
+
+/// Checks a signed request against its key and records its nonce
+fn authenticate(
+    conn: &mut PgConnection,
+    request: &SignedRequest,
+) -> Result<ApiKeyIdentity, SignatureError> {
+    let now = Utc::now();
+    if (now.timestamp_millis() - request.timestamp).abs() > RECV_WINDOW_MS {
+        return Err(SignatureError::Unauthorized(
+            "Request timestamp is outside the allowed window",
+        ));
+    }
+    if request.nonce.is_empty() || request.nonce.len() > MAX_NONCE_LENGTH {
+        return Err(SignatureError::Unauthorized("Invalid nonce"));
+    }
+
+    let api_key = {
+        use schema::api_keys::dsl::*;
+        api_keys
+            .filter(key.eq(&request.key))
+            .filter(revoked_at.is_null())
+            .first::<models::ApiKey>(conn)
+            .optional()?
+            .ok_or(SignatureError::Unauthorized("Invalid API key"))?
+    };
+
+    if api_key.expires_at.is_some_and(|expires| expires <= now) {
+        return Err(SignatureError::Unauthorized("API key has expired"));
+    }
+    if !ip_allowed(&api_key.allowed_ips, request.peer) {
+        return Err(SignatureError::Forbidden(
+            "Requests from this IP address are not allowed for this API key",
+        ));
+    }
+
+    let payload = signing_payload(
+        &request.method,
+        &request.path,
+        request.timestamp,
+        &request.nonce,
+        &request.body,
+    );
+    if !signature_matches(&api_key.secret, &payload, &request.signature) {
+        return Err(SignatureError::Unauthorized("Invalid signature"));
+    }
+
+    conn.transaction::<_, SignatureError, _>(|conn| {
+        use schema::api_key_nonces::dsl::*;
+
+        let recorded = diesel::insert_into(api_key_nonces)
+            .values((api_key_id.eq(api_key.id), nonce.eq(&request.nonce)))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        if recorded == 0 {
+            return Err(SignatureError::Unauthorized("Nonce has already been used"));
+        }
+
+        // Nonces older than the window cannot be replayed anyway
+        diesel::delete(
+            api_key_nonces
+                .filter(api_key_id.eq(api_key.id))
+                .filter(created_at.lt(now - Duration::milliseconds(2 * RECV_WINDOW_MS))),
+        )
+        .execute(conn)?;
+
+        diesel::update(schema::api_keys::table.find(api_key.id))
+            .set(schema::api_keys::last_used_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    Ok(ApiKeyIdentity {
+        key_id: api_key.id,
+        user_id: api_key.user_id,
+        scopes: api_key
+            .scopes
+            .iter()
+            .filter_map(|scope| Scope::parse(scope))
+            .collect(),
+    })
+}
+
+/// Authenticates requests that carry an `X-API-Key` header. The signature is
+/// an HMAC-SHA256 of `signing_payload` keyed with the API secret, hex
+/// encoded. Requests without the header are passed through for the usual
+/// JWT check in `auth::extract_user_id`.
+pub async fn verify_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if !req.headers().contains_key(KEY_HEADER) {
+        return next.call(req).await;
+    }
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let (Some(key), Some(timestamp), Some(nonce), Some(signature)) = (
+        header(KEY_HEADER),
+        header(TIMESTAMP_HEADER),
+        header(NONCE_HEADER),
+        header(SIGNATURE_HEADER),
+    ) else {
+        return Err(AppError::unauthorized(
+            "Signed requests need X-API-Key, X-API-Timestamp, X-API-Nonce and X-API-Signature headers",
+        )
+        .into());
+    };
+    let timestamp = timestamp.parse::<i64>().map_err(|_| {
+        AppError::unauthorized("X-API-Timestamp must be in milliseconds since the epoch")
+    })?;
+
+    let pool = req
+        .app_data::<web::Data<db::DbPool>>()
+        .cloned()
+        .ok_or_else(|| AppError::internal("Database is not configured"))?;
+    let body = req.extract::<web::Bytes>().await?;
+
+    let request = SignedRequest {
+        key,
+        timestamp,
+        nonce,
+        signature,
+        method: req.method().to_string(),
+        path: req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_default(),
+        body: body.clone(),
+        peer: req.peer_addr().map(|address| address.ip()),
+    };
+
+    let mut conn = pool.get().map_err(AppError::from)?;
+    let identity = web::block(move || authenticate(&mut conn, &request))
+        .await
+        .map_err(AppError::from)?
+        .map_err(AppError::from)?;
+
+    req.extensions_mut().insert(identity);
+    req.set_payload(Payload::from(body));
+    next.call(req).await
+}
+
+/// Checks a new key's settings and returns its scopes, de-duplicated
+fn validate_request(
+    key_data: &models::CreateApiKeyRequest,
+    now: chrono::DateTime<Utc>,
+) -> Result<Vec<String>, String> {
+    let label = key_data.label.trim();
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(format!(
+            "Label must be between 1 and {} characters",
+            MAX_LABEL_LENGTH
+        ));
+    }
+
+    if key_data.scopes.is_empty() {
+        return Err("At least one scope is required".to_string());
+    }
+    let mut scopes = Vec::new();
+    for scope in &key_data.scopes {
+        let scope = Scope::parse(scope).ok_or_else(|| {
+            format!(
+                "Unknown scope '{}', expected read, trade or withdraw",
+                scope
+            )
+        })?;
+        if !scopes.contains(&scope.as_str().to_string()) {
+            scopes.push(scope.as_str().to_string());
+        }
+    }
+
+    if let Some(entry) = key_data
+        .allowed_ips
+        .iter()
+        .find(|entry| parse_network(entry).is_none())
+    {
+        return Err(format!("'{}' is not an IP address or CIDR range", entry));
+    }
+
+    if key_data.expires_at.is_some_and(|expires| expires <= now) {
+        return Err("Expiry must be in the future".to_string());
+    }
+
+    Ok(scopes)
+}
+
+/// Creates an API key. The secret is only returned in this response.
+#[post("/api-keys")]
+pub async fn create_key(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    key_data: web::Json<models::CreateApiKeyRequest>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let key_data = key_data.into_inner();
+
+    let scopes = validate_request(&key_data, Utc::now()).map_err(AppError::BadRequest)?;
+
+    mfa::require_fresh_code(&pool, current_user_id, key_data.totp_code.clone()).await?;
+
+    let mut conn = pool.get()?;
+
+    let new_key = models::NewApiKey {
+        user_id: current_user_id,
+        label: key_data.label.trim().to_string(),
+        key: format!("ak_{}", random_hex(12)),
+        secret: random_hex(32),
+        scopes,
+        allowed_ips: key_data
+            .allowed_ips
+            .iter()
+            .map(|entry| entry.trim().to_string())
+            .collect(),
+        expires_at: key_data.expires_at,
+    };
+    let api_key = web::block(move || {
+        use schema::api_keys::dsl::*;
+
+        let active = api_keys
+            .filter(user_id.eq(current_user_id))
+            .filter(revoked_at.is_null())
+            .count()
+            .get_result::<i64>(&mut conn)?;
+        if active >= MAX_ACTIVE_KEYS {
+            return Err(AppError::bad_request(format!(
+                "At most {} API keys can be active at once",
+                MAX_ACTIVE_KEYS
+            )));
+        }
+
+        Ok(diesel::insert_into(api_keys)
+            .values(&new_key)
+            .get_result::<models::ApiKey>(&mut conn)?)
+    })
+    .await??;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "API key created. Store the secret now; it will not be shown again.",
+        "secret": api_key.secret,
+        "api_key": api_key
+    })))
+}
+
+/// Lists the current user's active API keys, without their secrets
+#[get("/api-keys")]
+pub async fn list_keys(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let keys = web::block(move || {
+        use schema::api_keys::dsl::*;
+
+        api_keys
+            .filter(user_id.eq(current_user_id))
+            .filter(revoked_at.is_null())
+            .order(created_at.desc())
+            .load::<models::ApiKey>(&mut conn)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "api_keys": keys })))
+}
+
+/// Revokes one of the current user's API keys
+#[delete("/api-keys/{key_id}")]
+pub async fn revoke_key(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let key_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let revoked = web::block(move || {
+        use schema::api_keys::dsl::*;
+
+        diesel::update(
+            api_keys
+                .find(key_id)
+                .filter(user_id.eq(current_user_id))
+                .filter(revoked_at.is_null()),
+        )
+        .set(revoked_at.eq(Utc::now()))
+        .execute(&mut conn)
+    })
+    .await??;
+
+    if revoked == 0 {
+        return Err(AppError::not_found("API key not found"));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "API key revoked"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn contains(entry: &str, address: &str) -> bool {
+        network_contains(parse_network(entry).unwrap(), ip(address))
+    }
+
+    #[test]
+    fn bare_addresses_are_single_host_networks() {
+        assert_eq!(parse_network(" 10.0.0.1 "), Some((ip("10.0.0.1"), 32)));
+        assert_eq!(parse_network("2001:db8::1"), Some((ip("2001:db8::1"), 128)));
+    }
+
+    #[test]
+    fn full_length_prefixes_match_one_address() {
+        assert!(contains("203.0.113.7/32", "203.0.113.7"));
+        assert!(!contains("203.0.113.7/32", "203.0.113.8"));
+        assert!(contains("2001:db8::7/128", "2001:db8::7"));
+        assert!(!contains("2001:db8::7/128", "2001:db8::8"));
+    }
+
+    #[test]
+    fn zero_prefixes_match_every_address_of_their_family() {
+        assert!(contains("0.0.0.0/0", "198.51.100.20"));
+        assert!(contains("10.1.2.3/0", "255.255.255.255"));
+        assert!(contains("::/0", "2001:db8:ffff::1"));
+    }
+
+    #[test]
+    fn ranges_match_by_prefix() {
+        assert!(contains("192.168.4.0/22", "192.168.7.255"));
+        assert!(!contains("192.168.4.0/22", "192.168.8.0"));
+        assert!(contains("2001:db8:abcd::/48", "2001:db8:abcd:12::1"));
+        assert!(!contains("2001:db8:abcd::/48", "2001:db8:abce::1"));
+    }
+
+    #[test]
+    fn families_never_match_each_other() {
+        assert!(!contains("0.0.0.0/0", "::ffff:10.0.0.1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+        assert!(!ip_allowed(&["::1".to_string()], Some(ip("127.0.0.1"))));
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        for entry in [
+            "",
+            "10.0.0.1/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/abc",
+            "10.0.0.256",
+            "10.0.0.0/8/8",
+            "example.com",
+        ] {
+            assert_eq!(parse_network(entry), None, "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped_not_trusted() {
+        let allowed = ["not-an-ip".to_string(), "10.0.0.0/8".to_string()];
+
+        assert!(ip_allowed(&allowed, Some(ip("10.20.30.40"))));
+        assert!(!ip_allowed(&allowed, Some(ip("11.0.0.1"))));
+        assert!(!ip_allowed(&allowed, None));
+        assert!(ip_allowed(&[], None));
+    }
+
+    #[test]
+    fn signature_matches_known_answer() {
+        let payload = signing_payload(
+            "POST",
+            "/user/orders?symbol=BTC-USDT",
+            1_700_000_000_000,
+            "n0nce-42",
+            br#"{"side":"buy","quantity":"0.5"}"#,
+        );
+        let expected = "9838852df5af08f2dc1a055005c83d40cf90eec43ff1f391fe49c0d589acd3ac";
+
+        assert_eq!(
+            payload,
+            b"POST\n/user/orders?symbol=BTC-USDT\n1700000000000\nn0nce-42\n{\"side\":\"buy\",\"quantity\":\"0.5\"}"
+        );
+        assert!(signature_matches("sk_test_secret", &payload, expected));
+        assert!(signature_matches(
+            "sk_test_secret",
+            &payload,
+            &expected.to_uppercase()
+        ));
+        assert!(!signature_matches("sk_other_secret", &payload, expected));
+        assert!(!signature_matches(
+            "sk_test_secret",
+            &payload[1..],
+            expected
+        ));
+        assert!(!signature_matches("sk_test_secret", &payload, "not hex"));
+    }
+}
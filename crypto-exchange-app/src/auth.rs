@@ -1,113 +1,617 @@
+use crate::api_keys::{self, Scope};
+use crate::config;
 use crate::db;
+use crate::error::AppError;
+use crate::models;
+use crate::roles;
 use crate::schema;
-use actix_web::{HttpRequest, HttpResponse, error::ErrorUnauthorized};
-use chrono::{Duration, Utc};
+use crate::sessions::{self, ClientInfo};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, http::Method, post, web};
+use chrono::{DateTime, Duration, Utc};
+use diesel::dsl::exists;
 use diesel::prelude::*;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
-use log::error;
+use log::{error, warn};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::env;
+use sha2::{Digest, Sha256};
+
+/// How long an access token is accepted. Clients renew it with their refresh
+/// token rather than logging in again.
+const ACCESS_TOKEN_TTL: Duration = Duration::minutes(15);
+
+/// How long a refresh token can be used, counted from when it was issued
+const REFRESH_TOKEN_TTL: Duration = Duration::days(30);
+
+/// How long a user has to enter their TOTP code after their password
+pub const MFA_TOKEN_TTL: Duration = Duration::minutes(5);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // Subject (user_id)
     pub exp: i64,    // Expiration time
     pub iat: i64,    // Issued at
+    pub jti: String, // Token id, checked against revoked_tokens
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Result<i32, AppError> {
+        self.sub
+            .parse::<i32>()
+            .map_err(|_| AppError::unauthorized("Invalid user ID in token"))
+    }
+}
+
+/// Access and refresh tokens handed to a client when it logs in or refreshes
+#[derive(Debug, Serialize)]
+pub struct Tokens {
+    /// Access token sent as `Authorization: Bearer <token>`
+    pub token: String,
+    pub refresh_token: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+    /// Set when a login comes from a device or IP address the user has not
+    /// logged in from before
+    #[serde(skip)]
+    pub new_device: bool,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    InvalidRefreshToken,
+    Token(jsonwebtoken::errors::Error),
+    Database(diesel::result::Error),
+}
+
+impl From<SessionError> for AppError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::InvalidRefreshToken => {
+                AppError::unauthorized("Invalid or expired refresh token")
+            }
+            SessionError::Token(e) => {
+                error!("Failed to generate authentication token: {}", e);
+                AppError::internal("Failed to generate authentication token")
+            }
+            SessionError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for SessionError {
+    fn from(e: diesel::result::Error) -> Self {
+        SessionError::Database(e)
+    }
 }
 
-pub fn generate_token(user_id: i32) -> Result<String, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "2117d884ab7b763f40f0c8e6a946d99016985544c851194b6add24bc23eb5d51ce2e4fa05099bf4030a0202e179bebf37645a12e022b17ab1cb31be3b06992e3".to_string());
-    let expiration = Utc::now() + Duration::hours(24);
+impl From<jsonwebtoken::errors::Error> for SessionError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        SessionError::Token(e)
+    }
+}
+
+fn jwt_secret() -> &'static str {
+    &config::get().auth.jwt_secret
+}
+
+pub fn generate_token(
+    user_id: i32,
+    jti: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: expires_at.timestamp(),
+        iat: Utc::now().timestamp(),
+        jti: jti.to_string(),
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret().as_bytes()),
+    )
+}
+
+/// Tokens for the second login step are signed with their own key, so they
+/// can never be used as access tokens
+fn mfa_secret() -> String {
+    format!("{}:mfa", jwt_secret())
+}
 
+/// Issues the token a password login returns when the user still has to pass
+/// two-factor authentication
+pub fn generate_mfa_token(user_id: i32) -> Result<String, jsonwebtoken::errors::Error> {
     let claims = Claims {
         sub: user_id.to_string(),
-        exp: expiration.timestamp(),
+        exp: (Utc::now() + MFA_TOKEN_TTL).timestamp(),
         iat: Utc::now().timestamp(),
+        jti: uuid::Uuid::new_v4().to_string(),
     };
 
     encode(
         &Header::default(),
         &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
+        &EncodingKey::from_secret(mfa_secret().as_bytes()),
     )
 }
 
-pub fn extract_user_id(req: &HttpRequest) -> Result<i32, actix_web::Error> {
+/// Returns the claims of a second-step token, including the user it was
+/// issued to
+pub fn decode_mfa_token(token: &str) -> Result<Claims, AppError> {
+    Ok(decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(mfa_secret().as_bytes()),
+        &Validation::default(),
+    )?
+    .claims)
+}
+
+/// Records a second-step token as used, so it can complete only one login.
+/// Returns false if it already had been. Used tokens go on the revocation
+/// list until they expire, like revoked access tokens.
+pub fn use_mfa_token(conn: &mut PgConnection, owner: i32, claims: &Claims) -> QueryResult<bool> {
+    let recorded = diesel::insert_into(schema::revoked_tokens::table)
+        .values(&models::NewRevokedToken {
+            jti: claims.jti.clone(),
+            user_id: owner,
+            expires_at: DateTime::from_timestamp(claims.exp, 0).unwrap_or_else(Utc::now),
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(recorded == 1)
+}
+
+/// Secrets handed to clients, such as refresh tokens, are only stored as
+/// their SHA-256 digest
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn new_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Stores a refresh token for a session and signs the access token that goes
+/// with it
+fn issue_in_family(
+    conn: &mut PgConnection,
+    owner: i32,
+    family: &str,
+) -> Result<(models::RefreshToken, Tokens), SessionError> {
+    let now = Utc::now();
+    let jti = uuid::Uuid::new_v4().to_string();
+    let refresh_token = new_refresh_token();
+
+    let token = generate_token(owner, &jti, now + ACCESS_TOKEN_TTL)?;
+
+    let session = diesel::insert_into(schema::refresh_tokens::table)
+        .values(&models::NewRefreshToken {
+            user_id: owner,
+            family_id: family.to_string(),
+            token_hash: hash_token(&refresh_token),
+            access_jti: jti,
+            access_expires_at: now + ACCESS_TOKEN_TTL,
+            expires_at: now + REFRESH_TOKEN_TTL,
+        })
+        .get_result::<models::RefreshToken>(conn)?;
+
+    Ok((
+        session,
+        Tokens {
+            token,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_TTL.num_seconds(),
+            new_device: false,
+        },
+    ))
+}
+
+/// Starts a new session for a user who has just logged in or signed up,
+/// recording the client it was started from
+pub fn issue_tokens(
+    conn: &mut PgConnection,
+    owner: i32,
+    client: &ClientInfo,
+) -> Result<Tokens, SessionError> {
+    conn.transaction(|conn| {
+        let family = uuid::Uuid::new_v4().to_string();
+        let (first, mut tokens) = issue_in_family(conn, owner, &family)?;
+        tokens.new_device = sessions::record(conn, owner, &family, client, first.expires_at)?;
+        Ok(tokens)
+    })
+}
+
+/// Exchanges a refresh token for new tokens in the same session, revoking the
+/// one presented. Presenting a token that was already exchanged means it has
+/// leaked, so the whole session is revoked.
+pub fn rotate_refresh_token(
+    conn: &mut PgConnection,
+    presented: &str,
+    client: &ClientInfo,
+) -> Result<Tokens, SessionError> {
+    use schema::refresh_tokens::dsl::*;
+
+    let presented_hash = hash_token(presented);
+    let rotated = conn.transaction::<_, SessionError, _>(|conn| {
+        let Some(current) = refresh_tokens
+            .filter(token_hash.eq(&presented_hash))
+            .for_update()
+            .first::<models::RefreshToken>(conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        if current.replaced_by.is_some() {
+            warn!(
+                "Refresh token reused for user {}, revoking its session",
+                current.user_id
+            );
+            revoke_families(conn, &[current.family_id])?;
+            return Ok(None);
+        }
+        if current.revoked_at.is_some() || current.expires_at <= Utc::now() {
+            return Ok(None);
+        }
+
+        let (next, tokens) = issue_in_family(conn, current.user_id, &current.family_id)?;
+        diesel::update(refresh_tokens.find(current.id))
+            .set((revoked_at.eq(Utc::now()), replaced_by.eq(next.id)))
+            .execute(conn)?;
+        sessions::touch(conn, &current.family_id, client, next.expires_at)?;
+
+        Ok(Some(tokens))
+    })?;
+
+    rotated.ok_or(SessionError::InvalidRefreshToken)
+}
+
+/// Adds the access tokens of revoked sessions to the revocation list, and
+/// drops entries for tokens that have expired anyway
+fn revoke_access_tokens(
+    conn: &mut PgConnection,
+    tokens: Vec<models::NewRevokedToken>,
+) -> QueryResult<()> {
+    use schema::revoked_tokens::dsl::*;
+
+    if !tokens.is_empty() {
+        diesel::insert_into(revoked_tokens)
+            .values(&tokens)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+    diesel::delete(revoked_tokens.filter(expires_at.le(Utc::now()))).execute(conn)?;
+    Ok(())
+}
+
+/// Revokes every refresh token in the given sessions along with any access
+/// token issued to them that has not expired. Returns the number of sessions
+/// that were still active.
+pub fn revoke_families(conn: &mut PgConnection, families: &[String]) -> QueryResult<usize> {
+    use schema::refresh_tokens::dsl::*;
+
+    let now = Utc::now();
+    let active = diesel::update(
+        refresh_tokens
+            .filter(family_id.eq_any(families))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(now))
+    .execute(conn)?;
+
+    let live = refresh_tokens
+        .filter(family_id.eq_any(families))
+        .filter(access_expires_at.gt(now))
+        .select((access_jti, user_id, access_expires_at))
+        .load::<(String, i32, DateTime<Utc>)>(conn)?
+        .into_iter()
+        .map(|(jti, owner, expires)| models::NewRevokedToken {
+            jti,
+            user_id: owner,
+            expires_at: expires,
+        })
+        .collect();
+    revoke_access_tokens(conn, live)?;
+    sessions::mark_revoked(conn, families)?;
+
+    Ok(active)
+}
+
+/// Ends the session an access token belongs to
+fn revoke_session(conn: &mut PgConnection, claims: &Claims) -> QueryResult<()> {
+    use schema::refresh_tokens::dsl::*;
+
+    conn.transaction(|conn| {
+        let family = refresh_tokens
+            .filter(access_jti.eq(&claims.jti))
+            .select(family_id)
+            .first::<String>(conn)
+            .optional()?;
+        if let Some(family) = family {
+            revoke_families(conn, &[family])?;
+        }
+
+        // Also covers tokens whose session row is gone
+        let owner = claims.user_id().unwrap_or_default();
+        revoke_access_tokens(
+            conn,
+            vec![models::NewRevokedToken {
+                jti: claims.jti.clone(),
+                user_id: owner,
+                expires_at: DateTime::from_timestamp(claims.exp, 0).unwrap_or_else(Utc::now),
+            }],
+        )
+    })
+}
+
+/// Ends every session of a user, so all of their tokens stop working. Used
+/// for "log out everywhere" and whenever a user's credentials or privileges
+/// change. Returns the number of sessions that were still active.
+pub fn revoke_all_sessions(conn: &mut PgConnection, owner: i32) -> QueryResult<usize> {
+    use schema::refresh_tokens::dsl::*;
+
+    conn.transaction(|conn| {
+        let families = refresh_tokens
+            .filter(user_id.eq(owner))
+            .select(family_id)
+            .distinct()
+            .load::<String>(conn)?;
+        revoke_families(conn, &families)
+    })
+}
+
+/// Ends every session of a user except the one an access token belongs to,
+/// so a user who changes their password stays logged in where they did it.
+/// Returns the number of sessions that were still active.
+pub fn revoke_other_sessions(
+    conn: &mut PgConnection,
+    owner: i32,
+    current_jti: &str,
+) -> QueryResult<usize> {
+    use schema::refresh_tokens::dsl::*;
+
+    conn.transaction(|conn| {
+        let current_family = refresh_tokens
+            .filter(access_jti.eq(current_jti))
+            .select(family_id)
+            .first::<String>(conn)
+            .optional()?;
+        let families = refresh_tokens
+            .filter(user_id.eq(owner))
+            .filter(family_id.nullable().ne_all(current_family))
+            .select(family_id)
+            .distinct()
+            .load::<String>(conn)?;
+        revoke_families(conn, &families)
+    })
+}
+
+/// Decodes a JWT and checks its signature and expiry. Whether it has since
+/// been revoked is checked separately by `token_is_live`.
+fn decode_token(token: &str) -> Result<Claims, AppError> {
+    let token_data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret().as_bytes()),
+        &Validation::default(),
+    )?;
+    Ok(token_data.claims)
+}
+
+/// Whether a decoded token is still accepted: it has not been revoked and its
+/// user still exists
+fn token_is_live(conn: &mut PgConnection, claims: &Claims) -> Result<bool, AppError> {
+    let owner = claims.user_id()?;
+    let (revoked, user_exists) = diesel::select((
+        exists(schema::revoked_tokens::table.find(&claims.jti)),
+        exists(schema::users::table.find(owner)),
+    ))
+    .get_result::<(bool, bool)>(conn)?;
+    Ok(!revoked && user_exists)
+}
+
+/// Outcome of the revocation check `check_bearer_token` ran for a request
+#[derive(Clone)]
+struct TokenLiveness {
+    jti: String,
+    live: bool,
+}
+
+/// Returns the token of a `Bearer` authorization header, if there is one
+fn bearer_token(req: &HttpRequest) -> Result<&str, AppError> {
     let auth_header = req
         .headers()
         .get("Authorization")
-        .ok_or_else(|| ErrorUnauthorized("Missing authorization header"))?;
+        .ok_or_else(|| AppError::unauthorized("Missing authorization header"))?;
 
     let auth_str = auth_header
         .to_str()
-        .map_err(|_| ErrorUnauthorized("Invalid authorization header"))?;
+        .map_err(|_| AppError::unauthorized("Invalid authorization header"))?;
+
+    auth_str
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| AppError::unauthorized("Invalid authorization format"))
+}
+
+/// Looks up whether the bearer token of a request has been revoked before the
+/// handler runs, so that `authenticate` can stay synchronous without querying
+/// the database on the async workers. Requests without a valid token are
+/// passed through; the route decides whether it needs one.
+pub async fn check_bearer_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Ok(claims) = bearer_token(req.request()).and_then(decode_token) else {
+        return next.call(req).await;
+    };
 
-    if !auth_str.starts_with("Bearer ") {
-        return Err(ErrorUnauthorized("Invalid authorization format"));
+    let pool = req
+        .app_data::<web::Data<db::DbPool>>()
+        .cloned()
+        .ok_or_else(|| AppError::internal("Database is not configured"))?;
+    let jti = claims.jti.clone();
+    let live = web::block(move || -> Result<bool, AppError> {
+        let mut conn = pool.get()?;
+        token_is_live(&mut conn, &claims)
+    })
+    .await
+    .map_err(AppError::from)??;
+
+    req.extensions_mut().insert(TokenLiveness { jti, live });
+    next.call(req).await
+}
+
+/// Verifies the bearer token of a request, using the revocation check
+/// `check_bearer_token` ran for it. Only login sessions pass; API keys do not.
+pub fn authenticate(req: &HttpRequest) -> Result<Claims, AppError> {
+    let claims = decode_token(bearer_token(req)?)?;
+
+    let liveness = req
+        .extensions()
+        .get::<TokenLiveness>()
+        .cloned()
+        .filter(|liveness| liveness.jti == claims.jti)
+        .ok_or_else(|| AppError::internal("Token revocation check did not run"))?;
+    if !liveness.live {
+        return Err(AppError::unauthorized("Token has been revoked"));
     }
 
-    let token = &auth_str[7..]; // Skip "Bearer "
+    Ok(claims)
+}
 
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "2117d884ab7b763f40f0c8e6a946d99016985544c851194b6add24bc23eb5d51ce2e4fa05099bf4030a0202e179bebf37645a12e022b17ab1cb31be3b06992e3".to_string());
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|_| ErrorUnauthorized("Invalid token"))?;
+/// Returns the user a request is authenticated as, by JWT or by API key.
+/// API keys are only accepted here for reads; routes that trade or withdraw
+/// use `extract_user_id_for` with the scope they need.
+pub fn extract_user_id(req: &HttpRequest) -> Result<i32, AppError> {
+    match api_keys::identity(req) {
+        Some(api_key) if req.method() == Method::GET => api_key.require(Scope::Read),
+        Some(_) => Err(AppError::forbidden(
+            "API keys cannot be used for this action; log in instead",
+        )),
+        None => authenticate(req)?.user_id(),
+    }
+}
 
-    let user_id = token_data
-        .claims
-        .sub
-        .parse::<i32>()
-        .map_err(|_| ErrorUnauthorized("Invalid user ID in token"))?;
+/// Like `extract_user_id`, but accepts API keys granted the given scope
+pub fn extract_user_id_for(req: &HttpRequest, scope: Scope) -> Result<i32, AppError> {
+    match api_keys::identity(req) {
+        Some(api_key) => api_key.require(scope),
+        None => authenticate(req)?.user_id(),
+    }
+}
 
-    Ok(user_id)
+/// Validates a JWT and returns the user id it was issued for. Used directly
+/// by clients that cannot send an Authorization header, such as browser
+/// WebSockets.
+pub async fn decode_user_id(pool: &db::DbPool, token: &str) -> Result<i32, AppError> {
+    let claims = decode_token(token)?;
+    let owner = claims.user_id()?;
+    let pool = pool.clone();
+    let live = web::block(move || -> Result<bool, AppError> {
+        let mut conn = pool.get()?;
+        token_is_live(&mut conn, &claims)
+    })
+    .await??;
+    if !live {
+        return Err(AppError::unauthorized("Token has been revoked"));
+    }
+    Ok(owner)
 }
 
-pub async fn require_admin(req: &HttpRequest, pool: &db::DbPool) -> Result<bool, HttpResponse> {
-    // First extract the user ID from the JWT token
-    let user_id = match extract_user_id(req) {
-        Ok(user_id_value) => user_id_value,
-        Err(_) => {
-            return Err(HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Authentication required"
-            })));
-        }
-    };
+/// Identifies the staff member behind an admin request. Admin actions always
+/// need a login session, so API keys are refused.
+fn admin_user_id(req: &HttpRequest) -> Result<i32, AppError> {
+    if api_keys::identity(req).is_some() {
+        return Err(AppError::forbidden(
+            "API keys cannot be used for admin actions",
+        ));
+    }
 
-    // Get a database connection
-    let mut conn = match pool.get() {
-        Ok(conn) => conn,
-        Err(e) => {
-            error!("Failed to get database connection: {}", e);
-            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Database error"
-            })));
-        }
-    };
+    authenticate(req)?.user_id()
+}
 
-    // Query the database to check if the user is an admin
-    use schema::users::dsl::*;
-    let is_user_admin = match users
-        .filter(id.eq(user_id))
-        .select(is_admin)
-        .first::<bool>(&mut conn)
-    {
-        Ok(admin_status) => admin_status,
-        Err(e) => {
-            error!("Failed to query user admin status: {}", e);
-            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Database error"
-            })));
-        }
-    };
+/// Checks that the caller holds at least one staff role and returns their
+/// user ID
+pub async fn require_staff(req: &HttpRequest, pool: &db::DbPool) -> Result<i32, AppError> {
+    let user_id = admin_user_id(req)?;
 
-    if is_user_admin {
-        Ok(true)
-    } else {
-        Err(HttpResponse::Forbidden().json(serde_json::json!({
-            "error": "Admin access required"
-        })))
+    let mut conn = pool.get()?;
+    if roles::role_names(&mut conn, user_id)?.is_empty() {
+        return Err(AppError::forbidden("Admin access required"));
     }
+    Ok(user_id)
+}
+
+/// Checks that the caller holds `permission` through one of their roles and
+/// returns their user ID
+pub async fn require_permission(
+    req: &HttpRequest,
+    pool: &db::DbPool,
+    permission: roles::Permission,
+) -> Result<i32, AppError> {
+    let user_id = admin_user_id(req)?;
+
+    let mut conn = pool.get()?;
+    if !roles::has_permission(&mut conn, user_id, permission)? {
+        return Err(AppError::PermissionDenied(permission));
+    }
+    Ok(user_id)
+}
+
+/// Exchanges a refresh token for a new access token and refresh token. The
+/// refresh token presented stops working.
+#[post("/refresh")]
+pub async fn refresh(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    refresh_data: web::Json<models::RefreshRequest>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.get()?;
+
+    let presented = refresh_data.into_inner().refresh_token;
+    let client = ClientInfo::from_request(&req);
+    let tokens = web::block(move || rotate_refresh_token(&mut conn, &presented, &client)).await??;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+/// Ends the current session: its access token and refresh token stop working
+#[post("/logout")]
+pub async fn logout(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let claims = authenticate(&req)?;
+    let mut conn = pool.get()?;
+
+    web::block(move || revoke_session(&mut conn, &claims)).await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Logged out"
+    })))
+}
+
+/// Ends every session of the current user, including this one
+#[post("/logout-all")]
+pub async fn logout_all(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let revoked = web::block(move || revoke_all_sessions(&mut conn, current_user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Logged out of all sessions",
+        "sessions_revoked": revoked
+    })))
 }
@@ -0,0 +1,503 @@
+use crate::db;
+use crate::error::AppError;
+use crate::ledger;
+use crate::markets::MarketsResponse;
+use crate::models;
+use crate::schema;
+use actix_web::{HttpResponse, get, web};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Double, Integer, Numeric, Timestamptz, Varchar};
+use log::info;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::FromPrimitive;
+use std::collections::BTreeMap;
+
+/// Most candles a single request may cover
+const MAX_CANDLES: i64 = 1000;
+
+/// Candles returned when a request gives no `from`
+const DEFAULT_CANDLES: i64 = 500;
+
+/// Candle resolutions, smallest first. Each one is rolled up from the one
+/// before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resolution {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 4] = [
+        Resolution::OneMinute,
+        Resolution::FiveMinutes,
+        Resolution::OneHour,
+        Resolution::OneDay,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::OneMinute => "1m",
+            Resolution::FiveMinutes => "5m",
+            Resolution::OneHour => "1h",
+            Resolution::OneDay => "1d",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Resolution> {
+        Resolution::ALL
+            .into_iter()
+            .find(|resolution| resolution.as_str() == value)
+    }
+
+    fn seconds(&self) -> i64 {
+        match self {
+            Resolution::OneMinute => 60,
+            Resolution::FiveMinutes => 5 * 60,
+            Resolution::OneHour => 60 * 60,
+            Resolution::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::seconds(self.seconds())
+    }
+
+    /// Start of the candle that contains `time`. Days start at midnight UTC.
+    fn bucket(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = time.timestamp();
+        let start = seconds - seconds.rem_euclid(self.seconds());
+        Utc.timestamp_opt(start, 0).single().unwrap_or(time)
+    }
+}
+
+/// One price observation: a trade, or a market data snapshot with no volume
+struct Tick {
+    time: DateTime<Utc>,
+    price: Decimal,
+    quantity: Decimal,
+    is_trade: bool,
+}
+
+/// Aggregated prices of one candle before it is stored
+struct Bar {
+    open_time: DateTime<Utc>,
+    open: Decimal,
+    high: Decimal,
+    low: Decimal,
+    close: Decimal,
+    volume: Decimal,
+    trade_count: i32,
+}
+
+impl Bar {
+    fn new(open_time: DateTime<Utc>, price: Decimal) -> Self {
+        Bar {
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: Decimal::ZERO,
+            trade_count: 0,
+        }
+    }
+}
+
+/// How a stored candle is combined with a new bar for the same period
+#[derive(Clone, Copy, PartialEq)]
+enum Merge {
+    /// Extend the stored candle with later ticks
+    Extend,
+    /// Replace the stored candle, as imported history does
+    Replace,
+}
+
+fn upsert_bar(
+    conn: &mut PgConnection,
+    symbol: &str,
+    resolution: Resolution,
+    bar: &Bar,
+    merge: Merge,
+) -> QueryResult<()> {
+    let on_conflict = match merge {
+        Merge::Extend => {
+            "high = GREATEST(candles.high, EXCLUDED.high), \
+             low = LEAST(candles.low, EXCLUDED.low), \
+             close = EXCLUDED.close, \
+             volume = candles.volume + EXCLUDED.volume, \
+             trade_count = candles.trade_count + EXCLUDED.trade_count"
+        }
+        Merge::Replace => {
+            "open = EXCLUDED.open, high = EXCLUDED.high, low = EXCLUDED.low, \
+             close = EXCLUDED.close, volume = EXCLUDED.volume, \
+             trade_count = EXCLUDED.trade_count"
+        }
+    };
+
+    diesel::sql_query(format!(
+        "INSERT INTO candles (symbol, resolution, open_time, open, high, low, close, volume, trade_count) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+         ON CONFLICT (symbol, resolution, open_time) DO UPDATE SET {}, updated_at = CURRENT_TIMESTAMP",
+        on_conflict
+    ))
+    .bind::<Varchar, _>(symbol)
+    .bind::<Varchar, _>(resolution.as_str())
+    .bind::<Timestamptz, _>(bar.open_time)
+    .bind::<Numeric, _>(bar.open)
+    .bind::<Numeric, _>(bar.high)
+    .bind::<Numeric, _>(bar.low)
+    .bind::<Numeric, _>(bar.close)
+    .bind::<Numeric, _>(bar.volume)
+    .bind::<Integer, _>(bar.trade_count)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Recomputes every candle above `base` that overlaps `from..to` from the
+/// candles one resolution below it
+fn roll_up(
+    conn: &mut PgConnection,
+    symbol: &str,
+    base: Resolution,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> QueryResult<()> {
+    let mut source = base;
+    for target in Resolution::ALL.into_iter().filter(|r| *r > base) {
+        let from_bucket = target.bucket(from);
+        let to_bucket = target.bucket(to - Duration::seconds(1)) + target.duration();
+
+        diesel::sql_query(
+            "INSERT INTO candles (symbol, resolution, open_time, open, high, low, close, volume, trade_count) \
+             SELECT symbol, $2, to_timestamp(floor(extract(epoch FROM open_time) / $3) * $3) AS bucket, \
+                 (array_agg(open ORDER BY open_time))[1], max(high), min(low), \
+                 (array_agg(close ORDER BY open_time DESC))[1], sum(volume), sum(trade_count) \
+             FROM candles \
+             WHERE symbol = $1 AND resolution = $4 AND open_time >= $5 AND open_time < $6 \
+             GROUP BY symbol, bucket \
+             ON CONFLICT (symbol, resolution, open_time) DO UPDATE SET \
+                 open = EXCLUDED.open, high = EXCLUDED.high, low = EXCLUDED.low, \
+                 close = EXCLUDED.close, volume = EXCLUDED.volume, \
+                 trade_count = EXCLUDED.trade_count, updated_at = CURRENT_TIMESTAMP",
+        )
+        .bind::<Varchar, _>(symbol)
+        .bind::<Varchar, _>(target.as_str())
+        .bind::<Double, _>(target.seconds() as f64)
+        .bind::<Varchar, _>(source.as_str())
+        .bind::<Timestamptz, _>(from_bucket)
+        .bind::<Timestamptz, _>(to_bucket)
+        .execute(conn)?;
+
+        source = target;
+    }
+    Ok(())
+}
+
+/// Folds ticks, oldest first, into minute candles and rolls them up
+fn record_ticks(conn: &mut PgConnection, symbol: &str, ticks: &[Tick]) -> QueryResult<()> {
+    let mut bars: BTreeMap<DateTime<Utc>, Bar> = BTreeMap::new();
+    for tick in ticks {
+        let open_time = Resolution::OneMinute.bucket(tick.time);
+        let bar = bars
+            .entry(open_time)
+            .or_insert_with(|| Bar::new(open_time, tick.price));
+        bar.high = bar.high.max(tick.price);
+        bar.low = bar.low.min(tick.price);
+        bar.close = tick.price;
+        bar.volume += tick.quantity;
+        if tick.is_trade {
+            bar.trade_count += 1;
+        }
+    }
+
+    let (Some(first), Some(last)) = (bars.keys().next().copied(), bars.keys().last().copied())
+    else {
+        return Ok(());
+    };
+    for bar in bars.values() {
+        upsert_bar(conn, symbol, Resolution::OneMinute, bar, Merge::Extend)?;
+    }
+    roll_up(
+        conn,
+        symbol,
+        Resolution::OneMinute,
+        first,
+        last + Resolution::OneMinute.duration(),
+    )
+}
+
+/// Adds stored fills of an exchange pair to its candles. Called in the
+/// transaction that stores the fills.
+pub fn record_fills(
+    conn: &mut PgConnection,
+    symbol: &str,
+    fills: &[models::FillRecord],
+) -> QueryResult<()> {
+    let ticks: Vec<Tick> = fills
+        .iter()
+        .map(|fill| Tick {
+            time: fill.created_at,
+            price: fill.price,
+            quantity: fill.quantity,
+            is_trade: true,
+        })
+        .collect();
+    record_ticks(conn, symbol, &ticks)
+}
+
+/// Adds the prices of a market data fetch to the candles of each coin, under
+/// the coin and its quote currency, such as `BTC/USD`
+pub fn record_snapshot(
+    conn: &mut PgConnection,
+    markets: &MarketsResponse,
+    time: DateTime<Utc>,
+) -> QueryResult<usize> {
+    conn.transaction(|conn| {
+        let mut recorded = 0;
+        for crypto in &markets.cryptocurrencies {
+            let Some(price) = Decimal::from_f64(crypto.price)
+                .map(|price| price.round_dp(ledger::SCALE))
+                .filter(|price| *price > Decimal::ZERO)
+            else {
+                continue;
+            };
+
+            let symbol = format!("{}/{}", crypto.symbol, markets.quote_currency);
+            let tick = Tick {
+                time,
+                price,
+                quantity: Decimal::ZERO,
+                is_trade: false,
+            };
+            record_ticks(conn, &symbol, &[tick])?;
+            recorded += 1;
+        }
+        Ok(recorded)
+    })
+}
+
+/// Parses a CSV timestamp: RFC 3339, or Unix time in seconds or milliseconds
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let number = value.parse::<i64>().ok()?;
+    if number.abs() >= 100_000_000_000 {
+        Utc.timestamp_millis_opt(number).single()
+    } else {
+        Utc.timestamp_opt(number, 0).single()
+    }
+}
+
+/// Parses one `timestamp,open,high,low,close[,volume]` row
+fn parse_row(line: &str, resolution: Resolution) -> Result<Bar, String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() < 5 || fields.len() > 6 {
+        return Err("expected timestamp,open,high,low,close[,volume]".to_string());
+    }
+
+    let open_time =
+        parse_timestamp(fields[0]).ok_or_else(|| format!("invalid timestamp {}", fields[0]))?;
+    if resolution.bucket(open_time) != open_time {
+        return Err(format!(
+            "{} is not the start of a {} candle",
+            fields[0],
+            resolution.as_str()
+        ));
+    }
+
+    let number = |value: &str| {
+        value
+            .parse::<Decimal>()
+            .map(|number| number.round_dp(ledger::SCALE))
+            .map_err(|_| format!("invalid number {}", value))
+    };
+    let (open, high, low, close) = (
+        number(fields[1])?,
+        number(fields[2])?,
+        number(fields[3])?,
+        number(fields[4])?,
+    );
+    let volume = match fields.get(5) {
+        Some(volume) => number(volume)?,
+        None => Decimal::ZERO,
+    };
+
+    if low <= Decimal::ZERO || volume < Decimal::ZERO {
+        return Err("prices must be positive and volume not negative".to_string());
+    }
+    if high < open.max(close).max(low) || low > open.min(close) {
+        return Err("high and low must bound open and close".to_string());
+    }
+
+    Ok(Bar {
+        open_time,
+        open,
+        high,
+        low,
+        close,
+        volume,
+        trade_count: 0,
+    })
+}
+
+/// Imports candle history from CSV and rolls it up to the larger
+/// resolutions. Rows replace candles already stored for their period. A
+/// header row and lines starting with `#` are skipped. The whole file is
+/// imported in one transaction, so a bad row imports nothing.
+pub fn import_csv(
+    conn: &mut PgConnection,
+    contents: &str,
+    symbol: &str,
+    resolution: Resolution,
+) -> Result<usize, String> {
+    let mut bars = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let first_field = line.split(',').next().unwrap_or_default().trim();
+        if index == 0 && parse_timestamp(first_field).is_none() {
+            continue;
+        }
+        let bar = parse_row(line, resolution).map_err(|e| format!("line {}: {}", index + 1, e))?;
+        bars.push(bar);
+    }
+
+    let (Some(first), Some(last)) = (
+        bars.iter().map(|bar| bar.open_time).min(),
+        bars.iter().map(|bar| bar.open_time).max(),
+    ) else {
+        return Ok(0);
+    };
+
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        for bar in &bars {
+            upsert_bar(conn, symbol, resolution, bar, Merge::Replace)?;
+        }
+        roll_up(
+            conn,
+            symbol,
+            resolution,
+            first,
+            last + resolution.duration(),
+        )
+    })
+    .map_err(|e| format!("Failed to store candles: {}", e))?;
+
+    Ok(bars.len())
+}
+
+/// Runs `backfill-candles --symbol SYMBOL --interval INTERVAL FILE...`,
+/// importing CSV history without starting the server
+pub fn run_backfill(pool: &db::DbPool, args: &[String]) -> Result<(), String> {
+    let usage = "Usage: backfill-candles --symbol SYMBOL --interval 1m|5m|1h|1d FILE...";
+
+    let mut symbol = None;
+    let mut resolution = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbol" => symbol = args.next().map(|value| value.to_uppercase()),
+            "--interval" => {
+                resolution = args.next().and_then(|value| Resolution::parse(value));
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+    let (Some(symbol), Some(resolution)) = (symbol, resolution) else {
+        return Err(usage.to_string());
+    };
+    if files.is_empty() {
+        return Err(usage.to_string());
+    }
+
+    let mut conn = pool
+        .get()
+        .map_err(|e| format!("Failed to get database connection: {}", e))?;
+
+    for file in files {
+        let contents = std::fs::read_to_string(&file)
+            .map_err(|e| format!("Failed to read {}: {}", file, e))?;
+        let imported = import_csv(&mut conn, &contents, &symbol, resolution)
+            .map_err(|e| format!("{}: {}", file, e))?;
+        info!(
+            "Imported {} {} candles for {} from {}",
+            imported,
+            resolution.as_str(),
+            symbol,
+            file
+        );
+    }
+    Ok(())
+}
+
+/// Returns OHLCV candles of an exchange pair or a market data symbol
+///
+/// # Query parameters
+/// - `symbol`: an exchange pair such as `BTC/USDT`, or a coin and quote
+///   currency from market data such as `BTC/USD`
+/// - `interval`: `1m`, `5m`, `1h` or `1d`
+/// - `from`, `to`: RFC 3339 times; `to` defaults to now and `from` to 500
+///   candles earlier. At most 1000 candles can be requested at once.
+///
+/// # Returns
+/// Candles oldest first. Periods without trades or snapshots have no candle.
+#[get("/api/candles")]
+pub async fn get_candles(
+    pool: web::Data<db::DbPool>,
+    query: web::Query<models::CandlesQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+
+    let Some(resolution) = Resolution::parse(&query.interval) else {
+        return Err(AppError::bad_request(
+            "Interval must be one of 1m, 5m, 1h or 1d",
+        ));
+    };
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query
+        .from
+        .unwrap_or_else(|| to - resolution.duration() * DEFAULT_CANDLES as i32);
+    if from >= to {
+        return Err(AppError::bad_request("'from' must be earlier than 'to'"));
+    }
+    if (to - from).num_seconds() / resolution.seconds() > MAX_CANDLES {
+        return Err(AppError::bad_request(format!(
+            "At most {} candles can be requested at once",
+            MAX_CANDLES
+        )));
+    }
+
+    let mut conn = pool.get()?;
+
+    let pair = query.symbol.to_uppercase();
+    let interval = resolution.as_str();
+    // The candle that contains `from` is included
+    let from = resolution.bucket(from);
+    let symbol_filter = pair.clone();
+    let rows = web::block(move || {
+        use schema::candles::dsl::*;
+
+        candles
+            .filter(symbol.eq(&symbol_filter))
+            .filter(resolution.eq(interval))
+            .filter(open_time.ge(from))
+            .filter(open_time.lt(to))
+            .order_by(open_time.asc())
+            .load::<models::Candle>(&mut conn)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "symbol": pair,
+        "interval": interval,
+        "candles": rows
+    })))
+}
@@ -0,0 +1,799 @@
+use crate::ledger;
+use log::warn;
+use rand::RngCore;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// File read when `CONFIG_FILE` is not set. It is optional, unlike a file
+/// named by `CONFIG_FILE`.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Shortest JWT secret accepted in production
+const MIN_JWT_SECRET_LEN: usize = 32;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Whether the server runs for development or for real users. Production
+/// refuses to start with missing secrets instead of falling back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Development,
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "development" | "dev" => Ok(Environment::Development),
+            "production" | "prod" => Ok(Environment::Production),
+            other => Err(format!("unknown environment '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    /// Most connections the pool keeps open
+    pub pool_size: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::new(),
+            pool_size: 10,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Key access tokens are signed with. Only development may leave it out,
+    /// in which case a random key is used until the server restarts.
+    pub jwt_secret: String,
+    /// Locked accounts stay locked until unlocked from an emailed link,
+    /// instead of unlocking after a while
+    pub unlock_by_email: bool,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["http://localhost:3000".to_string()],
+        }
+    }
+}
+
+/// Backend KYC documents are kept in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentStoreKind {
+    #[default]
+    Local,
+    S3,
+}
+
+impl FromStr for DocumentStoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "local" => Ok(DocumentStoreKind::Local),
+            "s3" => Ok(DocumentStoreKind::S3),
+            other => Err(format!("unknown document store '{}'", other)),
+        }
+    }
+}
+
+/// An S3-compatible bucket, such as AWS S3 or MinIO. Requests use path-style
+/// URLs, `{endpoint}/{bucket}/{key}`.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Directory uploads are stored under
+    pub dir: PathBuf,
+    pub store: DocumentStoreKind,
+    /// Hex-encoded 256-bit key that wraps the key of each document. Only
+    /// development may leave it out, in which case a random key is used and
+    /// documents become unreadable when the server restarts.
+    pub master_key: String,
+    pub s3: S3Config,
+    /// Largest file accepted, in bytes. Uploads are cut off as soon as they
+    /// go over it.
+    pub max_file_bytes: usize,
+    /// Most pages a PDF may have
+    pub max_pdf_pages: usize,
+}
+
+impl UploadConfig {
+    /// Where identity documents were written in plaintext before documents
+    /// were encrypted. They are moved into the document store at startup.
+    pub fn id_documents_dir(&self) -> PathBuf {
+        self.dir.join("id_documents")
+    }
+
+    /// Where the local document store keeps encrypted documents
+    pub fn documents_dir(&self) -> PathBuf {
+        self.dir.join("documents")
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            dir: PathBuf::from("uploads"),
+            store: DocumentStoreKind::Local,
+            master_key: String::new(),
+            s3: S3Config::default(),
+            max_file_bytes: 10 * 1024 * 1024,
+            max_pdf_pages: 10,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            host: "smtp.gmail.com".to_string(),
+            port: 587,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+/// Where market listings come from. Unset URLs and paths use each
+/// provider's default.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarketDataConfig {
+    /// `coinmarketcap`, `coingecko` or `fixture`. Defaults to CoinMarketCap
+    /// when it has an API key and to the fixture otherwise.
+    pub provider: Option<String>,
+    pub coinmarketcap_api_key: Option<String>,
+    pub coinmarketcap_api_url: Option<String>,
+    pub coingecko_api_key: Option<String>,
+    pub coingecko_api_url: Option<String>,
+    pub fixture_path: Option<String>,
+    /// How long market listings are cached
+    pub cache_ttl_secs: Option<u64>,
+}
+
+/// Most a user may move in a rolling day and in a rolling 30 days, valued in
+/// the reference asset. A missing limit means no limit.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeriodLimits {
+    pub daily: Option<Decimal>,
+    pub monthly: Option<Decimal>,
+}
+
+impl PeriodLimits {
+    fn new(daily: i64, monthly: i64) -> Self {
+        PeriodLimits {
+            daily: Some(Decimal::from(daily)),
+            monthly: Some(Decimal::from(monthly)),
+        }
+    }
+}
+
+/// Limits of one verification tier. A tier given in the file must list all
+/// three, so nothing is left unlimited by accident.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TierLimits {
+    pub deposit: PeriodLimits,
+    pub withdrawal: PeriodLimits,
+    pub trading: PeriodLimits,
+}
+
+impl TierLimits {
+    fn uniform(daily: i64, monthly: i64) -> Self {
+        TierLimits {
+            deposit: PeriodLimits::new(daily, monthly),
+            withdrawal: PeriodLimits::new(daily, monthly),
+            trading: PeriodLimits::new(daily, monthly),
+        }
+    }
+}
+
+/// Deposit, withdrawal and trading limits of each verification tier
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Asset limits are expressed in. Other assets are valued at the last
+    /// trade price of their market against it.
+    pub reference_asset: String,
+    /// Verified email only
+    pub tier0: TierLimits,
+    /// Personal details given
+    pub tier1: TierLimits,
+    /// Identity document and proof of address approved
+    pub tier2: TierLimits,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            reference_asset: "USDT".to_string(),
+            tier0: TierLimits::uniform(1_000, 5_000),
+            tier1: TierLimits::uniform(10_000, 50_000),
+            tier2: TierLimits::uniform(100_000, 1_000_000),
+        }
+    }
+}
+
+/// Every setting of the server, loaded once at startup from an optional TOML
+/// file with environment variables taking precedence
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub environment: Environment,
+    /// Base URL of the web app, used for links in emails
+    pub frontend_url: String,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+    pub uploads: UploadConfig,
+    pub smtp: SmtpConfig,
+    pub market_data: MarketDataConfig,
+    pub limits: LimitsConfig,
+}
+
+/// Why the configuration could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidVariable(&'static str, String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid {}: {}", path.display(), e),
+            ConfigError::InvalidVariable(name, value) => {
+                write!(f, "Invalid value '{}' for {}", value, name)
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid configuration: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The value of an environment variable, unless it is unset or empty
+fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+/// An environment variable parsed as `T`. A value that does not parse is an
+/// error rather than being ignored.
+fn env_parse<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match env_string(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidVariable(name, value)),
+        None => Ok(None),
+    }
+}
+
+fn env_bool(name: &'static str) -> Result<Option<bool>, ConfigError> {
+    match env_string(name) {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" => Ok(Some(true)),
+            "0" | "false" | "no" => Ok(Some(false)),
+            _ => Err(ConfigError::InvalidVariable(name, value)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    /// Reads the TOML file named by `CONFIG_FILE`, or `config.toml` if it
+    /// exists, applies environment variables over it and validates the result
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match env_string("CONFIG_FILE") {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Overrides settings with the environment variables that are set
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(environment) = env_parse("APP_ENV")? {
+            self.environment = environment;
+        }
+        if let Some(url) = env_string("FRONTEND_URL") {
+            self.frontend_url = url;
+        }
+
+        if let Some(host) = env_string("HOST") {
+            self.server.host = host;
+        }
+        if let Some(port) = env_parse("PORT")? {
+            self.server.port = port;
+        }
+
+        if let Some(url) = env_string("DATABASE_URL") {
+            self.database.url = url;
+        }
+        if let Some(pool_size) = env_parse("DATABASE_POOL_SIZE")? {
+            self.database.pool_size = pool_size;
+        }
+
+        if let Some(secret) = env_string("JWT_SECRET") {
+            self.auth.jwt_secret = secret;
+        }
+        if let Some(unlock_by_email) = env_bool("LOGIN_UNLOCK_BY_EMAIL")? {
+            self.auth.unlock_by_email = unlock_by_email;
+        }
+
+        if let Some(origins) = env_string("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+
+        if let Some(dir) = env_string("UPLOAD_DIR") {
+            self.uploads.dir = PathBuf::from(dir);
+        }
+        if let Some(store) = env_parse("DOCUMENT_STORE")? {
+            self.uploads.store = store;
+        }
+        if let Some(key) = env_string("DOCUMENT_MASTER_KEY") {
+            self.uploads.master_key = key;
+        }
+        if let Some(max_file_bytes) = env_parse("UPLOAD_MAX_FILE_BYTES")? {
+            self.uploads.max_file_bytes = max_file_bytes;
+        }
+        if let Some(max_pdf_pages) = env_parse("UPLOAD_MAX_PDF_PAGES")? {
+            self.uploads.max_pdf_pages = max_pdf_pages;
+        }
+        let s3 = &mut self.uploads.s3;
+        for (name, field) in [
+            ("S3_ENDPOINT", &mut s3.endpoint),
+            ("S3_BUCKET", &mut s3.bucket),
+            ("S3_REGION", &mut s3.region),
+            ("S3_ACCESS_KEY_ID", &mut s3.access_key_id),
+            ("S3_SECRET_ACCESS_KEY", &mut s3.secret_access_key),
+        ] {
+            if let Some(value) = env_string(name) {
+                *field = value;
+            }
+        }
+
+        if let Some(host) = env_string("SMTP_HOST") {
+            self.smtp.host = host;
+        }
+        if let Some(port) = env_parse("SMTP_PORT")? {
+            self.smtp.port = port;
+        }
+        if let Some(username) = env_string("SMTP_USERNAME") {
+            self.smtp.username = Some(username);
+        }
+        if let Some(password) = env_string("SMTP_PASSWORD") {
+            self.smtp.password = Some(password);
+        }
+
+        let market_data = &mut self.market_data;
+        for (name, field) in [
+            ("MARKET_DATA_PROVIDER", &mut market_data.provider),
+            (
+                "COINMARKETCAP_API_KEY",
+                &mut market_data.coinmarketcap_api_key,
+            ),
+            (
+                "COINMARKETCAP_API_URL",
+                &mut market_data.coinmarketcap_api_url,
+            ),
+            ("COINGECKO_API_KEY", &mut market_data.coingecko_api_key),
+            ("COINGECKO_API_URL", &mut market_data.coingecko_api_url),
+            ("MARKET_DATA_FIXTURE", &mut market_data.fixture_path),
+        ] {
+            if let Some(value) = env_string(name) {
+                *field = Some(value);
+            }
+        }
+        if let Some(ttl) = env_parse("MARKETS_CACHE_TTL_SECS")? {
+            market_data.cache_ttl_secs = Some(ttl);
+        }
+
+        if let Some(asset) = env_string("LIMITS_REFERENCE_ASSET") {
+            self.limits.reference_asset = asset.trim().to_string();
+        }
+
+        Ok(())
+    }
+
+    /// Collects every problem at once, so a bad deployment can be fixed in
+    /// one go. Development falls back where production fails.
+    fn validate(&mut self) -> Result<(), ConfigError> {
+        let production = self.environment == Environment::Production;
+        let mut problems = Vec::new();
+
+        if self.database.url.is_empty() {
+            problems.push("DATABASE_URL must be set".to_string());
+        }
+        if self.database.pool_size == 0 {
+            problems.push("the database pool size must be at least 1".to_string());
+        }
+        if self.market_data.cache_ttl_secs == Some(0) {
+            problems.push("the market cache TTL must be at least 1 second".to_string());
+        }
+
+        self.limits.validate(&mut problems);
+
+        if self.auth.jwt_secret.is_empty() {
+            if production {
+                problems.push("JWT_SECRET must be set in production".to_string());
+            } else {
+                warn!("JWT_SECRET is not set; tokens will stop working when the server restarts");
+                let mut secret = [0u8; 64];
+                rand::thread_rng().fill_bytes(&mut secret);
+                self.auth.jwt_secret = hex::encode(secret);
+            }
+        } else if production && self.auth.jwt_secret.len() < MIN_JWT_SECRET_LEN {
+            problems.push(format!(
+                "JWT_SECRET must be at least {} characters in production",
+                MIN_JWT_SECRET_LEN
+            ));
+        }
+
+        if self.uploads.master_key.is_empty() {
+            if production {
+                problems.push("DOCUMENT_MASTER_KEY must be set in production".to_string());
+            } else {
+                warn!(
+                    "DOCUMENT_MASTER_KEY is not set; uploaded documents will be unreadable after a restart"
+                );
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                self.uploads.master_key = hex::encode(key);
+            }
+        } else if hex::decode(&self.uploads.master_key).map_or(true, |key| key.len() != 32) {
+            problems.push("DOCUMENT_MASTER_KEY must be 64 hex characters".to_string());
+        }
+        if self.uploads.max_file_bytes == 0 {
+            problems.push("the upload size limit must be at least 1 byte".to_string());
+        }
+        if self.uploads.max_pdf_pages == 0 {
+            problems.push("the PDF page limit must be at least 1".to_string());
+        }
+        if self.uploads.store == DocumentStoreKind::S3 {
+            let s3 = &self.uploads.s3;
+            if s3.endpoint.is_empty() || s3.bucket.is_empty() {
+                problems.push("S3_ENDPOINT and S3_BUCKET must be set for the s3 store".to_string());
+            }
+            if s3.access_key_id.is_empty() || s3.secret_access_key.is_empty() {
+                problems.push(
+                    "S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set for the s3 store"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.smtp.username.is_none() || self.smtp.password.is_none() {
+            if production {
+                problems
+                    .push("SMTP_USERNAME and SMTP_PASSWORD must be set in production".to_string());
+            } else {
+                warn!("SMTP credentials are not set; emailed links will be logged instead");
+            }
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            problems.push("at least one CORS origin must be allowed".to_string());
+        }
+
+        if self.frontend_url.is_empty() {
+            if production {
+                problems.push("FRONTEND_URL must be set in production".to_string());
+            } else {
+                self.frontend_url = "http://localhost:3000".to_string();
+            }
+        }
+        self.frontend_url = self.frontend_url.trim_end_matches('/').to_string();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+impl LimitsConfig {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if !ledger::is_valid_asset(&self.reference_asset) {
+            problems.push(format!(
+                "'{}' is not a valid reference asset for limits",
+                self.reference_asset
+            ));
+        }
+
+        for (tier, limits) in [
+            ("tier0", &self.tier0),
+            ("tier1", &self.tier1),
+            ("tier2", &self.tier2),
+        ] {
+            for (activity, period) in [
+                ("deposit", limits.deposit),
+                ("withdrawal", limits.withdrawal),
+                ("trading", limits.trading),
+            ] {
+                if [period.daily, period.monthly]
+                    .iter()
+                    .flatten()
+                    .any(|limit| limit.is_sign_negative())
+                {
+                    problems.push(format!("limits.{}.{} must not be negative", tier, activity));
+                }
+                if let (Some(daily), Some(monthly)) = (period.daily, period.monthly)
+                    && daily > monthly
+                {
+                    problems.push(format!(
+                        "the daily limit of limits.{}.{} is above its monthly limit",
+                        tier, activity
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Makes the loaded configuration available to code that has no access to
+/// app data, such as token signing and email. Handlers take it as
+/// `web::Data<Config>` instead.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The configuration passed to `init` at startup
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("configuration is loaded at startup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A production configuration that passes validation
+    fn production() -> Config {
+        toml::from_str(
+            r#"
+            environment = "production"
+            frontend_url = "https://exchange.example.com/"
+
+            [database]
+            url = "postgres://exchange@db/exchange"
+
+            [auth]
+            jwt_secret = "0123456789abcdef0123456789abcdef"
+
+            [uploads]
+            master_key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+
+            [smtp]
+            username = "mailer"
+            password = "hunter2"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn problems(mut config: Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(other) => panic!("unexpected error: {}", other),
+        }
+    }
+
+    fn assert_refused(config: Config, expected: &str) {
+        let problems = problems(config);
+        assert!(
+            problems.iter().any(|problem| problem.contains(expected)),
+            "expected a problem mentioning {:?}, got {:?}",
+            expected,
+            problems
+        );
+    }
+
+    #[test]
+    fn accepts_a_complete_production_config() {
+        let mut config = production();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.frontend_url, "https://exchange.example.com");
+    }
+
+    #[test]
+    fn production_refuses_missing_secrets_all_at_once() {
+        let config = Config {
+            environment: Environment::Production,
+            ..Config::default()
+        };
+
+        let problems = problems(config);
+
+        for expected in [
+            "DATABASE_URL",
+            "JWT_SECRET",
+            "DOCUMENT_MASTER_KEY",
+            "SMTP_USERNAME",
+            "FRONTEND_URL",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem.contains(expected)),
+                "{} missing from {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn development_falls_back_for_missing_secrets() {
+        let mut config = Config::default();
+        config.database.url = "postgres://localhost/exchange".to_string();
+
+        assert!(config.validate().is_ok());
+        assert!(!config.auth.jwt_secret.is_empty());
+        assert_eq!(hex::decode(&config.uploads.master_key).unwrap().len(), 32);
+        assert_eq!(config.frontend_url, "http://localhost:3000");
+    }
+
+    #[test]
+    fn refuses_weak_or_malformed_secrets() {
+        let mut short_secret = production();
+        short_secret.auth.jwt_secret = "too-short".to_string();
+        assert_refused(short_secret, "JWT_SECRET must be at least");
+
+        for master_key in ["not hex", "0011", &"ab".repeat(33)] {
+            let mut config = production();
+            config.uploads.master_key = master_key.to_string();
+            assert_refused(config, "DOCUMENT_MASTER_KEY must be 64 hex characters");
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_values() {
+        let mut config = production();
+        config.database.pool_size = 0;
+        assert_refused(config, "pool size");
+
+        let mut config = production();
+        config.market_data.cache_ttl_secs = Some(0);
+        assert_refused(config, "market cache TTL");
+
+        let mut config = production();
+        config.uploads.max_file_bytes = 0;
+        assert_refused(config, "upload size limit");
+
+        let mut config = production();
+        config.uploads.max_pdf_pages = 0;
+        assert_refused(config, "PDF page limit");
+
+        let mut config = production();
+        config.cors.allowed_origins.clear();
+        assert_refused(config, "CORS origin");
+    }
+
+    #[test]
+    fn s3_store_needs_its_bucket_and_credentials() {
+        let mut config = production();
+        config.uploads.store = DocumentStoreKind::S3;
+
+        let problems = problems(config);
+
+        assert!(problems.iter().any(|problem| problem.contains("S3_BUCKET")));
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("S3_ACCESS_KEY_ID"))
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_limits() {
+        let mut config = production();
+        config.limits.reference_asset = "usdt".to_string();
+        assert_refused(config, "not a valid reference asset");
+
+        let mut config = production();
+        config.limits.tier1.withdrawal.daily = Some(Decimal::from(-1));
+        assert_refused(config, "limits.tier1.withdrawal must not be negative");
+
+        let mut config = production();
+        config.limits.tier2.trading = PeriodLimits::new(10, 5);
+        assert_refused(config, "daily limit of limits.tier2.trading is above");
+    }
+}
@@ -1,18 +1,13 @@
+use crate::config::DatabaseConfig;
 use diesel::pg::PgConnection;
 use diesel::r2d2::{self, ConnectionManager};
-use dotenv::dotenv;
-use std::env;
 
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
-pub fn establish_connection_pool() -> DbPool {
-    dotenv().ok();
-
-    let database_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set");
-    
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
+pub fn establish_connection_pool(config: &DatabaseConfig) -> DbPool {
+    let manager = ConnectionManager::<PgConnection>::new(&config.url);
     r2d2::Pool::builder()
+        .max_size(config.pool_size)
         .build(manager)
         .expect("Failed to create pool")
-}
\ No newline at end of file
+}
@@ -0,0 +1,498 @@
+use crate::config::{DocumentStoreKind, S3Config, UploadConfig};
+use crate::error::AppError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use log::error;
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Marks a sealed document and the version of its layout
+const SEALED_MAGIC: &[u8; 5] = b"KYCE1";
+const NONCE_LEN: usize = 12;
+/// A 256-bit data key and its authentication tag
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+const HEADER_LEN: usize = SEALED_MAGIC.len() + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+/// Longest storage key accepted
+const MAX_KEY_LEN: usize = 200;
+
+/// Errors that can occur while storing or reading a document
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    InvalidKey,
+    /// The stored bytes are not a document sealed with this master key
+    Corrupt,
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    Status(StatusCode),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound => write!(f, "document not found"),
+            StoreError::InvalidKey => write!(f, "invalid storage key"),
+            StoreError::Corrupt => write!(f, "document cannot be decrypted"),
+            StoreError::Io(e) => write!(f, "I/O error: {}", e),
+            StoreError::Http(e) => write!(f, "request failed: {}", e),
+            StoreError::Status(status) => write!(f, "unexpected status {}", status),
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            StoreError::NotFound
+        } else {
+            StoreError::Io(e)
+        }
+    }
+}
+
+impl From<reqwest::Error> for StoreError {
+    fn from(e: reqwest::Error) -> Self {
+        StoreError::Http(e)
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::NotFound => AppError::not_found("Document not found"),
+            StoreError::Http(_) | StoreError::Status(_) => {
+                error!("Document store error: {}", e);
+                AppError::Unavailable("Document storage is unavailable".to_string())
+            }
+            e => {
+                error!("Document store error: {}", e);
+                AppError::internal("Failed to access document")
+            }
+        }
+    }
+}
+
+/// Where KYC documents are kept. Implementations encrypt what they are given
+/// before it leaves the process, so neither disks nor buckets hold plaintext.
+pub trait DocumentStore: Send + Sync {
+    /// Stores a document, replacing any under the same key
+    fn put<'a>(&'a self, key: &'a str, contents: Vec<u8>) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>>;
+
+    /// Deleting a document that does not exist is not an error
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>>;
+}
+
+/// Keys are flat names such as `12_selfie_<uuid>.jpg`, so they can never
+/// point outside the store
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn checked_key(key: &str) -> Result<&str, StoreError> {
+    if is_valid_key(key) {
+        Ok(key)
+    } else {
+        Err(StoreError::InvalidKey)
+    }
+}
+
+/// Envelope encryption: every document is encrypted with its own random
+/// data key, and that key is stored next to it encrypted with the master
+/// key. Both use AES-256-GCM.
+///
+/// A sealed document is `KYCE1 | key nonce | wrapped data key | data nonce |
+/// ciphertext`, with everything before the ciphertext authenticated along
+/// with it.
+#[derive(Clone)]
+pub struct Envelope {
+    master: Aes256Gcm,
+}
+
+impl Envelope {
+    pub fn new(master_key: &[u8; 32]) -> Self {
+        Envelope {
+            master: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(master_key)),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let key_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = self
+            .master
+            .encrypt(&key_nonce, data_key.as_slice())
+            .map_err(|_| StoreError::Corrupt)?;
+        let data_nonce = Aes256Gcm::generate_nonce(OsRng);
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        sealed.extend_from_slice(SEALED_MAGIC);
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&wrapped_key);
+        sealed.extend_from_slice(&data_nonce);
+
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &data_nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &sealed,
+                },
+            )
+            .map_err(|_| StoreError::Corrupt)?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, StoreError> {
+        if sealed.len() < HEADER_LEN || !sealed.starts_with(SEALED_MAGIC) {
+            return Err(StoreError::Corrupt);
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let (key_nonce, rest) = header[SEALED_MAGIC.len()..].split_at(NONCE_LEN);
+        let (wrapped_key, data_nonce) = rest.split_at(WRAPPED_KEY_LEN);
+
+        let data_key = self
+            .master
+            .decrypt(Nonce::from_slice(key_nonce), wrapped_key)
+            .map_err(|_| StoreError::Corrupt)?;
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+            .decrypt(
+                Nonce::from_slice(data_nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| StoreError::Corrupt)
+    }
+}
+
+/// Keeps sealed documents as files in one directory
+pub struct LocalStore {
+    dir: PathBuf,
+    envelope: Envelope,
+}
+
+impl LocalStore {
+    pub fn new(dir: PathBuf, envelope: Envelope) -> Self {
+        LocalStore { dir, envelope }
+    }
+}
+
+impl DocumentStore for LocalStore {
+    fn put<'a>(&'a self, key: &'a str, contents: Vec<u8>) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let path = self.dir.join(checked_key(key)?);
+            let sealed = self.envelope.seal(&contents)?;
+            tokio::fs::create_dir_all(&self.dir).await?;
+
+            // Written next to its final name and renamed, so a document is
+            // never seen half written
+            let partial = self.dir.join(format!(".{}.partial", key));
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(&partial).await?;
+            tokio::io::AsyncWriteExt::write_all(&mut file, &sealed).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            let sealed = tokio::fs::read(self.dir.join(checked_key(key)?)).await?;
+            self.envelope.open(&sealed)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.dir.join(checked_key(key)?)).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => Ok(result?),
+            }
+        })
+    }
+}
+
+/// Keeps sealed documents in an S3-compatible bucket, signing requests with
+/// AWS Signature Version 4
+pub struct S3Store {
+    client: reqwest::Client,
+    endpoint: Url,
+    config: S3Config,
+    envelope: Envelope,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3Store {
+    pub fn new(config: S3Config, envelope: Envelope) -> Result<Self, String> {
+        let endpoint = Url::parse(config.endpoint.trim_end_matches('/'))
+            .map_err(|e| format!("invalid S3 endpoint '{}': {}", config.endpoint, e))?;
+        if endpoint.host_str().is_none() {
+            return Err(format!("invalid S3 endpoint '{}'", config.endpoint));
+        }
+        Ok(S3Store {
+            client: reqwest::Client::new(),
+            endpoint,
+            config,
+            envelope,
+        })
+    }
+
+    /// Sends a signed request for an object. Keys only contain characters
+    /// that need no escaping in a path.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, StoreError> {
+        let url = Url::parse(&format!(
+            "{}/{}/{}",
+            self.endpoint.as_str().trim_end_matches('/'),
+            self.config.bucket,
+            checked_key(key)?
+        ))
+        .map_err(|_| StoreError::InvalidKey)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(StoreError::InvalidKey),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            url.path(),
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = ["s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                &hmac_sha256(
+                    format!("AWS4{}", self.config.secret_access_key).as_bytes(),
+                    &date,
+                ),
+                &self.config.region,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+
+        let response = self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key_id, scope, signed_headers, signature
+                ),
+            )
+            .body(body)
+            .send()
+            .await?;
+        Ok(response)
+    }
+}
+
+impl DocumentStore for S3Store {
+    fn put<'a>(&'a self, key: &'a str, contents: Vec<u8>) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let sealed = self.envelope.seal(&contents)?;
+            let response = self.send(Method::PUT, key, sealed).await?;
+            if !response.status().is_success() {
+                return Err(StoreError::Status(response.status()));
+            }
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, StoreError>> {
+        Box::pin(async move {
+            let response = self.send(Method::GET, key, Vec::new()).await?;
+            match response.status() {
+                StatusCode::NOT_FOUND => Err(StoreError::NotFound),
+                status if status.is_success() => {
+                    let sealed = response.bytes().await?;
+                    self.envelope.open(&sealed)
+                }
+                status => Err(StoreError::Status(status)),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let response = self.send(Method::DELETE, key, Vec::new()).await?;
+            match response.status() {
+                StatusCode::NOT_FOUND => Ok(()),
+                status if status.is_success() => Ok(()),
+                status => Err(StoreError::Status(status)),
+            }
+        })
+    }
+}
+
+/// The store the configuration asks for. The master key has been checked
+/// by `Config::load`.
+pub fn from_config(config: &UploadConfig) -> Result<Arc<dyn DocumentStore>, String> {
+    let master_key: [u8; 32] = hex::decode(&config.master_key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or("the document master key must be 64 hex characters")?;
+    let envelope = Envelope::new(&master_key);
+
+    Ok(match config.store {
+        DocumentStoreKind::Local => Arc::new(LocalStore::new(config.documents_dir(), envelope)),
+        DocumentStoreKind::S3 => Arc::new(S3Store::new(config.s3.clone(), envelope)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER_KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn sealed_documents_open_to_the_original() {
+        let envelope = Envelope::new(&MASTER_KEY);
+        let sealed = envelope.seal(b"passport scan").unwrap();
+
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert!(!contains_plaintext(&sealed, b"passport scan"));
+        assert_eq!(envelope.open(&sealed).unwrap(), b"passport scan");
+        // Every document gets its own data key and nonces
+        assert_ne!(envelope.seal(b"passport scan").unwrap(), sealed);
+    }
+
+    #[test]
+    fn tampered_documents_do_not_open() {
+        let envelope = Envelope::new(&MASTER_KEY);
+        let sealed = envelope.seal(b"passport scan").unwrap();
+
+        // Magic, key nonce, wrapped key, data nonce and ciphertext
+        for at in [0, 6, 20, HEADER_LEN - 1, HEADER_LEN] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert!(
+                matches!(envelope.open(&tampered), Err(StoreError::Corrupt)),
+                "byte {} was not authenticated",
+                at
+            );
+        }
+        assert!(matches!(
+            envelope.open(&sealed[..HEADER_LEN - 1]),
+            Err(StoreError::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn documents_do_not_open_with_another_master_key() {
+        let sealed = Envelope::new(&MASTER_KEY).seal(b"passport scan").unwrap();
+
+        assert!(matches!(
+            Envelope::new(&[8; 32]).open(&sealed),
+            Err(StoreError::Corrupt)
+        ));
+    }
+
+    fn contains_plaintext(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn env_or(name: &str, default: &str) -> String {
+        std::env::var(name).unwrap_or_else(|_| default.to_string())
+    }
+
+    /// Runs against a real S3-compatible service, MinIO by default:
+    ///
+    /// ```text
+    /// docker run -d -p 9000:9000 minio/minio server /data
+    /// docker run --rm --network host --entrypoint sh minio/mc -c \
+    ///   "mc alias set local http://127.0.0.1:9000 minioadmin minioadmin && mc mb local/kyc-test"
+    /// cargo test s3_store -- --ignored
+    /// ```
+    ///
+    /// `S3_TEST_ENDPOINT`, `S3_TEST_BUCKET`, `S3_TEST_ACCESS_KEY_ID` and
+    /// `S3_TEST_SECRET_ACCESS_KEY` point it elsewhere.
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible service such as MinIO"]
+    async fn s3_store_puts_gets_and_deletes() {
+        let config = S3Config {
+            endpoint: env_or("S3_TEST_ENDPOINT", "http://127.0.0.1:9000"),
+            bucket: env_or("S3_TEST_BUCKET", "kyc-test"),
+            access_key_id: env_or("S3_TEST_ACCESS_KEY_ID", "minioadmin"),
+            secret_access_key: env_or("S3_TEST_SECRET_ACCESS_KEY", "minioadmin"),
+            ..S3Config::default()
+        };
+        let store = S3Store::new(config.clone(), Envelope::new(&MASTER_KEY)).unwrap();
+        let key = format!("test_{}.pdf", uuid::Uuid::new_v4());
+
+        store.put(&key, b"proof of address".to_vec()).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), b"proof of address");
+
+        // The bucket only ever sees the sealed document
+        let raw = store.send(Method::GET, &key, Vec::new()).await.unwrap();
+        let raw = raw.bytes().await.unwrap();
+        assert!(raw.starts_with(SEALED_MAGIC));
+        assert!(!contains_plaintext(&raw, b"proof of address"));
+
+        // Another master key cannot read it
+        let other = S3Store::new(config, Envelope::new(&[8; 32])).unwrap();
+        assert!(matches!(other.get(&key).await, Err(StoreError::Corrupt)));
+
+        store.delete(&key).await.unwrap();
+        assert!(matches!(store.get(&key).await, Err(StoreError::NotFound)));
+        // Deleting again is not an error
+        store.delete(&key).await.unwrap();
+    }
+}
@@ -1,23 +1,12 @@
+use crate::config;
+use chrono::{DateTime, Utc};
 use lettre::message::{MultiPart, SinglePart, header};
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::client::Tls;
 use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
 use log::{error, info};
-use std::env;
 
 pub async fn send_password_reset_email(email: &str, reset_token: &str) -> Result<(), String> {
-    // Get SMTP settings from environment variables
-    let smtp_host = env::var("SMTP_HOST").unwrap_or_else(|_| "smtp.gmail.com".to_string());
-    let smtp_port = env::var("SMTP_PORT")
-        .unwrap_or_else(|_| "587".to_string())
-        .parse::<u16>()
-        .unwrap_or(587);
-    let smtp_username =
-        env::var("SMTP_USERNAME").map_err(|_| "SMTP_USERNAME not configured".to_string())?;
-    let smtp_password =
-        env::var("SMTP_PASSWORD").map_err(|_| "SMTP_PASSWORD not configured".to_string())?;
-    let frontend_url =
-        env::var("FRONTEND_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let frontend_url = &config::get().frontend_url;
 
     // Create the reset link
     let reset_link = format!(
@@ -58,6 +47,233 @@ pub async fn send_password_reset_email(email: &str, reset_token: &str) -> Result
         reset_link
     );
 
+    send(
+        email,
+        "Password Reset Request",
+        email_body_text,
+        email_body_html,
+    )
+    .await
+}
+
+/// Asks a new user to confirm they own their email address
+pub async fn send_email_verification_email(
+    email: &str,
+    verification_token: &str,
+) -> Result<(), String> {
+    let frontend_url = &config::get().frontend_url;
+
+    let verification_link = format!("{}/verify-email?token={}", frontend_url, verification_token);
+
+    let email_body_html = format!(
+        r#"
+        <html>
+            <body>
+                <h2>Verify Your Email Address</h2>
+                <p>Thanks for signing up to our cryptocurrency exchange.</p>
+                <p>Please click the link below to verify your email address:</p>
+                <p><a href="{}">Verify Email</a></p>
+                <p>This link will expire in 24 hours.</p>
+                <p>If you did not create an account, you can safely ignore this email.</p>
+            </body>
+        </html>
+        "#,
+        verification_link
+    );
+
+    let email_body_text = format!(
+        r#"
+        Verify Your Email Address
+        
+        Thanks for signing up to our cryptocurrency exchange.
+        
+        Please copy and paste the following URL into your browser to verify your email address:
+        {}
+        
+        This link will expire in 24 hours.
+        
+        If you did not create an account, you can safely ignore this email.
+        "#,
+        verification_link
+    );
+
+    send(
+        email,
+        "Verify Your Email Address",
+        email_body_text,
+        email_body_html,
+    )
+    .await
+}
+
+/// Asks a user to confirm the new address they want to use for their account
+pub async fn send_email_change_email(email: &str, verification_token: &str) -> Result<(), String> {
+    let frontend_url = &config::get().frontend_url;
+
+    let verification_link = format!("{}/verify-email?token={}", frontend_url, verification_token);
+
+    let email_body_html = format!(
+        r#"
+        <html>
+            <body>
+                <h2>Confirm Your New Email Address</h2>
+                <p>You asked to use this address for your cryptocurrency exchange account.</p>
+                <p>Please click the link below to confirm the change:</p>
+                <p><a href="{}">Confirm Email</a></p>
+                <p>This link will expire in 24 hours. Until then, your account keeps its current address.</p>
+                <p>If you did not ask for this change, you can safely ignore this email.</p>
+            </body>
+        </html>
+        "#,
+        verification_link
+    );
+
+    let email_body_text = format!(
+        r#"
+        Confirm Your New Email Address
+        
+        You asked to use this address for your cryptocurrency exchange account.
+        
+        Please copy and paste the following URL into your browser to confirm the change:
+        {}
+        
+        This link will expire in 24 hours. Until then, your account keeps its current address.
+        
+        If you did not ask for this change, you can safely ignore this email.
+        "#,
+        verification_link
+    );
+
+    send(
+        email,
+        "Confirm Your New Email Address",
+        email_body_text,
+        email_body_html,
+    )
+    .await
+}
+
+/// Tells a user their account was locked after failed logins, with a link
+/// that unlocks it
+pub async fn send_account_unlock_email(email: &str, unlock_token: &str) -> Result<(), String> {
+    let frontend_url = &config::get().frontend_url;
+
+    let unlock_link = format!("{}/unlock-account?token={}", frontend_url, unlock_token);
+
+    let email_body_html = format!(
+        r#"
+        <html>
+            <body>
+                <h2>Your Account Has Been Locked</h2>
+                <p>We locked your cryptocurrency exchange account after several failed login attempts.</p>
+                <p>If these attempts were yours, click the link below to unlock your account:</p>
+                <p><a href="{}">Unlock Account</a></p>
+                <p>This link will expire in 24 hours.</p>
+                <p>If you did not try to log in, someone may be guessing your password. Consider resetting it.</p>
+            </body>
+        </html>
+        "#,
+        unlock_link
+    );
+
+    let email_body_text = format!(
+        r#"
+        Your Account Has Been Locked
+        
+        We locked your cryptocurrency exchange account after several failed login attempts.
+        
+        If these attempts were yours, copy and paste the following URL into your browser to unlock your account:
+        {}
+        
+        This link will expire in 24 hours.
+        
+        If you did not try to log in, someone may be guessing your password. Consider resetting it.
+        "#,
+        unlock_link
+    );
+
+    send(
+        email,
+        "Your Account Has Been Locked",
+        email_body_text,
+        email_body_html,
+    )
+    .await
+}
+
+/// Tells a user about a login from a device or IP address they have not
+/// used before
+pub async fn send_new_login_email(
+    email: &str,
+    ip_address: &str,
+    user_agent: &str,
+    logged_in_at: DateTime<Utc>,
+) -> Result<(), String> {
+    let frontend_url = &config::get().frontend_url;
+
+    let sessions_link = format!("{}/settings", frontend_url);
+    let logged_in_at = logged_in_at.format("%Y-%m-%d %H:%M UTC");
+
+    let email_body_html = format!(
+        r#"
+        <html>
+            <body>
+                <h2>New Login to Your Account</h2>
+                <p>Your cryptocurrency exchange account was just accessed from a new device or location.</p>
+                <p>Time: {}<br>IP address: {}<br>Device: {}</p>
+                <p>If this was you, you can ignore this email.</p>
+                <p>If it was not, <a href="{}">review your sessions</a>, log out the ones you do not recognise and reset your password.</p>
+            </body>
+        </html>
+        "#,
+        logged_in_at, ip_address, user_agent, sessions_link
+    );
+
+    let email_body_text = format!(
+        r#"
+        New Login to Your Account
+        
+        Your cryptocurrency exchange account was just accessed from a new device or location.
+        
+        Time: {}
+        IP address: {}
+        Device: {}
+        
+        If this was you, you can ignore this email.
+        
+        If it was not, review your sessions at the following URL, log out the ones you do not recognise and reset your password:
+        {}
+        "#,
+        logged_in_at, ip_address, user_agent, sessions_link
+    );
+
+    send(
+        email,
+        "New Login to Your Account",
+        email_body_text,
+        email_body_html,
+    )
+    .await
+}
+
+/// Sends an email with plain text and HTML bodies over the configured SMTP
+/// server
+async fn send(
+    email: &str,
+    subject: &str,
+    email_body_text: String,
+    email_body_html: String,
+) -> Result<(), String> {
+    let smtp = &config::get().smtp;
+    let smtp_username = smtp
+        .username
+        .clone()
+        .ok_or_else(|| "SMTP_USERNAME not configured".to_string())?;
+    let smtp_password = smtp
+        .password
+        .clone()
+        .ok_or_else(|| "SMTP_PASSWORD not configured".to_string())?;
+
     // Create message
     let message = match Message::builder()
         .from(
@@ -68,7 +284,7 @@ pub async fn send_password_reset_email(email: &str, reset_token: &str) -> Result
         .to(email
             .parse()
             .map_err(|e| format!("Invalid email address: {}", e))?)
-        .subject("Password Reset Request")
+        .subject(subject)
         .multipart(
             MultiPart::alternative()
                 .singlepart(
@@ -89,20 +305,20 @@ pub async fn send_password_reset_email(email: &str, reset_token: &str) -> Result
     // Configure SMTP transport
     let credentials = Credentials::new(smtp_username, smtp_password);
 
-    let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp_host)
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
         .map_err(|e| format!("Failed to create mailer: {}", e))?
-        .port(smtp_port)
+        .port(smtp.port)
         .credentials(credentials)
         .build();
 
     // Send the email
     match mailer.send(message).await {
         Ok(_) => {
-            info!("Password reset email sent to {}", email);
+            info!("Email \"{}\" sent to {}", subject, email);
             Ok(())
         }
         Err(e) => {
-            error!("Failed to send password reset email: {}", e);
+            error!("Failed to send email \"{}\": {}", subject, e);
             Err(format!("Failed to send email: {}", e))
         }
     }
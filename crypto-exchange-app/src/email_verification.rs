@@ -0,0 +1,251 @@
+use crate::auth;
+use crate::config;
+use crate::db;
+use crate::email;
+use crate::error::AppError;
+use crate::models;
+use crate::schema;
+use crate::throttle::LoginGuard;
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{error, info};
+
+diesel::define_sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+/// How long a verification link works
+const VERIFICATION_TOKEN_TTL: Duration = Duration::hours(24);
+
+/// What happened to a presented verification token
+enum Confirmation {
+    Verified(i32),
+    Invalid,
+    /// The token was for an email change, but another account took the new
+    /// address first
+    EmailTaken,
+}
+
+/// Whether an account other than `owner` already uses an email address
+pub fn email_in_use(conn: &mut PgConnection, address: &str, owner: i32) -> QueryResult<bool> {
+    use diesel::dsl::exists;
+    use schema::users::dsl::*;
+
+    diesel::select(exists(
+        users
+            .filter(lower(email).eq(address.to_lowercase()))
+            .filter(id.ne(owner)),
+    ))
+    .get_result(conn)
+}
+
+/// Replaces a user's unused verification tokens with a new one and returns
+/// it. A new address makes it a token that confirms an email change.
+fn create_token(
+    conn: &mut PgConnection,
+    owner: i32,
+    new_address: Option<String>,
+) -> QueryResult<String> {
+    use schema::email_verification_tokens::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::delete(
+            email_verification_tokens
+                .filter(user_id.eq(owner))
+                .filter(used.eq(false)),
+        )
+        .execute(conn)?;
+
+        let new_token = models::NewEmailVerificationToken {
+            user_id: owner,
+            token: uuid::Uuid::new_v4().to_string(),
+            expires_at: (Utc::now() + VERIFICATION_TOKEN_TTL).naive_utc(),
+            email: new_address,
+        };
+        diesel::insert_into(email_verification_tokens)
+            .values(&new_token)
+            .execute(conn)?;
+
+        Ok(new_token.token)
+    })
+}
+
+/// Marks the email of the user a verification token belongs to as verified,
+/// switching to the new address first if the token is for an email change
+fn confirm_token(conn: &mut PgConnection, presented: &str) -> QueryResult<Confirmation> {
+    use schema::email_verification_tokens::dsl::*;
+
+    conn.transaction(|conn| {
+        let Some(record) = email_verification_tokens
+            .filter(token.eq(presented))
+            .filter(used.eq(false))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .for_update()
+            .first::<models::EmailVerificationToken>(conn)
+            .optional()?
+        else {
+            return Ok(Confirmation::Invalid);
+        };
+
+        diesel::update(email_verification_tokens.find(record.id))
+            .set(used.eq(true))
+            .execute(conn)?;
+
+        use schema::users;
+        match record.email {
+            Some(new_address) => {
+                if email_in_use(conn, &new_address, record.user_id)? {
+                    return Ok(Confirmation::EmailTaken);
+                }
+                diesel::update(users::table.find(record.user_id))
+                    .set((
+                        users::email.eq(new_address),
+                        users::email_verified_at.eq(Utc::now()),
+                    ))
+                    .execute(conn)?;
+            }
+            None => {
+                diesel::update(
+                    users::table
+                        .find(record.user_id)
+                        .filter(users::email_verified_at.is_null()),
+                )
+                .set(users::email_verified_at.eq(Utc::now()))
+                .execute(conn)?;
+            }
+        }
+
+        Ok(Confirmation::Verified(record.user_id))
+    })
+}
+
+/// Creates a verification token for a user and emails the link to them.
+/// Failures are logged rather than returned, as the user can ask for the
+/// email again.
+pub async fn send_verification(pool: &db::DbPool, owner: i32, address: String) {
+    deliver(pool, owner, address, false).await
+}
+
+/// Emails a link to a user's new address that switches their account to it
+/// once followed. Failures are logged, as for `send_verification`.
+pub async fn send_email_change(pool: &db::DbPool, owner: i32, new_address: String) {
+    deliver(pool, owner, new_address, true).await
+}
+
+async fn deliver(pool: &db::DbPool, owner: i32, address: String, changes_email: bool) {
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Failed to get database connection: {}", e);
+            return;
+        }
+    };
+
+    let new_address = changes_email.then(|| address.clone());
+    let verification_token =
+        match web::block(move || create_token(&mut conn, owner, new_address)).await {
+            Ok(Ok(verification_token)) => verification_token,
+            Ok(Err(e)) => {
+                error!("Failed to store email verification token: {}", e);
+                return;
+            }
+            Err(e) => {
+                error!("Email verification task failed: {}", e);
+                return;
+            }
+        };
+
+    let sent = if changes_email {
+        email::send_email_change_email(&address, &verification_token).await
+    } else {
+        email::send_email_verification_email(&address, &verification_token).await
+    };
+    if let Err(err) = sent {
+        error!("Failed to send email verification email: {}", err);
+
+        // Fall back to logging the link for development environments
+        info!(
+            "Email verification link for {}: {}/verify-email?token={}",
+            address,
+            config::get().frontend_url,
+            verification_token
+        );
+    }
+}
+
+/// Refuses actions that need a verified email address
+pub async fn require_verified_email(pool: &db::DbPool, owner: i32) -> Result<(), AppError> {
+    let mut conn = pool.get()?;
+
+    let verified = web::block(move || {
+        use schema::users::dsl::*;
+
+        users
+            .find(owner)
+            .select(email_verified_at.is_not_null())
+            .first::<bool>(&mut conn)
+    })
+    .await??;
+
+    if !verified {
+        return Err(AppError::EmailNotVerified);
+    }
+    Ok(())
+}
+
+/// Confirms a user's email address with the token from the emailed link.
+/// The token identifies the user, so no login is needed.
+#[post("/verify-email")]
+pub async fn verify_email(
+    pool: web::Data<db::DbPool>,
+    verify_data: web::Json<models::VerifyEmailRequest>,
+) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.get()?;
+
+    let presented = verify_data.into_inner().token;
+    match web::block(move || confirm_token(&mut conn, &presented)).await?? {
+        Confirmation::Verified(verified_user_id) => {
+            info!("User {} verified their email address", verified_user_id);
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "message": "Email address verified"
+            })))
+        }
+        Confirmation::Invalid => Err(AppError::bad_request(
+            "Invalid or expired verification link",
+        )),
+        Confirmation::EmailTaken => Err(AppError::conflict("This email address is already in use")),
+    }
+}
+
+/// Sends the current user a new verification link, replacing earlier ones
+#[post("/resend-verification")]
+pub async fn resend_verification(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    guard: web::Data<LoginGuard>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let user = web::block(move || {
+        schema::users::table
+            .find(current_user_id)
+            .first::<models::User>(&mut conn)
+            .optional()
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("User not found"))?;
+
+    if user.email_verified_at.is_some() {
+        return Err(AppError::bad_request("Email address is already verified"));
+    }
+
+    guard.check_verification_resend(current_user_id)?;
+
+    send_verification(&pool, current_user_id, user.email).await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Verification email sent"
+    })))
+}
@@ -0,0 +1,309 @@
+use crate::roles::Permission;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{BlockingError, InternalError};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::Duration;
+use log::{error, warn};
+use std::fmt;
+
+/// Header carrying the ID of a request, taken from the client when it sends a
+/// usable one and generated otherwise
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request ID accepted from a client
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    /// ID of the request being handled, so error responses can include it
+    static REQUEST_ID: String;
+}
+
+/// ID of a request, also stored in its extensions for handlers that log it
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Every way a request can fail. Each variant maps to an HTTP status and a
+/// stable `code` clients can match on, and is sent as
+/// `{"error": message, "code": code, "request_id": id}`.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// Wrong email or password at login
+    InvalidCredentials,
+    /// A decoded token was malformed, expired or signed with another key
+    InvalidToken(jsonwebtoken::errors::Error),
+    /// The action needs a fresh two-factor code
+    MfaRequired,
+    /// The action needs a verified email address
+    EmailNotVerified,
+    PermissionDenied(Permission),
+    InsufficientFunds,
+    /// The amount would go over a limit of the user's verification tier
+    LimitExceeded(String),
+    /// An uploaded file is over the size limit
+    FileTooLarge(String),
+    /// An uploaded file is not one of the accepted types
+    UnsupportedFileType(String),
+    /// An uploaded file was refused for what it contains; `code` says why,
+    /// such as `file_type_mismatch`
+    InvalidFile {
+        code: &'static str,
+        message: String,
+    },
+    /// Too many attempts or requests; `locked` marks a locked account
+    TooManyRequests {
+        message: &'static str,
+        retry_after: Duration,
+        locked: bool,
+    },
+    /// An upstream service the request depends on failed
+    Unavailable(String),
+    /// A failure the client cannot fix. The message is sent as is, so it must
+    /// not leak details.
+    Internal(String),
+    Database(diesel::result::Error),
+    Pool(r2d2::Error),
+    Blocking(BlockingError),
+}
+
+impl AppError {
+    pub fn bad_request(message: impl Into<String>) -> AppError {
+        AppError::BadRequest(message.into())
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> AppError {
+        AppError::Unauthorized(message.into())
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> AppError {
+        AppError::Forbidden(message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> AppError {
+        AppError::NotFound(message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> AppError {
+        AppError::Conflict(message.into())
+    }
+
+    pub fn internal(message: impl Into<String>) -> AppError {
+        AppError::Internal(message.into())
+    }
+
+    /// Machine-readable code sent with the error. These never change once
+    /// clients depend on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::InvalidCredentials => "invalid_credentials",
+            AppError::InvalidToken(_) => "invalid_token",
+            AppError::MfaRequired => "mfa_required",
+            AppError::EmailNotVerified => "email_not_verified",
+            AppError::PermissionDenied(_) => "permission_denied",
+            AppError::InsufficientFunds => "insufficient_funds",
+            AppError::LimitExceeded(_) => "limit_exceeded",
+            AppError::FileTooLarge(_) => "file_too_large",
+            AppError::UnsupportedFileType(_) => "unsupported_file_type",
+            AppError::InvalidFile { code, .. } => code,
+            AppError::TooManyRequests { locked: true, .. } => "account_locked",
+            AppError::TooManyRequests { .. } => "rate_limited",
+            AppError::Unavailable(_) => "service_unavailable",
+            AppError::Internal(_) | AppError::Blocking(_) => "internal_error",
+            AppError::Database(diesel::result::Error::NotFound) => "not_found",
+            AppError::Database(_) => "database_error",
+            AppError::Pool(_) => "database_unavailable",
+        }
+    }
+
+    /// Message shown to the client. Internal failures get a generic one; the
+    /// details are logged instead.
+    fn message(&self) -> String {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::LimitExceeded(message)
+            | AppError::FileTooLarge(message)
+            | AppError::UnsupportedFileType(message)
+            | AppError::InvalidFile { message, .. }
+            | AppError::Unavailable(message)
+            | AppError::Internal(message) => message.clone(),
+            AppError::InvalidCredentials => "Invalid email or password".to_string(),
+            AppError::InvalidToken(_) => "Invalid or expired token".to_string(),
+            AppError::MfaRequired => "A two-factor authentication code is required".to_string(),
+            AppError::EmailNotVerified => "Please verify your email address first".to_string(),
+            AppError::PermissionDenied(permission) => {
+                format!("Permission '{}' required", permission.as_str())
+            }
+            AppError::InsufficientFunds => "Insufficient balance".to_string(),
+            AppError::TooManyRequests { message, .. } => message.to_string(),
+            AppError::Database(diesel::result::Error::NotFound) => "Not found".to_string(),
+            AppError::Database(_) => "Database error".to_string(),
+            AppError::Pool(_) => "Database unavailable".to_string(),
+            AppError::Blocking(_) => "Internal server error".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InvalidToken(e) => write!(f, "invalid token: {}", e),
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            AppError::Pool(e) => write!(f, "connection pool error: {}", e),
+            AppError::Blocking(e) => write!(f, "blocking task failed: {}", e),
+            other => write!(f, "{}", other.message()),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::InsufficientFunds => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_)
+            | AppError::InvalidCredentials
+            | AppError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_)
+            | AppError::MfaRequired
+            | AppError::EmailNotVerified
+            | AppError::PermissionDenied(_)
+            | AppError::LimitExceeded(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) | AppError::Database(diesel::result::Error::NotFound) => {
+                StatusCode::NOT_FOUND
+            }
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::FileTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedFileType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::InvalidFile { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) | AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) | AppError::Database(_) | AppError::Blocking(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+        let context = request_id.as_deref().unwrap_or("-");
+        if status.is_server_error() {
+            error!("Request {} failed: {}", context, self);
+        } else if let AppError::InvalidToken(e) = self {
+            warn!("Request {} had an invalid token: {}", context, e);
+        }
+
+        let mut body = serde_json::json!({
+            "error": self.message(),
+            "code": self.code(),
+            "request_id": request_id
+        });
+
+        let mut response = HttpResponse::build(status);
+        if let AppError::TooManyRequests { retry_after, .. } = self {
+            // Round up so clients never retry a moment too early
+            let seconds = (retry_after.num_milliseconds().max(0) + 999) / 1000;
+            body["retry_after"] = serde_json::json!(seconds);
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(body)
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(e: diesel::result::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(e: r2d2::Error) -> Self {
+        AppError::Pool(e)
+    }
+}
+
+impl From<BlockingError> for AppError {
+    fn from(e: BlockingError) -> Self {
+        AppError::Blocking(e)
+    }
+}
+
+/// Decoding failures are the client's; failures to sign a token are mapped
+/// to `AppError::Internal` where tokens are issued
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        AppError::InvalidToken(e)
+    }
+}
+
+/// Reports a JSON body, path or query string that could not be parsed as a
+/// bad request in the usual error format. Registered with `JsonConfig`,
+/// `PathConfig` and `QueryConfig`.
+pub fn extractor_error(e: impl fmt::Display, _req: &HttpRequest) -> actix_web::Error {
+    AppError::BadRequest(e.to_string()).into()
+}
+
+/// Answers requests that no route matches
+pub async fn route_not_found() -> Result<HttpResponse, AppError> {
+    Err(AppError::not_found("No such endpoint"))
+}
+
+fn usable_request_id(value: &HeaderValue) -> Option<String> {
+    let id = value.to_str().ok()?;
+    let usable = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    usable.then(|| id.to_string())
+}
+
+/// Gives every request an ID, returned in the `X-Request-Id` header and in
+/// the body of error responses
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(usable_request_id)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let header_value = HeaderValue::from_str(&id).ok();
+
+    // Errors from middleware are rendered here, while the ID is still in scope
+    let response_header = header_value.clone();
+    let mut res = REQUEST_ID
+        .scope(id, async move {
+            next.call(req).await.map_err(|e| {
+                let mut response = e.error_response();
+                if let Some(value) = response_header {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                actix_web::Error::from(InternalError::from_response(e, response))
+            })
+        })
+        .await?;
+
+    if let Some(value) = header_value {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(res)
+}
@@ -0,0 +1,774 @@
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::ledger;
+use crate::matching::DepthLevels;
+use crate::models;
+use crate::orders::Exchange;
+use crate::schema;
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use actix_ws::{CloseCode, CloseReason, Message as WsMessage};
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{error, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How often the server pings each client
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a client may stay silent, pongs included, before it is dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How often 24h tickers are recomputed for subscribed instruments
+const TICKER_INTERVAL: Duration = Duration::from_secs(2);
+/// Messages queued for a client before it is considered too slow and dropped
+const OUTBOX_CAPACITY: usize = 256;
+
+/// A stream of updates a connection can subscribe to. Public channels are
+/// per instrument; private channels belong to the authenticated user.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Channel {
+    Depth(String),
+    Trades(String),
+    Ticker(String),
+    Orders(i32),
+    Balances(i32),
+}
+
+impl Channel {
+    fn parse(name: &str, symbol: Option<String>, user_id: Option<i32>) -> Result<Channel, String> {
+        match (name, symbol, user_id) {
+            ("depth" | "trades" | "ticker", None, _) => {
+                Err(format!("The {} channel needs a symbol", name))
+            }
+            ("depth", Some(symbol), _) => Ok(Channel::Depth(symbol)),
+            ("trades", Some(symbol), _) => Ok(Channel::Trades(symbol)),
+            ("ticker", Some(symbol), _) => Ok(Channel::Ticker(symbol)),
+            ("orders" | "balances", _, None) => {
+                Err("Authenticate before subscribing to private channels".to_string())
+            }
+            ("orders", _, Some(user_id)) => Ok(Channel::Orders(user_id)),
+            ("balances", _, Some(user_id)) => Ok(Channel::Balances(user_id)),
+            _ => Err(format!("Unknown channel {}", name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Depth(_) => "depth",
+            Channel::Trades(_) => "trades",
+            Channel::Ticker(_) => "ticker",
+            Channel::Orders(_) => "orders",
+            Channel::Balances(_) => "balances",
+        }
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        match self {
+            Channel::Depth(symbol) | Channel::Trades(symbol) | Channel::Ticker(symbol) => {
+                Some(symbol)
+            }
+            Channel::Orders(_) | Channel::Balances(_) => None,
+        }
+    }
+}
+
+/// Commands a client sends over the socket
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Command {
+    Auth {
+        token: String,
+    },
+    Subscribe {
+        channel: String,
+        symbol: Option<String>,
+    },
+    Unsubscribe {
+        channel: String,
+        symbol: Option<String>,
+    },
+}
+
+/// A trade as shown on the public feed, without the parties or their fees
+#[derive(Serialize)]
+struct PublicTrade {
+    id: i32,
+    price: Decimal,
+    quantity: Decimal,
+    taker_side: String,
+    created_at: chrono::DateTime<Utc>,
+}
+
+/// Trading statistics of an instrument over the last 24 hours
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Ticker {
+    pub symbol: String,
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub last: Option<Decimal>,
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+    pub change: Option<Decimal>,
+    pub change_percent: Option<Decimal>,
+    pub trade_count: i64,
+}
+
+/// Computes an instrument's 24h ticker from its fills
+pub fn ticker_24h(conn: &mut PgConnection, pair: &str) -> QueryResult<Ticker> {
+    use diesel::dsl::{count, sum};
+    use schema::fills::dsl::*;
+
+    let since = Utc::now() - ChronoDuration::hours(24);
+    let window = || fills.filter(symbol.eq(pair)).filter(created_at.gt(since));
+
+    let (volume, quote_volume, trade_count) = window()
+        .select((sum(quantity), sum(price * quantity), count(id)))
+        .first::<(Option<Decimal>, Option<Decimal>, i64)>(conn)?;
+    let open = window()
+        .order_by(id.asc())
+        .select(price)
+        .first::<Decimal>(conn)
+        .optional()?;
+    let last = window()
+        .order_by(id.desc())
+        .select(price)
+        .first::<Decimal>(conn)
+        .optional()?;
+    let high = window()
+        .order_by(price.desc())
+        .select(price)
+        .first::<Decimal>(conn)
+        .optional()?;
+    let low = window()
+        .order_by(price.asc())
+        .select(price)
+        .first::<Decimal>(conn)
+        .optional()?;
+
+    let change = open.zip(last).map(|(open, last)| last - open);
+    let change_percent = open
+        .zip(change)
+        .filter(|(open, _)| !open.is_zero())
+        .map(|(open, change)| (change / open * Decimal::ONE_HUNDRED).round_dp(2));
+
+    Ok(Ticker {
+        symbol: pair.to_string(),
+        open,
+        high,
+        low,
+        last,
+        volume: volume.unwrap_or_default(),
+        quote_volume: quote_volume.unwrap_or_default().round_dp(ledger::SCALE),
+        change,
+        change_percent,
+        trade_count,
+    })
+}
+
+fn error_message(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn depth_message(depth: &DepthChanged) -> String {
+    serde_json::json!({
+        "channel": "depth",
+        "symbol": depth.symbol,
+        "type": if depth.snapshot { "snapshot" } else { "update" },
+        "sequence": depth.sequence,
+        "bids": depth.bids,
+        "asks": depth.asks
+    })
+    .to_string()
+}
+
+fn ticker_message(ticker: &Ticker) -> String {
+    serde_json::json!({
+        "channel": "ticker",
+        "symbol": ticker.symbol,
+        "data": ticker
+    })
+    .to_string()
+}
+
+fn balances_message(balances: &[models::BalanceResponse]) -> String {
+    serde_json::json!({
+        "channel": "balances",
+        "data": balances
+    })
+    .to_string()
+}
+
+/// Registers a connection with the hub, which pushes messages into `sender`
+#[derive(Message)]
+#[rtype(result = "u64")]
+struct Connect {
+    sender: mpsc::Sender<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Disconnect {
+    id: u64,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Subscribe {
+    id: u64,
+    channel: Channel,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Unsubscribe {
+    id: u64,
+    channel: Channel,
+}
+
+/// Level-2 depth of a book: either a full snapshot or the levels that
+/// changed, each with the book's sequence number at that point. A level with
+/// zero quantity in an update has been removed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DepthChanged {
+    pub symbol: String,
+    pub snapshot: bool,
+    pub sequence: u64,
+    pub bids: DepthLevels,
+    pub asks: DepthLevels,
+}
+
+/// Trades that have been stored
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TradesExecuted {
+    pub symbol: String,
+    pub trades: Vec<models::FillRecord>,
+}
+
+/// Orders whose stored state has changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OrdersUpdated {
+    pub orders: Vec<models::Order>,
+}
+
+/// Users whose balances have changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BalancesChanged {
+    pub user_ids: Vec<i32>,
+}
+
+/// Fans exchange events out to WebSocket connections by channel.
+///
+/// Publishers send events only after the changes behind them are committed.
+/// A depth subscription starts with a snapshot and is followed by updates
+/// whose sequence numbers increase by one; clients drop updates at or below
+/// the snapshot's sequence and resubscribe if they see a gap.
+pub struct FeedHub {
+    pool: db::DbPool,
+    exchange: web::Data<Exchange>,
+    sessions: HashMap<u64, mpsc::Sender<String>>,
+    subscriptions: HashMap<Channel, HashSet<u64>>,
+    last_tickers: HashMap<String, Ticker>,
+    next_id: u64,
+}
+
+impl FeedHub {
+    pub fn new(pool: db::DbPool, exchange: web::Data<Exchange>) -> Self {
+        FeedHub {
+            pool,
+            exchange,
+            sessions: HashMap::new(),
+            subscriptions: HashMap::new(),
+            last_tickers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn send_to(&mut self, id: u64, payload: String) {
+        let Some(sender) = self.sessions.get(&id) else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(payload) {
+            // Dropping the sender makes the session close the connection
+            warn!("Dropping feed connection {} that is not keeping up", id);
+            self.remove_session(id);
+        }
+    }
+
+    fn broadcast(&mut self, channel: &Channel, payload: String) {
+        let subscribers: Vec<u64> = match self.subscriptions.get(channel) {
+            Some(subscribers) => subscribers.iter().copied().collect(),
+            None => return,
+        };
+        for id in subscribers {
+            self.send_to(id, payload.clone());
+        }
+    }
+
+    fn has_subscribers(&self, channel: &Channel) -> bool {
+        self.subscriptions.contains_key(channel)
+    }
+
+    fn remove_session(&mut self, id: u64) {
+        self.sessions.remove(&id);
+        self.subscriptions.retain(|_, subscribers| {
+            subscribers.remove(&id);
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Recomputes the tickers of every subscribed instrument and pushes the
+    /// ones that changed
+    fn refresh_tickers(&mut self, ctx: &mut Context<Self>) {
+        let symbols: Vec<String> = self
+            .subscriptions
+            .keys()
+            .filter_map(|channel| match channel {
+                Channel::Ticker(symbol) => Some(symbol.clone()),
+                _ => None,
+            })
+            .collect();
+        if symbols.is_empty() {
+            return;
+        }
+
+        let pool = self.pool.clone();
+        let tickers = web::block(move || -> Result<Vec<Ticker>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            symbols
+                .iter()
+                .map(|symbol| ticker_24h(&mut conn, symbol).map_err(|e| e.to_string()))
+                .collect()
+        });
+
+        ctx.spawn(tickers.into_actor(self).map(|result, hub, _| match result {
+            Ok(Ok(tickers)) => {
+                for ticker in tickers {
+                    if hub.last_tickers.get(&ticker.symbol) == Some(&ticker) {
+                        continue;
+                    }
+                    let channel = Channel::Ticker(ticker.symbol.clone());
+                    hub.broadcast(&channel, ticker_message(&ticker));
+                    hub.last_tickers.insert(ticker.symbol.clone(), ticker);
+                }
+            }
+            Ok(Err(e)) => error!("Failed to compute tickers: {}", e),
+            Err(e) => error!("Ticker task failed: {}", e),
+        }));
+    }
+}
+
+impl Actor for FeedHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(TICKER_INTERVAL, |hub, ctx| hub.refresh_tickers(ctx));
+    }
+}
+
+impl Handler<Connect> for FeedHub {
+    type Result = u64;
+
+    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
+        self.next_id += 1;
+        self.sessions.insert(self.next_id, msg.sender);
+        self.next_id
+    }
+}
+
+impl Handler<Disconnect> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) {
+        self.remove_session(msg.id);
+    }
+}
+
+/// The state a new subscriber receives before any updates
+enum Snapshot {
+    Depth(DepthChanged),
+    Ticker(Ticker),
+    Balances(Vec<models::BalanceResponse>),
+}
+
+/// Loads what a new subscriber needs before updates start: a depth snapshot,
+/// the current ticker or the user's balances. Also checks that a public
+/// channel names a listed instrument.
+fn load_snapshot(
+    pool: &db::DbPool,
+    exchange: &Exchange,
+    channel: &Channel,
+) -> Result<Option<Snapshot>, String> {
+    if let Some(symbol) = channel.symbol()
+        && !exchange.has_book(symbol)
+    {
+        return Err("Unknown trading pair".to_string());
+    }
+
+    match channel {
+        Channel::Depth(symbol) => Ok(exchange.depth_snapshot(symbol).map(Snapshot::Depth)),
+        Channel::Ticker(symbol) => {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            let ticker = ticker_24h(&mut conn, symbol).map_err(|e| e.to_string())?;
+            Ok(Some(Snapshot::Ticker(ticker)))
+        }
+        Channel::Balances(user_id) => {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            let balances = ledger::balances(&mut conn, *user_id).map_err(|e| e.to_string())?;
+            Ok(Some(Snapshot::Balances(balances)))
+        }
+        Channel::Trades(_) | Channel::Orders(_) => Ok(None),
+    }
+}
+
+impl Handler<Subscribe> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, ctx: &mut Self::Context) {
+        let pool = self.pool.clone();
+        let exchange = self.exchange.clone();
+        let channel = msg.channel.clone();
+        let snapshot = web::block(move || load_snapshot(&pool, &exchange, &channel));
+
+        // Waiting holds back other events until the subscriber has its
+        // snapshot, so no depth update can fall between the two
+        ctx.wait(snapshot.into_actor(self).map(move |result, hub, _| {
+            let snapshot = match result {
+                Ok(Ok(snapshot)) => snapshot,
+                Ok(Err(message)) => {
+                    hub.send_to(msg.id, error_message(&message));
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to load feed subscription: {}", e);
+                    hub.send_to(msg.id, error_message("Failed to subscribe"));
+                    return;
+                }
+            };
+
+            if !hub.sessions.contains_key(&msg.id) {
+                return;
+            }
+            hub.subscriptions
+                .entry(msg.channel.clone())
+                .or_default()
+                .insert(msg.id);
+
+            let confirmation = serde_json::json!({
+                "event": "subscribed",
+                "channel": msg.channel.name(),
+                "symbol": msg.channel.symbol()
+            });
+            hub.send_to(msg.id, confirmation.to_string());
+            match snapshot {
+                Some(Snapshot::Depth(depth)) => hub.send_to(msg.id, depth_message(&depth)),
+                Some(Snapshot::Ticker(ticker)) => {
+                    // A newer ticker goes to every subscriber, as a refresh would
+                    if hub.last_tickers.get(&ticker.symbol) == Some(&ticker) {
+                        hub.send_to(msg.id, ticker_message(&ticker));
+                    } else {
+                        hub.broadcast(&msg.channel, ticker_message(&ticker));
+                        hub.last_tickers.insert(ticker.symbol.clone(), ticker);
+                    }
+                }
+                Some(Snapshot::Balances(balances)) => {
+                    hub.send_to(msg.id, balances_message(&balances))
+                }
+                None => {}
+            }
+        }));
+    }
+}
+
+impl Handler<Unsubscribe> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
+        if let Some(subscribers) = self.subscriptions.get_mut(&msg.channel) {
+            subscribers.remove(&msg.id);
+            if subscribers.is_empty() {
+                self.subscriptions.remove(&msg.channel);
+            }
+        }
+
+        let confirmation = serde_json::json!({
+            "event": "unsubscribed",
+            "channel": msg.channel.name(),
+            "symbol": msg.channel.symbol()
+        });
+        self.send_to(msg.id, confirmation.to_string());
+    }
+}
+
+impl Handler<DepthChanged> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: DepthChanged, _: &mut Self::Context) {
+        let channel = Channel::Depth(msg.symbol.clone());
+        self.broadcast(&channel, depth_message(&msg));
+    }
+}
+
+impl Handler<TradesExecuted> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: TradesExecuted, _: &mut Self::Context) {
+        let channel = Channel::Trades(msg.symbol.clone());
+        if !self.has_subscribers(&channel) {
+            return;
+        }
+
+        let trades: Vec<PublicTrade> = msg
+            .trades
+            .into_iter()
+            .map(|trade| PublicTrade {
+                id: trade.id,
+                price: trade.price,
+                quantity: trade.quantity,
+                taker_side: trade.taker_side,
+                created_at: trade.created_at,
+            })
+            .collect();
+        let payload = serde_json::json!({
+            "channel": "trades",
+            "symbol": msg.symbol,
+            "data": trades
+        });
+        self.broadcast(&channel, payload.to_string());
+    }
+}
+
+impl Handler<OrdersUpdated> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: OrdersUpdated, _: &mut Self::Context) {
+        for order in msg.orders {
+            let channel = Channel::Orders(order.user_id);
+            if !self.has_subscribers(&channel) {
+                continue;
+            }
+            let payload = serde_json::json!({
+                "channel": "orders",
+                "data": order
+            });
+            self.broadcast(&channel, payload.to_string());
+        }
+    }
+}
+
+impl Handler<BalancesChanged> for FeedHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: BalancesChanged, ctx: &mut Self::Context) {
+        let user_ids: Vec<i32> = msg
+            .user_ids
+            .into_iter()
+            .filter(|user_id| self.has_subscribers(&Channel::Balances(*user_id)))
+            .collect();
+        if user_ids.is_empty() {
+            return;
+        }
+
+        let pool = self.pool.clone();
+        let balances = web::block(move || -> Result<Vec<_>, String> {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            user_ids
+                .into_iter()
+                .map(|user_id| {
+                    ledger::balances(&mut conn, user_id)
+                        .map(|balances| (user_id, balances))
+                        .map_err(|e| e.to_string())
+                })
+                .collect()
+        });
+
+        // Waiting keeps balance updates for a user in the order they happened
+        ctx.wait(
+            balances
+                .into_actor(self)
+                .map(|result, hub, _| match result {
+                    Ok(Ok(balances)) => {
+                        for (user_id, balances) in balances {
+                            hub.broadcast(&Channel::Balances(user_id), balances_message(&balances));
+                        }
+                    }
+                    Ok(Err(e)) => error!("Failed to load balances for feed: {}", e),
+                    Err(e) => error!("Balance feed task failed: {}", e),
+                }),
+        );
+    }
+}
+
+/// Handles one text frame from a client and returns an immediate reply, if any
+async fn handle_command(
+    id: u64,
+    user_id: &mut Option<i32>,
+    hub: &Addr<FeedHub>,
+    pool: &db::DbPool,
+    text: &str,
+) -> Option<String> {
+    let command = match serde_json::from_str::<Command>(text) {
+        Ok(command) => command,
+        Err(_) => return Some(error_message("Invalid command")),
+    };
+
+    match command {
+        Command::Auth { token } => {
+            let Ok(authenticated) = auth::decode_user_id(pool, &token).await else {
+                return Some(error_message("Invalid token"));
+            };
+            if user_id.is_some_and(|current| current != authenticated) {
+                return Some(error_message("Already authenticated as another user"));
+            }
+            *user_id = Some(authenticated);
+            Some(
+                serde_json::json!({ "event": "authenticated", "user_id": authenticated })
+                    .to_string(),
+            )
+        }
+        Command::Subscribe { channel, symbol } => {
+            match Channel::parse(&channel, symbol, *user_id) {
+                Ok(channel) => {
+                    hub.do_send(Subscribe { id, channel });
+                    None
+                }
+                Err(message) => Some(error_message(&message)),
+            }
+        }
+        Command::Unsubscribe { channel, symbol } => {
+            match Channel::parse(&channel, symbol, *user_id) {
+                Ok(channel) => {
+                    hub.do_send(Unsubscribe { id, channel });
+                    None
+                }
+                Err(message) => Some(error_message(&message)),
+            }
+        }
+    }
+}
+
+/// Pumps messages between one WebSocket connection and the hub until either
+/// side closes it or the client stops answering pings
+async fn run_session(
+    id: u64,
+    mut user_id: Option<i32>,
+    hub: Addr<FeedHub>,
+    pool: db::DbPool,
+    mut session: actix_ws::Session,
+    mut stream: actix_ws::MessageStream,
+    mut outbox: mpsc::Receiver<String>,
+) {
+    let mut last_heartbeat = Instant::now();
+    let mut heartbeat = actix_web::rt::time::interval(HEARTBEAT_INTERVAL);
+
+    let reason = loop {
+        tokio::select! {
+            message = stream.recv() => {
+                match message {
+                    Some(Ok(WsMessage::Text(text))) => {
+                        last_heartbeat = Instant::now();
+                        if let Some(reply) = handle_command(id, &mut user_id, &hub, &pool, &text).await
+                            && session.text(reply).await.is_err()
+                        {
+                            break None;
+                        }
+                    }
+                    Some(Ok(WsMessage::Ping(bytes))) => {
+                        last_heartbeat = Instant::now();
+                        if session.pong(&bytes).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Some(Ok(WsMessage::Pong(_))) => last_heartbeat = Instant::now(),
+                    Some(Ok(WsMessage::Close(reason))) => break reason,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break None,
+                }
+            }
+            payload = outbox.recv() => {
+                match payload {
+                    Some(payload) => {
+                        if session.text(payload).await.is_err() {
+                            break None;
+                        }
+                    }
+                    // The hub dropped this connection
+                    None => {
+                        break Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("Client is not keeping up".to_string()),
+                        });
+                    }
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                    break None;
+                }
+                if session.ping(b"").await.is_err() {
+                    break None;
+                }
+            }
+        }
+    };
+
+    hub.do_send(Disconnect { id });
+    let _ = session.close(reason).await;
+}
+
+/// Opens a WebSocket connection to the real-time feed
+///
+/// Clients send JSON commands:
+/// - `{"op": "subscribe", "channel": "depth" | "trades" | "ticker", "symbol": "BTC/USDT"}`
+/// - `{"op": "auth", "token": "<JWT>"}`, then
+///   `{"op": "subscribe", "channel": "orders" | "balances"}` for private updates
+/// - `{"op": "unsubscribe", ...}` with the same fields as the subscription
+///
+/// Clients that can send an Authorization header may authenticate during the
+/// handshake instead. Errors are reported as `{"error": ...}` messages.
+///
+/// Failed handshakes keep the responses actix-ws gives them, so this handler
+/// returns `actix_web::Error` rather than `AppError`.
+#[get("/ws")]
+pub async fn feed_socket(
+    req: HttpRequest,
+    body: web::Payload,
+    hub: web::Data<Addr<FeedHub>>,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = if req.headers().contains_key("Authorization") {
+        Some(auth::extract_user_id(&req)?)
+    } else {
+        None
+    };
+
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+
+    let (sender, outbox) = mpsc::channel(OUTBOX_CAPACITY);
+    let id = hub
+        .send(Connect { sender })
+        .await
+        .map_err(|_| AppError::Unavailable("Real-time feed is unavailable".to_string()))?;
+    info!("Feed connection {} opened", id);
+
+    actix_web::rt::spawn(run_session(
+        id,
+        user_id,
+        hub.get_ref().clone(),
+        pool.get_ref().clone(),
+        session,
+        stream,
+        outbox,
+    ));
+
+    Ok(response)
+}
@@ -0,0 +1,361 @@
+use crate::auth;
+use crate::config;
+use crate::db;
+use crate::error::AppError;
+use crate::ledger::{self, AccountKind, LedgerError, Leg};
+use crate::limits::Valuer;
+use crate::models;
+use crate::roles::Permission;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, delete, get, put, web};
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use log::info;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::HashMap;
+
+/// Number of days of trading that count towards a user's fee tier
+const VOLUME_WINDOW_DAYS: i64 = 30;
+
+/// Maker and taker rates that apply to a user, as fractions of the amount
+/// they receive from a trade
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRates {
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+/// Errors that can occur while changing the fee schedule
+enum FeeError {
+    NotFound,
+    Invalid(String),
+    Database(diesel::result::Error),
+}
+
+impl From<FeeError> for AppError {
+    fn from(e: FeeError) -> Self {
+        match e {
+            FeeError::NotFound => AppError::not_found("User not found"),
+            FeeError::Invalid(message) => AppError::BadRequest(message),
+            FeeError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for FeeError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                FeeError::NotFound
+            }
+            e => FeeError::Database(e),
+        }
+    }
+}
+
+/// Rates are fractions of the received amount, so 0.001 is 0.1%
+fn validate_rates(maker_rate: Decimal, taker_rate: Decimal) -> Result<(), String> {
+    let valid = |rate: Decimal| rate >= Decimal::ZERO && rate < Decimal::ONE;
+    if !valid(maker_rate) || !valid(taker_rate) {
+        return Err("Fee rates must be at least 0 and below 1".to_string());
+    }
+    Ok(())
+}
+
+/// A schedule must start at zero volume so every user falls into a tier
+fn validate_schedule(tiers: &[models::NewFeeTier]) -> Result<(), String> {
+    if !tiers.iter().any(|tier| tier.min_volume.is_zero()) {
+        return Err("The fee schedule needs a tier starting at a volume of 0".to_string());
+    }
+    for (i, tier) in tiers.iter().enumerate() {
+        if tier.min_volume < Decimal::ZERO {
+            return Err("Tier volumes must not be negative".to_string());
+        }
+        if tiers[..i]
+            .iter()
+            .any(|other| other.min_volume == tier.min_volume)
+        {
+            return Err(format!(
+                "More than one tier starts at a volume of {}",
+                tier.min_volume.normalize()
+            ));
+        }
+        validate_rates(tier.maker_rate, tier.taker_rate)?;
+    }
+    Ok(())
+}
+
+/// Value of everything a user has traded, as maker or taker, over the last
+/// 30 days, in the reference asset that limits are valued in. Each market's
+/// quote volume is valued at the last price of its quote asset, and markets
+/// whose quote asset cannot be priced are left out.
+pub fn thirty_day_volume(conn: &mut PgConnection, owner: i32) -> QueryResult<Decimal> {
+    let since = Utc::now() - Duration::days(VOLUME_WINDOW_DAYS);
+    let by_symbol = {
+        use schema::fills::dsl::*;
+
+        fills
+            .filter(created_at.gt(since))
+            .filter(maker_user_id.eq(owner).or(taker_user_id.eq(owner)))
+            .group_by(symbol)
+            .select((symbol, diesel::dsl::sum(price * quantity)))
+            .load::<(String, Option<Decimal>)>(conn)?
+    };
+
+    let symbols: Vec<&String> = by_symbol.iter().map(|(market, _)| market).collect();
+    let quotes: HashMap<String, String> = schema::instruments::table
+        .filter(schema::instruments::symbol.eq_any(symbols))
+        .select((
+            schema::instruments::symbol,
+            schema::instruments::quote_asset,
+        ))
+        .load::<(String, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    let mut valuer = Valuer::new();
+    let mut volume = Decimal::ZERO;
+    for (market, quote_volume) in by_symbol {
+        let Some(quote) = quotes.get(&market) else {
+            continue;
+        };
+        if let Some(value) = valuer.value(conn, quote, quote_volume.unwrap_or_default())? {
+            volume += value;
+        }
+    }
+    Ok(volume.round_dp(ledger::SCALE))
+}
+
+/// Rates of the highest tier a volume reaches
+fn tier_rates(conn: &mut PgConnection, volume: Decimal) -> QueryResult<FeeRates> {
+    use schema::fee_tiers::dsl::*;
+
+    let tier = fee_tiers
+        .filter(min_volume.le(volume))
+        .order_by(min_volume.desc())
+        .first::<models::FeeTier>(conn)
+        .optional()?;
+
+    Ok(tier
+        .map(|tier| FeeRates {
+            maker_rate: tier.maker_rate,
+            taker_rate: tier.taker_rate,
+        })
+        .unwrap_or(FeeRates {
+            maker_rate: Decimal::ZERO,
+            taker_rate: Decimal::ZERO,
+        }))
+}
+
+/// Rates that apply to a user: their override if an admin set one,
+/// otherwise the tier their 30-day volume reaches
+pub fn rates_for(conn: &mut PgConnection, owner: i32) -> QueryResult<FeeRates> {
+    let fee_override = schema::user_fee_overrides::table
+        .find(owner)
+        .first::<models::FeeOverride>(conn)
+        .optional()?;
+
+    if let Some(fee_override) = fee_override {
+        return Ok(FeeRates {
+            maker_rate: fee_override.maker_rate,
+            taker_rate: fee_override.taker_rate,
+        });
+    }
+
+    let volume = thirty_day_volume(conn, owner)?;
+    tier_rates(conn, volume)
+}
+
+/// Fee on an amount received from a trade, rounded down to the ledger's
+/// precision so a user is never charged more than their rate
+pub fn fee_amount(received: Decimal, rate: Decimal) -> Decimal {
+    (received * rate).round_dp_with_strategy(ledger::SCALE, RoundingStrategy::ToZero)
+}
+
+/// Moves trading fees from users' available balances to the house fee
+/// account in a single journal entry. Each charge is (user, asset, amount).
+pub fn collect(
+    conn: &mut PgConnection,
+    reference: &str,
+    charges: &[(i32, &str, Decimal)],
+) -> Result<(), LedgerError> {
+    let legs: Vec<Leg> = charges
+        .iter()
+        .filter(|(_, _, amount)| !amount.is_zero())
+        .flat_map(|&(owner, asset, amount)| {
+            [
+                Leg::user(owner, asset, AccountKind::Available, -amount),
+                Leg::system(asset, AccountKind::Fees, amount),
+            ]
+        })
+        .collect();
+
+    if legs.is_empty() {
+        return Ok(());
+    }
+
+    ledger::post_entry(conn, "fee", reference, &legs)?;
+    Ok(())
+}
+
+/// Returns the authenticated user's 30-day volume and the fee rates they pay
+#[get("/fees")]
+pub async fn user_fees(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let (volume, rates) = web::block(move || -> QueryResult<_> {
+        let volume = thirty_day_volume(&mut conn, current_user_id)?;
+        let rates = rates_for(&mut conn, current_user_id)?;
+        Ok((volume, rates))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "volume_30d": volume,
+        "volume_asset": config::get().limits.reference_asset,
+        "maker_rate": rates.maker_rate,
+        "taker_rate": rates.taker_rate
+    })))
+}
+
+// Get the fee schedule and every per-user override (admin only)
+#[get("/fees")]
+pub async fn admin_get_fees(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FeesRead).await?;
+
+    let mut conn = pool.get()?;
+
+    let (tiers, overrides) = web::block(move || -> QueryResult<_> {
+        let tiers = schema::fee_tiers::table
+            .order_by(schema::fee_tiers::min_volume.asc())
+            .load::<models::FeeTier>(&mut conn)?;
+        let overrides = schema::user_fee_overrides::table
+            .order_by(schema::user_fee_overrides::user_id.asc())
+            .load::<models::FeeOverride>(&mut conn)?;
+        Ok((tiers, overrides))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "tiers": tiers,
+        "overrides": overrides
+    })))
+}
+
+// Replace the volume-based fee schedule (admin only)
+#[put("/fees/tiers")]
+pub async fn admin_update_fee_tiers(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    schedule: web::Json<models::FeeScheduleRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FeesManage).await?;
+
+    let schedule = schedule.into_inner();
+    validate_schedule(&schedule.tiers).map_err(FeeError::Invalid)?;
+
+    let mut conn = pool.get()?;
+
+    let tiers = web::block(move || {
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(schema::fee_tiers::table).execute(conn)?;
+            diesel::insert_into(schema::fee_tiers::table)
+                .values(&schedule.tiers)
+                .execute(conn)?;
+            schema::fee_tiers::table
+                .order_by(schema::fee_tiers::min_volume.asc())
+                .load::<models::FeeTier>(conn)
+        })
+    })
+    .await??;
+
+    info!("Fee schedule replaced with {} tiers", tiers.len());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Fee schedule updated successfully",
+        "tiers": tiers
+    })))
+}
+
+// Set the rates a single user pays, replacing their volume tier (admin only)
+#[put("/fees/users/{user_id}")]
+pub async fn admin_set_fee_override(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+    rates: web::Json<models::FeeOverrideRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FeesManage).await?;
+
+    let target_user_id = path.into_inner();
+    let rates = rates.into_inner();
+    validate_rates(rates.maker_rate, rates.taker_rate).map_err(FeeError::Invalid)?;
+
+    let mut conn = pool.get()?;
+
+    let fee_override = web::block(move || {
+        use schema::user_fee_overrides::dsl::*;
+
+        diesel::insert_into(user_fee_overrides)
+            .values(&models::NewFeeOverride {
+                user_id: target_user_id,
+                maker_rate: rates.maker_rate,
+                taker_rate: rates.taker_rate,
+            })
+            .on_conflict(user_id)
+            .do_update()
+            .set((
+                maker_rate.eq(rates.maker_rate),
+                taker_rate.eq(rates.taker_rate),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<models::FeeOverride>(&mut conn)
+            .map_err(FeeError::from)
+    })
+    .await??;
+
+    info!("Fee override set for user {}", fee_override.user_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Fee override set successfully",
+        "override": fee_override
+    })))
+}
+
+// Remove a user's fee override so their volume tier applies again (admin only)
+#[delete("/fees/users/{user_id}")]
+pub async fn admin_delete_fee_override(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FeesManage).await?;
+
+    let target_user_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let removed = web::block(move || {
+        diesel::delete(schema::user_fee_overrides::table.find(target_user_id)).execute(&mut conn)
+    })
+    .await??;
+
+    if removed == 0 {
+        return Err(AppError::not_found("Fee override not found"));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Fee override removed successfully"
+    })))
+}
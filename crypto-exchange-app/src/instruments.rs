@@ -0,0 +1,314 @@
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::ledger;
+use crate::models;
+use crate::orders::Exchange;
+use crate::roles::Permission;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, delete, get, post, put, web};
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use log::info;
+use rust_decimal::Decimal;
+
+/// Trading statuses an instrument can be in
+///
+/// - `active`: orders are accepted and matched normally
+/// - `halted`: no new orders are accepted
+/// - `post_only`: only orders that rest on the book without matching are accepted
+pub const STATUSES: [&str; 3] = ["active", "halted", "post_only"];
+
+/// Errors that can occur while changing an instrument
+enum InstrumentError {
+    NotFound,
+    Invalid(String),
+    Exists,
+    HasOrders,
+    Database(diesel::result::Error),
+}
+
+impl From<InstrumentError> for AppError {
+    fn from(e: InstrumentError) -> Self {
+        match e {
+            InstrumentError::NotFound => AppError::not_found("Instrument not found"),
+            InstrumentError::Invalid(message) => AppError::BadRequest(message),
+            InstrumentError::Exists => AppError::conflict("Instrument already exists"),
+            InstrumentError::HasOrders => AppError::conflict(
+                "Instrument has order history and cannot be deleted. Halt it instead.",
+            ),
+            InstrumentError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for InstrumentError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                InstrumentError::Exists
+            }
+            e => InstrumentError::Database(e),
+        }
+    }
+}
+
+/// Checks that an instrument's trading rules are internally consistent
+fn validate_rules(instrument: &models::NewInstrument) -> Result<(), String> {
+    if !ledger::is_valid_asset(&instrument.base_asset)
+        || !ledger::is_valid_asset(&instrument.quote_asset)
+    {
+        return Err("Assets must be upper-case codes such as BTC or USDT".to_string());
+    }
+    if instrument.base_asset == instrument.quote_asset {
+        return Err("Base and quote assets must differ".to_string());
+    }
+    if instrument.price_tick <= Decimal::ZERO {
+        return Err("Price tick must be positive".to_string());
+    }
+    if instrument.quantity_step <= Decimal::ZERO {
+        return Err("Quantity step must be positive".to_string());
+    }
+    if instrument.min_quantity <= Decimal::ZERO {
+        return Err("Minimum quantity must be positive".to_string());
+    }
+    if instrument.max_quantity < instrument.min_quantity {
+        return Err("Maximum quantity must not be below the minimum quantity".to_string());
+    }
+    if instrument.min_notional < Decimal::ZERO {
+        return Err("Minimum notional must not be negative".to_string());
+    }
+    if !STATUSES.contains(&instrument.status.as_str()) {
+        return Err("Invalid status. Must be 'active', 'halted' or 'post_only'.".to_string());
+    }
+    Ok(())
+}
+
+/// Checks an order's prices and quantity against an instrument's trading
+/// rules. Market orders have no price; their notional is checked against the
+/// stop price when they have one.
+pub fn validate_order(
+    instrument: &models::Instrument,
+    price: Option<Decimal>,
+    stop_price: Option<Decimal>,
+    quantity: Decimal,
+) -> Result<(), String> {
+    if price.is_some_and(|price| !(price % instrument.price_tick).is_zero()) {
+        return Err(format!(
+            "Price must be a multiple of the tick size {}",
+            instrument.price_tick.normalize()
+        ));
+    }
+    if stop_price.is_some_and(|stop| !(stop % instrument.price_tick).is_zero()) {
+        return Err(format!(
+            "Stop price must be a multiple of the tick size {}",
+            instrument.price_tick.normalize()
+        ));
+    }
+    if !(quantity % instrument.quantity_step).is_zero() {
+        return Err(format!(
+            "Quantity must be a multiple of the step size {}",
+            instrument.quantity_step.normalize()
+        ));
+    }
+    if quantity < instrument.min_quantity {
+        return Err(format!(
+            "Quantity is below the minimum of {}",
+            instrument.min_quantity.normalize()
+        ));
+    }
+    if quantity > instrument.max_quantity {
+        return Err(format!(
+            "Quantity is above the maximum of {}",
+            instrument.max_quantity.normalize()
+        ));
+    }
+    if let Some(price) = price.or(stop_price)
+        && price * quantity < instrument.min_notional
+    {
+        return Err(format!(
+            "Order value is below the minimum notional of {}",
+            instrument.min_notional.normalize()
+        ));
+    }
+    Ok(())
+}
+
+/// Lists every instrument the exchange defines, including halted ones
+///
+/// # Returns
+/// A JSON response with the instruments and their trading rules
+#[get("/api/instruments")]
+pub async fn list_instruments(pool: web::Data<db::DbPool>) -> Result<HttpResponse, AppError> {
+    let mut conn = pool.get()?;
+
+    let instrument_list = web::block(move || {
+        use schema::instruments::dsl::*;
+        instruments
+            .order_by(symbol.asc())
+            .load::<models::Instrument>(&mut conn)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "instruments": instrument_list
+    })))
+}
+
+// Create a new instrument (admin only)
+#[post("/instruments")]
+pub async fn admin_create_instrument(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    exchange: web::Data<Exchange>,
+    instrument_data: web::Json<models::InstrumentRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::InstrumentsManage).await?;
+
+    let instrument_data = instrument_data.into_inner();
+    let new_instrument = models::NewInstrument {
+        symbol: format!(
+            "{}/{}",
+            instrument_data.base_asset, instrument_data.quote_asset
+        ),
+        base_asset: instrument_data.base_asset,
+        quote_asset: instrument_data.quote_asset,
+        price_tick: instrument_data.price_tick,
+        quantity_step: instrument_data.quantity_step,
+        min_quantity: instrument_data.min_quantity,
+        max_quantity: instrument_data.max_quantity,
+        min_notional: instrument_data.min_notional,
+        status: instrument_data
+            .status
+            .unwrap_or_else(|| "active".to_string()),
+    };
+
+    validate_rules(&new_instrument).map_err(InstrumentError::Invalid)?;
+
+    let mut conn = pool.get()?;
+
+    let instrument = web::block(move || {
+        diesel::insert_into(schema::instruments::table)
+            .values(&new_instrument)
+            .get_result::<models::Instrument>(&mut conn)
+            .map_err(InstrumentError::from)
+    })
+    .await??;
+
+    exchange.add_book(&instrument.symbol);
+    info!("Instrument {} created", instrument.symbol);
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "Instrument created successfully",
+        "instrument": instrument
+    })))
+}
+
+// Update an instrument's trading rules or status (admin only)
+#[put("/instruments/{instrument_id}")]
+pub async fn admin_update_instrument(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+    instrument_data: web::Json<models::UpdateInstrumentRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::InstrumentsManage).await?;
+
+    let instrument_id = path.into_inner();
+    let update = instrument_data.into_inner();
+
+    let mut conn = pool.get()?;
+
+    let instrument = web::block(move || {
+        use schema::instruments::dsl::*;
+
+        conn.transaction::<_, InstrumentError, _>(|conn| {
+            let existing = instruments
+                .find(instrument_id)
+                .for_update()
+                .first::<models::Instrument>(conn)
+                .optional()?
+                .ok_or(InstrumentError::NotFound)?;
+
+            // Validate the instrument as it will look after the update
+            let merged = models::NewInstrument {
+                symbol: existing.symbol,
+                base_asset: existing.base_asset,
+                quote_asset: existing.quote_asset,
+                price_tick: update.price_tick.unwrap_or(existing.price_tick),
+                quantity_step: update.quantity_step.unwrap_or(existing.quantity_step),
+                min_quantity: update.min_quantity.unwrap_or(existing.min_quantity),
+                max_quantity: update.max_quantity.unwrap_or(existing.max_quantity),
+                min_notional: update.min_notional.unwrap_or(existing.min_notional),
+                status: update.status.unwrap_or(existing.status),
+            };
+            validate_rules(&merged).map_err(InstrumentError::Invalid)?;
+
+            diesel::update(instruments.find(instrument_id))
+                .set((
+                    price_tick.eq(merged.price_tick),
+                    quantity_step.eq(merged.quantity_step),
+                    min_quantity.eq(merged.min_quantity),
+                    max_quantity.eq(merged.max_quantity),
+                    min_notional.eq(merged.min_notional),
+                    status.eq(merged.status),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .get_result::<models::Instrument>(conn)
+                .map_err(InstrumentError::from)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Instrument updated successfully",
+        "instrument": instrument
+    })))
+}
+
+// Delete an instrument that has never been traded (admin only)
+#[delete("/instruments/{instrument_id}")]
+pub async fn admin_delete_instrument(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    exchange: web::Data<Exchange>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::InstrumentsManage).await?;
+
+    let instrument_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let instrument = web::block(move || {
+        use schema::instruments::dsl::*;
+
+        conn.transaction::<_, InstrumentError, _>(|conn| {
+            let existing = instruments
+                .find(instrument_id)
+                .for_update()
+                .first::<models::Instrument>(conn)
+                .optional()?
+                .ok_or(InstrumentError::NotFound)?;
+
+            let order_count = schema::orders::table
+                .filter(schema::orders::symbol.eq(&existing.symbol))
+                .count()
+                .get_result::<i64>(conn)?;
+            if order_count > 0 {
+                return Err(InstrumentError::HasOrders);
+            }
+
+            diesel::delete(instruments.find(instrument_id)).execute(conn)?;
+            Ok(existing)
+        })
+    })
+    .await??;
+
+    exchange.remove_book(&instrument.symbol);
+    info!("Instrument {} deleted", instrument.symbol);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Instrument deleted successfully"
+    })))
+}
@@ -0,0 +1,472 @@
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::models;
+use crate::roles::Permission;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::NaiveDate;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::info;
+
+/// How many times a user may be rejected before only support can reopen
+/// their verification
+const MAX_REJECTIONS: i64 = 3;
+
+/// Longest reason a reviewer can give
+pub const MAX_REASON_LEN: usize = 1000;
+
+/// States a KYC verification moves through
+///
+/// - `draft`: the user is filling in their details and documents
+/// - `submitted`: waiting for a reviewer
+/// - `in_review`: a reviewer has picked it up
+/// - `needs_more_info`: sent back to the user to fix or add something
+/// - `approved`, `rejected`: the reviewer's decision
+/// - `expired`: an approval, or a request for more information, that
+///   lapsed and must be redone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KycStatus {
+    Draft,
+    Submitted,
+    InReview,
+    NeedsMoreInfo,
+    Approved,
+    Rejected,
+    Expired,
+}
+
+impl KycStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KycStatus::Draft => "draft",
+            KycStatus::Submitted => "submitted",
+            KycStatus::InReview => "in_review",
+            KycStatus::NeedsMoreInfo => "needs_more_info",
+            KycStatus::Approved => "approved",
+            KycStatus::Rejected => "rejected",
+            KycStatus::Expired => "expired",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<KycStatus> {
+        match value {
+            "draft" => Some(KycStatus::Draft),
+            "submitted" => Some(KycStatus::Submitted),
+            "in_review" => Some(KycStatus::InReview),
+            "needs_more_info" => Some(KycStatus::NeedsMoreInfo),
+            "approved" => Some(KycStatus::Approved),
+            "rejected" => Some(KycStatus::Rejected),
+            "expired" => Some(KycStatus::Expired),
+            _ => None,
+        }
+    }
+
+    /// States a verification may move to from this one
+    pub fn next_states(&self) -> &'static [KycStatus] {
+        use KycStatus::*;
+
+        match self {
+            Draft => &[Submitted],
+            Submitted => &[InReview, NeedsMoreInfo, Approved, Rejected],
+            InReview => &[NeedsMoreInfo, Approved, Rejected],
+            NeedsMoreInfo => &[Submitted, Expired],
+            Approved => &[Expired],
+            Rejected | Expired => &[Draft],
+        }
+    }
+
+    pub fn can_move_to(&self, next: KycStatus) -> bool {
+        self.next_states().contains(&next)
+    }
+
+    /// Whether the user may change their details and documents
+    pub fn is_editable(&self) -> bool {
+        matches!(self, KycStatus::Draft | KycStatus::NeedsMoreInfo)
+    }
+
+    /// States only a reviewer can move a verification to
+    pub fn is_review_decision(&self) -> bool {
+        matches!(
+            self,
+            KycStatus::InReview
+                | KycStatus::NeedsMoreInfo
+                | KycStatus::Approved
+                | KycStatus::Rejected
+                | KycStatus::Expired
+        )
+    }
+
+    /// Moving to these states needs a reason the user is shown
+    pub fn needs_reason(&self) -> bool {
+        matches!(self, KycStatus::NeedsMoreInfo | KycStatus::Rejected)
+    }
+}
+
+/// `id_document.id_verification_status` as clients saw it before the state
+/// machine: "pending_review" while waiting for a reviewer
+pub fn legacy_document_status(status: &str) -> &str {
+    match status {
+        "submitted" | "in_review" => "pending_review",
+        other => other,
+    }
+}
+
+/// Errors that can occur while moving a verification between states
+pub enum KycError {
+    NotFound,
+    InvalidTransition(KycStatus, KycStatus),
+    ReasonRequired(KycStatus),
+    NotEditable(KycStatus),
+    TooManyRejections,
+    Database(diesel::result::Error),
+}
+
+impl From<KycError> for AppError {
+    fn from(e: KycError) -> Self {
+        match e {
+            KycError::NotFound => AppError::not_found("Verification not found"),
+            KycError::InvalidTransition(from, to) => AppError::conflict(format!(
+                "A verification that is {} cannot become {}",
+                from.as_str(),
+                to.as_str()
+            )),
+            KycError::ReasonRequired(status) => {
+                AppError::bad_request(format!("A reason is required for {}", status.as_str()))
+            }
+            KycError::NotEditable(status) => AppError::conflict(format!(
+                "Your verification is {} and can no longer be changed",
+                status.as_str()
+            )),
+            KycError::TooManyRejections => AppError::forbidden(
+                "Your verification was rejected too many times. Please contact support.",
+            ),
+            KycError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for KycError {
+    fn from(e: diesel::result::Error) -> Self {
+        KycError::Database(e)
+    }
+}
+
+/// Reads a stored status. The column's check constraint only allows the
+/// states `KycStatus` knows.
+pub fn status_of(verification: &models::UserVerification) -> KycStatus {
+    KycStatus::parse(&verification.verification_status).unwrap_or(KycStatus::Draft)
+}
+
+/// Whether the user has filled in every required personal detail: their
+/// name, a real date of birth, their address, phone number and occupation.
+/// The apartment is optional.
+pub fn has_personal_details(verification: &models::UserVerification) -> bool {
+    let required = [
+        &verification.first_name,
+        &verification.last_name,
+        &verification.street_address,
+        &verification.city,
+        &verification.postal_code,
+        &verification.country_code,
+        &verification.phone_number,
+        &verification.occupation,
+    ];
+    let date_of_birth = u32::try_from(verification.dob_month)
+        .ok()
+        .zip(u32::try_from(verification.dob_day).ok())
+        .and_then(|(month, day)| NaiveDate::from_ymd_opt(verification.dob_year, month, day));
+
+    required.iter().all(|field| !field.trim().is_empty()) && date_of_birth.is_some()
+}
+
+/// Locks a user's verification for the rest of the transaction
+pub fn lock_for_user(
+    conn: &mut PgConnection,
+    owner: i32,
+) -> Result<models::UserVerification, KycError> {
+    use schema::user_verifications::dsl::*;
+
+    user_verifications
+        .filter(user_id.eq(owner))
+        .for_update()
+        .first::<models::UserVerification>(conn)
+        .optional()?
+        .ok_or(KycError::NotFound)
+}
+
+/// Locks a verification for the rest of the transaction
+pub fn lock(conn: &mut PgConnection, target_id: i32) -> Result<models::UserVerification, KycError> {
+    schema::user_verifications::table
+        .find(target_id)
+        .for_update()
+        .first::<models::UserVerification>(conn)
+        .optional()?
+        .ok_or(KycError::NotFound)
+}
+
+/// Fails unless the user may still change a verification
+pub fn ensure_editable(verification: &models::UserVerification) -> Result<(), KycError> {
+    let status = status_of(verification);
+    if !status.is_editable() {
+        return Err(KycError::NotEditable(status));
+    }
+    Ok(())
+}
+
+/// Records the state a new verification starts in
+pub fn record_created(
+    conn: &mut PgConnection,
+    verification: &models::UserVerification,
+    changed_by: i32,
+) -> QueryResult<()> {
+    diesel::insert_into(schema::kyc_status_history::table)
+        .values(&models::NewKycStatusChange {
+            verification_id: verification.id,
+            from_status: None,
+            to_status: verification.verification_status.clone(),
+            reason: None,
+            changed_by: Some(changed_by),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Moves a verification to a new state if the state machine allows it, and
+/// records the change. `changed_by` is the user or reviewer making it.
+pub fn transition(
+    conn: &mut PgConnection,
+    target_id: i32,
+    to: KycStatus,
+    reason: Option<String>,
+    changed_by: i32,
+) -> Result<models::UserVerification, KycError> {
+    use schema::user_verifications::dsl::*;
+
+    let reason = reason
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty());
+    if to.needs_reason() && reason.is_none() {
+        return Err(KycError::ReasonRequired(to));
+    }
+
+    conn.transaction(|conn| {
+        let verification = lock(conn, target_id)?;
+
+        let from = status_of(&verification);
+        if !from.can_move_to(to) {
+            return Err(KycError::InvalidTransition(from, to));
+        }
+
+        let now = chrono::Utc::now().naive_utc();
+        let submitted = if to == KycStatus::Submitted {
+            Some(now)
+        } else {
+            verification.submitted_at
+        };
+        let reviewed = match to {
+            KycStatus::NeedsMoreInfo | KycStatus::Approved | KycStatus::Rejected => Some(now),
+            KycStatus::Draft => None,
+            _ => verification.reviewed_at,
+        };
+        // A new decision replaces the reason for the last one; resubmitting
+        // keeps it so the user can still see what they were asked to fix
+        let shown_reason = match to {
+            KycStatus::Draft | KycStatus::Submitted | KycStatus::InReview => {
+                verification.status_reason.clone()
+            }
+            _ => reason.clone(),
+        };
+
+        let updated = diesel::update(user_verifications.find(target_id))
+            .set((
+                verification_status.eq(to.as_str()),
+                status_reason.eq(shown_reason),
+                submitted_at.eq(submitted),
+                reviewed_at.eq(reviewed),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<models::UserVerification>(conn)?;
+
+        diesel::insert_into(schema::kyc_status_history::table)
+            .values(&models::NewKycStatusChange {
+                verification_id: target_id,
+                from_status: Some(from.as_str().to_string()),
+                to_status: to.as_str().to_string(),
+                reason,
+                changed_by: Some(changed_by),
+            })
+            .execute(conn)?;
+
+        Ok(updated)
+    })
+}
+
+/// Reopens a rejected or expired verification as a draft the user can
+/// change and submit again. Rejections are limited to `MAX_REJECTIONS`.
+fn reopen(conn: &mut PgConnection, owner: i32) -> Result<models::UserVerification, KycError> {
+    conn.transaction(|conn| {
+        let verification = lock_for_user(conn, owner)?;
+
+        if status_of(&verification) == KycStatus::Rejected {
+            use schema::kyc_status_history::dsl::*;
+
+            let rejections = kyc_status_history
+                .filter(verification_id.eq(verification.id))
+                .filter(to_status.eq(KycStatus::Rejected.as_str()))
+                .count()
+                .get_result::<i64>(conn)?;
+            if rejections >= MAX_REJECTIONS {
+                return Err(KycError::TooManyRejections);
+            }
+        }
+
+        transition(conn, verification.id, KycStatus::Draft, None, owner)
+    })
+}
+
+/// Starts a new attempt after a rejection or expiry. The details already
+/// given are kept so only what the reviewer asked about needs changing.
+#[post("/resubmit")]
+pub async fn resubmit(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let verification = web::block(move || reopen(&mut conn, current_user_id)).await??;
+
+    info!("User {} reopened their verification", current_user_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": verification.verification_status,
+        "verification": models::VerificationResponse::from(verification)
+    })))
+}
+
+/// Every state a verification has been in, oldest first (KYC staff only)
+#[get("/verify/{verification_id}/history")]
+pub async fn admin_verification_history(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::KycRead).await?;
+
+    let target_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let changes = web::block(move || {
+        use schema::kyc_status_history::dsl::*;
+
+        kyc_status_history
+            .filter(verification_id.eq(target_id))
+            .order_by(id.asc())
+            .load::<models::KycStatusChange>(&mut conn)
+    })
+    .await??;
+
+    if changes.is_empty() {
+        return Err(AppError::not_found("Verification not found"));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "verification_id": target_id,
+        "history": changes
+    })))
+}
+
+/// Checks a reviewer's decision before it is applied
+pub fn parse_decision(decision: &models::KycDecisionRequest) -> Result<KycStatus, AppError> {
+    let status = KycStatus::parse(&decision.status)
+        .filter(KycStatus::is_review_decision)
+        .ok_or_else(|| {
+            AppError::bad_request(
+                "Status must be one of in_review, needs_more_info, approved, rejected or expired",
+            )
+        })?;
+    if decision
+        .reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_REASON_LEN)
+    {
+        return Err(AppError::bad_request(format!(
+            "Reason must be at most {} characters",
+            MAX_REASON_LEN
+        )));
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [KycStatus; 7] = [
+        KycStatus::Draft,
+        KycStatus::Submitted,
+        KycStatus::InReview,
+        KycStatus::NeedsMoreInfo,
+        KycStatus::Approved,
+        KycStatus::Rejected,
+        KycStatus::Expired,
+    ];
+
+    fn walk(path: &[KycStatus]) {
+        for pair in path.windows(2) {
+            assert!(
+                pair[0].can_move_to(pair[1]),
+                "{} -> {} should be allowed",
+                pair[0].as_str(),
+                pair[1].as_str()
+            );
+        }
+    }
+
+    #[test]
+    fn walks_the_allowed_transitions() {
+        use KycStatus::*;
+
+        walk(&[Draft, Submitted, InReview, Approved, Expired, Draft]);
+        walk(&[Draft, Submitted, Approved]);
+        walk(&[
+            Submitted,
+            NeedsMoreInfo,
+            Submitted,
+            InReview,
+            NeedsMoreInfo,
+            Expired,
+        ]);
+        walk(&[Submitted, InReview, Rejected, Draft, Submitted, Rejected]);
+    }
+
+    #[test]
+    fn refuses_illegal_transitions() {
+        use KycStatus::*;
+
+        assert!(!Draft.can_move_to(Approved));
+        assert!(!Approved.can_move_to(Submitted));
+        assert!(!Draft.can_move_to(InReview));
+        assert!(!Rejected.can_move_to(Approved));
+        assert!(!Expired.can_move_to(Submitted));
+        for status in ALL {
+            assert!(!status.can_move_to(status), "{} -> itself", status.as_str());
+        }
+    }
+
+    #[test]
+    fn only_sending_back_and_rejecting_need_a_reason() {
+        let needing: Vec<KycStatus> = ALL.into_iter().filter(KycStatus::needs_reason).collect();
+
+        assert_eq!(needing, [KycStatus::NeedsMoreInfo, KycStatus::Rejected]);
+    }
+
+    #[test]
+    fn stored_names_round_trip() {
+        for status in ALL {
+            assert_eq!(KycStatus::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(KycStatus::parse("pending_review"), None);
+    }
+}
@@ -0,0 +1,747 @@
+use crate::auth;
+use crate::config::{Config, UploadConfig};
+use crate::db;
+use crate::document_store::{self, DocumentStore};
+use crate::email_verification;
+use crate::error::{AppError, RequestId};
+use crate::kyc::{self, KycError, KycStatus};
+use crate::models;
+use crate::roles::Permission;
+use crate::schema;
+use crate::sessions::ClientInfo;
+use crate::upload_validation::{self, UploadError};
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, get, post, put, web};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use futures::{StreamExt, TryStreamExt};
+use log::{info, warn};
+use std::path::Path;
+use uuid::Uuid;
+
+/// Kinds of document a user can upload for KYC. Each verification holds at
+/// most one of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentType {
+    IdFront,
+    IdBack,
+    Selfie,
+    ProofOfAddress,
+}
+
+impl DocumentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocumentType::IdFront => "id_front",
+            DocumentType::IdBack => "id_back",
+            DocumentType::Selfie => "selfie",
+            DocumentType::ProofOfAddress => "proof_of_address",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<DocumentType> {
+        match value {
+            "id_front" => Some(DocumentType::IdFront),
+            "id_back" => Some(DocumentType::IdBack),
+            "selfie" => Some(DocumentType::Selfie),
+            "proof_of_address" => Some(DocumentType::ProofOfAddress),
+            _ => None,
+        }
+    }
+}
+
+/// Documents a verification needs before it can be submitted. Tier 2 needs
+/// all of them approved.
+pub const REQUIRED_DOCUMENTS: [DocumentType; 2] =
+    [DocumentType::IdFront, DocumentType::ProofOfAddress];
+
+/// Review statuses of a document, which is pending until a reviewer judges it
+const PENDING: &str = "pending";
+const APPROVED: &str = "approved";
+const REJECTED: &str = "rejected";
+
+/// Uploads need the personal details to exist first
+pub fn verification_required(e: KycError) -> AppError {
+    match e {
+        KycError::NotFound => AppError::bad_request(
+            "You must complete personal information verification before uploading documents",
+        ),
+        e => e.into(),
+    }
+}
+
+/// Refuses uploads before anything is written when the user has no
+/// verification or can no longer change it
+pub async fn ensure_can_upload(pool: &db::DbPool, owner: i32) -> Result<(), AppError> {
+    let mut conn = pool.get()?;
+    web::block(move || {
+        use schema::user_verifications::dsl::*;
+
+        let verification = user_verifications
+            .filter(user_id.eq(owner))
+            .first::<models::UserVerification>(&mut conn)
+            .optional()?
+            .ok_or(KycError::NotFound)?;
+        kyc::ensure_editable(&verification)
+    })
+    .await?
+    .map_err(verification_required)
+}
+
+/// A file received from an upload, not yet stored
+pub struct ReceivedFile {
+    /// Key the file is stored under, which says whose it is and what it is
+    pub key: String,
+    pub content_type: String,
+    pub contents: Vec<u8>,
+}
+
+/// Reads the file of a multipart upload and checks it with
+/// `upload_validation`. Exactly one file is accepted: a second one is
+/// refused rather than silently replacing the first, and other fields are
+/// ignored. Reading stops as soon as the file goes over the size limit.
+pub async fn receive_file(
+    mut payload: Multipart,
+    owner: i32,
+    kind: DocumentType,
+    limits: &UploadConfig,
+) -> Result<ReceivedFile, AppError> {
+    let mut received: Option<ReceivedFile> = None;
+    while let Some(mut field) = payload.try_next().await.map_err(|e| {
+        warn!("Invalid multipart upload: {}", e);
+        AppError::bad_request("Invalid upload")
+    })? {
+        if field.content_disposition().get_filename().is_none() {
+            // Drain fields that are not files
+            while field.next().await.is_some() {}
+            continue;
+        }
+
+        if received.is_some() {
+            return Err(AppError::bad_request("Upload one file per request"));
+        }
+
+        let declared = field.content_type().cloned();
+        let file_name = field
+            .content_disposition()
+            .get_filename()
+            .map(str::to_string);
+        upload_validation::check_declared_type(declared.as_ref())?;
+
+        let mut contents = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let data = chunk.map_err(|e| {
+                warn!("Failed to read file chunk: {}", e);
+                AppError::bad_request("Failed to upload file")
+            })?;
+            if contents.len() + data.len() > limits.max_file_bytes {
+                return Err(UploadError::TooLarge(limits.max_file_bytes).into());
+            }
+            contents.extend_from_slice(&data);
+        }
+
+        let max_pdf_pages = limits.max_pdf_pages;
+        let (file_kind, contents) = web::block(move || {
+            upload_validation::validate(
+                contents,
+                declared.as_ref(),
+                file_name.as_deref(),
+                max_pdf_pages,
+            )
+        })
+        .await??;
+
+        received = Some(ReceivedFile {
+            key: format!(
+                "{}_{}_{}.{}",
+                owner,
+                kind.as_str(),
+                Uuid::new_v4(),
+                file_kind.extension()
+            ),
+            content_type: file_kind.mime().to_string(),
+            contents,
+        });
+    }
+
+    received.ok_or_else(|| AppError::bad_request("No file uploaded"))
+}
+
+/// Encrypts and stores an uploaded file, then records it as the user's
+/// document of its kind. The stored file is removed again if it cannot be
+/// recorded, and the file of the document it replaced once it is.
+pub async fn store_upload(
+    pool: &db::DbPool,
+    store: &dyn DocumentStore,
+    owner: i32,
+    kind: DocumentType,
+    file: ReceivedFile,
+) -> Result<models::KycDocument, AppError> {
+    store.put(&file.key, file.contents).await?;
+
+    let mut conn = pool.get()?;
+    let key = file.key.clone();
+    let recorded =
+        web::block(move || record_upload(&mut conn, owner, kind, &key, &file.content_type)).await?;
+    let (document, replaced) = match recorded {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            discard(store, &file.key).await;
+            return Err(verification_required(e));
+        }
+    };
+
+    if let Some(replaced) = replaced {
+        discard(store, &replaced).await;
+    }
+    Ok(document)
+}
+
+/// Deletes a stored file nothing refers to any more
+pub async fn discard(store: &dyn DocumentStore, key: &str) {
+    if let Err(e) = store.delete(key).await {
+        warn!("Failed to delete document {}: {}", key, e);
+    }
+}
+
+/// Records a stored file as the user's document of its kind, replacing any
+/// earlier one, which goes back to waiting for review. Returns the document
+/// and the storage key of the file it replaced.
+pub fn record_upload(
+    conn: &mut PgConnection,
+    owner: i32,
+    kind: DocumentType,
+    key: &str,
+    file_type: &str,
+) -> Result<(models::KycDocument, Option<String>), KycError> {
+    conn.transaction(|conn| {
+        let verification = kyc::lock_for_user(conn, owner)?;
+        kyc::ensure_editable(&verification)?;
+
+        use schema::kyc_documents::dsl::*;
+
+        let replaced = kyc_documents
+            .filter(verification_id.eq(verification.id))
+            .filter(document_type.eq(kind.as_str()))
+            .select(storage_key)
+            .first::<String>(conn)
+            .optional()?;
+
+        let document = diesel::insert_into(kyc_documents)
+            .values(&models::NewKycDocument {
+                verification_id: verification.id,
+                document_type: kind.as_str().to_string(),
+                storage_key: key.to_string(),
+                content_type: file_type.to_string(),
+            })
+            .on_conflict((verification_id, document_type))
+            .do_update()
+            .set((
+                storage_key.eq(key),
+                content_type.eq(file_type),
+                review_status.eq(PENDING),
+                reviewer_notes.eq(None::<String>),
+                reviewed_by.eq(None::<i32>),
+                reviewed_at.eq(None::<chrono::DateTime<chrono::Utc>>),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<models::KycDocument>(conn)?;
+
+        // Clients that know only one document still read the front of the ID
+        // from the verification
+        if kind == DocumentType::IdFront {
+            use schema::user_verifications::dsl as verifications;
+
+            diesel::update(verifications::user_verifications.find(verification.id))
+                .set((
+                    verifications::id_front_path.eq(key),
+                    verifications::updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok((document, replaced))
+    })
+}
+
+/// Every document of a verification, in upload order
+pub fn for_verification(
+    conn: &mut PgConnection,
+    target_id: i32,
+) -> QueryResult<Vec<models::KycDocument>> {
+    use schema::kyc_documents::dsl::*;
+
+    kyc_documents
+        .filter(verification_id.eq(target_id))
+        .order_by(id.asc())
+        .load::<models::KycDocument>(conn)
+}
+
+/// Whether every required document of a verification has been approved
+pub fn required_approved(conn: &mut PgConnection, target_id: i32) -> QueryResult<bool> {
+    use schema::kyc_documents::dsl::*;
+
+    let approved = kyc_documents
+        .filter(verification_id.eq(target_id))
+        .filter(document_type.eq_any(REQUIRED_DOCUMENTS.map(|kind| kind.as_str())))
+        .filter(review_status.eq(APPROVED))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(approved == REQUIRED_DOCUMENTS.len() as i64)
+}
+
+/// Uploads one of the user's KYC documents: `id_front`, `id_back`, `selfie`
+/// or `proof_of_address`. Uploading a type again replaces it.
+#[post("/documents/{document_type}")]
+pub async fn upload_document(
+    req: HttpRequest,
+    payload: Multipart,
+    path: web::Path<String>,
+    pool: web::Data<db::DbPool>,
+    store: web::Data<dyn DocumentStore>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let kind = DocumentType::parse(&path.into_inner()).ok_or_else(|| {
+        AppError::bad_request(
+            "Document type must be one of id_front, id_back, selfie or proof_of_address",
+        )
+    })?;
+
+    // KYC submission needs a verified email address
+    email_verification::require_verified_email(&pool, current_user_id).await?;
+
+    // Nothing is stored for verifications the user can no longer change
+    ensure_can_upload(&pool, current_user_id).await?;
+
+    let file = receive_file(payload, current_user_id, kind, &config.uploads).await?;
+    let document = store_upload(&pool, store.get_ref(), current_user_id, kind, file).await?;
+
+    info!(
+        "User {} uploaded their {} as document {}",
+        current_user_id,
+        kind.as_str(),
+        document.id
+    );
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "Document uploaded",
+        "document": models::KycDocumentResponse::from(document)
+    })))
+}
+
+/// Lists the user's KYC documents with their review status
+#[get("/documents")]
+pub async fn list_documents(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let documents = web::block(move || -> QueryResult<_> {
+        use schema::user_verifications::dsl::*;
+
+        let verification = user_verifications
+            .filter(user_id.eq(current_user_id))
+            .select(id)
+            .first::<i32>(&mut conn)
+            .optional()?;
+        match verification {
+            Some(verification) => for_verification(&mut conn, verification),
+            None => Ok(Vec::new()),
+        }
+    })
+    .await??;
+
+    let documents: Vec<models::KycDocumentResponse> =
+        documents.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "documents": documents
+    })))
+}
+
+/// Submits the user's verification for review once the required documents
+/// are uploaded and none is still rejected
+#[post("/submit")]
+pub async fn submit_verification(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    // KYC submission needs a verified email address
+    email_verification::require_verified_email(&pool, current_user_id).await?;
+
+    let mut conn = pool.get()?;
+
+    let verification = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            let verification =
+                kyc::lock_for_user(conn, current_user_id).map_err(verification_required)?;
+            let documents = for_verification(conn, verification.id)?;
+
+            if let Some(missing) = REQUIRED_DOCUMENTS.iter().find(|kind| {
+                !documents
+                    .iter()
+                    .any(|document| document.document_type == kind.as_str())
+            }) {
+                return Err(AppError::bad_request(format!(
+                    "Upload your {} before submitting",
+                    missing.as_str()
+                )));
+            }
+            if let Some(rejected) = documents
+                .iter()
+                .find(|document| document.review_status == REJECTED)
+            {
+                return Err(AppError::bad_request(format!(
+                    "Replace your rejected {} before submitting",
+                    rejected.document_type
+                )));
+            }
+
+            Ok(kyc::transition(
+                conn,
+                verification.id,
+                KycStatus::Submitted,
+                None,
+                current_user_id,
+            )?)
+        })
+    })
+    .await??;
+
+    info!("User {} submitted their verification", current_user_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": verification.verification_status,
+        "verification": models::VerificationResponse::from(verification)
+    })))
+}
+
+/// Every document of a verification (KYC staff only)
+#[get("/verify/{verification_id}/documents")]
+pub async fn admin_list_documents(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::KycRead).await?;
+
+    let target_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let documents = web::block(move || -> Result<_, AppError> {
+        schema::user_verifications::table
+            .find(target_id)
+            .select(schema::user_verifications::id)
+            .first::<i32>(&mut conn)
+            .optional()?
+            .ok_or_else(|| AppError::not_found("Verification not found"))?;
+        Ok(for_verification(&mut conn, target_id)?)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "verification_id": target_id,
+        "documents": documents
+    })))
+}
+
+/// Approves or rejects one document of a verification that is waiting for
+/// review. Rejections need notes, which the user is shown.
+#[put("/documents/{document_id}")]
+pub async fn admin_review_document(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+    review: web::Json<models::KycDocumentReviewRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may review KYC submissions
+    let reviewer_id = auth::require_permission(&req, &pool, Permission::KycReview).await?;
+
+    let document_id = path.into_inner();
+    let review = review.into_inner();
+    let new_status = match review.status.as_str() {
+        APPROVED => APPROVED,
+        REJECTED => REJECTED,
+        _ => {
+            return Err(AppError::bad_request(
+                "Status must be 'approved' or 'rejected'",
+            ));
+        }
+    };
+    let notes = review
+        .notes
+        .map(|notes| notes.trim().to_string())
+        .filter(|notes| !notes.is_empty());
+    if new_status == REJECTED && notes.is_none() {
+        return Err(AppError::bad_request(
+            "Notes are required when rejecting a document",
+        ));
+    }
+    if notes
+        .as_ref()
+        .is_some_and(|notes| notes.chars().count() > kyc::MAX_REASON_LEN)
+    {
+        return Err(AppError::bad_request(format!(
+            "Notes must be at most {} characters",
+            kyc::MAX_REASON_LEN
+        )));
+    }
+
+    let mut conn = pool.get()?;
+
+    let document = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            use schema::kyc_documents::dsl::*;
+
+            let document = kyc_documents
+                .find(document_id)
+                .first::<models::KycDocument>(conn)
+                .optional()?
+                .ok_or_else(|| AppError::not_found("Document not found"))?;
+
+            // Documents are only judged while their verification is with reviewers
+            let verification = kyc::lock(conn, document.verification_id)?;
+            let status = kyc::status_of(&verification);
+            if !matches!(status, KycStatus::Submitted | KycStatus::InReview) {
+                return Err(AppError::conflict(format!(
+                    "Documents of a verification that is {} cannot be reviewed",
+                    status.as_str()
+                )));
+            }
+
+            Ok(diesel::update(kyc_documents.find(document_id))
+                .set((
+                    review_status.eq(new_status),
+                    reviewer_notes.eq(notes),
+                    reviewed_by.eq(Some(reviewer_id)),
+                    reviewed_at.eq(diesel::dsl::now),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .get_result::<models::KycDocument>(conn)?)
+        })
+    })
+    .await??;
+
+    info!(
+        "Document {} {} by user {}",
+        document.id, new_status, reviewer_id
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "document": document
+    })))
+}
+
+/// Sends the file of a document after recording who read it. Nothing is
+/// sent unless the access could be recorded.
+pub async fn send_document(
+    req: &HttpRequest,
+    pool: &db::DbPool,
+    store: &dyn DocumentStore,
+    document: models::KycDocument,
+    accessed_by: i32,
+) -> Result<HttpResponse, AppError> {
+    let contents = store.get(&document.storage_key).await?;
+
+    let client = ClientInfo::from_request(req);
+    let access = models::NewKycDocumentAccess {
+        document_id: document.id,
+        accessed_by: Some(accessed_by),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        ip_address: client.ip_address,
+        user_agent: client.user_agent,
+    };
+    let mut conn = pool.get()?;
+    web::block(move || {
+        diesel::insert_into(schema::kyc_document_access_log::table)
+            .values(&access)
+            .execute(&mut conn)
+    })
+    .await??;
+
+    info!("User {} read document {}", accessed_by, document.id);
+    Ok(HttpResponse::Ok()
+        .content_type(document.content_type)
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(contents))
+}
+
+/// The file of one of the user's own documents
+#[get("/documents/{document_id}/file")]
+pub async fn download_document(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    store: web::Data<dyn DocumentStore>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let document_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let document = web::block(move || {
+        use schema::kyc_documents::dsl::*;
+
+        kyc_documents
+            .inner_join(schema::user_verifications::table)
+            .filter(id.eq(document_id))
+            .filter(schema::user_verifications::user_id.eq(current_user_id))
+            .select(models::KycDocument::as_select())
+            .first::<models::KycDocument>(&mut conn)
+            .optional()
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("Document not found"))?;
+
+    send_document(&req, &pool, store.get_ref(), document, current_user_id).await
+}
+
+/// The file of any document (KYC staff only). Every read is recorded.
+#[get("/documents/{document_id}/file")]
+pub async fn admin_download_document(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    store: web::Data<dyn DocumentStore>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let reviewer_id = auth::require_permission(&req, &pool, Permission::KycRead).await?;
+
+    let document_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let document = web::block(move || {
+        schema::kyc_documents::table
+            .find(document_id)
+            .first::<models::KycDocument>(&mut conn)
+            .optional()
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("Document not found"))?;
+
+    send_document(&req, &pool, store.get_ref(), document, reviewer_id).await
+}
+
+/// Who read a document and when, newest first (KYC staff only)
+#[get("/documents/{document_id}/access-log")]
+pub async fn admin_document_access_log(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::KycRead).await?;
+
+    let target_id = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let accesses = web::block(move || -> Result<_, AppError> {
+        schema::kyc_documents::table
+            .find(target_id)
+            .select(schema::kyc_documents::id)
+            .first::<i32>(&mut conn)
+            .optional()?
+            .ok_or_else(|| AppError::not_found("Document not found"))?;
+
+        use schema::kyc_document_access_log::dsl::*;
+
+        Ok(kyc_document_access_log
+            .filter(document_id.eq(target_id))
+            .order_by(id.desc())
+            .load::<models::KycDocumentAccess>(&mut conn)?)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "document_id": target_id,
+        "accesses": accesses
+    })))
+}
+
+/// Moves documents uploaded before they were encrypted into the store.
+/// Their paths still point under `/uploads/`; each file is stored under its
+/// name and the plaintext copy deleted once the document points at it.
+pub async fn import_legacy_documents(
+    pool: &db::DbPool,
+    store: &dyn DocumentStore,
+    legacy_dir: &Path,
+) -> Result<usize, AppError> {
+    let mut conn = pool.get()?;
+    let legacy = web::block(move || {
+        use schema::kyc_documents::dsl::*;
+
+        kyc_documents
+            .filter(storage_key.like("/uploads/%"))
+            .order_by(id.asc())
+            .load::<models::KycDocument>(&mut conn)
+    })
+    .await??;
+
+    let mut imported = 0;
+    for document in legacy {
+        let Some(key) = Path::new(&document.storage_key)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| document_store::is_valid_key(name))
+            .map(str::to_string)
+        else {
+            warn!(
+                "Document {} has an unusable path {}",
+                document.id, document.storage_key
+            );
+            continue;
+        };
+        let path = legacy_dir.join(&key);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!(
+                    "Cannot import document {} from {}: {}",
+                    document.id,
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        store.put(&key, contents).await?;
+
+        let mut conn = pool.get()?;
+        let stored_key = key.clone();
+        web::block(move || {
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                use schema::kyc_documents::dsl::*;
+
+                diesel::update(kyc_documents.find(document.id))
+                    .set(storage_key.eq(&stored_key))
+                    .execute(conn)?;
+
+                use schema::user_verifications::dsl as verifications;
+
+                diesel::update(
+                    verifications::user_verifications
+                        .filter(verifications::id.eq(document.verification_id))
+                        .filter(verifications::id_front_path.eq(&document.storage_key)),
+                )
+                .set(verifications::id_front_path.eq(&stored_key))
+                .execute(conn)?;
+                Ok(())
+            })
+        })
+        .await??;
+
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!("Failed to delete imported {}: {}", path.display(), e);
+        }
+        imported += 1;
+    }
+    Ok(imported)
+}
@@ -0,0 +1,314 @@
+use crate::models;
+use crate::schema;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Number of decimal places stored by the ledger's NUMERIC(30, 10) columns
+pub const SCALE: u32 = 10;
+
+/// Asset codes are short upper-case tickers such as BTC or USDT
+pub fn is_valid_asset(asset: &str) -> bool {
+    !asset.is_empty()
+        && asset.len() <= 20
+        && asset
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+}
+
+/// Kind of a ledger account
+///
+/// Every user has an `Available` and a `Held` account per asset. `External`
+/// is a system account that mirrors money entering and leaving the exchange,
+/// and `Fees` is the house account trading fees are paid into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccountKind {
+    Available,
+    Held,
+    External,
+    Fees,
+}
+
+impl AccountKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountKind::Available => "available",
+            AccountKind::Held => "held",
+            AccountKind::External => "external",
+            AccountKind::Fees => "fees",
+        }
+    }
+}
+
+/// One line of a journal entry: a signed amount posted to a single account
+#[derive(Clone, Debug)]
+pub struct Leg {
+    pub user_id: Option<i32>,
+    pub asset: String,
+    pub kind: AccountKind,
+    pub amount: Decimal,
+}
+
+impl Leg {
+    pub fn user(user_id: i32, asset: &str, kind: AccountKind, amount: Decimal) -> Self {
+        Leg {
+            user_id: Some(user_id),
+            asset: asset.to_string(),
+            kind,
+            amount,
+        }
+    }
+
+    pub fn system(asset: &str, kind: AccountKind, amount: Decimal) -> Self {
+        Leg {
+            user_id: None,
+            asset: asset.to_string(),
+            kind,
+            amount,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LedgerError {
+    /// The legs of an entry do not sum to zero for every asset
+    Unbalanced,
+    /// Posting the entry would take a user account below zero
+    InsufficientFunds,
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::Unbalanced => write!(f, "Journal entry does not balance"),
+            LedgerError::InsufficientFunds => write!(f, "Insufficient balance"),
+            LedgerError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for LedgerError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            // Raised by the non-negative balance constraint on user accounts
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::CheckViolation, _) => {
+                LedgerError::InsufficientFunds
+            }
+            e => LedgerError::Database(e),
+        }
+    }
+}
+
+/// Records an immutable journal entry and applies it to account balances.
+///
+/// Legs must sum to zero per asset. Accounts are created on first use and
+/// locked in id order, so concurrent entries touching the same accounts
+/// cannot deadlock. The entry runs in its own savepoint: if any user account
+/// would go negative, nothing is written and `InsufficientFunds` is returned.
+pub fn post_entry(
+    conn: &mut PgConnection,
+    entry_kind: &str,
+    entry_reference: &str,
+    legs: &[Leg],
+) -> Result<models::JournalEntry, LedgerError> {
+    let legs: Vec<&Leg> = legs.iter().filter(|leg| !leg.amount.is_zero()).collect();
+
+    let mut totals: BTreeMap<&str, Decimal> = BTreeMap::new();
+    for leg in &legs {
+        *totals.entry(leg.asset.as_str()).or_default() += leg.amount;
+    }
+    if legs.is_empty() || totals.values().any(|total| !total.is_zero()) {
+        return Err(LedgerError::Unbalanced);
+    }
+
+    conn.transaction(|conn| {
+        let entry = diesel::insert_into(schema::journal_entries::table)
+            .values(&models::NewJournalEntry {
+                kind: entry_kind.to_string(),
+                reference: entry_reference.to_string(),
+            })
+            .get_result::<models::JournalEntry>(conn)?;
+
+        let mut postings = Vec::with_capacity(legs.len());
+        let mut changes: BTreeMap<i32, Decimal> = BTreeMap::new();
+        for leg in &legs {
+            let account_id = account_id_for(conn, leg.user_id, &leg.asset, leg.kind)?;
+            *changes.entry(account_id).or_default() += leg.amount;
+            postings.push(models::NewJournalPosting {
+                entry_id: entry.id,
+                account_id,
+                asset: leg.asset.clone(),
+                amount: leg.amount,
+            });
+        }
+
+        {
+            use schema::ledger_accounts::dsl::*;
+            for (account, change) in changes {
+                diesel::update(ledger_accounts.find(account))
+                    .set((
+                        balance.eq(balance + change),
+                        updated_at.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+
+        diesel::insert_into(schema::journal_postings::table)
+            .values(&postings)
+            .execute(conn)?;
+
+        Ok(entry)
+    })
+}
+
+/// Looks up an account, creating it with a zero balance if it does not exist yet
+fn account_id_for(
+    conn: &mut PgConnection,
+    owner: Option<i32>,
+    account_asset: &str,
+    account_kind: AccountKind,
+) -> QueryResult<i32> {
+    use schema::ledger_accounts::dsl::*;
+
+    let find_account = |conn: &mut PgConnection| {
+        let mut query = ledger_accounts
+            .filter(asset.eq(account_asset))
+            .filter(kind.eq(account_kind.as_str()))
+            .select(id)
+            .into_boxed();
+        query = match owner {
+            Some(owner) => query.filter(user_id.eq(owner)),
+            None => query.filter(user_id.is_null()),
+        };
+        query.first::<i32>(conn).optional()
+    };
+
+    if let Some(account) = find_account(conn)? {
+        return Ok(account);
+    }
+
+    // Another transaction may create the same account concurrently
+    diesel::insert_into(ledger_accounts)
+        .values(&models::NewLedgerAccount {
+            user_id: owner,
+            asset: account_asset.to_string(),
+            kind: account_kind.as_str().to_string(),
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    find_account(conn)?.ok_or(diesel::result::Error::NotFound)
+}
+
+/// Moves funds from a user's available balance into their held balance
+pub fn hold(
+    conn: &mut PgConnection,
+    owner: i32,
+    asset: &str,
+    amount: Decimal,
+    reference: &str,
+) -> Result<models::JournalEntry, LedgerError> {
+    post_entry(
+        conn,
+        "hold",
+        reference,
+        &[
+            Leg::user(owner, asset, AccountKind::Available, -amount),
+            Leg::user(owner, asset, AccountKind::Held, amount),
+        ],
+    )
+}
+
+/// Returns previously held funds to a user's available balance
+pub fn release(
+    conn: &mut PgConnection,
+    owner: i32,
+    asset: &str,
+    amount: Decimal,
+    reference: &str,
+) -> Result<models::JournalEntry, LedgerError> {
+    post_entry(
+        conn,
+        "release",
+        reference,
+        &[
+            Leg::user(owner, asset, AccountKind::Held, -amount),
+            Leg::user(owner, asset, AccountKind::Available, amount),
+        ],
+    )
+}
+
+/// Credits funds that entered the exchange to a user's available balance
+pub fn deposit(
+    conn: &mut PgConnection,
+    owner: i32,
+    asset: &str,
+    amount: Decimal,
+    reference: &str,
+) -> Result<models::JournalEntry, LedgerError> {
+    post_entry(
+        conn,
+        "deposit",
+        reference,
+        &[
+            Leg::system(asset, AccountKind::External, -amount),
+            Leg::user(owner, asset, AccountKind::Available, amount),
+        ],
+    )
+}
+
+/// Pays out held funds that are leaving the exchange
+pub fn withdraw(
+    conn: &mut PgConnection,
+    owner: i32,
+    asset: &str,
+    amount: Decimal,
+    reference: &str,
+) -> Result<models::JournalEntry, LedgerError> {
+    post_entry(
+        conn,
+        "withdrawal",
+        reference,
+        &[
+            Leg::user(owner, asset, AccountKind::Held, -amount),
+            Leg::system(asset, AccountKind::External, amount),
+        ],
+    )
+}
+
+/// Available and held balances of every asset a user has touched
+pub fn balances(conn: &mut PgConnection, owner: i32) -> QueryResult<Vec<models::BalanceResponse>> {
+    use schema::ledger_accounts::dsl::*;
+
+    let accounts = ledger_accounts
+        .filter(user_id.eq(owner))
+        .order_by(asset.asc())
+        .load::<models::LedgerAccount>(conn)?;
+
+    let mut by_asset: BTreeMap<String, models::BalanceResponse> = BTreeMap::new();
+    for account in accounts {
+        let entry =
+            by_asset
+                .entry(account.asset.clone())
+                .or_insert_with(|| models::BalanceResponse {
+                    asset: account.asset.clone(),
+                    available: Decimal::ZERO,
+                    held: Decimal::ZERO,
+                    total: Decimal::ZERO,
+                });
+
+        if account.kind == AccountKind::Held.as_str() {
+            entry.held += account.balance;
+        } else {
+            entry.available += account.balance;
+        }
+        entry.total += account.balance;
+    }
+
+    Ok(by_asset.into_values().collect())
+}
@@ -0,0 +1,592 @@
+use crate::auth;
+use crate::config::{self, PeriodLimits, TierLimits};
+use crate::db;
+use crate::error::AppError;
+use crate::kyc::{self, KycStatus};
+use crate::kyc_documents;
+use crate::ledger::{self, AccountKind};
+use crate::models;
+use crate::orders::OPEN_STATUSES;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, get, web};
+use chrono::{DateTime, Duration, Utc};
+use diesel::dsl::sum;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Verification tier of a user, which decides how much money they can move.
+/// Users who have not verified their email address have no tier and cannot
+/// move money at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    /// Tier 0: a verified email address
+    Email,
+    /// Tier 1: personal details given
+    PersonalDetails,
+    /// Tier 2: identity document and proof of address approved
+    Documents,
+}
+
+impl Tier {
+    pub fn level(&self) -> u8 {
+        match self {
+            Tier::Email => 0,
+            Tier::PersonalDetails => 1,
+            Tier::Documents => 2,
+        }
+    }
+
+    fn limits(&self) -> &'static TierLimits {
+        let limits = &config::get().limits;
+        match self {
+            Tier::Email => &limits.tier0,
+            Tier::PersonalDetails => &limits.tier1,
+            Tier::Documents => &limits.tier2,
+        }
+    }
+}
+
+/// Ways money moves that tiers limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activity {
+    Deposit,
+    Withdrawal,
+    /// Quote value of orders placed
+    Trading,
+}
+
+impl Activity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Activity::Deposit => "deposit",
+            Activity::Withdrawal => "withdrawal",
+            Activity::Trading => "trading",
+        }
+    }
+
+    fn limits(&self, tier: Option<Tier>) -> PeriodLimits {
+        let Some(tier) = tier else {
+            return PeriodLimits {
+                daily: Some(Decimal::ZERO),
+                monthly: Some(Decimal::ZERO),
+            };
+        };
+        let limits = tier.limits();
+        match self {
+            Activity::Deposit => limits.deposit,
+            Activity::Withdrawal => limits.withdrawal,
+            Activity::Trading => limits.trading,
+        }
+    }
+}
+
+/// Rolling windows limits apply over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        }
+    }
+
+    fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Period::Daily => now - Duration::days(1),
+            Period::Monthly => now - Duration::days(30),
+        }
+    }
+}
+
+/// Errors that can occur while checking an amount against a user's limits
+pub enum LimitError {
+    EmailNotVerified,
+    Exceeded {
+        tier: Tier,
+        activity: Activity,
+        period: Period,
+        remaining: Decimal,
+    },
+    /// The amount is in an asset with no price in the reference asset
+    Unpriced(String),
+    Database(diesel::result::Error),
+}
+
+impl From<LimitError> for AppError {
+    fn from(e: LimitError) -> Self {
+        let reference = &config::get().limits.reference_asset;
+        match e {
+            LimitError::EmailNotVerified => AppError::LimitExceeded(
+                "Money cannot be moved until the email address is verified".to_string(),
+            ),
+            LimitError::Exceeded {
+                tier,
+                activity,
+                period,
+                remaining,
+            } => AppError::LimitExceeded(format!(
+                "This exceeds the {} {} limit of tier {}: {} {} remaining",
+                period.as_str(),
+                activity.as_str(),
+                tier.level(),
+                remaining.normalize(),
+                reference
+            )),
+            LimitError::Unpriced(asset) => AppError::LimitExceeded(format!(
+                "{} has no price in {} yet, so it cannot be checked against limits",
+                asset, reference
+            )),
+            LimitError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for LimitError {
+    fn from(e: diesel::result::Error) -> Self {
+        LimitError::Database(e)
+    }
+}
+
+/// Use of one limit. `None` means there is no limit.
+#[derive(Serialize)]
+pub struct PeriodAllowance {
+    pub limit: Option<Decimal>,
+    pub used: Decimal,
+    pub remaining: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct ActivityAllowance {
+    pub daily: PeriodAllowance,
+    pub monthly: PeriodAllowance,
+}
+
+/// A user's tier and what is left of each of its limits, valued in
+/// `reference_asset`
+#[derive(Serialize)]
+pub struct Allowance {
+    pub tier: Option<u8>,
+    pub reference_asset: String,
+    pub deposit: ActivityAllowance,
+    pub withdrawal: ActivityAllowance,
+    pub trading: ActivityAllowance,
+}
+
+/// Values amounts in the reference asset at the last trade price of the
+/// asset's market against it, looking each price up once
+pub struct Valuer {
+    reference: &'static str,
+    prices: HashMap<String, Option<Decimal>>,
+}
+
+impl Default for Valuer {
+    /// Values in the configured reference asset
+    fn default() -> Self {
+        Valuer {
+            reference: &config::get().limits.reference_asset,
+            prices: HashMap::new(),
+        }
+    }
+}
+
+impl Valuer {
+    pub fn new() -> Self {
+        Valuer::default()
+    }
+
+    /// The value of `amount` of `asset`, or None if no market prices it
+    pub fn value(
+        &mut self,
+        conn: &mut PgConnection,
+        asset: &str,
+        amount: Decimal,
+    ) -> QueryResult<Option<Decimal>> {
+        if asset == self.reference {
+            return Ok(Some(amount.round_dp(ledger::SCALE)));
+        }
+        let price = match self.prices.get(asset) {
+            Some(price) => *price,
+            None => {
+                let price = match last_price(conn, asset, self.reference)? {
+                    Some(price) => Some(price),
+                    // Markets may also be quoted the other way round
+                    None => last_price(conn, self.reference, asset)?
+                        .filter(|price| !price.is_zero())
+                        .map(|price| Decimal::ONE / price),
+                };
+                self.prices.insert(asset.to_string(), price);
+                price
+            }
+        };
+        Ok(price.map(|price| (amount * price).round_dp(ledger::SCALE)))
+    }
+}
+
+/// Last trade price of the market buying `base` with `quote`, if there is one
+fn last_price(conn: &mut PgConnection, base: &str, quote: &str) -> QueryResult<Option<Decimal>> {
+    let market = schema::instruments::table
+        .filter(schema::instruments::base_asset.eq(base))
+        .filter(schema::instruments::quote_asset.eq(quote))
+        .select(schema::instruments::symbol)
+        .first::<String>(conn)
+        .optional()?;
+
+    let Some(market) = market else {
+        return Ok(None);
+    };
+
+    use schema::fills::dsl::*;
+    fills
+        .filter(symbol.eq(market))
+        .order_by(id.desc())
+        .select(price)
+        .first::<Decimal>(conn)
+        .optional()
+}
+
+/// The tier a user has reached. Tier 2 needs the verification approved along
+/// with the front of the ID and the proof of address.
+pub fn tier_of(conn: &mut PgConnection, owner: i32) -> QueryResult<Option<Tier>> {
+    let email_verified = schema::users::table
+        .find(owner)
+        .select(schema::users::email_verified_at.is_not_null())
+        .first::<bool>(conn)?;
+    if !email_verified {
+        return Ok(None);
+    }
+
+    let verification = schema::user_verifications::table
+        .filter(schema::user_verifications::user_id.eq(owner))
+        .first::<models::UserVerification>(conn)
+        .optional()?;
+
+    let Some(verification) = verification else {
+        return Ok(Some(Tier::Email));
+    };
+    let documents_approved = kyc::status_of(&verification) == KycStatus::Approved
+        && kyc_documents::required_approved(conn, verification.id)?;
+    Ok(Some(verification_tier(&verification, documents_approved)))
+}
+
+/// The tier a verification earns on top of a verified email address. Tier 1
+/// needs every required personal detail filled in, and a rejected or expired
+/// case earns nothing until the user redoes it.
+fn verification_tier(verification: &models::UserVerification, documents_approved: bool) -> Tier {
+    match kyc::status_of(verification) {
+        KycStatus::Rejected | KycStatus::Expired => Tier::Email,
+        _ if !kyc::has_personal_details(verification) => Tier::Email,
+        KycStatus::Approved if documents_approved => Tier::Documents,
+        _ => Tier::PersonalDetails,
+    }
+}
+
+/// Amounts deposited to a user since `since`, per asset
+fn deposited(
+    conn: &mut PgConnection,
+    owner: i32,
+    since: DateTime<Utc>,
+) -> QueryResult<Vec<(String, Option<Decimal>)>> {
+    use schema::{journal_entries, journal_postings, ledger_accounts};
+
+    journal_postings::table
+        .inner_join(journal_entries::table)
+        .inner_join(ledger_accounts::table)
+        .filter(journal_entries::kind.eq("deposit"))
+        .filter(ledger_accounts::user_id.eq(owner))
+        .filter(ledger_accounts::kind.eq(AccountKind::Available.as_str()))
+        .filter(journal_postings::created_at.gt(since))
+        .group_by(journal_postings::asset)
+        .select((journal_postings::asset, sum(journal_postings::amount)))
+        .load(conn)
+}
+
+/// Amounts a user asked to withdraw since `since`, per asset. Pending
+/// requests count; rejected ones do not.
+fn withdrawn(
+    conn: &mut PgConnection,
+    owner: i32,
+    since: DateTime<Utc>,
+) -> QueryResult<Vec<(String, Option<Decimal>)>> {
+    use schema::withdrawals::dsl::*;
+
+    withdrawals
+        .filter(user_id.eq(owner))
+        .filter(status.ne("rejected"))
+        .filter(created_at.gt(since))
+        .group_by(asset)
+        .select((asset, sum(amount)))
+        .load(conn)
+}
+
+/// Quote value a user traded since `since`, plus what is left of their open
+/// orders at their limit or stop price, per quote asset
+fn traded(
+    conn: &mut PgConnection,
+    owner: i32,
+    since: DateTime<Utc>,
+) -> QueryResult<Vec<(String, Option<Decimal>)>> {
+    let mut by_symbol: Vec<(String, Option<Decimal>)> = {
+        use schema::fills::dsl::*;
+
+        fills
+            .filter(created_at.gt(since))
+            .filter(maker_user_id.eq(owner).or(taker_user_id.eq(owner)))
+            .group_by(symbol)
+            .select((symbol, sum(price * quantity)))
+            .load(conn)?
+    };
+
+    {
+        use schema::orders::dsl::*;
+
+        let open = orders
+            .filter(user_id.eq(owner))
+            .filter(status.eq_any(OPEN_STATUSES))
+            .select((symbol, price, stop_price, quantity, filled_quantity))
+            .load::<(String, Option<Decimal>, Option<Decimal>, Decimal, Decimal)>(conn)?;
+        for (market, limit, stop, total, filled) in open {
+            by_symbol.push((market, limit.or(stop).map(|at| at * (total - filled))));
+        }
+    }
+
+    let symbols: Vec<&String> = by_symbol.iter().map(|(market, _)| market).collect();
+    let quotes: HashMap<String, String> = schema::instruments::table
+        .filter(schema::instruments::symbol.eq_any(symbols))
+        .select((
+            schema::instruments::symbol,
+            schema::instruments::quote_asset,
+        ))
+        .load::<(String, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    Ok(by_symbol
+        .into_iter()
+        .filter_map(|(market, value)| Some((quotes.get(&market)?.clone(), value)))
+        .collect())
+}
+
+/// Value of what a user has used of a limit since `since`. Amounts in
+/// assets that cannot be valued are left out; new ones are refused by
+/// `check` while a limit applies.
+fn used(
+    conn: &mut PgConnection,
+    valuer: &mut Valuer,
+    owner: i32,
+    activity: Activity,
+    since: DateTime<Utc>,
+) -> QueryResult<Decimal> {
+    let amounts = match activity {
+        Activity::Deposit => deposited(conn, owner, since)?,
+        Activity::Withdrawal => withdrawn(conn, owner, since)?,
+        Activity::Trading => traded(conn, owner, since)?,
+    };
+
+    let mut total = Decimal::ZERO;
+    for (asset, amount) in amounts {
+        if let Some(value) = valuer.value(conn, &asset, amount.unwrap_or_default())? {
+            total += value;
+        }
+    }
+    Ok(total)
+}
+
+/// Refuses an amount that would take a user over a limit of their tier. Call
+/// it in the transaction that moves the money: it locks the user so
+/// concurrent requests cannot share the same allowance.
+pub fn check(
+    conn: &mut PgConnection,
+    owner: i32,
+    activity: Activity,
+    asset: &str,
+    amount: Decimal,
+) -> Result<(), LimitError> {
+    schema::users::table
+        .find(owner)
+        .select(schema::users::id)
+        .for_no_key_update()
+        .first::<i32>(conn)?;
+
+    let tier = tier_of(conn, owner)?.ok_or(LimitError::EmailNotVerified)?;
+    let limits = activity.limits(Some(tier));
+    if limits.daily.is_none() && limits.monthly.is_none() {
+        return Ok(());
+    }
+
+    let mut valuer = Valuer::new();
+    let value = valuer
+        .value(conn, asset, amount)?
+        .ok_or_else(|| LimitError::Unpriced(asset.to_string()))?;
+
+    let now = Utc::now();
+    for (period, limit) in [
+        (Period::Daily, limits.daily),
+        (Period::Monthly, limits.monthly),
+    ] {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let used = used(conn, &mut valuer, owner, activity, period.start(now))?;
+        let remaining = (limit - used).max(Decimal::ZERO);
+        if value > remaining {
+            return Err(LimitError::Exceeded {
+                tier,
+                activity,
+                period,
+                remaining,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A user's tier and what is left of each limit
+pub fn allowance(conn: &mut PgConnection, owner: i32) -> QueryResult<Allowance> {
+    let tier = tier_of(conn, owner)?;
+    let mut valuer = Valuer::new();
+    let now = Utc::now();
+
+    let mut activity_allowance = |activity: Activity| -> QueryResult<ActivityAllowance> {
+        let limits = activity.limits(tier);
+        let mut period_allowance = |period: Period, limit: Option<Decimal>| {
+            let used = used(conn, &mut valuer, owner, activity, period.start(now))?;
+            Ok::<_, diesel::result::Error>(PeriodAllowance {
+                limit,
+                used,
+                remaining: limit.map(|limit| (limit - used).max(Decimal::ZERO)),
+            })
+        };
+        Ok(ActivityAllowance {
+            daily: period_allowance(Period::Daily, limits.daily)?,
+            monthly: period_allowance(Period::Monthly, limits.monthly)?,
+        })
+    };
+
+    Ok(Allowance {
+        tier: tier.map(|tier| tier.level()),
+        reference_asset: config::get().limits.reference_asset.clone(),
+        deposit: activity_allowance(Activity::Deposit)?,
+        withdrawal: activity_allowance(Activity::Withdrawal)?,
+        trading: activity_allowance(Activity::Trading)?,
+    })
+}
+
+/// Returns the authenticated user's verification tier and how much they can
+/// still deposit, withdraw and trade today and this month
+#[get("/limits")]
+pub async fn user_limits(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let allowance = web::block(move || allowance(&mut conn, current_user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(allowance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verification(status: KycStatus) -> models::UserVerification {
+        let now = Utc::now().naive_utc();
+        models::UserVerification {
+            id: 1,
+            user_id: 1,
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+            dob_day: 10,
+            dob_month: 12,
+            dob_year: 1990,
+            street_address: "12 St James's Square".to_string(),
+            apartment: None,
+            city: "London".to_string(),
+            postal_code: "SW1Y 4JH".to_string(),
+            country_code: "GB".to_string(),
+            phone_number: "+442071234567".to_string(),
+            occupation: "Mathematician".to_string(),
+            verification_status: status.as_str().to_string(),
+            created_at: now,
+            updated_at: now,
+            id_front_path: None,
+            status_reason: None,
+            submitted_at: None,
+            reviewed_at: None,
+        }
+    }
+
+    fn bare_draft() -> models::UserVerification {
+        models::UserVerification {
+            first_name: String::new(),
+            last_name: String::new(),
+            dob_day: 0,
+            dob_month: 0,
+            dob_year: 0,
+            street_address: String::new(),
+            city: String::new(),
+            postal_code: String::new(),
+            country_code: String::new(),
+            phone_number: String::new(),
+            occupation: String::new(),
+            ..verification(KycStatus::Draft)
+        }
+    }
+
+    #[test]
+    fn bare_draft_stays_at_tier_0() {
+        assert_eq!(verification_tier(&bare_draft(), false), Tier::Email);
+    }
+
+    #[test]
+    fn any_missing_detail_keeps_tier_0() {
+        let blank_city = models::UserVerification {
+            city: "  ".to_string(),
+            ..verification(KycStatus::Draft)
+        };
+        let impossible_birthday = models::UserVerification {
+            dob_day: 31,
+            dob_month: 2,
+            ..verification(KycStatus::Draft)
+        };
+
+        assert_eq!(verification_tier(&blank_city, false), Tier::Email);
+        assert_eq!(verification_tier(&impossible_birthday, false), Tier::Email);
+    }
+
+    #[test]
+    fn filled_in_details_reach_tier_1() {
+        for status in [KycStatus::Draft, KycStatus::Submitted, KycStatus::InReview] {
+            assert_eq!(
+                verification_tier(&verification(status), false),
+                Tier::PersonalDetails
+            );
+        }
+    }
+
+    #[test]
+    fn rejected_and_expired_cases_drop_to_tier_0() {
+        for status in [KycStatus::Rejected, KycStatus::Expired] {
+            assert_eq!(verification_tier(&verification(status), true), Tier::Email);
+        }
+    }
+
+    #[test]
+    fn tier_2_needs_approval_and_approved_documents() {
+        let approved = verification(KycStatus::Approved);
+
+        assert_eq!(verification_tier(&approved, true), Tier::Documents);
+        assert_eq!(verification_tier(&approved, false), Tier::PersonalDetails);
+    }
+}
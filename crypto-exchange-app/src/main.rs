@@ -1,209 +1,122 @@
+use actix::Actor;
 use actix_cors::Cors;
-use actix_files::Files;
 use actix_multipart::Multipart;
-use actix_web::http::header;
-use actix_web::{
-    App, HttpRequest, HttpResponse, HttpServer, Responder, delete, get, post, put, web,
-};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, get, post, put, web};
+use config::Config;
 use diesel::RunQueryDsl;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use dotenv::dotenv;
-use env_logger;
-use futures::{StreamExt, TryStreamExt};
+use error::AppError;
 use log::{error, info};
-use mime::Mime;
-use sanitize_filename::sanitize;
+use std::collections::HashMap;
 use std::env;
-use std::fs;
-use std::io::Write;
-use std::path::Path;
-use uuid::Uuid;
 
+pub mod api_keys;
 pub mod auth;
+pub mod candles;
+pub mod config;
 pub mod db;
+pub mod document_store;
 pub mod email;
+pub mod email_verification;
+pub mod error;
+pub mod feed;
+pub mod fees;
+pub mod instruments;
+pub mod kyc;
+pub mod kyc_documents;
+pub mod ledger;
+pub mod limits;
+pub mod market_data;
 pub mod markets;
+pub mod matching;
+pub mod mfa;
 pub mod models;
+pub mod orders;
+pub mod roles;
 pub mod schema; // Add the markets module
+pub mod sessions;
+pub mod throttle;
+pub mod upload_validation;
+pub mod wallet;
 
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
 };
-use serde::{Deserialize, Serialize};
-
-const UPLOAD_DIR: &str =
-    "/home/maria/Documents/cryptocurrency-exchange/crypto-exchange-app/uploads/id_documents";
-
-fn is_valid_image(content_type: &Mime) -> bool {
-    match content_type.to_string().as_str() {
-        "image/jpeg" | "image/png" | "application/pdf" => true,
-        _ => false,
-    }
-}
-
+use document_store::DocumentStore;
+use kyc_documents::DocumentType;
+use roles::Permission;
+use serde::Serialize;
+
+/// Stores the front of the user's ID. Kept for older clients; like any other
+/// document it goes under `/verify/documents`, and the verification is only
+/// sent for review through `/verify/submit` once every required document is
+/// in.
 #[post("/id-document")]
 async fn upload_id_document(
     req: HttpRequest,
-    mut payload: Multipart,
+    payload: Multipart,
     pool: web::Data<db::DbPool>,
-) -> Result<HttpResponse, actix_web::Error> {
+    store: web::Data<dyn DocumentStore>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, AppError> {
     // Extract user_id from JWT token
     let current_user_id = auth::extract_user_id(&req)?;
 
-    // Create upload directory if it doesn't exist
-    if !Path::new(UPLOAD_DIR).exists() {
-        if let Err(e) = std::fs::create_dir_all(UPLOAD_DIR) {
-            error!("Failed to create upload directory: {}", e);
-            return Err(actix_web::error::ErrorInternalServerError(
-                "Failed to process upload",
-            ));
-        }
-    }
-
-    // Process file upload
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition();
-
-        let filename = content_disposition
-            .get_filename()
-            .map_or_else(|| Uuid::new_v4().to_string(), sanitize);
-
-        let content_type = field
-            .content_type()
-            .map(|ct| ct.clone())
-            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
-
-        // Validate file type
-        if !is_valid_image(&content_type) {
-            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Invalid file type. Only JPG, PNG, and PDF files are allowed."
-            })));
-        }
-
-        // Generate a unique filename
-        let file_ext = match content_type.to_string().as_str() {
-            "image/jpeg" => "jpg",
-            "image/png" => "png",
-            "application/pdf" => "pdf",
-            _ => "bin",
-        };
-
-        let file_name = format!(
-            "{}_id_front_{}.{}",
-            current_user_id,
-            Uuid::new_v4(),
-            file_ext
-        );
-        let file_path = format!("{}/{}", UPLOAD_DIR, file_name);
-        let db_path = format!("/uploads/id_documents/{}", file_name);
-        let db_path_clone = db_path.clone(); // Clone db_path to avoid move issues
-
-        // Create the file
-        let file_path_clone = file_path.clone();
-        use std::sync::{Arc, Mutex};
-        let file = Arc::new(Mutex::new(
-            match web::block(move || std::fs::File::create(&file_path_clone)).await {
-                Ok(file) => file,
-                Err(_) => {
-                    error!("Failed to create file: {}", file_path);
-                    return Err(actix_web::error::ErrorInternalServerError(
-                        "Failed to create file",
-                    ));
-                }
-            },
-        ));
-
-        // Write to the file
-        while let Some(chunk) = field.next().await {
-            let data = chunk.map_err(|_| {
-                error!("Failed to read file chunk");
-                actix_web::error::ErrorInternalServerError("Failed to upload file")
-            })?;
-
-            let file_clone = Arc::clone(&file);
-            web::block(move || {
-                let mut file = file_clone.lock().map_err(|_| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "Failed to acquire file lock")
-                })?;
-                if let Ok(ref mut file) = *file {
-                    file.write_all(&data)
-                } else {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Failed to access file",
-                    ))
-                }
-            })
-            .await
-            .map_err(|_| {
-                error!("Failed to write to file");
-                actix_web::error::ErrorInternalServerError("Failed to upload file")
-            })?;
-        }
-
-        // Update the user_verifications table
-        let mut conn = pool.get().map_err(|_| {
-            actix_web::error::ErrorInternalServerError("Failed to get database connection")
-        })?;
-
-        use schema::user_verifications::dsl::*;
-        let result = web::block(move || {
-            diesel::update(user_verifications.filter(user_id.eq(current_user_id)))
-                .set((
-                    id_front_path.eq(&db_path_clone),
-                    id_verification_status.eq("pending_review"),
-                    updated_at.eq(diesel::dsl::now),
-                ))
-                .get_result::<models::UserVerification>(&mut conn)
-                .optional()
+    // KYC submission needs a verified email address
+    email_verification::require_verified_email(&pool, current_user_id).await?;
+
+    // Nothing is stored for verifications the user can no longer change
+    kyc_documents::ensure_can_upload(&pool, current_user_id).await?;
+
+    let file = kyc_documents::receive_file(
+        payload,
+        current_user_id,
+        DocumentType::IdFront,
+        &config.uploads,
+    )
+    .await?;
+    let key = file.key.clone();
+    store.put(&file.key, file.contents).await?;
+
+    let mut conn = pool.get()?;
+    let recorded = key.clone();
+    let result = web::block(move || {
+        conn.transaction::<_, kyc::KycError, _>(|conn| {
+            let (document, replaced) = kyc_documents::record_upload(
+                conn,
+                current_user_id,
+                DocumentType::IdFront,
+                &recorded,
+                &file.content_type,
+            )?;
+
+            let status = schema::user_verifications::table
+                .find(document.verification_id)
+                .select(schema::user_verifications::verification_status)
+                .first::<String>(conn)?;
+            Ok((status, replaced))
         })
-        .await
-        .map_err(|_| {
-            error!("Failed to update verification record");
-            actix_web::error::ErrorInternalServerError("Failed to update verification record")
-        })?;
-
-        // Check if user has a verification record
-        if result.is_err() {
-            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "You must complete personal information verification before uploading ID document"
-            })));
-        }
+    })
+    .await?;
 
-        // Set appropriate permissions for the file
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = std::fs::metadata(&file_path)
-                .map_err(|e| {
-                    actix_web::error::ErrorInternalServerError(format!(
-                        "Failed to get permissions: {}",
-                        e
-                    ))
-                })?
-                .permissions();
-
-            // Set read/write permissions for owner and read permissions for group
-            perms.set_mode(0o644); // Owner can read/write, group and others can read
-            std::fs::set_permissions(&file_path, perms).map_err(|e| {
-                actix_web::error::ErrorInternalServerError(format!(
-                    "Failed to set permissions: {}",
-                    e
-                ))
-            })?;
+    let (status, replaced) = match result {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            kyc_documents::discard(store.get_ref(), &key).await;
+            return Err(kyc_documents::verification_required(e));
         }
-
-        return Ok(HttpResponse::Ok().json(serde_json::json!({
-            "message": "ID document uploaded successfully",
-            "status": "pending_review",
-            "file_path": db_path
-        })));
+    };
+    if let Some(replaced) = replaced {
+        kyc_documents::discard(store.get_ref(), &replaced).await;
     }
 
-    Ok(HttpResponse::BadRequest().json(serde_json::json!({
-        "error": "No file uploaded"
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "ID document uploaded successfully",
+        "status": status,
+        "file_path": key
     })))
 }
 
@@ -234,25 +147,30 @@ async fn health_check() -> impl Responder {
     }))
 }
 
-pub fn get_users(connection: &mut PgConnection) -> Vec<models::User> {
+pub fn get_users(connection: &mut PgConnection) -> QueryResult<Vec<models::User>> {
     use schema::users::dsl::*;
 
-    users
-        .load::<models::User>(connection)
-        .expect("Error loading users")
+    users.load::<models::User>(connection)
 }
 
+// Lists every account with its roles, so only staff who may view users see it
 #[get("/users")]
-async fn users_route(pool: web::Data<db::DbPool>) -> impl Responder {
-    let mut conn = pool.get().expect("Failed to get db connection from pool");
+async fn users_route(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::UsersRead).await?;
 
-    let users = web::block(move || get_users(&mut *conn)).await.unwrap();
+    let mut conn = pool.get()?;
 
     // Convert User objects to UserResponse objects to avoid sending passwords
-    let user_responses: Vec<models::UserResponse> =
-        users.into_iter().map(models::UserResponse::from).collect();
+    let user_responses = web::block(move || {
+        let users = get_users(&mut conn)?;
+        roles::user_responses(&mut conn, users)
+    })
+    .await??;
 
-    HttpResponse::Ok().json(user_responses)
+    Ok(HttpResponse::Ok().json(user_responses))
 }
 
 fn validate_password(password: &str) -> Result<(), String> {
@@ -277,34 +195,24 @@ fn validate_password(password: &str) -> Result<(), String> {
 
 #[post("/sign-up")]
 async fn sign_up(
+    req: HttpRequest,
     pool: web::Data<db::DbPool>,
     new_user: web::Json<models::NewUser>,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     // Existing validation code
-    if let Err(message) = validate_password(&new_user.password) {
-        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-            "error": message
-        })));
-    }
+    validate_password(&new_user.password).map_err(AppError::BadRequest)?;
 
     // Existing password hashing
-    let hashed_password = match hash_password(&new_user.password) {
-        Ok(hash) => hash,
-        Err(e) => {
-            error!("Password hashing error: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to process password"
-            })));
-        }
-    };
+    let hashed_password = hash_password(&new_user.password).map_err(|e| {
+        error!("Password hashing error: {}", e);
+        AppError::internal("Failed to process password")
+    })?;
 
     // Replace plain password with hashed password
     let mut new_user_inner = new_user.into_inner();
     new_user_inner.password = hashed_password;
 
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     // CHANGE: Get the created user record back
     let user = web::block(move || {
@@ -312,49 +220,41 @@ async fn sign_up(
             .values(&new_user_inner)
             .get_result::<models::User>(&mut *conn)
     })
-    .await
-    .map_err(|e| {
-        error!("Failed to create user: {:?}", e);
-        actix_web::error::ErrorInternalServerError("Failed to create user")
-    })?;
+    .await??;
 
-    // Generate token for the user
-    let user = match user {
-        Ok(user) => user,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to retrieve user data"
-            })));
-        }
-    };
+    let mut conn = pool.get()?;
 
-    let token = match auth::generate_token(user.id) {
-        Ok(token) => token,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to generate authentication token"
-            })));
-        }
-    };
+    let new_user_id = user.id;
+    let client = sessions::ClientInfo::from_request(&req);
+    let tokens = web::block(move || auth::issue_tokens(&mut conn, new_user_id, &client)).await??;
 
-    // Return user data with the token
-    let user_response = models::UserResponse::from(user);
+    // Ask the new user to prove they own their email address
+    email_verification::send_verification(&pool, user.id, user.email.clone()).await;
+
+    // Return user data with the tokens; new users have no roles
+    let user_response = models::UserResponse::new(user, Vec::new());
 
     Ok(HttpResponse::Created().json(serde_json::json!({
-        "message": "User created successfully",
+        "message": "User created successfully. Check your email to verify your address.",
         "user": user_response,
-        "token": token
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in
     })))
 }
 
 #[post("/login")]
 async fn login(
+    req: HttpRequest,
     pool: web::Data<db::DbPool>,
+    guard: web::Data<throttle::LoginGuard>,
     login_data: web::Json<models::LoginRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+) -> Result<HttpResponse, AppError> {
+    // Refuse the attempt outright while the email or IP is backing off or locked
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+    guard.check_login(&login_data.email, client_ip)?;
+
+    let mut conn = pool.get()?;
 
     // Extract credentials before moving login_data
     let user_email = login_data.email.clone();
@@ -362,55 +262,73 @@ async fn login(
 
     // Find the user by email
     use schema::users::dsl::*;
-    let user_result = web::block(move || {
+    let user = web::block(move || {
         users
             .filter(email.eq(&user_email))
             .first::<models::User>(&mut *conn)
             .optional()
     })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Failed to query database"))?;
-
-    // Fixed match statement
-    match user_result {
-        Ok(Some(user)) => {
-            match verify_password(&user.password, &user_password) {
-                Ok(true) => {
-                    // Generate JWT token
-                    let token = match auth::generate_token(user.id) {
-                        Ok(token) => token,
-                        Err(_) => {
-                            return Ok(HttpResponse::InternalServerError().json(
-                                serde_json::json!({
-                                    "error": "Failed to generate authentication token"
-                                }),
-                            ));
-                        }
-                    };
+    .await??;
 
-                    // Convert to UserResponse to avoid sending password
-                    let user_response = models::UserResponse::from(user);
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
-                        "message": "Login successful",
-                        "token": token,
-                        "user": user_response
-                    })))
-                }
-                Ok(false) => Ok(HttpResponse::Unauthorized().json(serde_json::json!({
-                    "error": "Invalid email or password"
-                }))),
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Authentication error"
-                }))),
-            }
+    let Some(user) = user else {
+        // Unknown emails count too, so lockouts do not reveal which exist
+        guard.login_failed(&login_data.email, client_ip, None).await;
+        return Err(AppError::InvalidCredentials);
+    };
+
+    let password_matches = verify_password(&user.password, &user_password).map_err(|e| {
+        error!("Password verification error: {}", e);
+        AppError::internal("Authentication error")
+    })?;
+    if !password_matches {
+        guard
+            .login_failed(&login_data.email, client_ip, Some(&user.email))
+            .await;
+        return Err(AppError::InvalidCredentials);
+    }
+
+    // Start a session, unless a second factor is needed first
+    let mut conn = pool.get()?;
+
+    let session_user_id = user.id;
+    let client = sessions::ClientInfo::from_request(&req);
+    let alert_client = client.clone();
+    let (step, role_names) = web::block(move || {
+        let step = mfa::start_login(&mut conn, session_user_id, &client)?;
+        let role_names = roles::role_names(&mut conn, session_user_id)?;
+        Ok::<_, mfa::MfaError>((step, role_names))
+    })
+    .await??;
+
+    // Failures are only forgiven once every factor has been checked
+    let tokens = match step {
+        mfa::LoginStep::Session(tokens) => {
+            guard.login_succeeded(&login_data.email);
+            tokens
         }
-        Ok(none) => Ok(HttpResponse::Unauthorized().json(serde_json::json!({
-            "error": "Invalid email or password"
-        }))),
-        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Database error during login"
-        }))),
+        mfa::LoginStep::MfaRequired(mfa_token) => {
+            return Ok(HttpResponse::Ok().json(serde_json::json!({
+                "message": "Two-factor authentication required",
+                "mfa_required": true,
+                "mfa_token": mfa_token,
+                "expires_in": auth::MFA_TOKEN_TTL.num_seconds()
+            })));
+        }
+    };
+
+    if tokens.new_device {
+        sessions::alert_new_login(user.email.clone(), alert_client);
     }
+
+    // Convert to UserResponse to avoid sending password
+    let user_response = models::UserResponse::new(user, role_names);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Login successful",
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in,
+        "user": user_response
+    })))
 }
 
 #[put("")]
@@ -418,106 +336,84 @@ async fn update_verify(
     pool: web::Data<db::DbPool>,
     verification_data: web::Json<models::VerificationRequest>,
     req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     // Extract user_id from JWT token
     let current_user_id = auth::extract_user_id(&req)?;
 
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    // KYC submission needs a verified email address
+    email_verification::require_verified_email(&pool, current_user_id).await?;
+
+    let mut conn = pool.get()?;
 
     // Update existing verification or create if it doesn't exist
     use schema::user_verifications::dsl::*;
 
-    // Try to find existing verification
-    let existing = web::block(move || {
-        user_verifications
-            .filter(user_id.eq(current_user_id))
-            .first::<models::UserVerification>(&mut conn)
-            .optional()
-    })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
-
-    // Update or insert verification
-    let verification_result = match existing {
-        Ok(Some(existing_verification)) => {
-            // Update existing record
-            web::block(move || {
-                diesel::update(user_verifications.find(existing_verification.id))
-                    .set((
-                        first_name.eq(&verification_data.first_name),
-                        last_name.eq(&verification_data.last_name),
-                        dob_day.eq(verification_data.dob_day),
-                        dob_month.eq(verification_data.dob_month),
-                        dob_year.eq(verification_data.dob_year),
-                        street_address.eq(&verification_data.street_address),
-                        apartment.eq(&verification_data.apartment),
-                        city.eq(&verification_data.city),
-                        postal_code.eq(&verification_data.postal_code),
-                        country_code.eq(&verification_data.country_code),
-                        phone_number.eq(&verification_data.phone_number),
-                        occupation.eq(&verification_data.occupation),
-                        updated_at.eq(diesel::dsl::now),
-                    ))
-                    .get_result::<models::UserVerification>(&mut conn)
-            })
-            .await
-        }
-        Ok(None) => {
-            // Create a new verification
-            let new_verification = models::NewUserVerification {
-                user_id: current_user_id,
-                first_name: verification_data.first_name.clone(),
-                last_name: verification_data.last_name.clone(),
-                dob_day: verification_data.dob_day,
-                dob_month: verification_data.dob_month,
-                dob_year: verification_data.dob_year,
-                street_address: verification_data.street_address.clone(),
-                apartment: verification_data.apartment.clone(),
-                city: verification_data.city.clone(),
-                postal_code: verification_data.postal_code.clone(),
-                country_code: verification_data.country_code.clone(),
-                phone_number: verification_data.phone_number.clone(),
-                occupation: verification_data.occupation.clone(),
-            };
-
-            web::block(move || {
-                diesel::insert_into(schema::user_verifications::table)
-                    .values(&new_verification)
-                    .get_result::<models::UserVerification>(&mut conn)
-            })
-            .await
-        }
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Database error when retrieving verification info"
-            })));
-        }
-    };
+    let verification = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            let existing = user_verifications
+                .filter(user_id.eq(current_user_id))
+                .for_update()
+                .first::<models::UserVerification>(conn)
+                .optional()?;
+
+            match existing {
+                Some(existing_verification) => {
+                    // Details are frozen while a reviewer may be looking at them
+                    kyc::ensure_editable(&existing_verification)?;
+
+                    Ok(
+                        diesel::update(user_verifications.find(existing_verification.id))
+                            .set((
+                                first_name.eq(&verification_data.first_name),
+                                last_name.eq(&verification_data.last_name),
+                                dob_day.eq(verification_data.dob_day),
+                                dob_month.eq(verification_data.dob_month),
+                                dob_year.eq(verification_data.dob_year),
+                                street_address.eq(&verification_data.street_address),
+                                apartment.eq(&verification_data.apartment),
+                                city.eq(&verification_data.city),
+                                postal_code.eq(&verification_data.postal_code),
+                                country_code.eq(&verification_data.country_code),
+                                phone_number.eq(&verification_data.phone_number),
+                                occupation.eq(&verification_data.occupation),
+                                updated_at.eq(diesel::dsl::now),
+                            ))
+                            .get_result::<models::UserVerification>(conn)?,
+                    )
+                }
+                None => {
+                    // Create a new verification, which starts as a draft
+                    let new_verification = models::NewUserVerification {
+                        user_id: current_user_id,
+                        first_name: verification_data.first_name.clone(),
+                        last_name: verification_data.last_name.clone(),
+                        dob_day: verification_data.dob_day,
+                        dob_month: verification_data.dob_month,
+                        dob_year: verification_data.dob_year,
+                        street_address: verification_data.street_address.clone(),
+                        apartment: verification_data.apartment.clone(),
+                        city: verification_data.city.clone(),
+                        postal_code: verification_data.postal_code.clone(),
+                        country_code: verification_data.country_code.clone(),
+                        phone_number: verification_data.phone_number.clone(),
+                        occupation: verification_data.occupation.clone(),
+                    };
 
-    // Replace the match block in the update_verify function (starting around line 461)
-    match verification_result {
-        Ok(verification) => {
-            // Now we're handling a Result<UserVerification, diesel::result::Error>
-            match verification {
-                Ok(verification_data) => Ok(HttpResponse::Ok().json(serde_json::json!({
-                    "status": verification_data.verification_status,
-                    "verification": models::VerificationResponse::from(verification_data)
-                }))),
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Database error updating verification"
-                }))),
+                    let verification = diesel::insert_into(schema::user_verifications::table)
+                        .values(&new_verification)
+                        .get_result::<models::UserVerification>(conn)?;
+                    kyc::record_created(conn, &verification, current_user_id)?;
+                    Ok(verification)
+                }
             }
-        }
-        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Failed to process verification request"
-        }))),
-    }
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": verification.verification_status,
+        "verification": models::VerificationResponse::from(verification)
+    })))
 }
 
 // Get verification status
@@ -525,41 +421,35 @@ async fn update_verify(
 async fn verification_status(
     pool: web::Data<db::DbPool>,
     req: HttpRequest,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     // Extract user_id from JWT token
     let current_user_id = auth::extract_user_id(&req)?;
 
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     // Get verification status
     use schema::user_verifications::dsl::*;
-    let verification_result = web::block(move || {
+    let verification = web::block(move || {
         user_verifications
             .filter(user_id.eq(current_user_id))
             .first::<models::UserVerification>(&mut conn)
             .optional()
     })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
+    .await??;
 
     // Return appropriate response
-    match verification_result {
-        Ok(Some(verification)) => Ok(HttpResponse::Ok().json(serde_json::json!({
+    match verification {
+        Some(verification) => Ok(HttpResponse::Ok().json(serde_json::json!({
             "status": verification.verification_status,
             "verification": models::VerificationResponse::from(verification)
         }))),
-        Ok(None) => {
+        None => {
             // Not an error - just means the user hasn't submitted verification yet
             Ok(HttpResponse::Ok().json(serde_json::json!({
                 "status": "not_submitted",
                 "verification": null
             })))
         }
-        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Database error when retrieving verification status"
-        }))),
     }
 }
 
@@ -567,37 +457,202 @@ async fn verification_status(
 async fn user_profile(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     // Extract user_id from JWT token
     let current_user_id = auth::extract_user_id(&req)?;
 
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     // Get user data
     use schema::users::dsl::*;
-    let user_result = web::block(move || {
+    let user = web::block(move || {
         users
             .filter(id.eq(current_user_id))
             .first::<models::User>(&mut conn)
+            .optional()?
+            .map(|user| roles::user_response(&mut conn, user))
+            .transpose()
     })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Failed to query database"))?;
+    .await??;
 
-    let user = match user_result {
-        Ok(user) => user,
-        Err(_) => {
-            return Ok(HttpResponse::NotFound().json(serde_json::json!({
-                "error": "User not found"
-            })));
+    // The response includes the user's staff roles
+    let response = user.ok_or_else(|| AppError::not_found("User not found"))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "user": response })))
+}
+
+/// Updates the current user's own account details. A new email address
+/// only replaces the current one after it has been verified.
+#[put("/profile")]
+async fn update_profile(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    profile_data: web::Json<models::UpdateProfileRequest>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let profile_data = profile_data.into_inner();
+
+    let new_username = profile_data.username.map(|name| name.trim().to_string());
+    if let Some(name) = &new_username
+        && (name.is_empty() || name.chars().count() > 255)
+    {
+        return Err(AppError::bad_request(
+            "Username must be between 1 and 255 characters",
+        ));
+    }
+
+    let new_email = profile_data.email.map(|address| address.trim().to_string());
+    if let Some(address) = &new_email
+        && (address.len() > 255 || !address.contains('@'))
+    {
+        return Err(AppError::bad_request("Invalid email address"));
+    }
+
+    if new_username.is_none() && new_email.is_none() {
+        return Err(AppError::bad_request("No valid fields to update"));
+    }
+
+    let mut conn = pool.get()?;
+
+    let checked_email = new_email.clone();
+    let user = web::block(move || {
+        use schema::users::dsl::*;
+
+        let Some(user) = users
+            .find(current_user_id)
+            .first::<models::User>(&mut conn)
+            .optional()?
+        else {
+            return Err(AppError::not_found("User not found"));
+        };
+
+        let email_taken = match &checked_email {
+            Some(address) if !address.eq_ignore_ascii_case(&user.email) => {
+                email_verification::email_in_use(&mut conn, address, current_user_id)?
+            }
+            _ => false,
+        };
+        if email_taken {
+            return Err(AppError::conflict("This email address is already in use"));
         }
+
+        let user = match &new_username {
+            Some(name) => diesel::update(users.find(current_user_id))
+                .set(username.eq(name))
+                .get_result::<models::User>(&mut conn)?,
+            None => user,
+        };
+        Ok(user)
+    })
+    .await??;
+
+    // The current address stays in use until the new one is confirmed
+    let pending_email = new_email.filter(|address| !address.eq_ignore_ascii_case(&user.email));
+    if let Some(address) = &pending_email {
+        email_verification::send_email_change(&pool, current_user_id, address.clone()).await;
+    }
+
+    let mut conn = pool.get()?;
+    let user_response = web::block(move || roles::user_response(&mut conn, user)).await??;
+
+    let message = if pending_email.is_some() {
+        "Profile updated. Check your new email address to confirm the change."
+    } else {
+        "Profile updated"
     };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": message,
+        "user": user_response,
+        "pending_email": pending_email
+    })))
+}
+
+/// Changes the current user's password after checking the current one, and
+/// logs them out everywhere except the session the change was made from
+#[post("/change-password")]
+async fn change_password(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    guard: web::Data<throttle::LoginGuard>,
+    password_data: web::Json<models::ChangePasswordRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Only a login session can change the password, never an API key
+    let claims = auth::authenticate(&req)?;
+    let current_user_id = claims.user_id()?;
+    let password_data = password_data.into_inner();
 
-    // Create response with UserResponse which now includes is_admin
-    let response = models::UserResponse::from(user);
+    let mut conn = pool.get()?;
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "user": response })))
+    let user = web::block(move || {
+        schema::users::table
+            .find(current_user_id)
+            .first::<models::User>(&mut conn)
+            .optional()
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("User not found"))?;
+
+    // Wrong current passwords count as failed logins, so a stolen session
+    // cannot be used to guess the password
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+    guard.check_login(&user.email, client_ip)?;
+
+    let password_matches = verify_password(&user.password, &password_data.current_password)
+        .map_err(|e| {
+            error!("Password verification error: {}", e);
+            AppError::internal("Authentication error")
+        })?;
+    if !password_matches {
+        guard
+            .login_failed(&user.email, client_ip, Some(&user.email))
+            .await;
+        return Err(AppError::unauthorized("Current password is incorrect"));
+    }
+    guard.login_succeeded(&user.email);
+
+    mfa::require_fresh_code(&pool, current_user_id, password_data.totp_code).await?;
+
+    validate_password(&password_data.new_password).map_err(AppError::BadRequest)?;
+
+    let hashed_password = hash_password(&password_data.new_password).map_err(|e| {
+        error!("Password hashing error: {}", e);
+        AppError::internal("Failed to process password")
+    })?;
+
+    let mut conn = pool.get()?;
+
+    let sessions_revoked = web::block(move || {
+        use schema::users::dsl::*;
+
+        conn.transaction(|conn| {
+            diesel::update(users.find(current_user_id))
+                .set(password.eq(hashed_password))
+                .execute(conn)?;
+            auth::revoke_other_sessions(conn, current_user_id, &claims.jti)
+        })
+    })
+    .await??;
+
+    info!("User {} changed their password", current_user_id);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Password changed. You have been logged out on other devices.",
+        "sessions_revoked": sessions_revoked
+    })))
+}
+
+#[get("/balances")]
+async fn user_balances(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id(&req)?;
+
+    let mut conn = pool.get()?;
+
+    let balances = web::block(move || ledger::balances(&mut conn, current_user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "balances": balances })))
 }
 
 // Add this new endpoint for admin access
@@ -606,102 +661,99 @@ async fn user_profile(
 async fn check_admin_access(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            // User is an admin
-            Ok(HttpResponse::Ok().json(serde_json::json!({
-                "status": "success",
-                "message": "You have admin access"
-            })))
-        }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
-        }
-    }
-}
+) -> Result<HttpResponse, AppError> {
+    // Any role gives access to the admin area; each route checks its own permission
+    let current_user_id = auth::require_staff(&req, &pool).await?;
 
-// Add these new imports at the top if not already present
-use chrono::NaiveDateTime;
+    let mut conn = pool.get()?;
+
+    let (role_names, permissions) = web::block(move || {
+        let role_names = roles::role_names(&mut conn, current_user_id)?;
+        let permissions = roles::permission_names(&mut conn, current_user_id)?;
+        Ok::<_, diesel::result::Error>((role_names, permissions))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "You have admin access",
+        "roles": role_names,
+        "permissions": permissions
+    })))
+}
 
 // Add this new endpoint for the verification queue
 #[get("/queue")]
 async fn verification_queue(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access first
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            // User is an admin, proceed to fetch the verification queue
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            // Get all user verifications with pending ID document verification
-            use schema::user_verifications::dsl::*;
-
-            let pending_verifications = web::block(move || {
-                user_verifications
-                    .filter(id_verification_status.eq("pending_review"))
-                    .order_by(updated_at.desc())
-                    .load::<models::UserVerification>(&mut conn)
-            })
-            .await
-            .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-            match pending_verifications {
-                Ok(verifications) => {
-                    // Structure to return verification with user info
-                    #[derive(Serialize)]
-                    struct VerificationWithUser {
-                        verification: models::UserVerification,
-                        user: Option<models::User>,
-                    }
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may read KYC submissions first
+    auth::require_permission(&req, &pool, Permission::KycRead).await?;
 
-                    // For each verification, get the associated user
-                    let mut verifications_with_users = Vec::new();
-                    for verification in verifications {
-                        let mut conn = pool.get().map_err(|_| {
-                            actix_web::error::ErrorInternalServerError(
-                                "Failed to get database connection",
-                            )
-                        })?;
-
-                        use schema::users::dsl::*;
-                        let user_result = web::block(move || {
-                            users
-                                .filter(id.eq(verification.user_id))
-                                .first::<models::User>(&mut conn)
-                                .optional()
-                        })
-                        .await
-                        .map_err(|_| {
-                            actix_web::error::ErrorInternalServerError("Database error")
-                        })?;
-
-                        verifications_with_users.push(VerificationWithUser {
-                            verification,
-                            user: user_result.unwrap_or(None),
-                        });
-                    }
+    let mut conn = pool.get()?;
 
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
-                        "queue": verifications_with_users
-                    })))
-                }
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to retrieve verification queue"
-                }))),
-            }
-        }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
+    // Get all user verifications with pending ID document verification
+    use schema::user_verifications::dsl::*;
+
+    let (verifications, mut documents) = web::block(move || -> QueryResult<_> {
+        let verifications = user_verifications
+            .filter(verification_status.eq_any([
+                kyc::KycStatus::Submitted.as_str(),
+                kyc::KycStatus::InReview.as_str(),
+            ]))
+            .order_by(updated_at.desc())
+            .load::<models::UserVerification>(&mut conn)?;
+
+        // Every document of every case, so reviewers see the whole submission
+        let case_ids: Vec<i32> = verifications.iter().map(|case| case.id).collect();
+        let mut documents: HashMap<i32, Vec<models::KycDocument>> = HashMap::new();
+        for document in schema::kyc_documents::table
+            .filter(schema::kyc_documents::verification_id.eq_any(case_ids))
+            .order_by(schema::kyc_documents::id.asc())
+            .load::<models::KycDocument>(&mut conn)?
+        {
+            documents
+                .entry(document.verification_id)
+                .or_default()
+                .push(document);
         }
+        Ok((verifications, documents))
+    })
+    .await??;
+
+    // Structure to return verification with user info
+    #[derive(Serialize)]
+    struct VerificationWithUser {
+        verification: models::UserVerification,
+        user: Option<models::User>,
+        documents: Vec<models::KycDocument>,
+    }
+
+    // For each verification, get the associated user
+    let mut verifications_with_users = Vec::new();
+    for verification in verifications {
+        let mut conn = pool.get()?;
+
+        use schema::users::dsl::*;
+        let user_result = web::block(move || {
+            users
+                .filter(id.eq(verification.user_id))
+                .first::<models::User>(&mut conn)
+                .optional()
+        })
+        .await?;
+
+        verifications_with_users.push(VerificationWithUser {
+            documents: documents.remove(&verification.id).unwrap_or_default(),
+            verification,
+            user: user_result.unwrap_or(None),
+        });
     }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "queue": verifications_with_users
+    })))
 }
 
 // Add an endpoint to update verification status
@@ -710,93 +762,79 @@ async fn update_verification_status(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
     path: web::Path<i32>,
-    status_update: web::Json<serde_json::Value>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            let verification_id = path.into_inner();
-            // Extract status string before moving status_update
-            let status = status_update
-                .get("status")
-                .and_then(|s| s.as_str())
-                .unwrap_or("pending_review")
-                .to_string(); // Convert to owned String to avoid borrowing issues
-
-            if status != "approved" && status != "rejected" {
-                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Invalid status. Must be 'approved' or 'rejected'."
-                })));
+    decision: web::Json<models::KycDecisionRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may review KYC submissions
+    let reviewer_id = auth::require_permission(&req, &pool, Permission::KycReview).await?;
+
+    let verification_id = path.into_inner();
+    let decision = decision.into_inner();
+    let status = kyc::parse_decision(&decision)?;
+
+    let mut conn = pool.get()?;
+
+    // The state machine decides whether the verification may move there
+    let updated_verification = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            if status == kyc::KycStatus::Approved {
+                kyc::lock(conn, verification_id)?;
+                if !kyc_documents::required_approved(conn, verification_id)? {
+                    return Err(AppError::conflict(
+                        "Approve the ID front and proof of address before the verification",
+                    ));
+                }
             }
 
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            // Update the verification status
-            use schema::user_verifications::dsl::*;
-            let result = web::block(move || {
-                diesel::update(user_verifications.filter(id.eq(verification_id)))
-                    .set((
-                        id_verification_status.eq(status),
-                        id_verified_at.eq(chrono::Local::now().naive_local()),
-                        updated_at.eq(chrono::Local::now().naive_local()),
-                    ))
-                    .get_result::<models::UserVerification>(&mut conn)
-            })
-            .await
-            .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-            match result {
-                Ok(updated_verification) => Ok(HttpResponse::Ok().json(serde_json::json!({
-                    "status": "success",
-                    "verification": models::VerificationResponse::from(updated_verification)
-                }))),
-                Err(_) => Ok(HttpResponse::NotFound().json(serde_json::json!({
-                    "error": "Verification not found or could not be updated"
-                }))),
-            }
-        }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
-        }
-    }
+            Ok(kyc::transition(
+                conn,
+                verification_id,
+                status,
+                decision.reason,
+                reviewer_id,
+            )?)
+        })
+    })
+    .await??;
+
+    info!(
+        "Verification {} moved to {} by user {}",
+        verification_id,
+        status.as_str(),
+        reviewer_id
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "verification": models::VerificationResponse::from(updated_verification)
+    })))
 }
 
-// Add this new endpoint
-
-use std::path::PathBuf;
-
+/// Serves a document by the file name clients were given in
+/// `id_front_path` (KYC staff only). Every read is recorded.
 #[get("/document/{filename}")]
 async fn serve_document(
     req: HttpRequest,
     filename: web::Path<String>,
     pool: web::Data<db::DbPool>,
-) -> Result<actix_files::NamedFile, actix_web::Error> {
-    // Only admins can access documents
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            // Admin is authenticated, serve the file
-            let filename = filename.into_inner();
-            // Fix: Look for files in the correct directory
-            let path = PathBuf::from("uploads/id_documents").join(&filename);
-
-            log::info!("Attempting to serve document: {:?}", path);
-
-            match actix_files::NamedFile::open(&path) {
-                Ok(file) => {
-                    log::info!("Successfully serving document: {:?}", filename);
-                    Ok(file)
-                }
-                Err(e) => {
-                    log::error!("Failed to open document {}: {}", filename, e);
-                    Err(actix_web::error::ErrorNotFound("Document not found"))
-                }
-            }
-        }
-        Err(_) => Err(actix_web::error::ErrorForbidden("Admin access required")),
-    }
+    store: web::Data<dyn DocumentStore>,
+) -> Result<HttpResponse, AppError> {
+    // Only KYC staff can access documents
+    let reviewer_id = auth::require_permission(&req, &pool, Permission::KycRead).await?;
+
+    let filename = filename.into_inner();
+    let mut conn = pool.get()?;
+
+    let document = web::block(move || {
+        use schema::kyc_documents::dsl::*;
+
+        kyc_documents
+            .filter(storage_key.eq(filename))
+            .first::<models::KycDocument>(&mut conn)
+            .optional()
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("Document not found"))?;
+
+    kyc_documents::send_document(&req, &pool, store.get_ref(), document, reviewer_id).await
 }
 
 // Get all users (for admin)
@@ -804,44 +842,25 @@ async fn serve_document(
 async fn admin_get_users(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access first
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            // User is an admin, fetch all users
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            // Get all users
-            use schema::users::dsl::*;
-            let all_users =
-                web::block(move || users.order_by(id.asc()).load::<models::User>(&mut conn))
-                    .await
-                    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-            match all_users {
-                Ok(user_list) => {
-                    // Convert users to UserResponse to avoid sending passwords
-                    let user_responses: Vec<models::UserResponse> = user_list
-                        .into_iter()
-                        .map(models::UserResponse::from)
-                        .collect();
-
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
-                        "users": user_responses
-                    })))
-                }
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to retrieve users"
-                }))),
-            }
-        }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
-        }
-    }
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may read accounts first
+    auth::require_permission(&req, &pool, Permission::UsersRead).await?;
+
+    // Fetch all users with their roles
+    let mut conn = pool.get()?;
+
+    // Get all users
+    use schema::users::dsl::*;
+    let user_responses = web::block(move || {
+        let user_list = users.order_by(id.asc()).load::<models::User>(&mut conn)?;
+        roles::user_responses(&mut conn, user_list)
+    })
+    .await??;
+
+    // UserResponse avoids sending passwords
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "users": user_responses
+    })))
 }
 
 // Create new user (admin only)
@@ -850,66 +869,40 @@ async fn admin_create_user(
     req: HttpRequest,
     pool: web::Data<db::DbPool>,
     new_user_data: web::Json<models::NewUser>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            // Validate password
-            if let Err(message) = validate_password(&new_user_data.password) {
-                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": message
-                })));
-            }
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may manage accounts
+    auth::require_permission(&req, &pool, Permission::UsersManage).await?;
 
-            // Hash the password
-            let hashed_password = match hash_password(&new_user_data.password) {
-                Ok(hash) => hash,
-                Err(e) => {
-                    error!("Password hashing error: {}", e);
-                    return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                        "error": "Failed to process password"
-                    })));
-                }
-            };
-
-            // Create new user with hashed password
-            let mut new_user = new_user_data.into_inner();
-            new_user.password = hashed_password;
-
-            // Insert into database
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            let user_result = web::block(move || {
-                diesel::insert_into(schema::users::table)
-                    .values(&new_user)
-                    .get_result::<models::User>(&mut conn)
-            })
-            .await
-            .map_err(|e| {
-                error!("Failed to create user: {:?}", e);
-                actix_web::error::ErrorInternalServerError("Failed to create user")
-            })?;
-
-            match user_result {
-                Ok(user) => {
-                    let user_response = models::UserResponse::from(user);
-                    Ok(HttpResponse::Created().json(serde_json::json!({
-                        "message": "User created successfully",
-                        "user": user_response
-                    })))
-                }
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to create user"
-                }))),
-            }
-        }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
-        }
-    }
+    // Validate password
+    validate_password(&new_user_data.password).map_err(AppError::BadRequest)?;
+
+    // Hash the password
+    let hashed_password = hash_password(&new_user_data.password).map_err(|e| {
+        error!("Password hashing error: {}", e);
+        AppError::internal("Failed to process password")
+    })?;
+
+    // Create new user with hashed password
+    let mut new_user = new_user_data.into_inner();
+    new_user.password = hashed_password;
+
+    // Insert into database
+    let mut conn = pool.get()?;
+
+    let user = web::block(move || {
+        diesel::insert_into(schema::users::table)
+            .values(&new_user)
+            .get_result::<models::User>(&mut conn)
+    })
+    .await??;
+
+    email_verification::send_verification(&pool, user.id, user.email.clone()).await;
+
+    let user_response = models::UserResponse::new(user, Vec::new());
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "User created successfully",
+        "user": user_response
+    })))
 }
 
 // Update existing user (admin only)
@@ -919,207 +912,187 @@ async fn admin_update_user(
     path: web::Path<i32>,
     pool: web::Data<db::DbPool>,
     user_data: web::Json<serde_json::Value>,
-) -> Result<HttpResponse, actix_web::Error> {
-    // Check if the user has admin access
-    match auth::require_admin(&req, &pool).await {
-        Ok(_) => {
-            let user_id = path.into_inner();
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            // Check if user exists
-            use schema::users::dsl::*;
-            let user_exists = web::block(move || {
-                users
-                    .filter(id.eq(user_id))
-                    .count()
-                    .get_result::<i64>(&mut conn)
-            })
-            .await
-            .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-            if user_exists.unwrap_or(0) == 0 {
-                return Ok(HttpResponse::NotFound().json(serde_json::json!({
-                    "error": "User not found"
-                })));
-            }
+) -> Result<HttpResponse, AppError> {
+    // Check that the user may manage accounts
+    let admin_id = auth::require_permission(&req, &pool, Permission::UsersManage).await?;
 
-            // Prepare update data
-            let mut update_data = serde_json::Map::new();
+    let user_id = path.into_inner();
+    let mut conn = pool.get()?;
 
-            // Extract fields to update
-            if let Some(username_val) = user_data.get("username") {
-                if let Some(username_str) = username_val.as_str() {
-                    update_data.insert(
-                        "username".to_string(),
-                        serde_json::Value::String(username_str.to_string()),
-                    );
-                }
-            }
+    // Check if user exists
+    use schema::users::dsl::*;
+    let user_exists = web::block(move || {
+        users
+            .filter(id.eq(user_id))
+            .count()
+            .get_result::<i64>(&mut conn)
+    })
+    .await??;
 
-            if let Some(email_val) = user_data.get("email") {
-                if let Some(email_str) = email_val.as_str() {
-                    update_data.insert(
-                        "email".to_string(),
-                        serde_json::Value::String(email_str.to_string()),
-                    );
-                }
-            }
+    if user_exists == 0 {
+        return Err(AppError::not_found("User not found"));
+    }
 
-            if let Some(is_admin_val) = user_data.get("is_admin") {
-                if let Some(is_admin_bool) = is_admin_val.as_bool() {
-                    update_data.insert(
-                        "is_admin".to_string(),
-                        serde_json::Value::Bool(is_admin_bool),
-                    );
-                }
-            }
+    // Prepare update data
+    let mut update_data = serde_json::Map::new();
 
-            // Handle password separately (needs to be hashed)
-            let mut conn = pool.get().map_err(|_| {
-                actix_web::error::ErrorInternalServerError("Failed to get database connection")
-            })?;
-
-            if let Some(password_val) = user_data.get("password") {
-                if let Some(password_str) = password_val.as_str() {
-                    if !password_str.is_empty() {
-                        // Validate password
-                        if let Err(message) = validate_password(password_str) {
-                            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                                "error": message
-                            })));
-                        }
+    // Extract fields to update
+    if let Some(username_val) = user_data.get("username")
+        && let Some(username_str) = username_val.as_str()
+    {
+        update_data.insert(
+            "username".to_string(),
+            serde_json::Value::String(username_str.to_string()),
+        );
+    }
 
-                        // Hash the password
-                        let hashed_password = match hash_password(password_str) {
-                            Ok(hash) => hash,
-                            Err(e) => {
-                                error!("Password hashing error: {}", e);
-                                return Ok(HttpResponse::InternalServerError().json(
-                                    serde_json::json!({
-                                        "error": "Failed to process password"
-                                    }),
-                                ));
-                            }
-                        };
-
-                        update_data.insert(
-                            "password".to_string(),
-                            serde_json::Value::String(hashed_password),
-                        );
-                    }
-                }
-            }
+    if let Some(email_val) = user_data.get("email")
+        && let Some(email_str) = email_val.as_str()
+    {
+        update_data.insert(
+            "email".to_string(),
+            serde_json::Value::String(email_str.to_string()),
+        );
+    }
 
-            // If nothing to update, return early
-            if update_data.is_empty() {
-                return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "No valid fields to update"
-                })));
-            }
+    // `is_admin` is kept for older clients: true grants the super admin
+    // role and false removes every role
+    let admin_change = user_data.get("is_admin").and_then(|value| value.as_bool());
 
-            // Perform the update
-            let update_user_id = user_id; // Clone for the closure
-            let update_result = web::block(move || -> Result<i32, diesel::result::Error> {
-                // Perform updates directly without collecting them first
-
-                // Collect all the fields to update
-                if let Some(username_val) = update_data.get("username") {
-                    if let Some(username_str) = username_val.as_str() {
-                        diesel::update(users.filter(id.eq(update_user_id)))
-                            .set(username.eq(username_str))
-                            .execute(&mut conn)?;
-                    }
-                }
+    // Handle password separately (needs to be hashed)
+    let mut conn = pool.get()?;
 
-                if let Some(email_val) = update_data.get("email") {
-                    if let Some(email_str) = email_val.as_str() {
-                        diesel::update(users.filter(id.eq(update_user_id)))
-                            .set(email.eq(email_str))
-                            .execute(&mut conn)?;
-                    }
-                }
+    if let Some(password_val) = user_data.get("password")
+        && let Some(password_str) = password_val.as_str()
+        && !password_str.is_empty()
+    {
+        // Validate password
+        validate_password(password_str).map_err(AppError::BadRequest)?;
 
-                if let Some(password_val) = update_data.get("password") {
-                    if let Some(password_str) = password_val.as_str() {
-                        diesel::update(users.filter(id.eq(update_user_id)))
-                            .set(password.eq(password_str))
-                            .execute(&mut conn)?;
-                    }
-                }
+        // Hash the password
+        let hashed_password = hash_password(password_str).map_err(|e| {
+            error!("Password hashing error: {}", e);
+            AppError::internal("Failed to process password")
+        })?;
 
-                if let Some(is_admin_val) = update_data.get("is_admin") {
-                    if let Some(is_admin_bool) = is_admin_val.as_bool() {
-                        diesel::update(users.filter(id.eq(update_user_id)))
-                            .set(is_admin.eq(is_admin_bool))
-                            .execute(&mut conn)?;
-                    }
-                }
+        update_data.insert(
+            "password".to_string(),
+            serde_json::Value::String(hashed_password),
+        );
+    }
 
-                // Return 1 to indicate success
-                Ok(1)
-            })
-            .await
-            .map_err(|e| {
-                error!("Failed to update user: {:?}", e);
-                actix_web::error::ErrorInternalServerError("Failed to update user")
-            })?;
-
-            match update_result {
-                Ok(_) => {
-                    // Fetch the updated user to return in response
-                    let mut conn = pool.get().map_err(|_| {
-                        actix_web::error::ErrorInternalServerError(
-                            "Failed to get database connection",
-                        )
-                    })?;
-
-                    let updated_user = web::block(move || {
-                        users
-                            .filter(id.eq(user_id))
-                            .first::<models::User>(&mut conn)
-                    })
-                    .await
-                    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-                    match updated_user {
-                        Ok(user) => {
-                            let user_response = models::UserResponse::from(user);
-                            Ok(HttpResponse::Ok().json(serde_json::json!({
-                                "message": "User updated successfully",
-                                "user": user_response
-                            })))
-                        }
-                        Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                            "error": "Failed to retrieve updated user"
-                        }))),
-                    }
-                }
-                Err(_) => Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to update user"
-                }))),
+    // If nothing to update, return early
+    if update_data.is_empty() && admin_change.is_none() {
+        return Err(AppError::bad_request("No valid fields to update"));
+    }
+
+    // Support staff may edit customers but not take over staff accounts
+    let credentials = update_data.contains_key("email") || update_data.contains_key("password");
+    let mut check_conn = pool.get()?;
+    web::block(move || roles::check_account_edit(&mut check_conn, admin_id, user_id, credentials))
+        .await??;
+
+    // Changing roles needs its own permission, and is applied first so
+    // a refused change leaves the account untouched
+    if let Some(make_admin) = admin_change {
+        auth::require_permission(&req, &pool, Permission::RolesManage).await?;
+
+        let names = if make_admin {
+            vec![roles::SUPER_ADMIN.to_string()]
+        } else {
+            Vec::new()
+        };
+        roles::set_roles(&pool, user_id, names, admin_id).await?;
+    }
+
+    // Perform the update
+    let update_user_id = user_id; // Clone for the closure
+    let changed_email = web::block(move || -> Result<Option<String>, diesel::result::Error> {
+        // Perform updates directly without collecting them first
+
+        // Collect all the fields to update
+        if let Some(username_val) = update_data.get("username")
+            && let Some(username_str) = username_val.as_str()
+        {
+            diesel::update(users.filter(id.eq(update_user_id)))
+                .set(username.eq(username_str))
+                .execute(&mut conn)?;
+        }
+
+        // A new address has to be verified again by the user
+        let mut changed_email = None;
+        if let Some(email_val) = update_data.get("email")
+            && let Some(email_str) = email_val.as_str()
+        {
+            let changed = diesel::update(
+                users
+                    .filter(id.eq(update_user_id))
+                    .filter(email.ne(email_str)),
+            )
+            .set((
+                email.eq(email_str),
+                email_verified_at.eq(None::<chrono::DateTime<chrono::Utc>>),
+            ))
+            .execute(&mut conn)?;
+            if changed > 0 {
+                changed_email = Some(email_str.to_string());
             }
         }
-        Err(response) => {
-            // Not an admin, return the error response
-            Ok(response)
+
+        if let Some(password_val) = update_data.get("password")
+            && let Some(password_str) = password_val.as_str()
+        {
+            diesel::update(users.filter(id.eq(update_user_id)))
+                .set(password.eq(password_str))
+                .execute(&mut conn)?;
+        }
+
+        // A new password ends existing sessions
+        if update_data.contains_key("password") {
+            auth::revoke_all_sessions(&mut conn, update_user_id)?;
         }
+
+        Ok(changed_email)
+    })
+    .await??;
+
+    if let Some(address) = changed_email {
+        email_verification::send_verification(&pool, user_id, address).await;
     }
+
+    // Fetch the updated user to return in response
+    let mut conn = pool.get()?;
+
+    let user_response = web::block(move || {
+        users
+            .filter(id.eq(user_id))
+            .first::<models::User>(&mut conn)
+            .and_then(|user| roles::user_response(&mut conn, user))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "User updated successfully",
+        "user": user_response
+    })))
 }
 
 // This would go in your main.rs or a separate auth file
 #[post("/user/request-password-reset")]
 async fn request_password_reset(
+    req: HttpRequest,
     pool: web::Data<db::DbPool>,
+    guard: web::Data<throttle::LoginGuard>,
     request_data: web::Json<models::PasswordResetRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     let user_email = request_data.email.clone();
 
+    // Limit how often reset emails can be requested for an address or from an IP
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+    guard.check_password_reset(&user_email, client_ip)?;
+
     // Check if user exists first
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     use schema::users::dsl::*;
     // Clone the email before moving it into the closure
@@ -1130,8 +1103,7 @@ async fn request_password_reset(
             .first::<models::User>(&mut conn)
             .optional()
     })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
+    .await?;
 
     // We'll generate and send a reset token only if the user exists
     if let Ok(Some(user)) = user_result {
@@ -1142,9 +1114,7 @@ async fn request_password_reset(
         let token_expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
 
         // Get a new connection for the next operation
-        let mut conn = pool.get().map_err(|_| {
-            actix_web::error::ErrorInternalServerError("Failed to get database connection")
-        })?;
+        let mut conn = pool.get()?;
 
         // Store token in password_reset_tokens table
         use schema::password_reset_tokens::dsl::*;
@@ -1163,9 +1133,7 @@ async fn request_password_reset(
         }
 
         // Get a new connection after the delete operation
-        let mut conn = pool.get().map_err(|_| {
-            actix_web::error::ErrorInternalServerError("Failed to get database connection")
-        })?;
+        let mut conn = pool.get()?;
 
         // Create new reset token record
         let new_token = models::NewPasswordResetToken {
@@ -1192,8 +1160,11 @@ async fn request_password_reset(
 
                     // Fall back to logging the token for development environments
                     info!(
-                        "Password reset link for {}: http://localhost:3000/reset-password?token={}&email={}",
-                        user.email, reset_token, user.email
+                        "Password reset link for {}: {}/reset-password?token={}&email={}",
+                        user.email,
+                        config::get().frontend_url,
+                        reset_token,
+                        user.email
                     );
                 }
             }
@@ -1221,17 +1192,11 @@ async fn request_password_reset(
 async fn reset_password(
     pool: web::Data<db::DbPool>,
     request_data: web::Json<models::ResetPasswordRequest>,
-) -> Result<HttpResponse, actix_web::Error> {
+) -> Result<HttpResponse, AppError> {
     // Validate the new password
-    if let Err(message) = validate_password(&request_data.new_password) {
-        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-            "error": message
-        })));
-    }
+    validate_password(&request_data.new_password).map_err(AppError::BadRequest)?;
 
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     // Get user ID from email
     use schema::users::dsl as users_dsl;
@@ -1243,13 +1208,10 @@ async fn reset_password(
             .first::<i32>(&mut conn)
             .optional()
     })
-    .await
-    .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
+    .await?;
 
     // Get new connection for next query
-    let mut conn = pool.get().map_err(|_| {
-        actix_web::error::ErrorInternalServerError("Failed to get database connection")
-    })?;
+    let mut conn = pool.get()?;
 
     // Check if user exists and validate the token
     if let Ok(Some(user_id)) = user_result {
@@ -1265,26 +1227,21 @@ async fn reset_password(
                 .first::<models::PasswordResetToken>(&mut conn)
                 .optional()
         })
-        .await
-        .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
+        .await?;
 
         match token_result {
             Ok(Some(token_record)) => {
+                // Users with two-factor authentication also need a fresh code
+                mfa::require_fresh_code(&pool, user_id, request_data.totp_code.clone()).await?;
+
                 // Token is valid, hash the new password
-                let hashed_password = match hash_password(&request_data.new_password) {
-                    Ok(hash) => hash,
-                    Err(e) => {
-                        error!("Password hashing error: {}", e);
-                        return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                            "error": "Failed to process password"
-                        })));
-                    }
-                };
+                let hashed_password = hash_password(&request_data.new_password).map_err(|e| {
+                    error!("Password hashing error: {}", e);
+                    AppError::internal("Failed to process password")
+                })?;
 
                 // Get new connection for update operations
-                let mut conn = pool.get().map_err(|_| {
-                    actix_web::error::ErrorInternalServerError("Failed to get database connection")
-                })?;
+                let mut conn = pool.get()?;
 
                 // Update the user's password
                 let user_id_for_update = token_record.user_id;
@@ -1293,75 +1250,147 @@ async fn reset_password(
                         .set(users_dsl::password.eq(&hashed_password))
                         .execute(&mut conn)
                 })
-                .await
-                .map_err(|_| actix_web::error::ErrorInternalServerError("Database error"))?;
-
-                if let Ok(rows_affected) = update_result {
-                    if rows_affected > 0 {
-                        // Password updated successfully, now mark the token as used
-                        let mut conn = pool.get().map_err(|_| {
-                            actix_web::error::ErrorInternalServerError(
-                                "Failed to get database connection",
-                            )
-                        })?;
-
-                        let token_id = token_record.id;
-                        let _ = web::block(move || {
-                            diesel::update(tokens_dsl::password_reset_tokens.find(token_id))
-                                .set(tokens_dsl::used.eq(true))
-                                .execute(&mut conn)
-                        })
-                        .await;
-
-                        return Ok(HttpResponse::Ok().json(serde_json::json!({
-                            "message": "Password has been reset successfully. You can now log in with your new password."
-                        })));
+                .await?;
+
+                if let Ok(rows_affected) = update_result
+                    && rows_affected > 0
+                {
+                    // Password updated successfully, now mark the token as used
+                    let mut conn = pool.get()?;
+
+                    let token_id = token_record.id;
+                    let _ = web::block(move || {
+                        diesel::update(tokens_dsl::password_reset_tokens.find(token_id))
+                            .set(tokens_dsl::used.eq(true))
+                            .execute(&mut conn)
+                    })
+                    .await;
+
+                    // Sign out sessions that may have been opened with the old password
+                    let mut conn = pool.get()?;
+                    let revoked = web::block(move || {
+                        auth::revoke_all_sessions(&mut conn, user_id_for_update)
+                    })
+                    .await;
+                    match revoked {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => {
+                            error!("Failed to revoke sessions after password reset: {}", e)
+                        }
+                        Err(e) => error!("Failed to revoke sessions after password reset: {}", e),
                     }
+
+                    return Ok(HttpResponse::Ok().json(serde_json::json!({
+                        "message": "Password has been reset successfully. You can now log in with your new password."
+                    })));
                 }
 
                 // If we reach this point, password update failed
-                Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Failed to update password"
-                })))
+                Err(AppError::internal("Failed to update password"))
             }
             _ => {
                 // Token is invalid, expired, or already used
-                Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                    "error": "Invalid or expired password reset token"
-                })))
+                Err(AppError::bad_request(
+                    "Invalid or expired password reset token",
+                ))
             }
         }
     } else {
         // User not found, but for security reasons we use the same error message
-        Ok(HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Invalid or expired password reset token"
-        })))
+        Err(AppError::bad_request(
+            "Invalid or expired password reset token",
+        ))
     }
 }
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    if std::env::var("SMTP_PASSWORD").is_err() {
-        eprintln!("Warning: SMTP_PASSWORD not found in environment");
-    }
     env_logger::init();
 
-    let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    // Refuse to start with a configuration that is missing what it needs
+    let config = match Config::load() {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            error!("{}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    let pool = db::establish_connection_pool(&config.database);
+
+    // `backfill-candles` imports candle history and exits without serving
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("backfill-candles") {
+        return candles::run_backfill(&pool, &args[2..]).map_err(|e| {
+            error!("{}", e);
+            std::io::Error::other(e)
+        });
+    }
+
+    // Rebuild the in-memory order books from open orders in the database
+    let exchange = match orders::Exchange::load(&pool) {
+        Ok(exchange) => web::Data::new(exchange),
+        Err(e) => {
+            error!("{}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    // Real-time feed that order and wallet handlers publish to
+    let feed = web::Data::new(feed::FeedHub::new(pool.clone(), exchange.clone()).start());
+
+    // Market listings are cached and refreshed in the background
+    let markets = web::Data::new(markets::MarketCache::new(&config.market_data));
+    actix_web::rt::spawn(markets::run_refresher(markets.clone(), pool.clone()));
+
+    // Failed logins and reset requests are counted in memory
+    let login_guard = web::Data::new(throttle::LoginGuard::in_memory(&config.auth));
 
-    let pool = db::establish_connection_pool();
+    // Expire GTD orders in the background
+    actix_web::rt::spawn(orders::run_expiry_sweeper(
+        pool.clone(),
+        exchange.clone(),
+        feed.clone(),
+    ));
 
-    info!("Starting server at {}:{}", host, port);
+    info!(
+        "Starting server at {}:{}",
+        config.server.host, config.server.port
+    );
 
-    // Create uploads directory if it doesn't exist
-    let uploads_dir = Path::new("uploads");
-    if !uploads_dir.exists() {
-        std::fs::create_dir_all(uploads_dir).expect("Failed to create uploads directory");
+    // KYC documents are kept encrypted, on disk or in a bucket
+    let store = match document_store::from_config(&config.uploads) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Invalid document store: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+    // Documents uploaded before then are still plaintext under the uploads
+    // directory
+    match kyc_documents::import_legacy_documents(
+        &pool,
+        store.as_ref(),
+        &config.uploads.id_documents_dir(),
+    )
+    .await
+    {
+        Ok(0) => {}
+        Ok(imported) => info!(
+            "Moved {} legacy documents into the document store",
+            imported
+        ),
+        Err(e) => error!("Failed to import legacy documents: {}", e),
     }
+    let store = web::Data::from(store);
 
+    let app_config = web::Data::new(config.clone());
     HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
+        let cors = config
+            .cors
+            .allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
             .allowed_headers(vec![
                 actix_web::http::header::AUTHORIZATION,
@@ -1373,38 +1402,103 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(exchange.clone())
+            .app_data(feed.clone())
+            .app_data(markets.clone())
+            .app_data(login_guard.clone())
+            .app_data(app_config.clone())
+            .app_data(store.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::extractor_error))
+            .app_data(web::PathConfig::default().error_handler(error::extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(error::extractor_error))
+            .wrap(actix_web::middleware::from_fn(auth::check_bearer_token))
+            .wrap(actix_web::middleware::from_fn(api_keys::verify_signature))
             .wrap(actix_web::middleware::Logger::default())
             .wrap(cors)
-            // Add this line to serve static files
-            .service(Files::new("/uploads", "uploads").show_files_listing())
+            .wrap(actix_web::middleware::from_fn(error::assign_request_id))
             .service(health_check)
             .service(users_route)
             .service(sign_up)
             .service(login)
             .service(request_password_reset)
             .service(reset_password)
+            .service(
+                web::scope("/auth")
+                    .service(auth::refresh)
+                    .service(auth::logout)
+                    .service(auth::logout_all)
+                    .service(throttle::unlock_account)
+                    .service(mfa::verify_login),
+            )
             .service(
                 web::scope("/verify")
                     .service(update_verify)
                     .service(verification_status)
-                    .service(upload_id_document),
+                    .service(upload_id_document)
+                    .service(kyc_documents::upload_document)
+                    .service(kyc_documents::list_documents)
+                    .service(kyc_documents::download_document)
+                    .service(kyc_documents::submit_verification)
+                    .service(kyc::resubmit),
             )
             .service(
-                web::scope("/user").service(user_profile), // Add this line
+                web::scope("/user")
+                    .service(user_profile)
+                    .service(update_profile)
+                    .service(change_password)
+                    .service(user_balances)
+                    .service(email_verification::verify_email)
+                    .service(email_verification::resend_verification)
+                    .service(sessions::list_sessions)
+                    .service(sessions::revoke_session)
+                    .service(fees::user_fees)
+                    .service(limits::user_limits)
+                    .service(mfa::status)
+                    .service(mfa::setup)
+                    .service(mfa::enable)
+                    .service(mfa::disable)
+                    .service(mfa::regenerate_recovery_codes)
+                    .service(api_keys::create_key)
+                    .service(api_keys::list_keys)
+                    .service(api_keys::revoke_key)
+                    .service(wallet::request_withdrawal),
             )
             .service(
                 web::scope("/admin")
                     .service(check_admin_access)
                     .service(verification_queue)
                     .service(update_verification_status)
+                    .service(kyc::admin_verification_history)
+                    .service(kyc_documents::admin_list_documents)
+                    .service(kyc_documents::admin_review_document)
+                    .service(kyc_documents::admin_download_document)
+                    .service(kyc_documents::admin_document_access_log)
                     .service(serve_document)
                     .service(admin_get_users)
                     .service(admin_create_user)
-                    .service(admin_update_user), // Remove the password reset endpoint from here
+                    .service(admin_update_user) // Remove the password reset endpoint from here
+                    .service(roles::admin_list_roles)
+                    .service(roles::admin_set_user_roles)
+                    .service(sessions::admin_list_user_sessions)
+                    .service(wallet::admin_credit_deposit)
+                    .service(wallet::admin_update_withdrawal)
+                    .service(instruments::admin_create_instrument)
+                    .service(instruments::admin_update_instrument)
+                    .service(instruments::admin_delete_instrument)
+                    .service(fees::admin_get_fees)
+                    .service(fees::admin_update_fee_tiers)
+                    .service(fees::admin_set_fee_override)
+                    .service(fees::admin_delete_fee_override),
             )
             .service(markets::get_markets) // Add the markets endpoint
+            .service(candles::get_candles)
+            .service(instruments::list_instruments)
+            .service(orders::place_order)
+            .service(orders::cancel_order)
+            .service(feed::feed_socket)
+            .default_service(web::to(error::route_not_found))
     })
-    .bind(format!("{}:{}", host, port))?
+    .bind((config.server.host.as_str(), config.server.port))?
     .run()
     .await
 }
@@ -0,0 +1,748 @@
+use crate::config::MarketDataConfig;
+use crate::markets::{CryptoCurrencyResponse, MarketsResponse};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+const COINMARKETCAP_API_URL: &str = "https://pro-api.coinmarketcap.com";
+const COINGECKO_API_URL: &str = "https://api.coingecko.com";
+const DEFAULT_FIXTURE_PATH: &str = "fixtures/markets.json";
+
+/// Upstream requests taking longer than this count as failed
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound on `ListingsQuery::limit`, the most CoinMarketCap returns
+pub const MAX_LIMIT: u32 = 5000;
+
+/// Upper bound on `ListingsQuery::start`. Every distinct page is cached and
+/// costs credits, and ranks past this are coins nobody trades.
+pub const MAX_START: u32 = 5000;
+
+/// Most symbols one request can look up
+pub const MAX_SYMBOLS: usize = 50;
+
+/// Currencies quotes can be converted to. Both CoinMarketCap and CoinGecko
+/// support these; each other one would be cached separately.
+pub const SUPPORTED_CONVERT: &[&str] = &[
+    "USD", "EUR", "GBP", "JPY", "CHF", "CAD", "AUD", "CNY", "KRW", "INR", "BRL", "BTC", "ETH",
+];
+
+/// What listings sort on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ListingSort {
+    MarketCap,
+    Price,
+    Volume24h,
+    PercentChange1h,
+    PercentChange24h,
+    PercentChange7d,
+    Name,
+    Symbol,
+}
+
+impl ListingSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingSort::MarketCap => "market_cap",
+            ListingSort::Price => "price",
+            ListingSort::Volume24h => "volume_24h",
+            ListingSort::PercentChange1h => "percent_change_1h",
+            ListingSort::PercentChange24h => "percent_change_24h",
+            ListingSort::PercentChange7d => "percent_change_7d",
+            ListingSort::Name => "name",
+            ListingSort::Symbol => "symbol",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ListingSort> {
+        [
+            ListingSort::MarketCap,
+            ListingSort::Price,
+            ListingSort::Volume24h,
+            ListingSort::PercentChange1h,
+            ListingSort::PercentChange24h,
+            ListingSort::PercentChange7d,
+            ListingSort::Name,
+            ListingSort::Symbol,
+        ]
+        .into_iter()
+        .find(|sort| sort.as_str() == value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+impl SortDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortDirection::Asc => "asc",
+            SortDirection::Desc => "desc",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<SortDirection> {
+        match value {
+            "asc" => Some(SortDirection::Asc),
+            "desc" => Some(SortDirection::Desc),
+            _ => None,
+        }
+    }
+}
+
+/// Which page of listings to fetch and how to price them
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ListingsQuery {
+    /// Number of listings, at most `MAX_LIMIT`
+    pub limit: u32,
+    /// 1-based rank of the first listing in the requested sort order
+    pub start: u32,
+    /// Upper-case code of the currency quotes are converted to
+    pub convert: String,
+    pub sort: ListingSort,
+    pub sort_dir: SortDirection,
+    /// Sorted, upper-case symbols to look up instead of a page of listings.
+    /// `limit` and `start` do not apply when there are any.
+    pub symbols: Vec<String>,
+}
+
+impl Default for ListingsQuery {
+    /// The top 100 coins by market cap, priced in USD
+    fn default() -> Self {
+        ListingsQuery {
+            limit: 100,
+            start: 1,
+            convert: "USD".to_string(),
+            sort: ListingSort::MarketCap,
+            sort_dir: SortDirection::Desc,
+            symbols: Vec::new(),
+        }
+    }
+}
+
+/// A source of cryptocurrency listings for the markets page
+pub trait MarketDataProvider: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Fetches one page of the latest listings. Providers that cannot sort
+    /// on the requested field upstream sort the page they return instead.
+    fn fetch_listings<'a>(
+        &'a self,
+        query: &'a ListingsQuery,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>>;
+
+    /// Fetches the latest quotes of the coins with the given symbols,
+    /// whatever their rank, in no particular order. Symbols shared by
+    /// several coins return the one with the largest market cap, and
+    /// unknown symbols are left out.
+    fn fetch_symbols<'a>(
+        &'a self,
+        symbols: &'a [String],
+        convert: &'a str,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>>;
+}
+
+#[derive(Debug)]
+pub enum MarketDataError {
+    MissingApiKey(&'static str),
+    Request(reqwest::Error),
+    Status(reqwest::StatusCode),
+    Parse(String),
+    Fixture(std::io::Error),
+    /// The query asks for something the provider cannot serve
+    InvalidQuery(String),
+    /// The last upstream call failed moments ago and nothing is cached
+    Unavailable,
+}
+
+impl MarketDataError {
+    /// Message shown to API clients
+    pub fn message(&self) -> String {
+        match self {
+            MarketDataError::MissingApiKey(_) => "API key configuration error".to_string(),
+            MarketDataError::Request(_) | MarketDataError::Unavailable => {
+                "Failed to fetch cryptocurrency data".to_string()
+            }
+            MarketDataError::Status(status) => format!("API returned error status: {}", status),
+            MarketDataError::Parse(_) => "Failed to parse cryptocurrency data".to_string(),
+            MarketDataError::Fixture(_) => "Failed to load cryptocurrency data".to_string(),
+            MarketDataError::InvalidQuery(message) => message.clone(),
+        }
+    }
+}
+
+impl fmt::Display for MarketDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarketDataError::MissingApiKey(variable) => {
+                write!(f, "{} is not configured", variable)
+            }
+            MarketDataError::Request(e) => write!(f, "request failed: {}", e),
+            MarketDataError::Status(status) => write!(f, "returned error status {}", status),
+            MarketDataError::Parse(e) => write!(f, "invalid response: {}", e),
+            MarketDataError::Fixture(e) => write!(f, "failed to read fixture: {}", e),
+            MarketDataError::InvalidQuery(message) => write!(f, "rejected query: {}", message),
+            MarketDataError::Unavailable => write!(f, "unavailable"),
+        }
+    }
+}
+
+/// Chooses the configured provider: `coinmarketcap`, `coingecko` or
+/// `fixture`. Without one, CoinMarketCap is used when it has an API key and
+/// the fixture file otherwise, so machines without network access or an API
+/// key still get listings.
+pub fn provider(config: &MarketDataConfig) -> Box<dyn MarketDataProvider> {
+    let client = reqwest::Client::builder()
+        .timeout(UPSTREAM_TIMEOUT)
+        .build()
+        .unwrap_or_else(|e| {
+            warn!(
+                "Failed to configure market data client, using defaults: {}",
+                e
+            );
+            reqwest::Client::new()
+        });
+
+    let cmc_api_key = config.coinmarketcap_api_key.clone();
+    let choice = config.provider.clone().unwrap_or_else(|| {
+        if cmc_api_key.is_some() {
+            "coinmarketcap".to_string()
+        } else {
+            "fixture".to_string()
+        }
+    });
+
+    let provider: Box<dyn MarketDataProvider> = match choice.to_lowercase().as_str() {
+        "coingecko" => Box::new(CoinGeckoProvider {
+            client,
+            base_url: config
+                .coingecko_api_url
+                .clone()
+                .unwrap_or_else(|| COINGECKO_API_URL.to_string()),
+            api_key: config.coingecko_api_key.clone(),
+        }),
+        "fixture" => Box::new(FixtureProvider {
+            path: config
+                .fixture_path
+                .clone()
+                .unwrap_or_else(|| DEFAULT_FIXTURE_PATH.to_string()),
+        }),
+        other => {
+            if other != "coinmarketcap" {
+                warn!(
+                    "Unknown market data provider {}, using CoinMarketCap",
+                    other
+                );
+            }
+            Box::new(CoinMarketCapProvider {
+                client,
+                base_url: config
+                    .coinmarketcap_api_url
+                    .clone()
+                    .unwrap_or_else(|| COINMARKETCAP_API_URL.to_string()),
+                api_key: cmc_api_key,
+            })
+        }
+    };
+
+    info!("Using {} market data provider", provider.name());
+    provider
+}
+
+/// Structure to represent CoinMarketCap API response
+#[derive(Deserialize, Debug)]
+pub struct CoinMarketCapResponse {
+    status: Status,
+    data: Vec<CryptoCurrency>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Status {
+    timestamp: String,
+    error_code: i32,
+    error_message: Option<String>,
+    elapsed: i32,
+    credit_count: i32,
+}
+
+/// Response of the CoinMarketCap v2 quotes endpoint, keyed by symbol. A
+/// symbol maps to every coin that uses it.
+#[derive(Deserialize, Debug)]
+pub struct CoinMarketCapQuotesResponse {
+    status: Status,
+    data: HashMap<String, Vec<CryptoCurrency>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct CryptoCurrency {
+    id: i32,
+    name: String,
+    symbol: String,
+    slug: String,
+    /// Missing for inactive coins, which the quotes endpoint can return
+    cmc_rank: Option<i32>,
+    /// Prices keyed by the currency they are quoted in
+    quote: HashMap<String, CurrencyQuote>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct CurrencyQuote {
+    price: f64,
+    volume_24h: f64,
+    percent_change_1h: f64,
+    percent_change_24h: f64,
+    percent_change_7d: f64,
+    market_cap: f64,
+    last_updated: String,
+}
+
+impl CryptoCurrency {
+    fn into_response(mut self, convert: &str) -> Result<CryptoCurrencyResponse, MarketDataError> {
+        let quote = self.quote.remove(convert).ok_or_else(|| {
+            MarketDataError::InvalidQuery(format!("Quotes in {} are not available", convert))
+        })?;
+        Ok(CryptoCurrencyResponse {
+            id: self.id,
+            name: self.name,
+            symbol: self.symbol,
+            price: quote.price,
+            market_cap: quote.market_cap,
+            volume_24h: quote.volume_24h,
+            percent_change_1h: quote.percent_change_1h,
+            percent_change_24h: quote.percent_change_24h,
+            percent_change_7d: quote.percent_change_7d,
+        })
+    }
+}
+
+impl CoinMarketCapResponse {
+    /// Transform the data for frontend, priced in `convert`
+    fn into_markets(self, convert: &str) -> Result<MarketsResponse, MarketDataError> {
+        let cryptocurrencies = self
+            .data
+            .into_iter()
+            .map(|crypto| crypto.into_response(convert))
+            .collect::<Result<Vec<_>, MarketDataError>>()?;
+
+        Ok(MarketsResponse::new(
+            cryptocurrencies,
+            self.status.timestamp,
+            convert,
+        ))
+    }
+}
+
+impl CoinMarketCapQuotesResponse {
+    /// The best-ranked coin for each symbol, priced in `convert`
+    fn into_markets(self, convert: &str) -> Result<MarketsResponse, MarketDataError> {
+        let cryptocurrencies = self
+            .data
+            .into_values()
+            .filter_map(|coins| {
+                coins
+                    .into_iter()
+                    .min_by_key(|crypto| crypto.cmc_rank.unwrap_or(i32::MAX))
+            })
+            .map(|crypto| crypto.into_response(convert))
+            .collect::<Result<Vec<_>, MarketDataError>>()?;
+
+        Ok(MarketsResponse::new(
+            cryptocurrencies,
+            self.status.timestamp,
+            convert,
+        ))
+    }
+}
+
+/// Sorts listings locally, for providers that cannot sort upstream
+pub fn sort_listings(
+    cryptocurrencies: &mut [CryptoCurrencyResponse],
+    sort: ListingSort,
+    direction: SortDirection,
+) {
+    cryptocurrencies.sort_by(|a, b| {
+        let ordering = match sort {
+            ListingSort::MarketCap => a.market_cap.total_cmp(&b.market_cap),
+            ListingSort::Price => a.price.total_cmp(&b.price),
+            ListingSort::Volume24h => a.volume_24h.total_cmp(&b.volume_24h),
+            ListingSort::PercentChange1h => a.percent_change_1h.total_cmp(&b.percent_change_1h),
+            ListingSort::PercentChange24h => a.percent_change_24h.total_cmp(&b.percent_change_24h),
+            ListingSort::PercentChange7d => a.percent_change_7d.total_cmp(&b.percent_change_7d),
+            ListingSort::Name => a.name.cmp(&b.name),
+            ListingSort::Symbol => a.symbol.cmp(&b.symbol),
+        };
+        match direction {
+            SortDirection::Asc => ordering,
+            SortDirection::Desc => ordering.reverse(),
+        }
+    });
+}
+
+/// Listings from the CoinMarketCap Pro API
+pub struct CoinMarketCapProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl MarketDataProvider for CoinMarketCapProvider {
+    fn name(&self) -> &'static str {
+        "CoinMarketCap"
+    }
+
+    fn fetch_listings<'a>(
+        &'a self,
+        query: &'a ListingsQuery,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            let api_key = self
+                .api_key
+                .as_deref()
+                .ok_or(MarketDataError::MissingApiKey("COINMARKETCAP_API_KEY"))?;
+
+            let response = self
+                .client
+                .get(format!(
+                    "{}/v1/cryptocurrency/listings/latest",
+                    self.base_url
+                ))
+                .query(&[
+                    ("limit", query.limit.to_string().as_str()),
+                    ("start", query.start.to_string().as_str()),
+                    ("convert", query.convert.as_str()),
+                    ("sort", query.sort.as_str()),
+                    ("sort_dir", query.sort_dir.as_str()),
+                ])
+                .header("X-CMC_PRO_API_KEY", api_key)
+                .send()
+                .await
+                .map_err(MarketDataError::Request)?;
+
+            if !response.status().is_success() {
+                return Err(MarketDataError::Status(response.status()));
+            }
+
+            let cmc_data = response
+                .json::<CoinMarketCapResponse>()
+                .await
+                .map_err(|e| MarketDataError::Parse(e.to_string()))?;
+
+            cmc_data.into_markets(&query.convert)
+        })
+    }
+
+    fn fetch_symbols<'a>(
+        &'a self,
+        symbols: &'a [String],
+        convert: &'a str,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            let api_key = self
+                .api_key
+                .as_deref()
+                .ok_or(MarketDataError::MissingApiKey("COINMARKETCAP_API_KEY"))?;
+
+            // Without skip_invalid, one unknown symbol fails the whole request
+            let response = self
+                .client
+                .get(format!("{}/v2/cryptocurrency/quotes/latest", self.base_url))
+                .query(&[
+                    ("symbol", symbols.join(",").as_str()),
+                    ("convert", convert),
+                    ("skip_invalid", "true"),
+                ])
+                .header("X-CMC_PRO_API_KEY", api_key)
+                .send()
+                .await
+                .map_err(MarketDataError::Request)?;
+
+            if !response.status().is_success() {
+                return Err(MarketDataError::Status(response.status()));
+            }
+
+            let cmc_data = response
+                .json::<CoinMarketCapQuotesResponse>()
+                .await
+                .map_err(|e| MarketDataError::Parse(e.to_string()))?;
+
+            cmc_data.into_markets(convert)
+        })
+    }
+}
+
+/// One coin from the CoinGecko `/coins/markets` endpoint. Percent changes
+/// are missing for coins without enough history.
+#[derive(Deserialize, Debug)]
+pub struct CoinGeckoMarket {
+    symbol: String,
+    name: String,
+    current_price: Option<f64>,
+    market_cap: Option<f64>,
+    market_cap_rank: Option<i32>,
+    total_volume: Option<f64>,
+    price_change_percentage_1h_in_currency: Option<f64>,
+    price_change_percentage_24h_in_currency: Option<f64>,
+    price_change_percentage_7d_in_currency: Option<f64>,
+    last_updated: Option<String>,
+}
+
+/// Most coins CoinGecko returns in one page
+const COINGECKO_MAX_PER_PAGE: u32 = 250;
+
+impl CoinGeckoMarket {
+    fn into_response(self, position: usize) -> CryptoCurrencyResponse {
+        CryptoCurrencyResponse {
+            // CoinGecko ids are slugs, so the market cap rank identifies coins
+            id: self.market_cap_rank.unwrap_or(position as i32 + 1),
+            name: self.name,
+            symbol: self.symbol.to_uppercase(),
+            price: self.current_price.unwrap_or_default(),
+            market_cap: self.market_cap.unwrap_or_default(),
+            volume_24h: self.total_volume.unwrap_or_default(),
+            percent_change_1h: self
+                .price_change_percentage_1h_in_currency
+                .unwrap_or_default(),
+            percent_change_24h: self
+                .price_change_percentage_24h_in_currency
+                .unwrap_or_default(),
+            percent_change_7d: self
+                .price_change_percentage_7d_in_currency
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Listings from the CoinGecko API or a service with the same interface.
+/// CoinGecko orders by market cap or volume; other sorts apply to the page
+/// of coins with the largest market caps.
+pub struct CoinGeckoProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl CoinGeckoProvider {
+    async fn fetch_page(
+        &self,
+        query: &ListingsQuery,
+        order: &str,
+        page: u32,
+    ) -> Result<Vec<CoinGeckoMarket>, MarketDataError> {
+        self.fetch_markets(
+            &query.convert,
+            &[
+                ("order", order),
+                ("per_page", query.limit.to_string().as_str()),
+                ("page", page.to_string().as_str()),
+            ],
+        )
+        .await
+    }
+
+    /// Calls `/coins/markets` priced in `convert` with the given parameters
+    async fn fetch_markets(
+        &self,
+        convert: &str,
+        parameters: &[(&str, &str)],
+    ) -> Result<Vec<CoinGeckoMarket>, MarketDataError> {
+        let mut request = self
+            .client
+            .get(format!("{}/api/v3/coins/markets", self.base_url))
+            .query(&[
+                ("vs_currency", convert.to_lowercase().as_str()),
+                ("price_change_percentage", "1h,24h,7d"),
+            ])
+            .query(parameters);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("x-cg-pro-api-key", api_key);
+        }
+
+        let response = request.send().await.map_err(MarketDataError::Request)?;
+        if response.status() == reqwest::StatusCode::BAD_REQUEST {
+            return Err(MarketDataError::InvalidQuery(format!(
+                "Quotes in {} are not available",
+                convert
+            )));
+        }
+        if !response.status().is_success() {
+            return Err(MarketDataError::Status(response.status()));
+        }
+
+        response
+            .json::<Vec<CoinGeckoMarket>>()
+            .await
+            .map_err(|e| MarketDataError::Parse(e.to_string()))
+    }
+}
+
+impl MarketDataProvider for CoinGeckoProvider {
+    fn name(&self) -> &'static str {
+        "CoinGecko"
+    }
+
+    fn fetch_listings<'a>(
+        &'a self,
+        query: &'a ListingsQuery,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            if query.limit > COINGECKO_MAX_PER_PAGE {
+                return Err(MarketDataError::InvalidQuery(format!(
+                    "Limit must be at most {}",
+                    COINGECKO_MAX_PER_PAGE
+                )));
+            }
+
+            let order = match query.sort {
+                ListingSort::Volume24h => format!("volume_{}", query.sort_dir.as_str()),
+                ListingSort::MarketCap => format!("market_cap_{}", query.sort_dir.as_str()),
+                _ => "market_cap_desc".to_string(),
+            };
+
+            // CoinGecko pages by page number, so a start that falls inside
+            // a page needs the next page as well
+            let offset = (query.start - 1) % query.limit;
+            let page = (query.start - 1) / query.limit + 1;
+            let mut coins = self.fetch_page(query, &order, page).await?;
+            if offset > 0 {
+                coins.extend(self.fetch_page(query, &order, page + 1).await?);
+            }
+
+            let last_updated = coins
+                .iter()
+                .filter_map(|coin| coin.last_updated.clone())
+                .max()
+                .unwrap_or_default();
+
+            let mut cryptocurrencies: Vec<_> = coins
+                .into_iter()
+                .enumerate()
+                .map(|(position, coin)| coin.into_response(position))
+                .skip(offset as usize)
+                .take(query.limit as usize)
+                .collect();
+            if !matches!(query.sort, ListingSort::MarketCap | ListingSort::Volume24h) {
+                sort_listings(&mut cryptocurrencies, query.sort, query.sort_dir);
+            }
+
+            Ok(MarketsResponse::new(
+                cryptocurrencies,
+                last_updated,
+                &query.convert,
+            ))
+        })
+    }
+
+    fn fetch_symbols<'a>(
+        &'a self,
+        symbols: &'a [String],
+        convert: &'a str,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            let symbols = symbols.join(",").to_lowercase();
+            let coins = self
+                .fetch_markets(
+                    convert,
+                    &[("symbols", symbols.as_str()), ("order", "market_cap_desc")],
+                )
+                .await?;
+
+            let last_updated = coins
+                .iter()
+                .filter_map(|coin| coin.last_updated.clone())
+                .max()
+                .unwrap_or_default();
+
+            // Coins come largest first, so the first of each symbol is kept
+            let mut cryptocurrencies: Vec<CryptoCurrencyResponse> = Vec::new();
+            for (position, coin) in coins.into_iter().enumerate() {
+                let coin = coin.into_response(position);
+                if !cryptocurrencies
+                    .iter()
+                    .any(|kept| kept.symbol == coin.symbol)
+                {
+                    cryptocurrencies.push(coin);
+                }
+            }
+
+            Ok(MarketsResponse::new(
+                cryptocurrencies,
+                last_updated,
+                convert,
+            ))
+        })
+    }
+}
+
+/// Listings read from a recorded CoinMarketCap listings response on disk,
+/// sorted and paged locally. Only currencies recorded in the file can be
+/// converted to. The file is read on every fetch, so edits show up after the
+/// next refresh.
+pub struct FixtureProvider {
+    path: String,
+}
+
+impl MarketDataProvider for FixtureProvider {
+    fn name(&self) -> &'static str {
+        "fixture"
+    }
+
+    fn fetch_listings<'a>(
+        &'a self,
+        query: &'a ListingsQuery,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            let contents = tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(MarketDataError::Fixture)?;
+            let fixture = serde_json::from_str::<CoinMarketCapResponse>(&contents)
+                .map_err(|e| MarketDataError::Parse(e.to_string()))?;
+
+            let mut markets = fixture.into_markets(&query.convert)?;
+            sort_listings(&mut markets.cryptocurrencies, query.sort, query.sort_dir);
+            markets.cryptocurrencies = markets
+                .cryptocurrencies
+                .into_iter()
+                .skip(query.start as usize - 1)
+                .take(query.limit as usize)
+                .collect();
+            Ok(markets)
+        })
+    }
+
+    fn fetch_symbols<'a>(
+        &'a self,
+        symbols: &'a [String],
+        convert: &'a str,
+    ) -> BoxFuture<'a, Result<MarketsResponse, MarketDataError>> {
+        Box::pin(async move {
+            let contents = tokio::fs::read_to_string(&self.path)
+                .await
+                .map_err(MarketDataError::Fixture)?;
+            let fixture = serde_json::from_str::<CoinMarketCapResponse>(&contents)
+                .map_err(|e| MarketDataError::Parse(e.to_string()))?;
+
+            // The file is in rank order, so the first coin of each symbol is kept
+            let mut markets = fixture.into_markets(convert)?;
+            let mut kept: Vec<CryptoCurrencyResponse> = Vec::new();
+            for crypto in markets.cryptocurrencies {
+                if symbols.contains(&crypto.symbol)
+                    && !kept.iter().any(|other| other.symbol == crypto.symbol)
+                {
+                    kept.push(crypto);
+                }
+            }
+            markets.cryptocurrencies = kept;
+            Ok(markets)
+        })
+    }
+}
@@ -1,156 +1,477 @@
-use actix_web::{HttpResponse, Responder, get, web};
-use log::{error, info};
+use crate::candles;
+use crate::config::MarketDataConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::market_data::{
+    self, ListingSort, ListingsQuery, MAX_LIMIT, MAX_START, MAX_SYMBOLS, MarketDataError,
+    MarketDataProvider, SUPPORTED_CONVERT, SortDirection,
+};
+use actix_web::{HttpResponse, get, web};
+use chrono::Utc;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
 
-/// Structure to represent CoinMarketCap API response
-#[derive(Deserialize, Debug)]
-pub struct CoinMarketCapResponse {
-    status: Status,
-    data: Vec<CryptoCurrency>,
-}
+/// How long fetched listings are served before they are refreshed, unless
+/// `MARKETS_CACHE_TTL_SECS` says otherwise
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
 
-#[derive(Deserialize, Debug)]
-pub struct Status {
-    timestamp: String,
-    error_code: i32,
-    error_message: Option<String>,
-    elapsed: i32,
-    credit_count: i32,
-}
+/// After a failed upstream call, requests serve what is cached instead of
+/// retrying until this much time has passed
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
 
-#[derive(Deserialize, Debug)]
-pub struct CryptoCurrency {
-    id: i32,
-    name: String,
-    symbol: String,
-    slug: String,
-    cmc_rank: i32,
-    quote: Quote,
-}
+/// Distinct queries whose listings are kept; the least recently requested
+/// is dropped when another one arrives
+const MAX_CACHED_QUERIES: usize = 64;
+
+/// Upstream calls requests may make in each `UPSTREAM_BUDGET_WINDOW`, across
+/// all queries. Past it they are served what is cached, so clients cycling
+/// through distinct queries cannot spend the provider's credits. The
+/// background refresh of the default listings is not counted.
+const UPSTREAM_BUDGET: u32 = 30;
+
+const UPSTREAM_BUDGET_WINDOW: Duration = Duration::from_secs(60);
 
-#[derive(Deserialize, Debug)]
-pub struct Quote {
-    USD: UsdQuote,
+/// Query parameters accepted by `GET /api/markets`
+#[derive(Deserialize)]
+pub struct MarketsQuery {
+    limit: Option<u32>,
+    start: Option<u32>,
+    convert: Option<String>,
+    sort: Option<String>,
+    sort_dir: Option<String>,
+    /// Comma-separated symbols, e.g. `BTC,ETH`
+    symbols: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct UsdQuote {
-    price: f64,
-    volume_24h: f64,
-    percent_change_1h: f64,
-    percent_change_24h: f64,
-    percent_change_7d: f64,
-    market_cap: f64,
-    last_updated: String,
+impl MarketsQuery {
+    /// Checks the request and turns it into the listings to fetch
+    fn parse(self) -> Result<ListingsQuery, String> {
+        let defaults = ListingsQuery::default();
+
+        let limit = self.limit.unwrap_or(defaults.limit);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(format!("Limit must be between 1 and {}", MAX_LIMIT));
+        }
+        let start = self.start.unwrap_or(defaults.start);
+        if start == 0 || start > MAX_START {
+            return Err(format!("Start must be between 1 and {}", MAX_START));
+        }
+
+        let convert = match self.convert {
+            Some(convert) => convert.trim().to_uppercase(),
+            None => defaults.convert,
+        };
+        if !SUPPORTED_CONVERT.contains(&convert.as_str()) {
+            return Err(format!(
+                "Convert must be one of {}",
+                SUPPORTED_CONVERT.join(", ")
+            ));
+        }
+
+        let sort = match self.sort.as_deref() {
+            Some(sort) => ListingSort::parse(sort).ok_or_else(|| {
+                "Sort must be one of market_cap, price, volume_24h, percent_change_1h, \
+                 percent_change_24h, percent_change_7d, name or symbol"
+                    .to_string()
+            })?,
+            None => defaults.sort,
+        };
+        let sort_dir = match self.sort_dir.as_deref() {
+            Some(sort_dir) => SortDirection::parse(sort_dir)
+                .ok_or_else(|| "Sort direction must be 'asc' or 'desc'".to_string())?,
+            None => defaults.sort_dir,
+        };
+
+        let mut symbols: Vec<String> = self
+            .symbols
+            .iter()
+            .flat_map(|symbols| symbols.split(','))
+            .map(|symbol| symbol.trim().to_uppercase())
+            .filter(|symbol| !symbol.is_empty())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        if symbols.len() > MAX_SYMBOLS {
+            return Err(format!("At most {} symbols can be requested", MAX_SYMBOLS));
+        }
+        if let Some(invalid) = symbols
+            .iter()
+            .find(|symbol| symbol.len() > 20 || !symbol.chars().all(|c| c.is_ascii_alphanumeric()))
+        {
+            return Err(format!("Invalid symbol '{}'", invalid));
+        }
+
+        // Lookups by symbol are not paged, so every page shares one entry
+        let (limit, start) = if symbols.is_empty() {
+            (limit, start)
+        } else {
+            (defaults.limit, defaults.start)
+        };
+
+        Ok(ListingsQuery {
+            limit,
+            start,
+            convert,
+            sort,
+            sort_dir,
+            symbols,
+        })
+    }
 }
 
 /// Structure for our API response
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct MarketsResponse {
-    cryptocurrencies: Vec<CryptoCurrencyResponse>,
-    last_updated: String,
+    pub cryptocurrencies: Vec<CryptoCurrencyResponse>,
+    pub last_updated: String,
+    /// Currency every price, market cap and volume is quoted in
+    pub quote_currency: String,
+    /// Set when the upstream API could not be reached and the data served is
+    /// older than the cache TTL
+    pub stale: bool,
+    /// Requested symbols the provider does not know
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing_symbols: Vec<String>,
+}
+
+impl MarketsResponse {
+    pub fn new(
+        cryptocurrencies: Vec<CryptoCurrencyResponse>,
+        last_updated: String,
+        quote_currency: &str,
+    ) -> Self {
+        MarketsResponse {
+            cryptocurrencies,
+            last_updated,
+            quote_currency: quote_currency.to_string(),
+            stale: false,
+            missing_symbols: Vec::new(),
+        }
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct CryptoCurrencyResponse {
-    id: i32,
-    name: String,
-    symbol: String,
-    price: f64,
-    market_cap: f64,
-    volume_24h: f64,
-    percent_change_1h: f64,
-    percent_change_24h: f64,
-    percent_change_7d: f64,
+    pub id: i32,
+    pub name: String,
+    pub symbol: String,
+    pub price: f64,
+    pub market_cap: f64,
+    pub volume_24h: f64,
+    pub percent_change_1h: f64,
+    pub percent_change_24h: f64,
+    pub percent_change_7d: f64,
 }
 
-/// Fetches the latest cryptocurrency listings from CoinMarketCap
-///
-/// This endpoint retrieves cryptocurrency data from CoinMarketCap's API
-/// using the API key stored in the environment variables. It formats the
-/// data for the frontend to display in the markets table.
+struct CachedMarkets {
+    markets: MarketsResponse,
+    fetched_at: Instant,
+}
+
+/// Listings cached for one query
+#[derive(Default)]
+struct CacheEntry {
+    cached: RwLock<Option<CachedMarkets>>,
+    /// Held while an upstream call is in flight; stores when the last one
+    /// failed
+    refresh: Mutex<Option<Instant>>,
+}
+
+/// Keeps the last good listings from the market data provider in memory so
+/// page loads do not spend API credits or wait on the upstream API.
 ///
-/// # Returns
-/// A JSON response with an array of cryptocurrencies and their market data
-#[get("/api/markets")]
-pub async fn get_markets() -> impl Responder {
-    // Get API key from environment variables
-    let api_key = match env::var("COINMARKETCAP_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            error!("COINMARKETCAP_API_KEY not found in environment variables");
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "API key configuration error"
-            }));
-        }
-    };
-
-    // Create client with API key header
-    let client = reqwest::Client::new();
-
-    // Make API request to CoinMarketCap
-    let response = match client
-        .get("https://pro-api.coinmarketcap.com/v1/cryptocurrency/listings/latest")
-        .query(&[("limit", "100"), ("convert", "USD")])
-        .header("X-CMC_PRO_API_KEY", api_key)
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("Failed to fetch data from CoinMarketCap API: {}", e);
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to fetch cryptocurrency data"
-            }));
-        }
-    };
-
-    // Check if response is successful
-    if !response.status().is_success() {
-        let status = response.status();
-        error!("CoinMarketCap API returned error status: {}", status);
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("API returned error status: {}", status)
-        }));
+/// A background task refreshes the default listings every TTL; other
+/// queries are cached when first requested. Requests that find the cache
+/// expired refresh it themselves, one at a time per query: concurrent
+/// requests wait for the refresh in flight rather than starting their own.
+/// When the upstream API fails, or requests have spent their budget of
+/// upstream calls, the last good listings are served marked stale.
+pub struct MarketCache {
+    provider: Box<dyn MarketDataProvider>,
+    ttl: Duration,
+    /// Cached queries and when each was last requested
+    entries: std::sync::Mutex<HashMap<ListingsQuery, (Arc<CacheEntry>, Instant)>>,
+    /// Start of the current upstream budget window and the calls made in it
+    budget: std::sync::Mutex<(Instant, u32)>,
+}
+
+impl MarketCache {
+    /// Uses the configured provider and cache TTL
+    pub fn new(config: &MarketDataConfig) -> MarketCache {
+        let ttl = config
+            .cache_ttl_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CACHE_TTL);
+
+        MarketCache {
+            provider: market_data::provider(config),
+            ttl,
+            entries: std::sync::Mutex::new(HashMap::new()),
+            budget: std::sync::Mutex::new((Instant::now(), 0)),
+        }
     }
 
-    // Parse the response
-    match response.json::<CoinMarketCapResponse>().await {
-        Ok(cmc_data) => {
-            info!(
-                "Successfully fetched data for {} cryptocurrencies",
-                cmc_data.data.len()
-            );
+    /// The cache entry of a query, created if it is new
+    fn entry(&self, query: &ListingsQuery) -> Arc<CacheEntry> {
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some((entry, requested_at)) = entries.get_mut(query) {
+            *requested_at = Instant::now();
+            return entry.clone();
+        }
 
-            // Transform the data for frontend
-            let cryptocurrencies = cmc_data
-                .data
+        if entries.len() >= MAX_CACHED_QUERIES
+            && let Some(oldest) = entries
                 .iter()
-                .map(|crypto| CryptoCurrencyResponse {
-                    id: crypto.id,
-                    name: crypto.name.clone(),
-                    symbol: crypto.symbol.clone(),
-                    price: crypto.quote.USD.price,
-                    market_cap: crypto.quote.USD.market_cap,
-                    volume_24h: crypto.quote.USD.volume_24h,
-                    percent_change_1h: crypto.quote.USD.percent_change_1h,
-                    percent_change_24h: crypto.quote.USD.percent_change_24h,
-                    percent_change_7d: crypto.quote.USD.percent_change_7d,
+                .min_by_key(|(_, (_, requested_at))| *requested_at)
+                .map(|(query, _)| query.clone())
+        {
+            entries.remove(&oldest);
+        }
+
+        let entry = Arc::new(CacheEntry::default());
+        entries.insert(query.clone(), (entry.clone(), Instant::now()));
+        entry
+    }
+
+    /// Cached listings if they are younger than the TTL
+    async fn fresh(&self, entry: &CacheEntry) -> Option<MarketsResponse> {
+        let cached = entry.cached.read().await;
+        cached
+            .as_ref()
+            .filter(|cached| cached.fetched_at.elapsed() < self.ttl)
+            .map(|cached| cached.markets.clone())
+    }
+
+    /// Cached listings of any age, flagged stale when older than the TTL
+    async fn latest(&self, entry: &CacheEntry) -> Option<MarketsResponse> {
+        let cached = entry.cached.read().await;
+        cached.as_ref().map(|cached| MarketsResponse {
+            stale: cached.fetched_at.elapsed() >= self.ttl,
+            ..cached.markets.clone()
+        })
+    }
+
+    /// Returns the listings for a query, refreshing them from upstream if
+    /// the cache has expired. Falls back to stale listings when the refresh
+    /// fails.
+    pub async fn get(&self, query: &ListingsQuery) -> Result<MarketsResponse, MarketDataError> {
+        let entry = self.entry(query);
+        if let Some(markets) = self.fresh(&entry).await {
+            return Ok(markets);
+        }
+
+        let mut last_failure = entry.refresh.lock().await;
+        // Another request may have refreshed the cache while this one waited
+        if let Some(markets) = self.fresh(&entry).await {
+            return Ok(markets);
+        }
+        if last_failure.is_some_and(|failed_at| failed_at.elapsed() < RETRY_INTERVAL) {
+            return self
+                .latest(&entry)
+                .await
+                .ok_or(MarketDataError::Unavailable);
+        }
+        if !self.spend_budget() {
+            warn!(
+                "{} market data budget of {} calls spent, serving cached listings",
+                self.provider.name(),
+                UPSTREAM_BUDGET
+            );
+            return self
+                .latest(&entry)
+                .await
+                .ok_or(MarketDataError::Unavailable);
+        }
+
+        match self.fetch(query).await {
+            Ok(markets) => {
+                *last_failure = None;
+                Self::store(&entry, markets.clone()).await;
+                Ok(markets)
+            }
+            // The query itself is at fault, so cached listings do not help
+            Err(e @ MarketDataError::InvalidQuery(_)) => Err(e),
+            Err(e) => {
+                *last_failure = Some(Instant::now());
+                self.latest(&entry).await.ok_or(e)
+            }
+        }
+    }
+
+    /// Fetches the listings for a query from upstream and caches them,
+    /// regardless of the age of what is cached, unless a refresh completes
+    /// while waiting for the one in flight. Returns the listings fetched.
+    async fn refresh(&self, query: &ListingsQuery) -> Option<MarketsResponse> {
+        let entry = self.entry(query);
+        let requested_at = Instant::now();
+        let mut last_failure = entry.refresh.lock().await;
+        // Listings fetched by a request while this one waited are just as new
+        let refreshed = entry
+            .cached
+            .read()
+            .await
+            .as_ref()
+            .map(|cached| cached.fetched_at);
+        if refreshed.is_some_and(|fetched_at| fetched_at >= requested_at) {
+            return None;
+        }
+
+        match self.fetch(query).await {
+            Ok(markets) => {
+                *last_failure = None;
+                Self::store(&entry, markets.clone()).await;
+                Some(markets)
+            }
+            Err(_) => {
+                *last_failure = Some(Instant::now());
+                None
+            }
+        }
+    }
+
+    async fn store(entry: &CacheEntry, markets: MarketsResponse) {
+        *entry.cached.write().await = Some(CachedMarkets {
+            markets,
+            fetched_at: Instant::now(),
+        });
+    }
+
+    /// Counts an upstream call against the budget, refusing it once the
+    /// current window's budget is spent
+    fn spend_budget(&self) -> bool {
+        let mut budget = self
+            .budget
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (window_start, calls) = &mut *budget;
+        if window_start.elapsed() >= UPSTREAM_BUDGET_WINDOW {
+            *window_start = Instant::now();
+            *calls = 0;
+        }
+        if *calls >= UPSTREAM_BUDGET {
+            return false;
+        }
+        *calls += 1;
+        true
+    }
+
+    /// Requests listings from the provider, logging failures. Lookups by
+    /// symbol are sorted here and list the symbols that were not found.
+    async fn fetch(&self, query: &ListingsQuery) -> Result<MarketsResponse, MarketDataError> {
+        let result = if query.symbols.is_empty() {
+            self.provider.fetch_listings(query).await
+        } else {
+            self.provider
+                .fetch_symbols(&query.symbols, &query.convert)
+                .await
+                .map(|mut markets| {
+                    market_data::sort_listings(
+                        &mut markets.cryptocurrencies,
+                        query.sort,
+                        query.sort_dir,
+                    );
+                    markets.missing_symbols = query
+                        .symbols
+                        .iter()
+                        .filter(|symbol| {
+                            !markets
+                                .cryptocurrencies
+                                .iter()
+                                .any(|crypto| &crypto.symbol == *symbol)
+                        })
+                        .cloned()
+                        .collect();
+                    markets
                 })
-                .collect();
+        };
+        if let Err(e) = &result {
+            error!("{} market data {}", self.provider.name(), e);
+        }
+        result
+    }
+}
+
+/// Refreshes the cached default listings, which the markets page shows,
+/// every TTL so requests rarely find them expired, and records their prices
+/// as candles
+pub async fn run_refresher(cache: web::Data<MarketCache>, pool: db::DbPool) {
+    let query = ListingsQuery::default();
+    let mut interval = actix_web::rt::time::interval(cache.ttl);
+    loop {
+        interval.tick().await;
+        let Some(markets) = cache.refresh(&query).await else {
+            continue;
+        };
 
-            // Return successful response
-            HttpResponse::Ok().json(MarketsResponse {
-                cryptocurrencies,
-                last_updated: cmc_data.status.timestamp,
-            })
+        let pool = pool.clone();
+        let result = web::block(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|e| format!("Failed to get database connection: {}", e))?;
+            candles::record_snapshot(&mut conn, &markets, Utc::now()).map_err(|e| e.to_string())
+        })
+        .await;
+
+        match result {
+            Ok(Ok(recorded)) => info!("Recorded market data candles for {} coins", recorded),
+            Ok(Err(e)) => error!("Failed to record market data candles: {}", e),
+            Err(e) => error!("Market data candle task failed: {}", e),
         }
-        Err(e) => {
-            error!("Failed to parse CoinMarketCap API response: {}", e);
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to parse cryptocurrency data"
-            }))
+    }
+}
+
+impl From<MarketDataError> for AppError {
+    fn from(e: MarketDataError) -> Self {
+        match e {
+            MarketDataError::InvalidQuery(message) => AppError::BadRequest(message),
+            MarketDataError::Request(_)
+            | MarketDataError::Status(_)
+            | MarketDataError::Unavailable => AppError::Unavailable(e.message()),
+            e => {
+                error!("Market data error: {}", e);
+                AppError::Internal(e.message())
+            }
         }
     }
 }
+
+/// Returns the latest cryptocurrency listings from the market data provider
+///
+/// Listings are served from an in-memory cache that a background task keeps
+/// up to date, so most requests never reach the provider. When it
+/// cannot be reached, the last listings fetched are returned with `stale`
+/// set to true.
+///
+/// # Query parameters
+/// - `limit`: number of listings, 100 by default
+/// - `start`: 1-based rank of the first listing, at most 5000; 1 by default
+/// - `convert`: currency to quote prices in, one of `USD` (default), `EUR`, `GBP`,
+///   `JPY`, `CHF`, `CAD`, `AUD`, `CNY`, `KRW`, `INR`, `BRL`, `BTC` or `ETH`
+/// - `sort`: `market_cap` (default), `price`, `volume_24h`, `percent_change_1h`,
+///   `percent_change_24h`, `percent_change_7d`, `name` or `symbol`
+/// - `sort_dir`: `asc` or `desc` (default)
+/// - `symbols`: comma-separated symbols such as `BTC,ETH` to look up whatever
+///   their rank, at most 50; `limit` and `start` then do not apply
+///
+/// # Returns
+/// A JSON response with an array of cryptocurrencies and their market data,
+/// the currency they are quoted in and, for lookups by symbol, the symbols
+/// that were not found
+#[get("/api/markets")]
+pub async fn get_markets(
+    cache: web::Data<MarketCache>,
+    query: web::Query<MarketsQuery>,
+) -> Result<HttpResponse, AppError> {
+    let listings_query = query.into_inner().parse().map_err(AppError::BadRequest)?;
+
+    let markets = cache.get(&listings_query).await?;
+    Ok(HttpResponse::Ok().json(markets))
+}
@@ -0,0 +1,805 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+/// Side of an order in the book
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Side> {
+        match value {
+            "buy" => Some(Side::Buy),
+            "sell" => Some(Side::Sell),
+            _ => None,
+        }
+    }
+
+    pub fn opposite(&self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+/// How an order is priced and when it becomes live
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,
+    /// Becomes a limit order once the last trade price reaches the stop price
+    StopLimit,
+    /// Becomes a market order once the last trade price reaches the stop price
+    StopMarket,
+}
+
+impl OrderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderType::Limit => "limit",
+            OrderType::Market => "market",
+            OrderType::StopLimit => "stop_limit",
+            OrderType::StopMarket => "stop_market",
+        }
+    }
+
+    pub fn is_stop(&self) -> bool {
+        matches!(self, OrderType::StopLimit | OrderType::StopMarket)
+    }
+
+    /// Whether orders of this type carry a limit price
+    pub fn has_limit(&self) -> bool {
+        matches!(self, OrderType::Limit | OrderType::StopLimit)
+    }
+}
+
+/// How long an order stays active
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeInForce {
+    /// Good till cancelled: rests on the book until filled or cancelled
+    Gtc,
+    /// Immediate or cancel: fills what it can now, the remainder is cancelled
+    Ioc,
+    /// Fill or kill: fills completely right now or not at all
+    Fok,
+    /// Good till date: like GTC, but expires at a given time
+    Gtd,
+}
+
+impl TimeInForce {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeInForce::Gtc => "gtc",
+            TimeInForce::Ioc => "ioc",
+            TimeInForce::Fok => "fok",
+            TimeInForce::Gtd => "gtd",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<TimeInForce> {
+        match value {
+            "gtc" => Some(TimeInForce::Gtc),
+            "ioc" => Some(TimeInForce::Ioc),
+            "fok" => Some(TimeInForce::Fok),
+            "gtd" => Some(TimeInForce::Gtd),
+            _ => None,
+        }
+    }
+
+    /// Whether an unfilled remainder may rest on the book
+    pub fn rests(&self) -> bool {
+        matches!(self, TimeInForce::Gtc | TimeInForce::Gtd)
+    }
+}
+
+/// An order resting in the book
+#[derive(Clone, Debug)]
+pub struct BookOrder {
+    pub id: i32,
+    pub user_id: i32,
+    pub side: Side,
+    pub price: Decimal,
+    pub remaining: Decimal,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// An order being submitted for matching. Without a limit price it is a
+/// market order and trades at whatever prices the book offers.
+#[derive(Clone, Debug)]
+pub struct IncomingOrder {
+    pub id: i32,
+    pub user_id: i32,
+    pub side: Side,
+    pub limit: Option<Decimal>,
+    pub quantity: Decimal,
+    pub time_in_force: TimeInForce,
+    pub post_only: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A stop order waiting for the last trade price to reach its stop price
+#[derive(Clone, Debug)]
+pub struct StopOrder {
+    pub stop_price: Decimal,
+    pub order: IncomingOrder,
+}
+
+/// Reasons the book refuses an order without executing any of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    PostOnlyWouldCross,
+    FillOrKillUnfillable,
+    NoLiquidity,
+}
+
+impl Rejection {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Rejection::PostOnlyWouldCross => "Post-only order would match immediately",
+            Rejection::FillOrKillUnfillable => {
+                "Fill-or-kill order cannot be filled completely at its limit price"
+            }
+            Rejection::NoLiquidity => "No liquidity available for market order",
+        }
+    }
+}
+
+/// Aggregated (price, quantity) levels on one side of the book
+pub type DepthLevels = Vec<(Decimal, Decimal)>;
+
+/// What changed in a book since the previous call to `take_changes`
+#[derive(Debug, Default)]
+pub struct BookChanges {
+    /// Sequence number of this update. Consecutive updates differ by one.
+    pub sequence: u64,
+    /// The book was cleared and rebuilt, so only a full snapshot describes it
+    pub reset: bool,
+    /// New aggregated quantity of every changed bid level; zero removes the level
+    pub bids: DepthLevels,
+    /// New aggregated quantity of every changed ask level; zero removes the level
+    pub asks: DepthLevels,
+    /// Ids of orders that were added, matched, cancelled, triggered or expired
+    pub orders: Vec<i32>,
+}
+
+impl BookChanges {
+    pub fn is_empty(&self) -> bool {
+        !self.reset && self.bids.is_empty() && self.asks.is_empty() && self.orders.is_empty()
+    }
+}
+
+/// A single execution between a resting maker order and an incoming taker order
+#[derive(Clone, Debug, Serialize)]
+pub struct Fill {
+    pub maker_order_id: i32,
+    pub maker_user_id: i32,
+    pub taker_order_id: i32,
+    pub taker_user_id: i32,
+    pub taker_side: Side,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// Whether this fill completed the maker order
+    pub maker_filled: bool,
+}
+
+/// Outcome of submitting an order to the book
+#[derive(Debug)]
+pub struct MatchResult {
+    pub fills: Vec<Fill>,
+    /// Quantity of the incoming order left unfilled after matching
+    pub remaining: Decimal,
+    /// Whether the unfilled remainder was placed on the book
+    pub resting: bool,
+}
+
+/// A price-time-priority limit order book for a single trading pair.
+///
+/// The book holds no clock and performs no I/O: the result of a sequence of
+/// submissions, cancellations and expiry sweeps depends only on their order
+/// and the times passed in, so matching is fully deterministic. It records
+/// which levels and orders each operation changed so callers can publish
+/// them once the changes are stored.
+#[derive(Clone, Debug, Default)]
+pub struct OrderBook {
+    pub symbol: String,
+    bids: BTreeMap<Decimal, VecDeque<BookOrder>>,
+    asks: BTreeMap<Decimal, VecDeque<BookOrder>>,
+    index: HashMap<i32, (Side, Decimal)>,
+    /// Untriggered stop orders, oldest first
+    stops: Vec<StopOrder>,
+    /// Expiry times of GTD orders, both resting and untriggered
+    expiries: BTreeSet<(DateTime<Utc>, i32)>,
+    /// Price of the most recent fill, which stop orders trigger on
+    pub last_price: Option<Decimal>,
+    /// Bid and ask levels changed since the last call to `take_changes`
+    changed_bids: BTreeSet<Decimal>,
+    changed_asks: BTreeSet<Decimal>,
+    /// Orders changed since the last call to `take_changes`
+    touched_orders: BTreeSet<i32>,
+    /// Whether the book was cleared since the last call to `take_changes`
+    reset: bool,
+    /// Sequence number of the last depth update
+    sequence: u64,
+}
+
+impl OrderBook {
+    pub fn new(symbol: &str) -> Self {
+        OrderBook {
+            symbol: symbol.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Empties the book before it is rebuilt from storage. The sequence
+    /// number carries on so subscribers never see it go backwards.
+    pub fn clear(&mut self) {
+        *self = OrderBook {
+            symbol: std::mem::take(&mut self.symbol),
+            sequence: self.sequence,
+            reset: true,
+            ..Default::default()
+        };
+    }
+
+    /// Matches an incoming order against the opposite side of the book.
+    ///
+    /// Makers are consumed best price first and, within a price level, in the
+    /// order they were added. Every fill executes at the maker's price. A GTC
+    /// or GTD limit order rests its unfilled remainder at its limit price; any
+    /// other remainder is dropped. A rejected order leaves the book untouched.
+    pub fn submit(&mut self, mut order: IncomingOrder) -> Result<MatchResult, Rejection> {
+        if order.post_only
+            && let Some(limit) = order.limit
+            && self.would_cross(order.side, limit)
+        {
+            return Err(Rejection::PostOnlyWouldCross);
+        }
+
+        let fillable = self.fillable_quantity(order.side, order.limit, order.quantity);
+        if order.time_in_force == TimeInForce::Fok && fillable < order.quantity {
+            return Err(Rejection::FillOrKillUnfillable);
+        }
+        if order.limit.is_none() && fillable.is_zero() {
+            return Err(Rejection::NoLiquidity);
+        }
+
+        let mut fills = Vec::new();
+
+        loop {
+            if order.quantity.is_zero() {
+                break;
+            }
+
+            let best = match order.side {
+                Side::Buy => self.best_ask(),
+                Side::Sell => self.best_bid(),
+            };
+
+            let level_price = match best {
+                Some(price) if crosses(order.side, order.limit, price) => price,
+                _ => break,
+            };
+
+            let levels = match order.side {
+                Side::Buy => &mut self.asks,
+                Side::Sell => &mut self.bids,
+            };
+            let queue = levels
+                .get_mut(&level_price)
+                .expect("price level present in book");
+
+            while let Some(maker) = queue.front_mut() {
+                if order.quantity.is_zero() {
+                    break;
+                }
+
+                let quantity = maker.remaining.min(order.quantity);
+                maker.remaining -= quantity;
+                order.quantity -= quantity;
+
+                let maker_filled = maker.remaining.is_zero();
+                self.touched_orders.insert(maker.id);
+                fills.push(Fill {
+                    maker_order_id: maker.id,
+                    maker_user_id: maker.user_id,
+                    taker_order_id: order.id,
+                    taker_user_id: order.user_id,
+                    taker_side: order.side,
+                    price: level_price,
+                    quantity,
+                    maker_filled,
+                });
+
+                if maker_filled {
+                    let maker_id = maker.id;
+                    let maker_expiry = maker.expires_at;
+                    queue.pop_front();
+                    self.index.remove(&maker_id);
+                    if let Some(expires_at) = maker_expiry {
+                        self.expiries.remove(&(expires_at, maker_id));
+                    }
+                }
+            }
+
+            if queue.is_empty() {
+                levels.remove(&level_price);
+            }
+            self.mark_level(order.side.opposite(), level_price);
+        }
+
+        self.touched_orders.insert(order.id);
+        if let Some(fill) = fills.last() {
+            self.last_price = Some(fill.price);
+        }
+
+        let remaining = order.quantity;
+        let mut resting = false;
+        if !remaining.is_zero()
+            && order.time_in_force.rests()
+            && let Some(limit) = order.limit
+        {
+            self.insert(BookOrder {
+                id: order.id,
+                user_id: order.user_id,
+                side: order.side,
+                price: limit,
+                remaining,
+                expires_at: order.expires_at,
+            });
+            resting = true;
+        }
+
+        Ok(MatchResult {
+            fills,
+            remaining,
+            resting,
+        })
+    }
+
+    /// Places an order on the book without matching it. Used when restoring
+    /// open orders from the database at startup.
+    pub fn insert(&mut self, order: BookOrder) {
+        self.mark_level(order.side, order.price);
+        self.touched_orders.insert(order.id);
+        self.index.insert(order.id, (order.side, order.price));
+        if let Some(expires_at) = order.expires_at {
+            self.expiries.insert((expires_at, order.id));
+        }
+        let levels = match order.side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        levels.entry(order.price).or_default().push_back(order);
+    }
+
+    /// Parks a stop order until the last trade price reaches its stop price
+    pub fn add_stop(&mut self, stop: StopOrder) {
+        self.touched_orders.insert(stop.order.id);
+        if let Some(expires_at) = stop.order.expires_at {
+            self.expiries.insert((expires_at, stop.order.id));
+        }
+        let position = self
+            .stops
+            .partition_point(|waiting| waiting.order.id < stop.order.id);
+        self.stops.insert(position, stop);
+    }
+
+    /// Whether a stop order on `side` would trigger at the current last price.
+    /// Buy stops trigger when the price rises to the stop price, sell stops
+    /// when it falls to it.
+    pub fn would_trigger(&self, side: Side, stop_price: Decimal) -> bool {
+        self.last_price.is_some_and(|last_price| match side {
+            Side::Buy => last_price >= stop_price,
+            Side::Sell => last_price <= stop_price,
+        })
+    }
+
+    /// Removes and returns every stop order the last price has triggered,
+    /// oldest first
+    pub fn take_triggered(&mut self) -> Vec<StopOrder> {
+        let (triggered, waiting): (Vec<StopOrder>, Vec<StopOrder>) =
+            std::mem::take(&mut self.stops)
+                .into_iter()
+                .partition(|stop| self.would_trigger(stop.order.side, stop.stop_price));
+        self.stops = waiting;
+
+        for stop in &triggered {
+            self.touched_orders.insert(stop.order.id);
+            if let Some(expires_at) = stop.order.expires_at {
+                self.expiries.remove(&(expires_at, stop.order.id));
+            }
+        }
+        triggered
+    }
+
+    /// Removes every resting or stop order that expires at or before `now`
+    /// and returns their ids
+    pub fn take_expired(&mut self, now: DateTime<Utc>) -> Vec<i32> {
+        let mut expired = Vec::new();
+        while let Some(&(expires_at, order_id)) = self.expiries.first() {
+            if expires_at > now {
+                break;
+            }
+            self.cancel(order_id);
+            expired.push(order_id);
+        }
+        expired
+    }
+
+    /// Removes a resting or stop order from the book, returning whether it was present
+    pub fn cancel(&mut self, order_id: i32) -> bool {
+        if let Some(position) = self.stops.iter().position(|stop| stop.order.id == order_id) {
+            let stop = self.stops.remove(position);
+            self.touched_orders.insert(order_id);
+            if let Some(expires_at) = stop.order.expires_at {
+                self.expiries.remove(&(expires_at, order_id));
+            }
+            return true;
+        }
+
+        let Some((side, price)) = self.index.remove(&order_id) else {
+            return false;
+        };
+        self.mark_level(side, price);
+        self.touched_orders.insert(order_id);
+        let levels = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+
+        let Some(queue) = levels.get_mut(&price) else {
+            return false;
+        };
+        let Some(position) = queue.iter().position(|order| order.id == order_id) else {
+            return false;
+        };
+        if let Some(order) = queue.remove(position)
+            && let Some(expires_at) = order.expires_at
+        {
+            self.expiries.remove(&(expires_at, order_id));
+        }
+        if queue.is_empty() {
+            levels.remove(&price);
+        }
+        true
+    }
+
+    /// Whether an order at `price` on `side` would match immediately
+    pub fn would_cross(&self, side: Side, price: Decimal) -> bool {
+        let best = match side {
+            Side::Buy => self.best_ask(),
+            Side::Sell => self.best_bid(),
+        };
+        best.is_some_and(|best| crosses(side, Some(price), best))
+    }
+
+    /// Quantity, up to `max`, that an order on `side` could fill right now
+    pub fn fillable_quantity(&self, side: Side, limit: Option<Decimal>, max: Decimal) -> Decimal {
+        let mut fillable = Decimal::ZERO;
+        for (price, quantity) in self.opposite_levels(side) {
+            if fillable >= max || !crosses(side, limit, price) {
+                break;
+            }
+            fillable += quantity;
+        }
+        fillable.min(max)
+    }
+
+    /// Quote amount a market buy of `quantity` would spend against the current asks
+    pub fn market_buy_cost(&self, quantity: Decimal) -> Decimal {
+        let mut remaining = quantity;
+        let mut cost = Decimal::ZERO;
+        for (price, available) in self.opposite_levels(Side::Buy) {
+            if remaining.is_zero() {
+                break;
+            }
+            let taken = available.min(remaining);
+            cost += price * taken;
+            remaining -= taken;
+        }
+        cost
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Aggregated (price, quantity) levels, best first, for each side
+    pub fn depth(&self, max_levels: usize) -> (DepthLevels, DepthLevels) {
+        let bids = self.levels(Side::Buy).take(max_levels).collect();
+        let asks = self.levels(Side::Sell).take(max_levels).collect();
+        (bids, asks)
+    }
+
+    /// Takes the changes made since the previous call. Each call that reports
+    /// changed levels, or a reset, advances the sequence number by one.
+    pub fn take_changes(&mut self) -> BookChanges {
+        let changed_bids = std::mem::take(&mut self.changed_bids);
+        let changed_asks = std::mem::take(&mut self.changed_asks);
+        let touched_orders = std::mem::take(&mut self.touched_orders);
+
+        if self.reset {
+            // Nothing was stored for a rebuilt book, so only the levels changed
+            self.reset = false;
+            self.sequence += 1;
+            return BookChanges {
+                sequence: self.sequence,
+                reset: true,
+                ..Default::default()
+            };
+        }
+
+        if !changed_bids.is_empty() || !changed_asks.is_empty() {
+            self.sequence += 1;
+        }
+        BookChanges {
+            sequence: self.sequence,
+            reset: false,
+            bids: changed_bids
+                .into_iter()
+                .map(|price| (price, self.level_quantity(Side::Buy, price)))
+                .collect(),
+            asks: changed_asks
+                .into_iter()
+                .map(|price| (price, self.level_quantity(Side::Sell, price)))
+                .collect(),
+            orders: touched_orders.into_iter().collect(),
+        }
+    }
+
+    /// Whether anything changed since the previous call to `take_changes`
+    pub fn has_changes(&self) -> bool {
+        self.reset
+            || !self.changed_bids.is_empty()
+            || !self.changed_asks.is_empty()
+            || !self.touched_orders.is_empty()
+    }
+
+    /// Sequence number of the most recent depth update
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn mark_level(&mut self, side: Side, price: Decimal) {
+        match side {
+            Side::Buy => self.changed_bids.insert(price),
+            Side::Sell => self.changed_asks.insert(price),
+        };
+    }
+
+    /// Total quantity resting at a price level, zero if the level is empty
+    fn level_quantity(&self, side: Side, price: Decimal) -> Decimal {
+        let levels = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        levels
+            .get(&price)
+            .map(|queue| queue.iter().map(|order| order.remaining).sum())
+            .unwrap_or_default()
+    }
+
+    /// Aggregated levels of one side of the book, best price first
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = (Decimal, Decimal)> + '_> {
+        let aggregate = |(price, queue): (&Decimal, &VecDeque<BookOrder>)| {
+            (*price, queue.iter().map(|order| order.remaining).sum())
+        };
+        match side {
+            Side::Buy => Box::new(self.bids.iter().rev().map(aggregate)),
+            Side::Sell => Box::new(self.asks.iter().map(aggregate)),
+        }
+    }
+
+    /// Levels an order on `side` would trade against, best price first
+    fn opposite_levels(&self, side: Side) -> Box<dyn Iterator<Item = (Decimal, Decimal)> + '_> {
+        self.levels(side.opposite())
+    }
+}
+
+/// Whether a taker on `side` can trade against a maker at `maker_price`.
+/// Market orders, which have no limit, cross any price.
+fn crosses(side: Side, limit: Option<Decimal>, maker_price: Decimal) -> bool {
+    match (side, limit) {
+        (_, None) => true,
+        (Side::Buy, Some(limit)) => limit >= maker_price,
+        (Side::Sell, Some(limit)) => limit <= maker_price,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn limit(id: i32, side: Side, price: &str, quantity: &str) -> IncomingOrder {
+        IncomingOrder {
+            id,
+            user_id: id * 10,
+            side,
+            limit: Some(dec(price)),
+            quantity: dec(quantity),
+            time_in_force: TimeInForce::Gtc,
+            post_only: false,
+            expires_at: None,
+        }
+    }
+
+    fn market(id: i32, side: Side, quantity: &str) -> IncomingOrder {
+        IncomingOrder {
+            limit: None,
+            time_in_force: TimeInForce::Ioc,
+            ..limit(id, side, "0", quantity)
+        }
+    }
+
+    #[test]
+    fn fills_best_price_first_then_oldest_first() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Sell, "101", "1")).unwrap();
+        book.submit(limit(2, Side::Sell, "100", "1")).unwrap();
+        book.submit(limit(3, Side::Sell, "100", "1")).unwrap();
+
+        let result = book.submit(limit(4, Side::Buy, "101", "2.5")).unwrap();
+
+        let makers: Vec<i32> = result
+            .fills
+            .iter()
+            .map(|fill| fill.maker_order_id)
+            .collect();
+        assert_eq!(makers, vec![2, 3, 1]);
+        assert_eq!(result.fills[2].quantity, dec("0.5"));
+        assert!(result.remaining.is_zero());
+        assert!(!result.resting);
+        assert_eq!(book.last_price, Some(dec("101")));
+    }
+
+    #[test]
+    fn partial_fill_trades_at_maker_price_and_rests_remainder() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Sell, "100", "1")).unwrap();
+
+        let result = book.submit(limit(2, Side::Buy, "105", "3")).unwrap();
+
+        assert_eq!(result.fills.len(), 1);
+        assert_eq!(result.fills[0].price, dec("100"));
+        assert_eq!(result.fills[0].quantity, dec("1"));
+        assert!(result.fills[0].maker_filled);
+        assert_eq!(result.remaining, dec("2"));
+        assert!(result.resting);
+        assert_eq!(book.best_bid(), Some(dec("105")));
+        assert_eq!(book.best_ask(), None);
+    }
+
+    #[test]
+    fn partially_filled_maker_keeps_its_place() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Buy, "100", "2")).unwrap();
+        book.submit(limit(2, Side::Buy, "100", "1")).unwrap();
+
+        let first = book.submit(limit(3, Side::Sell, "100", "1")).unwrap();
+        assert_eq!(first.fills[0].maker_order_id, 1);
+        assert!(!first.fills[0].maker_filled);
+
+        let second = book.submit(limit(4, Side::Sell, "100", "1")).unwrap();
+        assert_eq!(second.fills[0].maker_order_id, 1);
+        assert!(second.fills[0].maker_filled);
+    }
+
+    #[test]
+    fn cancel_removes_order_and_empty_level() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Buy, "99", "1")).unwrap();
+        book.submit(limit(2, Side::Buy, "98", "1")).unwrap();
+
+        assert!(book.cancel(1));
+        assert!(!book.cancel(1));
+        assert_eq!(book.best_bid(), Some(dec("98")));
+
+        let result = book.submit(limit(3, Side::Sell, "98", "1")).unwrap();
+        assert_eq!(result.fills[0].maker_order_id, 2);
+    }
+
+    #[test]
+    fn depth_aggregates_levels_best_first() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Buy, "99", "1")).unwrap();
+        book.submit(limit(2, Side::Buy, "99", "2")).unwrap();
+        book.submit(limit(3, Side::Buy, "98", "4")).unwrap();
+        book.submit(limit(4, Side::Sell, "101", "1.5")).unwrap();
+        book.submit(limit(5, Side::Sell, "102", "1")).unwrap();
+
+        let (bids, asks) = book.depth(10);
+        assert_eq!(bids, vec![(dec("99"), dec("3")), (dec("98"), dec("4"))]);
+        assert_eq!(asks, vec![(dec("101"), dec("1.5")), (dec("102"), dec("1"))]);
+
+        let (bids, asks) = book.depth(1);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(asks.len(), 1);
+    }
+
+    #[test]
+    fn rejections_leave_book_untouched() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Sell, "100", "1")).unwrap();
+
+        let post_only = IncomingOrder {
+            post_only: true,
+            ..limit(2, Side::Buy, "100", "1")
+        };
+        assert_eq!(
+            book.submit(post_only).unwrap_err(),
+            Rejection::PostOnlyWouldCross
+        );
+
+        let fill_or_kill = IncomingOrder {
+            time_in_force: TimeInForce::Fok,
+            ..limit(3, Side::Buy, "100", "2")
+        };
+        assert_eq!(
+            book.submit(fill_or_kill).unwrap_err(),
+            Rejection::FillOrKillUnfillable
+        );
+
+        assert_eq!(
+            book.submit(market(4, Side::Sell, "1")).unwrap_err(),
+            Rejection::NoLiquidity
+        );
+        assert_eq!(book.depth(10).1, vec![(dec("100"), dec("1"))]);
+    }
+
+    #[test]
+    fn market_order_sweeps_levels_and_drops_remainder() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Sell, "100", "1")).unwrap();
+        book.submit(limit(2, Side::Sell, "110", "1")).unwrap();
+
+        assert_eq!(book.market_buy_cost(dec("1.5")), dec("155"));
+
+        let result = book.submit(market(3, Side::Buy, "3")).unwrap();
+        let prices: Vec<Decimal> = result.fills.iter().map(|fill| fill.price).collect();
+        assert_eq!(prices, vec![dec("100"), dec("110")]);
+        assert_eq!(result.remaining, dec("1"));
+        assert!(!result.resting);
+        assert_eq!(book.best_ask(), None);
+    }
+
+    #[test]
+    fn reports_changes_until_they_are_taken() {
+        let mut book = OrderBook::new("BTC/USDT");
+        book.submit(limit(1, Side::Sell, "100", "1")).unwrap();
+        book.take_changes();
+        assert!(!book.has_changes());
+
+        assert!(book.submit(market(2, Side::Sell, "1")).is_err());
+        assert!(!book.has_changes());
+
+        book.submit(limit(3, Side::Buy, "100", "0.4")).unwrap();
+        assert!(book.has_changes());
+        let changes = book.take_changes();
+        assert_eq!(changes.asks, vec![(dec("100"), dec("0.6"))]);
+        assert!(!book.has_changes());
+    }
+}
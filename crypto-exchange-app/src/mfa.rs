@@ -0,0 +1,514 @@
+use crate::auth::{self, SessionError, Tokens};
+use crate::db;
+use crate::error::AppError;
+use crate::models;
+use crate::roles;
+use crate::schema;
+use crate::sessions::{self, ClientInfo};
+use crate::throttle;
+use actix_web::{HttpRequest, HttpResponse, get, post, web};
+use chrono::Utc;
+use diesel::dsl::exists;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::error;
+use rand::RngCore;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Shown as the account's issuer in authenticator apps
+const ISSUER: &str = "Crypto Exchange";
+
+/// Length of a TOTP time step in seconds
+const STEP_SECONDS: u64 = 30;
+
+/// Codes from this many steps either side of the current one are accepted,
+/// to allow for clock drift on the user's device
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+/// Number of recovery codes issued when two-factor authentication is enabled
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Errors that can occur while setting up or checking a second factor
+#[derive(Debug)]
+pub enum MfaError {
+    AlreadyEnabled,
+    NotEnabled,
+    NotSetUp,
+    CodeRequired,
+    InvalidCode,
+    TokenUsed,
+    Totp(String),
+    Session(SessionError),
+    Database(diesel::result::Error),
+}
+
+impl From<MfaError> for AppError {
+    fn from(e: MfaError) -> Self {
+        match e {
+            MfaError::AlreadyEnabled => {
+                AppError::conflict("Two-factor authentication is already enabled")
+            }
+            MfaError::NotEnabled => {
+                AppError::bad_request("Two-factor authentication is not enabled")
+            }
+            MfaError::NotSetUp => {
+                AppError::bad_request("Start two-factor setup before confirming it")
+            }
+            MfaError::CodeRequired => AppError::MfaRequired,
+            MfaError::InvalidCode => {
+                AppError::unauthorized("Invalid two-factor authentication code")
+            }
+            MfaError::TokenUsed => {
+                AppError::unauthorized("This login has already been completed; log in again")
+            }
+            MfaError::Totp(e) => {
+                error!("TOTP error: {}", e);
+                AppError::internal("Failed to process two-factor authentication")
+            }
+            MfaError::Session(e) => e.into(),
+            MfaError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for MfaError {
+    fn from(e: diesel::result::Error) -> Self {
+        MfaError::Database(e)
+    }
+}
+
+impl From<SessionError> for MfaError {
+    fn from(e: SessionError) -> Self {
+        MfaError::Session(e)
+    }
+}
+
+/// What a password login leads to
+pub enum LoginStep {
+    Session(Tokens),
+    /// The user has to send a TOTP or recovery code along with this token
+    MfaRequired(String),
+}
+
+fn totp_for(secret: &str, account: &str) -> Result<TOTP, MfaError> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| MfaError::Totp(format!("{:?}", e)))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECONDS,
+        bytes,
+        Some(ISSUER.to_string()),
+        account.to_string(),
+    )
+    .map_err(|e| MfaError::Totp(e.to_string()))
+}
+
+/// Finds the time step a code was generated for, if it is close enough to now
+fn matching_step(totp: &TOTP, code: &str, now: i64) -> Option<i64> {
+    let current = now / STEP_SECONDS as i64;
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .find(|step| totp.check(code, *step as u64 * STEP_SECONDS))
+}
+
+/// Recovery codes are accepted with or without the dash and in any case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}", &hex[..5], &hex[5..])
+}
+
+/// Returns the user's TOTP settings once they have been confirmed, locking
+/// the row so a code cannot be used twice by concurrent requests
+fn enabled_totp(conn: &mut PgConnection, owner: i32) -> QueryResult<Option<models::UserTotp>> {
+    use schema::user_totp::dsl::*;
+
+    user_totp
+        .find(owner)
+        .filter(enabled_at.is_not_null())
+        .for_update()
+        .first::<models::UserTotp>(conn)
+        .optional()
+}
+
+pub fn is_enabled(conn: &mut PgConnection, owner: i32) -> QueryResult<bool> {
+    use schema::user_totp::dsl::*;
+
+    diesel::select(exists(
+        user_totp.find(owner).filter(enabled_at.is_not_null()),
+    ))
+    .get_result(conn)
+}
+
+/// Accepts a TOTP code unless it, or a later one, has already been used
+fn accept_totp(
+    conn: &mut PgConnection,
+    settings: &models::UserTotp,
+    code: &str,
+) -> Result<bool, MfaError> {
+    use schema::user_totp::dsl::*;
+
+    let totp = totp_for(&settings.secret, "")?;
+    let Some(step) = matching_step(&totp, code.trim(), Utc::now().timestamp()) else {
+        return Ok(false);
+    };
+    if settings.last_used_step.is_some_and(|last| step <= last) {
+        return Ok(false);
+    }
+
+    diesel::update(user_totp.find(settings.user_id))
+        .set((last_used_step.eq(step), updated_at.eq(Utc::now())))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// Accepts an unused recovery code and marks it used
+fn accept_recovery_code(conn: &mut PgConnection, owner: i32, code: &str) -> QueryResult<bool> {
+    use schema::totp_recovery_codes::dsl::*;
+
+    let used = diesel::update(
+        totp_recovery_codes
+            .filter(user_id.eq(owner))
+            .filter(code_hash.eq(auth::hash_token(&normalize_recovery_code(code))))
+            .filter(used_at.is_null()),
+    )
+    .set(used_at.eq(Utc::now()))
+    .execute(conn)?;
+    Ok(used > 0)
+}
+
+/// Checks a TOTP or recovery code for a user with two-factor authentication
+/// enabled. Must run inside a transaction.
+fn accept_code(conn: &mut PgConnection, owner: i32, code: &str) -> Result<(), MfaError> {
+    let settings = enabled_totp(conn, owner)?.ok_or(MfaError::NotEnabled)?;
+    if accept_totp(conn, &settings, code)? || accept_recovery_code(conn, owner, code)? {
+        Ok(())
+    } else {
+        Err(MfaError::InvalidCode)
+    }
+}
+
+/// Replaces a user's recovery codes and returns the new ones, which are
+/// shown to them once
+fn replace_recovery_codes(conn: &mut PgConnection, owner: i32) -> QueryResult<Vec<String>> {
+    use schema::totp_recovery_codes::dsl::*;
+
+    diesel::delete(totp_recovery_codes.filter(user_id.eq(owner))).execute(conn)?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| new_recovery_code())
+        .collect();
+    let rows: Vec<models::NewRecoveryCode> = codes
+        .iter()
+        .map(|code| models::NewRecoveryCode {
+            user_id: owner,
+            code_hash: auth::hash_token(&normalize_recovery_code(code)),
+        })
+        .collect();
+    diesel::insert_into(totp_recovery_codes)
+        .values(&rows)
+        .execute(conn)?;
+
+    Ok(codes)
+}
+
+/// Starts a session after a correct password, or asks for a second factor
+/// first if the user has enabled one
+pub fn start_login(
+    conn: &mut PgConnection,
+    owner: i32,
+    client: &ClientInfo,
+) -> Result<LoginStep, MfaError> {
+    if is_enabled(conn, owner)? {
+        let mfa_token = auth::generate_mfa_token(owner).map_err(SessionError::from)?;
+        return Ok(LoginStep::MfaRequired(mfa_token));
+    }
+    Ok(LoginStep::Session(auth::issue_tokens(conn, owner, client)?))
+}
+
+/// Checks the second factor for a sensitive action such as a withdrawal.
+/// Users without two-factor authentication pass; everyone else has to send a
+/// code that has not been used before.
+pub fn check_fresh_code(
+    conn: &mut PgConnection,
+    owner: i32,
+    code: Option<&str>,
+) -> Result<(), MfaError> {
+    conn.transaction(|conn| {
+        if enabled_totp(conn, owner)?.is_none() {
+            return Ok(());
+        }
+        let code = code.ok_or(MfaError::CodeRequired)?;
+        accept_code(conn, owner, code)
+    })
+}
+
+/// Async wrapper around `check_fresh_code` for handlers
+pub async fn require_fresh_code(
+    pool: &db::DbPool,
+    owner: i32,
+    code: Option<String>,
+) -> Result<(), AppError> {
+    let mut conn = pool.get()?;
+    web::block(move || check_fresh_code(&mut conn, owner, code.as_deref())).await??;
+    Ok(())
+}
+
+/// Completes a login for a user with two-factor authentication, using the
+/// token returned by `/login` and a TOTP or recovery code. Wrong codes count
+/// as failed logins for the account's email, and each token completes one
+/// login at most.
+#[post("/mfa")]
+pub async fn verify_login(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    guard: web::Data<throttle::LoginGuard>,
+    login_data: web::Json<models::MfaLoginRequest>,
+) -> Result<HttpResponse, AppError> {
+    let login_data = login_data.into_inner();
+    let claims = auth::decode_mfa_token(&login_data.mfa_token)?;
+    let owner = claims.user_id()?;
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+    let client = ClientInfo::from_request(&req);
+    let alert_client = client.clone();
+
+    let mut conn = pool.get()?;
+    let account_email = web::block(move || {
+        schema::users::table
+            .find(owner)
+            .select(schema::users::email)
+            .first::<String>(&mut conn)
+    })
+    .await??;
+    guard.check_login(&account_email, client_ip)?;
+
+    let mut conn = pool.get()?;
+    let outcome = web::block(move || {
+        conn.transaction::<_, MfaError, _>(|conn| {
+            // Recording the token first makes a concurrent attempt with it
+            // wait for this one, and a failed code rolls the record back
+            if !auth::use_mfa_token(conn, owner, &claims)? {
+                return Err(MfaError::TokenUsed);
+            }
+            accept_code(conn, owner, &login_data.code)
+        })?;
+
+        let tokens = auth::issue_tokens(&mut conn, owner, &client)?;
+        let user = schema::users::table
+            .find(owner)
+            .first::<models::User>(&mut conn)?;
+        let user = roles::user_response(&mut conn, user)?;
+        Ok::<_, MfaError>((user, tokens))
+    })
+    .await?;
+
+    let (user, tokens) = match outcome {
+        Ok(outcome) => outcome,
+        Err(MfaError::InvalidCode) => {
+            guard
+                .login_failed(&account_email, client_ip, Some(&account_email))
+                .await;
+            return Err(MfaError::InvalidCode.into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    guard.login_succeeded(&account_email);
+
+    if tokens.new_device {
+        sessions::alert_new_login(user.email.clone(), alert_client);
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Login successful",
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in,
+        "user": user
+    })))
+}
+
+/// Whether two-factor authentication is enabled for the current user
+#[get("/2fa")]
+pub async fn status(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let (enabled, remaining) = web::block(move || {
+        use schema::totp_recovery_codes::dsl::*;
+
+        let enabled = is_enabled(&mut conn, current_user_id)?;
+        let remaining = totp_recovery_codes
+            .filter(user_id.eq(current_user_id))
+            .filter(used_at.is_null())
+            .count()
+            .get_result::<i64>(&mut conn)?;
+        Ok::<_, diesel::result::Error>((enabled, remaining))
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": enabled,
+        "recovery_codes_remaining": remaining
+    })))
+}
+
+/// Generates a new TOTP secret for the current user. It is not enforced until
+/// confirmed with a code through `/user/2fa/enable`.
+#[post("/2fa/setup")]
+pub async fn setup(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let (secret, uri) = web::block(move || {
+        conn.transaction::<_, MfaError, _>(|conn| {
+            use schema::user_totp::dsl::*;
+
+            if enabled_totp(conn, current_user_id)?.is_some() {
+                return Err(MfaError::AlreadyEnabled);
+            }
+
+            let account = schema::users::table
+                .find(current_user_id)
+                .select(schema::users::email)
+                .first::<String>(conn)?;
+            let Secret::Encoded(new_secret) = Secret::generate_secret().to_encoded() else {
+                return Err(MfaError::Totp("Failed to encode secret".to_string()));
+            };
+            let uri = totp_for(&new_secret, &account)?.get_url();
+
+            diesel::insert_into(user_totp)
+                .values((user_id.eq(current_user_id), secret.eq(&new_secret)))
+                .on_conflict(user_id)
+                .do_update()
+                .set((
+                    secret.eq(&new_secret),
+                    last_used_step.eq(None::<i64>),
+                    updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)?;
+
+            Ok((new_secret, uri))
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Add the account to an authenticator app, then confirm it with a code",
+        "secret": secret,
+        "otpauth_uri": uri
+    })))
+}
+
+/// Enables two-factor authentication once the user proves their authenticator
+/// works, and returns their recovery codes
+#[post("/2fa/enable")]
+pub async fn enable(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    code_data: web::Json<models::TotpCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let code = code_data.into_inner().code;
+    let recovery_codes = web::block(move || {
+        conn.transaction::<_, MfaError, _>(|conn| {
+            use schema::user_totp::dsl::*;
+
+            let settings = user_totp
+                .find(current_user_id)
+                .for_update()
+                .first::<models::UserTotp>(conn)
+                .optional()?
+                .ok_or(MfaError::NotSetUp)?;
+            if settings.enabled_at.is_some() {
+                return Err(MfaError::AlreadyEnabled);
+            }
+            if !accept_totp(conn, &settings, &code)? {
+                return Err(MfaError::InvalidCode);
+            }
+
+            diesel::update(user_totp.find(current_user_id))
+                .set((enabled_at.eq(Utc::now()), updated_at.eq(Utc::now())))
+                .execute(conn)?;
+            Ok(replace_recovery_codes(conn, current_user_id)?)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Two-factor authentication enabled. Store the recovery codes somewhere safe.",
+        "recovery_codes": recovery_codes
+    })))
+}
+
+/// Turns off two-factor authentication. Takes a TOTP or recovery code.
+#[post("/2fa/disable")]
+pub async fn disable(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    code_data: web::Json<models::TotpCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let code = code_data.into_inner().code;
+    let () = web::block(move || {
+        conn.transaction::<_, MfaError, _>(|conn| {
+            accept_code(conn, current_user_id, &code)?;
+
+            diesel::delete(schema::user_totp::table.find(current_user_id)).execute(conn)?;
+            diesel::delete(
+                schema::totp_recovery_codes::table
+                    .filter(schema::totp_recovery_codes::user_id.eq(current_user_id)),
+            )
+            .execute(conn)?;
+            Ok(())
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Two-factor authentication disabled"
+    })))
+}
+
+/// Replaces the current user's recovery codes. Takes a TOTP or recovery code.
+#[post("/2fa/recovery-codes")]
+pub async fn regenerate_recovery_codes(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    code_data: web::Json<models::TotpCodeRequest>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::extract_user_id(&req)?;
+    let mut conn = pool.get()?;
+
+    let code = code_data.into_inner().code;
+    let recovery_codes = web::block(move || {
+        conn.transaction::<_, MfaError, _>(|conn| {
+            accept_code(conn, current_user_id, &code)?;
+            Ok(replace_recovery_codes(conn, current_user_id)?)
+        })
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Recovery codes replaced. The previous codes no longer work.",
+        "recovery_codes": recovery_codes
+    })))
+}
@@ -1,4 +1,5 @@
 use diesel::prelude::*;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 #[derive(Queryable, Selectable, Debug, Clone, Serialize)]
@@ -10,7 +11,7 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
-    pub is_admin: bool, // Add this new field
+    pub email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -27,17 +28,23 @@ pub struct UserResponse {
     pub username: String,
     pub email: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
-    pub is_admin: bool, // Add this new field
+    /// Trading and KYC submission stay blocked until this is true
+    pub email_verified: bool,
+    /// True for staff with any role, which gives access to the admin area
+    pub is_admin: bool,
+    pub roles: Vec<String>,
 }
 
-impl From<User> for UserResponse {
-    fn from(user: User) -> Self {
+impl UserResponse {
+    pub fn new(user: User, roles: Vec<String>) -> Self {
         UserResponse {
             id: user.id,
             username: user.username,
             email: user.email,
             created_at: user.created_at,
-            is_admin: user.is_admin, // Add this new field
+            email_verified: user.email_verified_at.is_some(),
+            is_admin: !roles.is_empty(),
+            roles,
         }
     }
 }
@@ -48,6 +55,12 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct UnlockAccountRequest {
+    /// Token from the emailed unlock link
+    pub token: String,
+}
+
 #[derive(Queryable, Selectable, Debug, Clone, Serialize)]
 #[diesel(table_name = crate::schema::user_verifications)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -70,8 +83,9 @@ pub struct UserVerification {
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
     pub id_front_path: Option<String>,
-    pub id_verification_status: String,
-    pub id_verified_at: Option<chrono::NaiveDateTime>,
+    pub status_reason: Option<String>,
+    pub submitted_at: Option<chrono::NaiveDateTime>,
+    pub reviewed_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -112,61 +126,38 @@ pub struct VerificationRequest {
 pub struct VerificationResponse {
     pub id: i32,
     pub verification_status: String,
+    /// Why the verification was rejected or what more is needed
+    pub status_reason: Option<String>,
     pub created_at: chrono::NaiveDateTime,
+    pub submitted_at: Option<chrono::NaiveDateTime>,
+    pub reviewed_at: Option<chrono::NaiveDateTime>,
     pub id_document: Option<IdDocumentResponse>,
 }
 
 impl From<UserVerification> for VerificationResponse {
     fn from(verification: UserVerification) -> Self {
-        let id_document = if verification.id_front_path.is_some() {
-            Some(IdDocumentResponse {
-                id_verification_status: verification.id_verification_status,
-                id_front_path: verification.id_front_path,
-                id_verified_at: verification.id_verified_at,
-            })
-        } else {
-            None
-        };
+        let id_document = verification.id_front_path.map(|path| IdDocumentResponse {
+            id_verification_status: crate::kyc::legacy_document_status(
+                &verification.verification_status,
+            )
+            .to_string(),
+            id_front_path: Some(path),
+            id_verified_at: verification.reviewed_at,
+        });
 
         Self {
             id: verification.id,
             verification_status: verification.verification_status,
+            status_reason: verification.status_reason,
             created_at: verification.created_at,
+            submitted_at: verification.submitted_at,
+            reviewed_at: verification.reviewed_at,
             id_document,
         }
     }
 }
 
-impl<E> From<Result<UserVerification, E>> for VerificationResponse {
-    fn from(verification_result: Result<UserVerification, E>) -> Self {
-        match verification_result {
-            Ok(verification) => Self {
-                id: verification.id,
-                verification_status: verification.verification_status,
-                created_at: verification.created_at,
-                id_document: if verification.id_front_path.is_some() {
-                    Some(IdDocumentResponse {
-                        id_verification_status: verification.id_verification_status,
-                        id_front_path: verification.id_front_path,
-                        id_verified_at: verification.id_verified_at,
-                    })
-                } else {
-                    None
-                },
-            },
-            Err(_) => {
-                // Provide default values for error case
-                Self {
-                    id: 0,
-                    verification_status: "error".to_string(),
-                    created_at: chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap_or_default(),
-                    id_document: None,
-                }
-            }
-        }
-    }
-}
-
+/// The document part of a verification, in the shape older clients expect
 #[derive(Debug, Serialize)]
 pub struct IdDocumentResponse {
     pub id_verification_status: String,
@@ -174,6 +165,124 @@ pub struct IdDocumentResponse {
     pub id_verified_at: Option<chrono::NaiveDateTime>,
 }
 
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::kyc_status_history)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct KycStatusChange {
+    pub id: i32,
+    pub verification_id: i32,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub reason: Option<String>,
+    pub changed_by: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::kyc_status_history)]
+pub struct NewKycStatusChange {
+    pub verification_id: i32,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub reason: Option<String>,
+    pub changed_by: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::kyc_documents)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct KycDocument {
+    pub id: i32,
+    pub verification_id: i32,
+    pub document_type: String,
+    pub storage_key: String,
+    pub content_type: String,
+    pub review_status: String,
+    pub reviewer_notes: Option<String>,
+    pub reviewed_by: Option<i32>,
+    pub reviewed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::kyc_documents)]
+pub struct NewKycDocument {
+    pub verification_id: i32,
+    pub document_type: String,
+    pub storage_key: String,
+    pub content_type: String,
+}
+
+/// A KYC document as its owner sees it, without who reviewed it
+#[derive(Serialize)]
+pub struct KycDocumentResponse {
+    pub id: i32,
+    pub document_type: String,
+    pub content_type: String,
+    pub review_status: String,
+    pub reviewer_notes: Option<String>,
+    pub reviewed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<KycDocument> for KycDocumentResponse {
+    fn from(document: KycDocument) -> Self {
+        KycDocumentResponse {
+            id: document.id,
+            document_type: document.document_type,
+            content_type: document.content_type,
+            review_status: document.review_status,
+            reviewer_notes: document.reviewer_notes,
+            reviewed_at: document.reviewed_at,
+            created_at: document.created_at,
+            updated_at: document.updated_at,
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::kyc_document_access_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct KycDocumentAccess {
+    pub id: i32,
+    pub document_id: i32,
+    pub accessed_by: Option<i32>,
+    pub request_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::kyc_document_access_log)]
+pub struct NewKycDocumentAccess {
+    pub document_id: i32,
+    pub accessed_by: Option<i32>,
+    pub request_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A reviewer's verdict on one KYC document
+#[derive(Deserialize)]
+pub struct KycDocumentReviewRequest {
+    /// "approved" or "rejected"
+    pub status: String,
+    /// Shown to the user; required for "rejected"
+    pub notes: Option<String>,
+}
+
+/// A reviewer's decision on a verification
+#[derive(Deserialize)]
+pub struct KycDecisionRequest {
+    /// One of "in_review", "needs_more_info", "approved", "rejected" or "expired"
+    pub status: String,
+    /// Shown to the user; required for "needs_more_info" and "rejected"
+    pub reason: Option<String>,
+}
+
 // Add this struct for password reset requests
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PasswordResetRequest {
@@ -200,6 +309,50 @@ pub struct NewPasswordResetToken {
     pub expires_at: chrono::NaiveDateTime,
 }
 
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::email_verification_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailVerificationToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub used: bool,
+    /// New address the token confirms, when it is for an email change
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::email_verification_tokens)]
+pub struct NewEmailVerificationToken {
+    pub user_id: i32,
+    pub token: String,
+    pub expires_at: chrono::NaiveDateTime,
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    /// Only replaces the current address once the new one is verified
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    /// Required when two-factor authentication is enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+    /// Token from the emailed verification link
+    pub token: String,
+}
+
 // Add this new model for password reset validation
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -207,4 +360,491 @@ pub struct ResetPasswordRequest {
     pub email: String,
     pub token: String,
     pub new_password: String,
+    /// Required when the user has two-factor authentication enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::orders)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Order {
+    pub id: i32,
+    pub user_id: i32,
+    pub symbol: String,
+    pub side: String,
+    pub price: Option<Decimal>,
+    pub quantity: Decimal,
+    pub filled_quantity: Decimal,
+    pub status: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub reserved: Decimal,
+    pub order_type: String,
+    pub time_in_force: String,
+    pub post_only: bool,
+    pub stop_price: Option<Decimal>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub triggered_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub reject_reason: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::orders)]
+pub struct NewOrder {
+    pub user_id: i32,
+    pub symbol: String,
+    pub side: String,
+    pub price: Option<Decimal>,
+    pub quantity: Decimal,
+    pub reserved: Decimal,
+    pub status: String,
+    pub order_type: String,
+    pub time_in_force: String,
+    pub post_only: bool,
+    pub stop_price: Option<Decimal>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct PlaceOrderRequest {
+    pub symbol: String,
+    pub side: crate::matching::Side,
+    #[serde(default)]
+    pub order_type: crate::matching::OrderType,
+    pub price: Option<Decimal>,
+    pub quantity: Decimal,
+    pub stop_price: Option<Decimal>,
+    /// Defaults to GTC for limit orders and IOC for market orders
+    pub time_in_force: Option<crate::matching::TimeInForce>,
+    #[serde(default)]
+    pub post_only: bool,
+    /// Required for GTD orders
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::fills)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FillRecord {
+    pub id: i32,
+    pub symbol: String,
+    pub maker_order_id: i32,
+    pub taker_order_id: i32,
+    pub maker_user_id: i32,
+    pub taker_user_id: i32,
+    pub taker_side: String,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub maker_fee: Decimal,
+    pub maker_fee_asset: String,
+    pub taker_fee: Decimal,
+    pub taker_fee_asset: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::fills)]
+pub struct NewFill {
+    pub symbol: String,
+    pub maker_order_id: i32,
+    pub taker_order_id: i32,
+    pub maker_user_id: i32,
+    pub taker_user_id: i32,
+    pub taker_side: String,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    pub maker_fee: Decimal,
+    pub maker_fee_asset: String,
+    pub taker_fee: Decimal,
+    pub taker_fee_asset: String,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::ledger_accounts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LedgerAccount {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub asset: String,
+    pub kind: String,
+    pub balance: Decimal,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::ledger_accounts)]
+pub struct NewLedgerAccount {
+    pub user_id: Option<i32>,
+    pub asset: String,
+    pub kind: String,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::journal_entries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct JournalEntry {
+    pub id: i32,
+    pub kind: String,
+    pub reference: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::journal_entries)]
+pub struct NewJournalEntry {
+    pub kind: String,
+    pub reference: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::journal_postings)]
+pub struct NewJournalPosting {
+    pub entry_id: i32,
+    pub account_id: i32,
+    pub asset: String,
+    pub amount: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct BalanceResponse {
+    pub asset: String,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::withdrawals)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Withdrawal {
+    pub id: i32,
+    pub user_id: i32,
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+    pub status: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::withdrawals)]
+pub struct NewWithdrawal {
+    pub user_id: i32,
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawalRequest {
+    pub asset: String,
+    pub amount: Decimal,
+    pub address: String,
+    /// Required when the user has two-factor authentication enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DepositRequest {
+    pub user_id: i32,
+    pub asset: String,
+    pub amount: Decimal,
+    pub reference: String,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::instruments)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Instrument {
+    pub id: i32,
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub price_tick: Decimal,
+    pub quantity_step: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Decimal,
+    pub min_notional: Decimal,
+    pub status: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::instruments)]
+pub struct NewInstrument {
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub price_tick: Decimal,
+    pub quantity_step: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Decimal,
+    pub min_notional: Decimal,
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+pub struct InstrumentRequest {
+    pub base_asset: String,
+    pub quote_asset: String,
+    pub price_tick: Decimal,
+    pub quantity_step: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Decimal,
+    pub min_notional: Decimal,
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateInstrumentRequest {
+    pub price_tick: Option<Decimal>,
+    pub quantity_step: Option<Decimal>,
+    pub min_quantity: Option<Decimal>,
+    pub max_quantity: Option<Decimal>,
+    pub min_notional: Option<Decimal>,
+    pub status: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::fee_tiers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FeeTier {
+    pub id: i32,
+    pub min_volume: Decimal,
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::fee_tiers)]
+pub struct NewFeeTier {
+    pub min_volume: Decimal,
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct FeeScheduleRequest {
+    pub tiers: Vec<NewFeeTier>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::user_fee_overrides)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FeeOverride {
+    pub user_id: i32,
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::user_fee_overrides)]
+pub struct NewFeeOverride {
+    pub user_id: i32,
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct FeeOverrideRequest {
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::candles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Candle {
+    pub id: i32,
+    pub symbol: String,
+    pub resolution: String,
+    pub open_time: chrono::DateTime<chrono::Utc>,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub trade_count: i32,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CandlesQuery {
+    pub symbol: String,
+    pub interval: String,
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: String,
+    pub token_hash: String,
+    pub access_jti: String,
+    pub access_expires_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub replaced_by: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::refresh_tokens)]
+pub struct NewRefreshToken {
+    pub user_id: i32,
+    pub family_id: String,
+    pub token_hash: String,
+    pub access_jti: String,
+    pub access_expires_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::revoked_tokens)]
+pub struct NewRevokedToken {
+    pub jti: String,
+    pub user_id: i32,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Session {
+    pub id: i32,
+    #[serde(skip_serializing)]
+    pub user_id: i32,
+    #[serde(skip_serializing)]
+    pub family_id: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub last_ip_address: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_seen_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::sessions)]
+pub struct NewSession {
+    pub user_id: i32,
+    pub family_id: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub last_ip_address: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::user_totp)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserTotp {
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_used_step: Option<i64>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::totp_recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct MfaLoginRequest {
+    pub mfa_token: String,
+    /// A TOTP code or an unused recovery code
+    pub code: String,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::api_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub label: String,
+    pub key: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub scopes: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::api_keys)]
+pub struct NewApiKey {
+    pub user_id: i32,
+    pub label: String,
+    pub key: String,
+    pub secret: String,
+    pub scopes: Vec<String>,
+    pub allowed_ips: Vec<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub label: String,
+    /// Any of "read", "trade" and "withdraw"
+    pub scopes: Vec<String>,
+    /// IP addresses or CIDR ranges; empty allows any address
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Required when the user has two-factor authentication enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone, Serialize)]
+#[diesel(table_name = crate::schema::roles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::user_roles)]
+pub struct NewUserRole {
+    pub user_id: i32,
+    pub role_id: i32,
+    pub granted_by: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct UserRolesRequest {
+    pub roles: Vec<String>,
 }
@@ -0,0 +1,1081 @@
+use crate::api_keys::Scope;
+use crate::auth;
+use crate::candles;
+use crate::db;
+use crate::email_verification;
+use crate::error::AppError;
+use crate::feed::{self, FeedHub};
+use crate::fees::{self, FeeRates};
+use crate::instruments;
+use crate::ledger::{self, AccountKind, LedgerError, Leg};
+use crate::limits::{self, Activity, LimitError};
+use crate::matching::{
+    BookOrder, Fill, IncomingOrder, MatchResult, OrderBook, OrderType, Side, StopOrder, TimeInForce,
+};
+use crate::models;
+use crate::schema;
+use actix::Addr;
+use actix_web::{HttpRequest, HttpResponse, delete, post, web};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{error, info, warn};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Order statuses the in-memory books still hold: stop orders waiting for
+/// their trigger and orders with quantity resting on the book
+pub const OPEN_STATUSES: [&str; 3] = ["untriggered", "open", "partially_filled"];
+
+/// How often resting GTD orders are checked for expiry
+const EXPIRY_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// In-memory order books for every tradable pair, shared across workers
+pub struct Exchange {
+    books: Mutex<HashMap<String, OrderBook>>,
+}
+
+impl Exchange {
+    /// Creates a book for each instrument in the registry and restores open
+    /// orders from Postgres
+    pub fn load(pool: &db::DbPool) -> Result<Exchange, String> {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get database connection: {}", e))?;
+
+        let symbols = schema::instruments::table
+            .select(schema::instruments::symbol)
+            .load::<String>(&mut conn)
+            .map_err(|e| format!("Failed to load instruments: {}", e))?;
+
+        let mut books = HashMap::new();
+        for symbol in symbols {
+            let mut book = OrderBook::new(&symbol);
+            restore_book(&mut conn, &mut book)
+                .map_err(|e| format!("Failed to restore {} order book: {}", symbol, e))?;
+            books.insert(symbol, book);
+        }
+
+        info!("Loaded order books for {} instruments", books.len());
+
+        Ok(Exchange {
+            books: Mutex::new(books),
+        })
+    }
+
+    /// Opens an empty book for a newly listed instrument
+    pub fn add_book(&self, symbol: &str) {
+        match self.books.lock() {
+            Ok(mut books) => {
+                books
+                    .entry(symbol.to_string())
+                    .or_insert_with(|| OrderBook::new(symbol));
+            }
+            Err(_) => error!("Failed to acquire order book lock to add {}", symbol),
+        }
+    }
+
+    /// Drops the book of a delisted instrument
+    pub fn remove_book(&self, symbol: &str) {
+        match self.books.lock() {
+            Ok(mut books) => {
+                books.remove(symbol);
+            }
+            Err(_) => error!("Failed to acquire order book lock to remove {}", symbol),
+        }
+    }
+
+    /// Whether a book is open for `symbol`
+    pub fn has_book(&self, symbol: &str) -> bool {
+        self.books
+            .lock()
+            .map(|books| books.contains_key(symbol))
+            .unwrap_or(false)
+    }
+
+    /// Full level-2 depth of a book, stamped with the sequence number of the
+    /// last depth update published for it
+    pub fn depth_snapshot(&self, symbol: &str) -> Option<feed::DepthChanged> {
+        let books = self.books.lock().ok()?;
+        let book = books.get(symbol)?;
+        Some(depth_snapshot(book))
+    }
+
+    /// Expires every GTD order whose expiry time has passed, in all books
+    fn expire_due(
+        &self,
+        conn: &mut PgConnection,
+        feed: &Addr<FeedHub>,
+        now: DateTime<Utc>,
+    ) -> Result<(), OrderError> {
+        let mut books = self
+            .books
+            .lock()
+            .map_err(|_| OrderError::Internal("Failed to acquire order book lock"))?;
+
+        for book in books.values_mut() {
+            let outcome = expire_orders(conn, book, now);
+            publish(conn, book, feed, Vec::new());
+            outcome?;
+        }
+        Ok(())
+    }
+}
+
+/// Periodically expires GTD orders on books that see no other activity.
+/// Books are also swept before every order placement, so expired orders
+/// never trade.
+pub async fn run_expiry_sweeper(
+    pool: db::DbPool,
+    exchange: web::Data<Exchange>,
+    feed: web::Data<Addr<FeedHub>>,
+) {
+    let mut interval = actix_web::rt::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let pool = pool.clone();
+        let exchange = exchange.clone();
+        let feed = feed.clone();
+        let result = web::block(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|_| OrderError::Internal("Failed to get database connection"))?;
+            exchange.expire_due(&mut conn, &feed, Utc::now())
+        })
+        .await;
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Failed to expire orders: {}", e.message()),
+            Err(e) => error!("Order expiry task failed: {}", e),
+        }
+    }
+}
+
+/// Splits a `BASE/QUOTE` symbol into its base and quote assets
+pub fn split_symbol(symbol: &str) -> Option<(&str, &str)> {
+    symbol
+        .split_once('/')
+        .filter(|(base, quote)| !base.is_empty() && !quote.is_empty())
+}
+
+/// Errors that can occur while placing or cancelling an order
+enum OrderError {
+    Rejected(String),
+    UnknownSymbol,
+    InsufficientFunds,
+    NotFound,
+    Limit(LimitError),
+    Internal(&'static str),
+}
+
+impl OrderError {
+    fn message(&self) -> &str {
+        match self {
+            OrderError::Rejected(message) => message,
+            OrderError::Internal(message) => message,
+            OrderError::UnknownSymbol => "Unknown trading pair",
+            OrderError::InsufficientFunds => "Insufficient balance",
+            OrderError::NotFound => "Open order not found",
+            OrderError::Limit(_) => "Trading limit exceeded",
+        }
+    }
+}
+
+impl From<OrderError> for AppError {
+    fn from(e: OrderError) -> Self {
+        match e {
+            OrderError::Rejected(message) => AppError::BadRequest(message),
+            OrderError::InsufficientFunds => AppError::InsufficientFunds,
+            OrderError::NotFound => AppError::not_found(e.message()),
+            OrderError::UnknownSymbol => AppError::bad_request(e.message()),
+            OrderError::Limit(e) => e.into(),
+            OrderError::Internal(message) => AppError::internal(message),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for OrderError {
+    fn from(e: diesel::result::Error) -> Self {
+        error!("Order database error: {}", e);
+        OrderError::Internal("Database error")
+    }
+}
+
+impl From<LedgerError> for OrderError {
+    fn from(e: LedgerError) -> Self {
+        match e {
+            LedgerError::InsufficientFunds => OrderError::InsufficientFunds,
+            e => {
+                error!("Order ledger error: {}", e);
+                OrderError::Internal("Ledger error")
+            }
+        }
+    }
+}
+
+impl From<LimitError> for OrderError {
+    fn from(e: LimitError) -> Self {
+        match e {
+            LimitError::Database(e) => e.into(),
+            e => OrderError::Limit(e),
+        }
+    }
+}
+
+/// Rebuilds a book from the orders stored in Postgres, oldest first so time
+/// priority is preserved, and restores the last trade price stop orders
+/// trigger on
+fn restore_book(conn: &mut PgConnection, book: &mut OrderBook) -> QueryResult<()> {
+    use schema::orders::dsl::*;
+
+    let active_orders = orders
+        .filter(symbol.eq(&book.symbol))
+        .filter(status.eq_any(OPEN_STATUSES))
+        .order_by(id.asc())
+        .load::<models::Order>(conn)?;
+
+    book.clear();
+    book.last_price = schema::fills::table
+        .filter(schema::fills::symbol.eq(&book.symbol))
+        .order_by(schema::fills::id.desc())
+        .select(schema::fills::price)
+        .first::<Decimal>(conn)
+        .optional()?;
+
+    for order in active_orders {
+        let Some(incoming) = incoming_order(&order) else {
+            warn!(
+                "Skipping order {} with invalid side {} or time in force {}",
+                order.id, order.side, order.time_in_force
+            );
+            continue;
+        };
+
+        match (order.status.as_str(), order.stop_price, incoming.limit) {
+            ("untriggered", Some(stop), _) => book.add_stop(StopOrder {
+                stop_price: stop,
+                order: incoming,
+            }),
+            (_, _, Some(limit)) => book.insert(BookOrder {
+                id: incoming.id,
+                user_id: incoming.user_id,
+                side: incoming.side,
+                price: limit,
+                remaining: incoming.quantity,
+                expires_at: incoming.expires_at,
+            }),
+            _ => warn!("Skipping order {} that cannot rest on the book", order.id),
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebuilds a book after a failed transaction so memory and storage never
+/// drift apart
+fn rebuild_book(conn: &mut PgConnection, book: &mut OrderBook, message: &str) {
+    error!(
+        "Failed to persist order changes, rebuilding {} book: {}",
+        book.symbol, message
+    );
+    if let Err(e) = restore_book(conn, book) {
+        error!("Failed to rebuild {} book: {}", book.symbol, e);
+    }
+}
+
+fn depth_snapshot(book: &OrderBook) -> feed::DepthChanged {
+    let (bids, asks) = book.depth(usize::MAX);
+    feed::DepthChanged {
+        symbol: book.symbol.clone(),
+        snapshot: true,
+        sequence: book.sequence(),
+        bids,
+        asks,
+    }
+}
+
+/// Sends what changed in a book to the real-time feed: its depth, the trades
+/// stored for it and the orders and balances those changes touched. Called
+/// with the book still locked once the changes are committed, so updates
+/// reach the feed in sequence order. A rebuilt book is sent as a snapshot.
+fn publish(
+    conn: &mut PgConnection,
+    book: &mut OrderBook,
+    feed: &Addr<FeedHub>,
+    trades: Vec<models::FillRecord>,
+) {
+    let changes = book.take_changes();
+    if changes.reset {
+        feed.do_send(depth_snapshot(book));
+    } else if !changes.bids.is_empty() || !changes.asks.is_empty() {
+        feed.do_send(feed::DepthChanged {
+            symbol: book.symbol.clone(),
+            snapshot: false,
+            sequence: changes.sequence,
+            bids: changes.bids,
+            asks: changes.asks,
+        });
+    }
+
+    if !trades.is_empty() {
+        feed.do_send(feed::TradesExecuted {
+            symbol: book.symbol.clone(),
+            trades,
+        });
+    }
+
+    if changes.orders.is_empty() {
+        return;
+    }
+    let updated = schema::orders::table
+        .filter(schema::orders::id.eq_any(&changes.orders))
+        .order_by(schema::orders::id.asc())
+        .load::<models::Order>(conn);
+    match updated {
+        Ok(updated) => {
+            let mut user_ids: Vec<i32> = updated.iter().map(|order| order.user_id).collect();
+            user_ids.sort_unstable();
+            user_ids.dedup();
+            feed.do_send(feed::OrdersUpdated { orders: updated });
+            feed.do_send(feed::BalancesChanged { user_ids });
+        }
+        Err(e) => error!("Failed to load updated orders for the feed: {}", e),
+    }
+}
+
+/// The matching-engine view of a stored order's unfilled quantity
+fn incoming_order(order: &models::Order) -> Option<IncomingOrder> {
+    Some(IncomingOrder {
+        id: order.id,
+        user_id: order.user_id,
+        side: Side::parse(&order.side)?,
+        limit: order.price,
+        quantity: order.quantity - order.filled_quantity,
+        time_in_force: TimeInForce::parse(&order.time_in_force)?,
+        post_only: order.post_only,
+        expires_at: order.expires_at,
+    })
+}
+
+/// Asset an order reserves while open: the quote asset for buys and the
+/// base asset for sells
+fn reserved_asset(order: &models::Order) -> Result<&str, OrderError> {
+    let (base, quote) =
+        split_symbol(&order.symbol).ok_or(OrderError::Internal("Invalid trading pair"))?;
+    match Side::parse(&order.side) {
+        Some(Side::Buy) => Ok(quote),
+        Some(Side::Sell) => Ok(base),
+        None => Err(OrderError::Internal("Invalid order side")),
+    }
+}
+
+/// Moves the funds an order needs into the owner's held balance: the base
+/// quantity for sells, the quote value at the limit price for limit buys and,
+/// for market buys, the quote cost of sweeping the asks currently on the book
+fn reserve(
+    conn: &mut PgConnection,
+    book: &OrderBook,
+    order: models::Order,
+    incoming: &IncomingOrder,
+) -> Result<models::Order, OrderError> {
+    use schema::orders::dsl::*;
+
+    let amount = match (incoming.side, incoming.limit) {
+        (Side::Sell, _) => incoming.quantity,
+        (Side::Buy, Some(limit)) => limit * incoming.quantity,
+        (Side::Buy, None) => book.market_buy_cost(incoming.quantity),
+    }
+    .round_dp_with_strategy(ledger::SCALE, RoundingStrategy::AwayFromZero);
+
+    // A market buy against an empty book reserves nothing and is rejected
+    // by the book
+    if amount.is_zero() {
+        return Ok(order);
+    }
+
+    ledger::hold(
+        conn,
+        order.user_id,
+        reserved_asset(&order)?,
+        amount,
+        &format!("order:{}", order.id),
+    )?;
+
+    Ok(diesel::update(orders.find(order.id))
+        .set(reserved.eq(reserved + amount))
+        .get_result::<models::Order>(conn)?)
+}
+
+/// Returns whatever an order still has reserved to the owner's available balance
+fn release_reserved(conn: &mut PgConnection, order: &models::Order) -> Result<(), OrderError> {
+    use schema::orders::dsl::*;
+
+    if order.reserved > Decimal::ZERO {
+        ledger::release(
+            conn,
+            order.user_id,
+            reserved_asset(order)?,
+            order.reserved,
+            &format!("order:{}", order.id),
+        )?;
+    }
+
+    diesel::update(orders.find(order.id))
+        .set(reserved.eq(Decimal::ZERO))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Stores a fill, books the exchange of assets between buyer and seller,
+/// charges both sides their trading fee and updates the maker order. Each
+/// side pays its fee in the asset it receives. Returns the amount of the
+/// taker's reservation that the fill consumed and the stored fill.
+fn settle_fill(
+    conn: &mut PgConnection,
+    pair: &str,
+    fill: &Fill,
+    taker_rates: FeeRates,
+) -> Result<(Decimal, models::FillRecord), OrderError> {
+    use schema::orders::dsl::*;
+
+    let (base, quote) = split_symbol(pair).ok_or(OrderError::Internal("Invalid trading pair"))?;
+
+    // The buyer never pays more than was reserved at their limit price
+    let notional = (fill.price * fill.quantity)
+        .round_dp_with_strategy(ledger::SCALE, RoundingStrategy::ToZero);
+
+    let maker_rates = fees::rates_for(conn, fill.maker_user_id)?;
+    let (maker_fee, maker_fee_asset, taker_fee, taker_fee_asset) = match fill.taker_side {
+        Side::Buy => (
+            fees::fee_amount(notional, maker_rates.maker_rate),
+            quote,
+            fees::fee_amount(fill.quantity, taker_rates.taker_rate),
+            base,
+        ),
+        Side::Sell => (
+            fees::fee_amount(fill.quantity, maker_rates.maker_rate),
+            base,
+            fees::fee_amount(notional, taker_rates.taker_rate),
+            quote,
+        ),
+    };
+
+    let record = diesel::insert_into(schema::fills::table)
+        .values(&models::NewFill {
+            symbol: pair.to_string(),
+            maker_order_id: fill.maker_order_id,
+            taker_order_id: fill.taker_order_id,
+            maker_user_id: fill.maker_user_id,
+            taker_user_id: fill.taker_user_id,
+            taker_side: fill.taker_side.as_str().to_string(),
+            price: fill.price,
+            quantity: fill.quantity,
+            maker_fee,
+            maker_fee_asset: maker_fee_asset.to_string(),
+            taker_fee,
+            taker_fee_asset: taker_fee_asset.to_string(),
+        })
+        .get_result::<models::FillRecord>(conn)?;
+
+    let (buyer, seller) = match fill.taker_side {
+        Side::Buy => (fill.taker_user_id, fill.maker_user_id),
+        Side::Sell => (fill.maker_user_id, fill.taker_user_id),
+    };
+
+    let reference = format!("fill:{}", record.id);
+    ledger::post_entry(
+        conn,
+        "trade",
+        &reference,
+        &[
+            Leg::user(buyer, quote, AccountKind::Held, -notional),
+            Leg::user(seller, quote, AccountKind::Available, notional),
+            Leg::user(seller, base, AccountKind::Held, -fill.quantity),
+            Leg::user(buyer, base, AccountKind::Available, fill.quantity),
+        ],
+    )?;
+    fees::collect(
+        conn,
+        &reference,
+        &[
+            (fill.maker_user_id, maker_fee_asset, maker_fee),
+            (fill.taker_user_id, taker_fee_asset, taker_fee),
+        ],
+    )?;
+
+    let (maker_consumed, taker_consumed) = match fill.taker_side {
+        Side::Buy => (fill.quantity, notional),
+        Side::Sell => (notional, fill.quantity),
+    };
+    let maker_status = if fill.maker_filled {
+        "filled"
+    } else {
+        "partially_filled"
+    };
+
+    let maker = diesel::update(orders.find(fill.maker_order_id))
+        .set((
+            filled_quantity.eq(filled_quantity + fill.quantity),
+            reserved.eq(reserved - maker_consumed),
+            status.eq(maker_status),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .get_result::<models::Order>(conn)?;
+
+    if fill.maker_filled {
+        release_reserved(conn, &maker)?;
+    }
+
+    Ok((taker_consumed, record))
+}
+
+/// Writes the fills produced by a match and updates every order they touched.
+/// A taker whose remainder did not rest on the book is done: it is filled,
+/// or cancelled if IOC or market quantity was left over, and whatever it
+/// still has reserved is released.
+fn persist_match(
+    conn: &mut PgConnection,
+    taker: &models::Order,
+    result: &MatchResult,
+) -> Result<(models::Order, Vec<models::FillRecord>), OrderError> {
+    use schema::orders::dsl::*;
+
+    let mut consumed = Decimal::ZERO;
+    let mut records = Vec::with_capacity(result.fills.len());
+    if !result.fills.is_empty() {
+        let taker_rates = fees::rates_for(conn, taker.user_id)?;
+        for fill in &result.fills {
+            let (taker_consumed, record) = settle_fill(conn, &taker.symbol, fill, taker_rates)?;
+            consumed += taker_consumed;
+            records.push(record);
+        }
+        candles::record_fills(conn, &taker.symbol, &records)?;
+    }
+
+    let taker_status = if result.remaining.is_zero() {
+        "filled"
+    } else if !result.resting {
+        "cancelled"
+    } else if result.fills.is_empty() {
+        "open"
+    } else {
+        "partially_filled"
+    };
+
+    let mut taker = diesel::update(orders.find(taker.id))
+        .set((
+            filled_quantity.eq(taker.quantity - result.remaining),
+            reserved.eq(reserved - consumed),
+            status.eq(taker_status),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .get_result::<models::Order>(conn)?;
+
+    if !result.resting {
+        release_reserved(conn, &taker)?;
+        taker = orders.find(taker.id).first::<models::Order>(conn)?;
+    }
+
+    Ok((taker, records))
+}
+
+/// Reserves funds for a live order, matches it against the book and stores
+/// the outcome, returning the updated order and its fills. Shared by newly
+/// placed orders and triggered stop orders.
+///
+/// Failing to reserve or a rejection by the book leaves the book untouched,
+/// but once the book has matched, an error storing the outcome leaves it
+/// ahead of storage. Callers restore it when that happens.
+fn execute(
+    conn: &mut PgConnection,
+    book: &mut OrderBook,
+    order: models::Order,
+    incoming: IncomingOrder,
+) -> Result<(models::Order, Vec<models::FillRecord>), OrderError> {
+    // Stop orders reserve when they are placed, except stop-market buys
+    // whose cost is only known once they trigger
+    let order = if order.reserved.is_zero() {
+        reserve(conn, book, order, &incoming)?
+    } else {
+        order
+    };
+
+    let result = book
+        .submit(incoming)
+        .map_err(|rejection| OrderError::Rejected(rejection.message().to_string()))?;
+
+    persist_match(conn, &order, &result)
+}
+
+/// Runs every stop order that the last trade price has reached through the
+/// normal matching path. Fills from triggered orders can trigger further
+/// stops, so this repeats until none are left. A triggered order that is
+/// rejected, for instance for lack of funds, is marked rejected with the
+/// reason and its reservation released. Returns the fills of triggered orders.
+fn process_triggers(
+    conn: &mut PgConnection,
+    book: &mut OrderBook,
+) -> Result<Vec<models::FillRecord>, OrderError> {
+    use schema::orders::dsl::*;
+
+    let mut fills = Vec::new();
+    loop {
+        let triggered = book.take_triggered();
+        if triggered.is_empty() {
+            return Ok(fills);
+        }
+
+        for stop in triggered {
+            let order = diesel::update(
+                orders
+                    .filter(id.eq(stop.order.id))
+                    .filter(status.eq("untriggered")),
+            )
+            .set((
+                status.eq("open"),
+                triggered_at.eq(diesel::dsl::now),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .get_result::<models::Order>(conn)?;
+
+            // The savepoint undoes a failed stop in storage; the snapshot
+            // undoes whatever it already matched in the book
+            let snapshot = book.clone();
+            let outcome = conn.transaction(|conn| execute(conn, book, order.clone(), stop.order));
+            if outcome.is_err() {
+                *book = snapshot;
+            }
+            let reason = match outcome {
+                Ok((order, records)) => {
+                    info!(
+                        "Stop order {} triggered at {} with {} fills",
+                        order.id,
+                        stop.stop_price,
+                        records.len()
+                    );
+                    fills.extend(records);
+                    continue;
+                }
+                Err(OrderError::Rejected(reason)) => reason,
+                Err(OrderError::InsufficientFunds) => {
+                    OrderError::InsufficientFunds.message().to_string()
+                }
+                Err(e) => return Err(e),
+            };
+
+            info!("Triggered stop order {} rejected: {}", order.id, reason);
+            let order = diesel::update(orders.find(order.id))
+                .set((
+                    status.eq("rejected"),
+                    reject_reason.eq(&reason),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .get_result::<models::Order>(conn)?;
+            release_reserved(conn, &order)?;
+        }
+    }
+}
+
+/// Removes orders that expired at or before `now` from the book, marks them
+/// expired and releases their reservations
+fn expire_orders(
+    conn: &mut PgConnection,
+    book: &mut OrderBook,
+    now: DateTime<Utc>,
+) -> Result<(), OrderError> {
+    use schema::orders::dsl::*;
+
+    let expired = book.take_expired(now);
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let outcome = conn.transaction::<_, OrderError, _>(|conn| {
+        for order_id in &expired {
+            let order = diesel::update(
+                orders
+                    .filter(id.eq(order_id))
+                    .filter(status.eq_any(OPEN_STATUSES)),
+            )
+            .set((status.eq("expired"), updated_at.eq(diesel::dsl::now)))
+            .get_result::<models::Order>(conn)
+            .optional()?;
+
+            if let Some(order) = order {
+                release_reserved(conn, &order)?;
+            }
+        }
+        Ok(())
+    });
+
+    match outcome {
+        Ok(()) => {
+            info!("Expired {} orders on {}", expired.len(), book.symbol);
+            Ok(())
+        }
+        Err(e) => {
+            rebuild_book(conn, book, e.message());
+            Err(e)
+        }
+    }
+}
+
+/// Checks that the fields of an order request fit its type and returns the
+/// effective time in force: GTC for orders with a limit price, IOC otherwise
+fn validate_request(
+    order_data: &models::PlaceOrderRequest,
+    now: DateTime<Utc>,
+) -> Result<TimeInForce, String> {
+    let order_type = order_data.order_type;
+
+    if order_data.quantity <= Decimal::ZERO {
+        return Err("Quantity must be positive".to_string());
+    }
+
+    match (order_type.has_limit(), order_data.price) {
+        (true, None) => {
+            return Err(format!(
+                "Price is required for {} orders",
+                order_type.as_str()
+            ));
+        }
+        (true, Some(price)) if price <= Decimal::ZERO => {
+            return Err("Price must be positive".to_string());
+        }
+        (false, Some(_)) => {
+            return Err(format!(
+                "Price is not accepted for {} orders, which trade at the best available price",
+                order_type.as_str()
+            ));
+        }
+        _ => {}
+    }
+
+    match (order_type.is_stop(), order_data.stop_price) {
+        (true, None) => return Err("Stop price is required for stop orders".to_string()),
+        (true, Some(stop)) if stop <= Decimal::ZERO => {
+            return Err("Stop price must be positive".to_string());
+        }
+        (false, Some(_)) => return Err("Only stop orders take a stop price".to_string()),
+        _ => {}
+    }
+
+    let time_in_force = order_data
+        .time_in_force
+        .unwrap_or(if order_type.has_limit() {
+            TimeInForce::Gtc
+        } else {
+            TimeInForce::Ioc
+        });
+
+    if !order_type.has_limit() && time_in_force.rests() {
+        return Err(format!(
+            "Time in force must be 'ioc' or 'fok' for {} orders",
+            order_type.as_str()
+        ));
+    }
+
+    if order_data.post_only {
+        if !order_type.has_limit() {
+            return Err(format!(
+                "Post-only is not available for {} orders",
+                order_type.as_str()
+            ));
+        }
+        if !time_in_force.rests() {
+            return Err("Post-only orders must be GTC or GTD".to_string());
+        }
+    }
+
+    match (time_in_force, order_data.expires_at) {
+        (TimeInForce::Gtd, None) => {
+            return Err("Expiry time is required for GTD orders".to_string());
+        }
+        (TimeInForce::Gtd, Some(expires_at)) if expires_at <= now => {
+            return Err("Expiry time must be in the future".to_string());
+        }
+        (TimeInForce::Gtc | TimeInForce::Ioc | TimeInForce::Fok, Some(_)) => {
+            return Err("Only GTD orders take an expiry time".to_string());
+        }
+        _ => {}
+    }
+
+    Ok(time_in_force)
+}
+
+/// Quote value of an order, counted against the user's trading limit when it
+/// is placed. Market orders are valued at the last trade price, or at the
+/// best price they could trade at if the book has not traded yet.
+fn order_value(book: &OrderBook, order_data: &models::PlaceOrderRequest) -> Option<Decimal> {
+    let price = order_data
+        .price
+        .or(order_data.stop_price)
+        .or(book.last_price)
+        .or(match order_data.side {
+            Side::Buy => book.best_ask(),
+            Side::Sell => book.best_bid(),
+        })?;
+    Some((price * order_data.quantity).round_dp(ledger::SCALE))
+}
+
+/// Places an order and matches it against the book
+///
+/// Supported order types are `limit`, `market`, `stop_limit` and
+/// `stop_market`, with time in force `gtc`, `ioc`, `fok` or `gtd` (which
+/// needs `expires_at`). Post-only limit orders are rejected if they would
+/// match immediately. Stop orders wait off the book until the last trade
+/// price reaches their stop price, then go through the same matching path as
+/// any other order.
+///
+/// The order's value must fit in the user's trading limits. Funds for the
+/// order are moved to the user's held balance before matching: the quote
+/// amount at the limit price for buys, the base quantity for sells.
+/// The order is matched under the book lock and the resulting fills, and any
+/// stop orders they trigger, are written in the same database transaction as
+/// the order itself. If that transaction fails, the book is rebuilt from
+/// Postgres so memory and storage never drift apart.
+///
+/// # Returns
+/// The stored order together with any fills it produced
+#[post("/orders")]
+pub async fn place_order(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    exchange: web::Data<Exchange>,
+    feed: web::Data<Addr<FeedHub>>,
+    order_data: web::Json<models::PlaceOrderRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id_for(&req, Scope::Trade)?;
+    let order_data = order_data.into_inner();
+    let now = Utc::now();
+
+    // Trading needs a verified email address
+    email_verification::require_verified_email(&pool, current_user_id).await?;
+
+    let time_in_force = validate_request(&order_data, now).map_err(OrderError::Rejected)?;
+
+    let mut conn = pool.get()?;
+
+    let (order, fills) = web::block(move || -> Result<_, OrderError> {
+        let mut books = exchange
+            .books
+            .lock()
+            .map_err(|_| OrderError::Internal("Failed to acquire order book lock"))?;
+        let book = books
+            .get_mut(&order_data.symbol)
+            .ok_or(OrderError::UnknownSymbol)?;
+
+        // Expired orders must leave the book before anything can trade with them
+        if let Err(e) = expire_orders(&mut conn, book, now) {
+            publish(&mut conn, book, &feed, Vec::new());
+            return Err(e);
+        }
+
+        let order_type = order_data.order_type;
+        let outcome = conn.transaction::<_, OrderError, _>(|conn| {
+            let instrument = schema::instruments::table
+                .filter(schema::instruments::symbol.eq(&order_data.symbol))
+                .first::<models::Instrument>(conn)
+                .optional()?
+                .ok_or(OrderError::UnknownSymbol)?;
+
+            match instrument.status.as_str() {
+                "halted" => {
+                    return Err(OrderError::Rejected(format!(
+                        "Trading is halted for {}",
+                        instrument.symbol
+                    )));
+                }
+                "post_only" if order_type != OrderType::Limit => {
+                    return Err(OrderError::Rejected(format!(
+                        "{} is in post-only mode and only accepts limit orders",
+                        instrument.symbol
+                    )));
+                }
+                "post_only"
+                    if order_data
+                        .price
+                        .is_some_and(|price| book.would_cross(order_data.side, price)) =>
+                {
+                    return Err(OrderError::Rejected(format!(
+                        "{} is in post-only mode and this order would match immediately",
+                        instrument.symbol
+                    )));
+                }
+                _ => {}
+            }
+
+            instruments::validate_order(
+                &instrument,
+                order_data.price,
+                order_data.stop_price,
+                order_data.quantity,
+            )
+            .map_err(OrderError::Rejected)?;
+
+            if let Some(value) = order_value(book, &order_data) {
+                limits::check(
+                    conn,
+                    current_user_id,
+                    Activity::Trading,
+                    &instrument.quote_asset,
+                    value,
+                )?;
+            }
+
+            if let Some(stop) = order_data.stop_price
+                && book.would_trigger(order_data.side, stop)
+            {
+                return Err(OrderError::Rejected(format!(
+                    "Stop price would trigger immediately: last trade price is {}",
+                    book.last_price.unwrap_or_default().normalize()
+                )));
+            }
+
+            let order = diesel::insert_into(schema::orders::table)
+                .values(&models::NewOrder {
+                    user_id: current_user_id,
+                    symbol: order_data.symbol.clone(),
+                    side: order_data.side.as_str().to_string(),
+                    price: order_data.price,
+                    quantity: order_data.quantity,
+                    reserved: Decimal::ZERO,
+                    status: if order_type.is_stop() {
+                        "untriggered".to_string()
+                    } else {
+                        "open".to_string()
+                    },
+                    order_type: order_type.as_str().to_string(),
+                    time_in_force: time_in_force.as_str().to_string(),
+                    post_only: order_data.post_only,
+                    stop_price: order_data.stop_price,
+                    expires_at: order_data.expires_at,
+                })
+                .get_result::<models::Order>(conn)?;
+
+            let incoming = incoming_order(&order).ok_or(OrderError::Internal("Invalid order"))?;
+
+            if let Some(stop) = order_data.stop_price {
+                // The cost of a stop-market buy is only known once it triggers
+                let order = if order_type == OrderType::StopMarket && order_data.side == Side::Buy {
+                    order
+                } else {
+                    reserve(conn, book, order, &incoming)?
+                };
+
+                book.add_stop(StopOrder {
+                    stop_price: stop,
+                    order: incoming,
+                });
+                return Ok((order, Vec::new(), Vec::new()));
+            }
+
+            let (order, records) = execute(conn, book, order, incoming)?;
+            let triggered = process_triggers(conn, book)?;
+
+            // Triggered stops may have traded against this order
+            let order = schema::orders::table
+                .find(order.id)
+                .first::<models::Order>(conn)?;
+            Ok((order, records, triggered))
+        });
+
+        let (result, trades) = match outcome {
+            Ok((order, records, triggered)) => {
+                let trades = records.iter().cloned().chain(triggered).collect();
+                (Ok((order, records)), trades)
+            }
+            Err(OrderError::Internal(message)) => {
+                rebuild_book(&mut conn, book, message);
+                (
+                    Err(OrderError::Internal("Failed to place order")),
+                    Vec::new(),
+                )
+            }
+            // The book may have matched before storing the outcome failed
+            Err(e) if book.has_changes() => {
+                rebuild_book(&mut conn, book, e.message());
+                (Err(e), Vec::new())
+            }
+            Err(e) => (Err(e), Vec::new()),
+        };
+
+        publish(&mut conn, book, &feed, trades);
+        result
+    })
+    .await??;
+
+    info!(
+        "{} order {} placed on {} with {} fills",
+        order.order_type,
+        order.id,
+        order.symbol,
+        fills.len()
+    );
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "order": order,
+        "fills": fills
+    })))
+}
+
+/// Cancels one of the authenticated user's open orders, removes it from the
+/// book and releases its remaining reservation
+#[delete("/orders/{order_id}")]
+pub async fn cancel_order(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    pool: web::Data<db::DbPool>,
+    exchange: web::Data<Exchange>,
+    feed: web::Data<Addr<FeedHub>>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id_for(&req, Scope::Trade)?;
+    let order_id = path.into_inner();
+
+    let mut conn = pool.get()?;
+
+    let order = web::block(move || -> Result<models::Order, OrderError> {
+        use schema::orders::dsl::*;
+
+        let mut books = exchange
+            .books
+            .lock()
+            .map_err(|_| OrderError::Internal("Failed to acquire order book lock"))?;
+
+        let order = conn.transaction::<_, OrderError, _>(|conn| {
+            // The status filter makes the update a no-op for orders that were
+            // filled or cancelled in the meantime
+            let order = diesel::update(
+                orders
+                    .filter(id.eq(order_id))
+                    .filter(user_id.eq(current_user_id))
+                    .filter(status.eq_any(OPEN_STATUSES)),
+            )
+            .set((status.eq("cancelled"), updated_at.eq(diesel::dsl::now)))
+            .get_result::<models::Order>(conn)
+            .optional()?
+            .ok_or(OrderError::NotFound)?;
+
+            release_reserved(conn, &order)?;
+            Ok(orders.find(order.id).first::<models::Order>(conn)?)
+        })?;
+
+        if let Some(book) = books.get_mut(&order.symbol) {
+            book.cancel(order.id);
+            publish(&mut conn, book, &feed, Vec::new());
+        }
+
+        Ok(order)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Order cancelled",
+        "order": order
+    })))
+}
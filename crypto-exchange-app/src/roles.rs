@@ -0,0 +1,358 @@
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::models;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, get, put, web};
+use diesel::dsl::exists;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+/// The role that holds every permission, including granting roles
+pub const SUPER_ADMIN: &str = "super_admin";
+
+/// Permissions checked by admin routes. Their names match the
+/// `permissions` table, and roles are granted them in `role_permissions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    UsersRead,
+    UsersManage,
+    RolesManage,
+    KycRead,
+    KycReview,
+    FundsDeposit,
+    FundsWithdrawals,
+    FeesRead,
+    FeesManage,
+    InstrumentsManage,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::UsersRead => "users.read",
+            Permission::UsersManage => "users.manage",
+            Permission::RolesManage => "roles.manage",
+            Permission::KycRead => "kyc.read",
+            Permission::KycReview => "kyc.review",
+            Permission::FundsDeposit => "funds.deposit",
+            Permission::FundsWithdrawals => "funds.withdrawals",
+            Permission::FeesRead => "fees.read",
+            Permission::FeesManage => "fees.manage",
+            Permission::InstrumentsManage => "instruments.manage",
+        }
+    }
+}
+
+/// Errors that can occur while changing a user's roles
+enum RoleError {
+    NotFound,
+    UnknownRole(String),
+    LastSuperAdmin,
+    Database(diesel::result::Error),
+}
+
+impl From<RoleError> for AppError {
+    fn from(e: RoleError) -> Self {
+        match e {
+            RoleError::NotFound => AppError::not_found("User not found"),
+            RoleError::UnknownRole(name) => {
+                AppError::bad_request(format!("Unknown role '{}'", name))
+            }
+            RoleError::LastSuperAdmin => {
+                AppError::bad_request("The last super admin cannot lose the role")
+            }
+            RoleError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for RoleError {
+    fn from(e: diesel::result::Error) -> Self {
+        RoleError::Database(e)
+    }
+}
+
+pub fn has_permission(
+    conn: &mut PgConnection,
+    owner: i32,
+    permission: Permission,
+) -> QueryResult<bool> {
+    use schema::{permissions, role_permissions, user_roles};
+
+    diesel::select(exists(
+        user_roles::table
+            .inner_join(
+                role_permissions::table.on(role_permissions::role_id.eq(user_roles::role_id)),
+            )
+            .inner_join(permissions::table.on(permissions::id.eq(role_permissions::permission_id)))
+            .filter(user_roles::user_id.eq(owner))
+            .filter(permissions::name.eq(permission.as_str())),
+    ))
+    .get_result(conn)
+}
+
+/// Names of the permissions a user holds through any of their roles
+pub fn permission_names(conn: &mut PgConnection, owner: i32) -> QueryResult<Vec<String>> {
+    use schema::{permissions, role_permissions, user_roles};
+
+    user_roles::table
+        .inner_join(role_permissions::table.on(role_permissions::role_id.eq(user_roles::role_id)))
+        .inner_join(permissions::table.on(permissions::id.eq(role_permissions::permission_id)))
+        .filter(user_roles::user_id.eq(owner))
+        .select(permissions::name)
+        .distinct()
+        .order(permissions::name.asc())
+        .load(conn)
+}
+
+pub fn role_names(conn: &mut PgConnection, owner: i32) -> QueryResult<Vec<String>> {
+    use schema::{roles, user_roles};
+
+    user_roles::table
+        .inner_join(roles::table)
+        .filter(user_roles::user_id.eq(owner))
+        .select(roles::name)
+        .order(roles::name.asc())
+        .load(conn)
+}
+
+/// Role names for several users at once, keyed by user id. Users without
+/// roles are left out.
+pub fn role_names_by_user(
+    conn: &mut PgConnection,
+    owners: &[i32],
+) -> QueryResult<HashMap<i32, Vec<String>>> {
+    use schema::{roles, user_roles};
+
+    let mut by_user: HashMap<i32, Vec<String>> = HashMap::new();
+    for (owner, role) in user_roles::table
+        .inner_join(roles::table)
+        .filter(user_roles::user_id.eq_any(owners))
+        .select((user_roles::user_id, roles::name))
+        .order(roles::name.asc())
+        .load::<(i32, String)>(conn)?
+    {
+        by_user.entry(owner).or_default().push(role);
+    }
+    Ok(by_user)
+}
+
+/// How much authority a set of roles carries: super admins outrank other
+/// staff, who outrank regular users
+fn rank(roles: &[String]) -> u8 {
+    if roles.iter().any(|role| role == SUPER_ADMIN) {
+        2
+    } else if roles.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Checks that an admin may edit another user's account. Nobody may edit
+/// an account that outranks their own. Taking over a staff account hands
+/// out its permissions, so changing the email or password of anyone with a
+/// role needs `roles.manage`, or a super admin editing lower-ranked staff.
+pub fn check_account_edit(
+    conn: &mut PgConnection,
+    editor: i32,
+    owner: i32,
+    credentials: bool,
+) -> Result<(), AppError> {
+    let editor_roles = role_names(conn, editor)?;
+    let editor_rank = rank(&editor_roles);
+    let owner_rank = rank(&role_names(conn, owner)?);
+
+    if owner_rank > editor_rank {
+        return Err(AppError::forbidden(
+            "You cannot edit an account with more access than your own",
+        ));
+    }
+    if credentials
+        && owner_rank > 0
+        && !(editor_roles.iter().any(|role| role == SUPER_ADMIN) && owner_rank < editor_rank)
+        && !has_permission(conn, editor, Permission::RolesManage)?
+    {
+        return Err(AppError::forbidden(
+            "Changing the email or password of a staff account requires roles.manage",
+        ));
+    }
+    Ok(())
+}
+
+/// Builds the public view of a user, including their roles
+pub fn user_response(
+    conn: &mut PgConnection,
+    user: models::User,
+) -> QueryResult<models::UserResponse> {
+    let roles = role_names(conn, user.id)?;
+    Ok(models::UserResponse::new(user, roles))
+}
+
+/// Builds the public view of several users, including their roles
+pub fn user_responses(
+    conn: &mut PgConnection,
+    users: Vec<models::User>,
+) -> QueryResult<Vec<models::UserResponse>> {
+    let ids: Vec<i32> = users.iter().map(|user| user.id).collect();
+    let mut by_user = role_names_by_user(conn, &ids)?;
+    Ok(users
+        .into_iter()
+        .map(|user| {
+            let roles = by_user.remove(&user.id).unwrap_or_default();
+            models::UserResponse::new(user, roles)
+        })
+        .collect())
+}
+
+/// Replaces a user's roles with the given ones and returns them. Removing a
+/// role ends the user's sessions, and the last super admin cannot lose the
+/// role.
+fn assign_roles(
+    conn: &mut PgConnection,
+    owner: i32,
+    names: &[String],
+    granted_by: i32,
+) -> Result<Vec<String>, RoleError> {
+    use schema::{roles, user_roles};
+
+    conn.transaction(|conn| {
+        let user_exists =
+            diesel::select(exists(schema::users::table.find(owner))).get_result::<bool>(conn)?;
+        if !user_exists {
+            return Err(RoleError::NotFound);
+        }
+
+        let wanted = roles::table
+            .filter(roles::name.eq_any(names))
+            .load::<models::Role>(conn)?;
+        if let Some(unknown) = names
+            .iter()
+            .find(|name| !wanted.iter().any(|role| &role.name == *name))
+        {
+            return Err(RoleError::UnknownRole(unknown.clone()));
+        }
+
+        let current = role_names(conn, owner)?;
+        if current.iter().any(|role| role == SUPER_ADMIN)
+            && !names.iter().any(|role| role == SUPER_ADMIN)
+        {
+            // Lock the super admins so two of them cannot demote each other at once
+            let super_admins = user_roles::table
+                .inner_join(roles::table)
+                .filter(roles::name.eq(SUPER_ADMIN))
+                .select(user_roles::user_id)
+                .for_update()
+                .load::<i32>(conn)?;
+            if super_admins.iter().all(|admin| *admin == owner) {
+                return Err(RoleError::LastSuperAdmin);
+            }
+        }
+
+        let wanted_ids: Vec<i32> = wanted.iter().map(|role| role.id).collect();
+        diesel::delete(
+            user_roles::table
+                .filter(user_roles::user_id.eq(owner))
+                .filter(user_roles::role_id.ne_all(&wanted_ids)),
+        )
+        .execute(conn)?;
+
+        let granted: Vec<models::NewUserRole> = wanted_ids
+            .iter()
+            .map(|role_id| models::NewUserRole {
+                user_id: owner,
+                role_id: *role_id,
+                granted_by: Some(granted_by),
+            })
+            .collect();
+        diesel::insert_into(user_roles::table)
+            .values(&granted)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        if current.iter().any(|role| !names.contains(role)) {
+            auth::revoke_all_sessions(conn, owner)?;
+        }
+
+        Ok(role_names(conn, owner)?)
+    })
+}
+
+/// Async wrapper around `assign_roles` for admin handlers
+pub async fn set_roles(
+    pool: &db::DbPool,
+    owner: i32,
+    names: Vec<String>,
+    granted_by: i32,
+) -> Result<Vec<String>, AppError> {
+    let mut conn = pool.get()?;
+    Ok(web::block(move || assign_roles(&mut conn, owner, &names, granted_by)).await??)
+}
+
+/// Lists the staff roles and the permissions each grants
+#[get("/roles")]
+pub async fn admin_list_roles(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::UsersRead).await?;
+
+    let mut conn = pool.get()?;
+
+    let (all_roles, grants) = web::block(move || {
+        use schema::{permissions, role_permissions, roles};
+
+        let all_roles = roles::table
+            .order(roles::id.asc())
+            .load::<models::Role>(&mut conn)?;
+        let grants = role_permissions::table
+            .inner_join(permissions::table)
+            .select((role_permissions::role_id, permissions::name))
+            .order(permissions::name.asc())
+            .load::<(i32, String)>(&mut conn)?;
+        Ok::<_, diesel::result::Error>((all_roles, grants))
+    })
+    .await??;
+
+    let roles: Vec<serde_json::Value> = all_roles
+        .into_iter()
+        .map(|role| {
+            let permissions: Vec<&String> = grants
+                .iter()
+                .filter(|(role_id, _)| *role_id == role.id)
+                .map(|(_, permission)| permission)
+                .collect();
+            serde_json::json!({
+                "name": role.name,
+                "description": role.description,
+                "permissions": permissions
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "roles": roles })))
+}
+
+/// Replaces a user's roles. An empty list makes them a regular user again.
+#[put("/users/{user_id}/roles")]
+pub async fn admin_set_user_roles(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    pool: web::Data<db::DbPool>,
+    roles_data: web::Json<models::UserRolesRequest>,
+) -> Result<HttpResponse, AppError> {
+    let admin_id = auth::require_permission(&req, &pool, Permission::RolesManage).await?;
+
+    let user_id = path.into_inner();
+    let mut names = roles_data.into_inner().roles;
+    names.sort();
+    names.dedup();
+
+    let roles = set_roles(&pool, user_id, names, admin_id).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Roles updated",
+        "user_id": user_id,
+        "roles": roles
+    })))
+}
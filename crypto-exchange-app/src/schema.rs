@@ -1,5 +1,234 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_key_nonces (api_key_id, nonce) {
+        api_key_id -> Int4,
+        #[max_length = 64]
+        nonce -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    api_keys (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 100]
+        label -> Varchar,
+        #[max_length = 32]
+        key -> Varchar,
+        #[max_length = 64]
+        secret -> Varchar,
+        scopes -> Array<Text>,
+        allowed_ips -> Array<Text>,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    candles (id) {
+        id -> Int4,
+        #[max_length = 30]
+        symbol -> Varchar,
+        #[max_length = 3]
+        resolution -> Varchar,
+        open_time -> Timestamptz,
+        open -> Numeric,
+        high -> Numeric,
+        low -> Numeric,
+        close -> Numeric,
+        volume -> Numeric,
+        trade_count -> Int4,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used -> Bool,
+        #[max_length = 255]
+        email -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    fee_tiers (id) {
+        id -> Int4,
+        min_volume -> Numeric,
+        maker_rate -> Numeric,
+        taker_rate -> Numeric,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    fills (id) {
+        id -> Int4,
+        #[max_length = 20]
+        symbol -> Varchar,
+        maker_order_id -> Int4,
+        taker_order_id -> Int4,
+        maker_user_id -> Int4,
+        taker_user_id -> Int4,
+        #[max_length = 4]
+        taker_side -> Varchar,
+        price -> Numeric,
+        quantity -> Numeric,
+        created_at -> Timestamptz,
+        maker_fee -> Numeric,
+        #[max_length = 20]
+        maker_fee_asset -> Varchar,
+        taker_fee -> Numeric,
+        #[max_length = 20]
+        taker_fee_asset -> Varchar,
+    }
+}
+
+diesel::table! {
+    instruments (id) {
+        id -> Int4,
+        #[max_length = 20]
+        symbol -> Varchar,
+        #[max_length = 20]
+        base_asset -> Varchar,
+        #[max_length = 20]
+        quote_asset -> Varchar,
+        price_tick -> Numeric,
+        quantity_step -> Numeric,
+        min_quantity -> Numeric,
+        max_quantity -> Numeric,
+        min_notional -> Numeric,
+        #[max_length = 20]
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    journal_entries (id) {
+        id -> Int4,
+        #[max_length = 30]
+        kind -> Varchar,
+        #[max_length = 100]
+        reference -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    journal_postings (id) {
+        id -> Int4,
+        entry_id -> Int4,
+        account_id -> Int4,
+        #[max_length = 20]
+        asset -> Varchar,
+        amount -> Numeric,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    kyc_document_access_log (id) {
+        id -> Int4,
+        document_id -> Int4,
+        accessed_by -> Nullable<Int4>,
+        #[max_length = 64]
+        request_id -> Nullable<Varchar>,
+        #[max_length = 45]
+        ip_address -> Nullable<Varchar>,
+        #[max_length = 512]
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    kyc_documents (id) {
+        id -> Int4,
+        verification_id -> Int4,
+        #[max_length = 30]
+        document_type -> Varchar,
+        #[max_length = 255]
+        storage_key -> Varchar,
+        #[max_length = 100]
+        content_type -> Varchar,
+        #[max_length = 20]
+        review_status -> Varchar,
+        reviewer_notes -> Nullable<Text>,
+        reviewed_by -> Nullable<Int4>,
+        reviewed_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    kyc_status_history (id) {
+        id -> Int4,
+        verification_id -> Int4,
+        #[max_length = 50]
+        from_status -> Nullable<Varchar>,
+        #[max_length = 50]
+        to_status -> Varchar,
+        reason -> Nullable<Text>,
+        changed_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    ledger_accounts (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        #[max_length = 20]
+        asset -> Varchar,
+        #[max_length = 20]
+        kind -> Varchar,
+        balance -> Numeric,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    orders (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 20]
+        symbol -> Varchar,
+        #[max_length = 4]
+        side -> Varchar,
+        price -> Nullable<Numeric>,
+        quantity -> Numeric,
+        filled_quantity -> Numeric,
+        #[max_length = 20]
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        reserved -> Numeric,
+        #[max_length = 20]
+        order_type -> Varchar,
+        #[max_length = 3]
+        time_in_force -> Varchar,
+        post_only -> Bool,
+        stop_price -> Nullable<Numeric>,
+        expires_at -> Nullable<Timestamptz>,
+        triggered_at -> Nullable<Timestamptz>,
+        #[max_length = 255]
+        reject_reason -> Nullable<Varchar>,
+    }
+}
+
 diesel::table! {
     password_reset_tokens (id) {
         id -> Int4,
@@ -11,6 +240,123 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    permissions (id) {
+        id -> Int4,
+        #[max_length = 50]
+        name -> Varchar,
+        #[max_length = 255]
+        description -> Varchar,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 36]
+        family_id -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        #[max_length = 36]
+        access_jti -> Varchar,
+        access_expires_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+        replaced_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    revoked_tokens (jti) {
+        #[max_length = 36]
+        jti -> Varchar,
+        user_id -> Int4,
+        expires_at -> Timestamptz,
+        revoked_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Int4,
+        permission_id -> Int4,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Int4,
+        #[max_length = 50]
+        name -> Varchar,
+        #[max_length = 255]
+        description -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 36]
+        family_id -> Varchar,
+        #[max_length = 45]
+        ip_address -> Nullable<Varchar>,
+        #[max_length = 512]
+        user_agent -> Nullable<Varchar>,
+        #[max_length = 45]
+        last_ip_address -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    totp_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_fee_overrides (user_id) {
+        user_id -> Int4,
+        maker_rate -> Numeric,
+        taker_rate -> Numeric,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_roles (user_id, role_id) {
+        user_id -> Int4,
+        role_id -> Int4,
+        granted_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_totp (user_id) {
+        user_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        enabled_at -> Nullable<Timestamptz>,
+        last_used_step -> Nullable<Int8>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     user_verifications (id) {
         id -> Int4,
@@ -42,9 +388,9 @@ diesel::table! {
         updated_at -> Timestamptz,
         #[max_length = 255]
         id_front_path -> Nullable<Varchar>,
-        #[max_length = 50]
-        id_verification_status -> Varchar,
-        id_verified_at -> Nullable<Timestamptz>,
+        status_reason -> Nullable<Text>,
+        submitted_at -> Nullable<Timestamptz>,
+        reviewed_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -58,15 +404,78 @@ diesel::table! {
         #[max_length = 255]
         password -> Varchar,
         created_at -> Timestamptz,
-        is_admin -> Bool,
+        email_verified_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    withdrawals (id) {
+        id -> Int4,
+        user_id -> Int4,
+        #[max_length = 20]
+        asset -> Varchar,
+        amount -> Numeric,
+        #[max_length = 255]
+        address -> Varchar,
+        #[max_length = 20]
+        status -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
     }
 }
 
+diesel::joinable!(api_key_nonces -> api_keys (api_key_id));
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(email_verification_tokens -> users (user_id));
+diesel::joinable!(journal_postings -> journal_entries (entry_id));
+diesel::joinable!(journal_postings -> ledger_accounts (account_id));
+diesel::joinable!(kyc_document_access_log -> kyc_documents (document_id));
+diesel::joinable!(kyc_document_access_log -> users (accessed_by));
+diesel::joinable!(kyc_documents -> user_verifications (verification_id));
+diesel::joinable!(kyc_documents -> users (reviewed_by));
+diesel::joinable!(kyc_status_history -> user_verifications (verification_id));
+diesel::joinable!(kyc_status_history -> users (changed_by));
+diesel::joinable!(ledger_accounts -> users (user_id));
+diesel::joinable!(orders -> users (user_id));
 diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(role_permissions -> permissions (permission_id));
+diesel::joinable!(role_permissions -> roles (role_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(totp_recovery_codes -> users (user_id));
+diesel::joinable!(user_fee_overrides -> users (user_id));
+diesel::joinable!(user_roles -> roles (role_id));
+diesel::joinable!(user_totp -> users (user_id));
 diesel::joinable!(user_verifications -> users (user_id));
+diesel::joinable!(withdrawals -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    api_key_nonces,
+    api_keys,
+    candles,
+    email_verification_tokens,
+    fee_tiers,
+    fills,
+    instruments,
+    journal_entries,
+    journal_postings,
+    kyc_document_access_log,
+    kyc_documents,
+    kyc_status_history,
+    ledger_accounts,
+    orders,
     password_reset_tokens,
+    permissions,
+    refresh_tokens,
+    revoked_tokens,
+    role_permissions,
+    roles,
+    sessions,
+    totp_recovery_codes,
+    user_fee_overrides,
+    user_roles,
+    user_totp,
     user_verifications,
     users,
+    withdrawals,
 );
@@ -0,0 +1,243 @@
+use crate::auth;
+use crate::db;
+use crate::email;
+use crate::error::AppError;
+use crate::models;
+use crate::roles::Permission;
+use crate::schema;
+use actix_web::{HttpRequest, HttpResponse, delete, get, http::header, web};
+use chrono::{DateTime, Utc};
+use diesel::dsl::exists;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{error, info};
+
+/// Longest user agent stored for a session
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// Where a request came from, as recorded for its session
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> ClientInfo {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect());
+
+        ClientInfo {
+            ip_address: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent,
+        }
+    }
+}
+
+/// Records a new session for a refresh token family. Returns true when the
+/// user has logged in before but never from this device or IP address.
+pub fn record(
+    conn: &mut PgConnection,
+    owner: i32,
+    family: &str,
+    client: &ClientInfo,
+    expires: DateTime<Utc>,
+) -> QueryResult<bool> {
+    use schema::sessions::dsl::*;
+
+    let earlier = sessions.filter(user_id.eq(owner));
+    let (has_earlier, seen_ip, seen_device) = diesel::select((
+        exists(earlier),
+        exists(earlier.filter(ip_address.eq(&client.ip_address))),
+        exists(earlier.filter(user_agent.eq(&client.user_agent))),
+    ))
+    .get_result::<(bool, bool, bool)>(conn)?;
+
+    diesel::insert_into(sessions)
+        .values(&models::NewSession {
+            user_id: owner,
+            family_id: family.to_string(),
+            ip_address: client.ip_address.clone(),
+            user_agent: client.user_agent.clone(),
+            last_ip_address: client.ip_address.clone(),
+            expires_at: expires,
+        })
+        .execute(conn)?;
+
+    Ok(has_earlier && !(seen_ip && seen_device))
+}
+
+/// Notes that a session was just used to refresh its tokens
+pub fn touch(
+    conn: &mut PgConnection,
+    family: &str,
+    client: &ClientInfo,
+    expires: DateTime<Utc>,
+) -> QueryResult<()> {
+    use schema::sessions::dsl::*;
+
+    diesel::update(sessions.filter(family_id.eq(family)))
+        .set((
+            last_seen_at.eq(Utc::now()),
+            last_ip_address.eq(&client.ip_address),
+            expires_at.eq(expires),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Marks the sessions of revoked refresh token families as ended
+pub fn mark_revoked(conn: &mut PgConnection, families: &[String]) -> QueryResult<()> {
+    use schema::sessions::dsl::*;
+
+    diesel::update(
+        sessions
+            .filter(family_id.eq_any(families))
+            .filter(revoked_at.is_null()),
+    )
+    .set(revoked_at.eq(Utc::now()))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Emails a user about a login from a device or IP address they have not
+/// used before. Sent in the background so the login is not held up.
+pub fn alert_new_login(address: String, client: ClientInfo) {
+    actix_web::rt::spawn(async move {
+        let ip = client.ip_address.as_deref().unwrap_or("unknown");
+        let agent = client.user_agent.as_deref().unwrap_or("unknown");
+
+        if let Err(err) = email::send_new_login_email(&address, ip, agent, Utc::now()).await {
+            error!("Failed to send new login alert: {}", err);
+            info!(
+                "New login alert for {} from {} using {}",
+                address, ip, agent
+            );
+        }
+    });
+}
+
+/// Sessions of a user, newest first, optionally including ended ones
+fn load_sessions(
+    conn: &mut PgConnection,
+    owner: i32,
+    include_ended: bool,
+) -> QueryResult<Vec<models::Session>> {
+    use schema::sessions::dsl::*;
+
+    let mut query = sessions
+        .filter(user_id.eq(owner))
+        .order(last_seen_at.desc())
+        .into_boxed();
+    if !include_ended {
+        query = query
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(Utc::now()));
+    }
+    query.load::<models::Session>(conn)
+}
+
+/// The session an access token was issued to
+fn family_of(conn: &mut PgConnection, jti: &str) -> QueryResult<Option<String>> {
+    use schema::refresh_tokens::dsl::*;
+
+    refresh_tokens
+        .filter(access_jti.eq(jti))
+        .select(family_id)
+        .first::<String>(conn)
+        .optional()
+}
+
+/// Lists the devices the current user is logged in on
+#[get("/sessions")]
+pub async fn list_sessions(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    // Sessions are managed from a login session, never with an API key
+    let claims = auth::authenticate(&req)?;
+    let current_user_id = claims.user_id()?;
+
+    let mut conn = pool.get()?;
+
+    let (current_family, active) = web::block(move || {
+        let current_family = family_of(&mut conn, &claims.jti)?;
+        let active = load_sessions(&mut conn, current_user_id, false)?;
+        Ok::<_, diesel::result::Error>((current_family, active))
+    })
+    .await??;
+
+    let sessions: Vec<serde_json::Value> = active
+        .into_iter()
+        .map(|session| {
+            let current = current_family.as_deref() == Some(session.family_id.as_str());
+            let mut value = serde_json::json!(session);
+            value["current"] = serde_json::Value::Bool(current);
+            value
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "sessions": sessions })))
+}
+
+/// Logs the current user out of one of their sessions
+#[delete("/sessions/{session_id}")]
+pub async fn revoke_session(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let current_user_id = auth::authenticate(&req)?.user_id()?;
+    let target_id = path.into_inner();
+
+    let mut conn = pool.get()?;
+
+    let revoked = web::block(move || {
+        use schema::sessions::dsl::*;
+
+        conn.transaction(|conn| {
+            let family = sessions
+                .filter(id.eq(target_id))
+                .filter(user_id.eq(current_user_id))
+                .filter(revoked_at.is_null())
+                .select(family_id)
+                .first::<String>(conn)
+                .optional()?;
+            match family {
+                Some(family) => auth::revoke_families(conn, &[family]).map(|_| true),
+                None => Ok(false),
+            }
+        })
+    })
+    .await??;
+
+    if !revoked {
+        return Err(AppError::not_found("Session not found"));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Session revoked"
+    })))
+}
+
+/// Every session a user has had, including ended ones, for support staff
+/// looking into a compromised account
+#[get("/users/{user_id}/sessions")]
+pub async fn admin_list_user_sessions(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    pool: web::Data<db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    auth::require_permission(&req, &pool, Permission::UsersRead).await?;
+
+    let owner = path.into_inner();
+    let mut conn = pool.get()?;
+
+    let all_sessions = web::block(move || load_sessions(&mut conn, owner, true)).await??;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "user_id": owner,
+        "sessions": all_sessions
+    })))
+}
@@ -0,0 +1,613 @@
+use crate::auth;
+use crate::config::{self, AuthConfig};
+use crate::email;
+use crate::error::AppError;
+use crate::models;
+use actix_web::{HttpResponse, post, web};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+/// Failed logins allowed for an email before each further attempt has to wait
+const FREE_EMAIL_FAILURES: u32 = 3;
+
+/// Failed logins allowed from an IP address before each further attempt has
+/// to wait. Higher than per email, as many users can share an address.
+const FREE_IP_FAILURES: u32 = 10;
+
+/// Wait after the first failure past the free ones, doubled after each
+/// further failure
+const BASE_BACKOFF: Duration = Duration::seconds(1);
+
+/// Longest wait between two login attempts, short of a lockout
+const MAX_BACKOFF: Duration = Duration::minutes(5);
+
+/// Failed logins after which an account is locked
+const LOCKOUT_THRESHOLD: u32 = 10;
+
+/// How long a locked account stays locked
+const LOCKOUT_DURATION: Duration = Duration::minutes(15);
+
+/// How long an account stays locked when it has to be unlocked by email, and
+/// how long the unlock link works
+const UNLOCK_LINK_TTL: Duration = Duration::hours(24);
+
+/// Failures are forgotten once this long has passed since the last one
+const FAILURE_TTL: Duration = Duration::hours(1);
+
+/// Window over which password reset requests are counted
+const RESET_WINDOW: Duration = Duration::hours(1);
+
+/// Password reset requests allowed per email in `RESET_WINDOW`
+const RESET_EMAIL_LIMIT: u32 = 3;
+
+/// Password reset requests allowed per IP address in `RESET_WINDOW`
+const RESET_IP_LIMIT: u32 = 10;
+
+/// Verification emails a user can have resent in `RESET_WINDOW`
+const VERIFICATION_RESEND_LIMIT: u32 = 3;
+
+/// Entries kept by `MemoryStore` before expired ones are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Failed attempts recorded under one key, such as an email or IP address
+#[derive(Clone, Debug, Default)]
+pub struct Attempts {
+    pub failures: u32,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Hash of the emailed token that lifts the lock early
+    pub unlock_token_hash: Option<String>,
+}
+
+impl Attempts {
+    fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Where failed attempts and request counts are kept. Every method is a
+/// single step so that a shared backend such as Redis can make each one
+/// atomic.
+pub trait AttemptStore: Send + Sync {
+    /// The attempts recorded for `key`, unless they have expired
+    fn get(&self, key: &str, now: DateTime<Utc>) -> Option<Attempts>;
+
+    /// Counts a failure for `key` and returns the attempts including it. They
+    /// are kept for `keep_for`, or longer while the key is locked.
+    fn add_failure(&self, key: &str, now: DateTime<Utc>, keep_for: Duration) -> Attempts;
+
+    /// Locks `key` until `until`. An unlock token hash lets `unlock` lift the
+    /// lock before then.
+    fn lock(&self, key: &str, until: DateTime<Utc>, unlock_token_hash: Option<String>);
+
+    /// Forgets everything recorded for `key`
+    fn clear(&self, key: &str);
+
+    /// Clears the key locked with `unlock_token_hash` and returns it
+    fn unlock(&self, unlock_token_hash: &str, now: DateTime<Utc>) -> Option<String>;
+
+    /// Counts a request for `key` in a fixed window that starts with its
+    /// first request. Returns the count including this one and when the
+    /// window ends.
+    fn hit(&self, key: &str, now: DateTime<Utc>, window: Duration) -> (u32, DateTime<Utc>);
+}
+
+/// Keeps attempts in memory, so they are per process and lost on restart
+#[derive(Default)]
+pub struct MemoryStore {
+    attempts: Mutex<HashMap<String, (Attempts, DateTime<Utc>)>>,
+    windows: Mutex<HashMap<String, (u32, DateTime<Utc>)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn attempts(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Attempts, DateTime<Utc>)>> {
+        self.attempts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn windows(&self) -> std::sync::MutexGuard<'_, HashMap<String, (u32, DateTime<Utc>)>> {
+        self.windows
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl AttemptStore for MemoryStore {
+    fn get(&self, key: &str, now: DateTime<Utc>) -> Option<Attempts> {
+        self.attempts()
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(attempts, _)| attempts.clone())
+    }
+
+    fn add_failure(&self, key: &str, now: DateTime<Utc>, keep_for: Duration) -> Attempts {
+        let mut entries = self.attempts();
+        if entries.len() >= PRUNE_THRESHOLD {
+            entries.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        let (attempts, expires_at) = entries
+            .entry(key.to_string())
+            .or_insert_with(|| (Attempts::default(), now));
+        if *expires_at <= now {
+            *attempts = Attempts::default();
+        }
+
+        attempts.failures += 1;
+        attempts.last_failure = Some(now);
+        *expires_at = (now + keep_for).max(attempts.locked_until.unwrap_or(now));
+        attempts.clone()
+    }
+
+    fn lock(&self, key: &str, until: DateTime<Utc>, unlock_token_hash: Option<String>) {
+        let mut entries = self.attempts();
+        let (attempts, expires_at) = entries
+            .entry(key.to_string())
+            .or_insert_with(|| (Attempts::default(), until));
+        attempts.locked_until = Some(until);
+        attempts.unlock_token_hash = unlock_token_hash;
+        *expires_at = (*expires_at).max(until);
+    }
+
+    fn clear(&self, key: &str) {
+        self.attempts().remove(key);
+    }
+
+    fn unlock(&self, unlock_token_hash: &str, now: DateTime<Utc>) -> Option<String> {
+        let mut entries = self.attempts();
+        let key = entries
+            .iter()
+            .find(|(_, (attempts, _))| {
+                attempts.is_locked(now)
+                    && attempts.unlock_token_hash.as_deref() == Some(unlock_token_hash)
+            })
+            .map(|(key, _)| key.clone())?;
+        entries.remove(&key);
+        Some(key)
+    }
+
+    fn hit(&self, key: &str, now: DateTime<Utc>, window: Duration) -> (u32, DateTime<Utc>) {
+        let mut windows = self.windows();
+        if windows.len() >= PRUNE_THRESHOLD {
+            windows.retain(|_, (_, ends_at)| *ends_at > now);
+        }
+
+        let (count, ends_at) = windows.entry(key.to_string()).or_insert((0, now + window));
+        if *ends_at <= now {
+            *count = 0;
+            *ends_at = now + window;
+        }
+        *count += 1;
+        (*count, *ends_at)
+    }
+}
+
+/// Why a login or password reset request was refused
+#[derive(Debug)]
+pub enum Throttled {
+    /// Too many recent failures; the next attempt has to wait
+    Backoff(Duration),
+    /// The account is locked after too many failures
+    Locked(Duration),
+    /// Too many requests in the current window
+    RateLimited(Duration),
+}
+
+impl From<Throttled> for AppError {
+    fn from(throttled: Throttled) -> Self {
+        match throttled {
+            Throttled::Backoff(wait) => AppError::TooManyRequests {
+                message: "Too many failed login attempts. Please wait before trying again",
+                retry_after: wait,
+                locked: false,
+            },
+            Throttled::Locked(wait) => AppError::TooManyRequests {
+                message: "This account is temporarily locked after too many failed login attempts",
+                retry_after: wait,
+                locked: true,
+            },
+            Throttled::RateLimited(wait) => AppError::TooManyRequests {
+                message: "Too many requests. Please try again later",
+                retry_after: wait,
+                locked: false,
+            },
+        }
+    }
+}
+
+/// Applies backoff and lockout to logins and rate limits password reset
+/// requests, per email and per IP address, and verification email resends
+/// per user
+pub struct LoginGuard {
+    store: Box<dyn AttemptStore>,
+    /// Locked accounts stay locked until the emailed unlock link is used, or
+    /// for `UNLOCK_LINK_TTL` at most
+    unlock_by_email: bool,
+}
+
+impl LoginGuard {
+    pub fn new(store: Box<dyn AttemptStore>, unlock_by_email: bool) -> LoginGuard {
+        LoginGuard {
+            store,
+            unlock_by_email,
+        }
+    }
+
+    /// Keeps attempts in memory
+    pub fn in_memory(config: &AuthConfig) -> LoginGuard {
+        LoginGuard::new(Box::new(MemoryStore::new()), config.unlock_by_email)
+    }
+
+    /// Refuses a login attempt while the email or IP address is locked or
+    /// still has to wait after recent failures
+    pub fn check_login(&self, email: &str, ip: Option<IpAddr>) -> Result<(), Throttled> {
+        self.check_login_at(email, ip, Utc::now())
+    }
+
+    fn check_login_at(
+        &self,
+        email: &str,
+        ip: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Throttled> {
+        if let Some(attempts) = self.store.get(&email_key(email), now) {
+            if let Some(until) = attempts.locked_until
+                && until > now
+            {
+                return Err(Throttled::Locked(until - now));
+            }
+            check_backoff(&attempts, FREE_EMAIL_FAILURES, now)?;
+        }
+
+        if let Some(ip) = ip
+            && let Some(attempts) = self.store.get(&ip_key(ip), now)
+        {
+            check_backoff(&attempts, FREE_IP_FAILURES, now)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a failed login. Locks the email once it reaches
+    /// `LOCKOUT_THRESHOLD` failures and, when unlocking by email is on, mails
+    /// an unlock link to the account's address if there is an account.
+    pub async fn login_failed(&self, email: &str, ip: Option<IpAddr>, account_email: Option<&str>) {
+        let Some(unlock_token) = self.record_login_failure(email, ip, Utc::now()) else {
+            return;
+        };
+
+        if let Some(account_email) = account_email
+            && let Err(err) = email::send_account_unlock_email(account_email, &unlock_token).await
+        {
+            error!("Failed to send account unlock email: {}", err);
+
+            // Fall back to logging the link for development environments
+            info!(
+                "Account unlock link for {}: {}/unlock-account?token={}",
+                account_email,
+                config::get().frontend_url,
+                unlock_token
+            );
+        }
+    }
+
+    /// Counts a failed login and locks the email once it reaches
+    /// `LOCKOUT_THRESHOLD` failures. Returns the unlock token to mail when the
+    /// lock can be lifted by email.
+    fn record_login_failure(
+        &self,
+        email: &str,
+        ip: Option<IpAddr>,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        if let Some(ip) = ip {
+            self.store.add_failure(&ip_key(ip), now, FAILURE_TTL);
+        }
+
+        let key = email_key(email);
+        let attempts = self.store.add_failure(&key, now, FAILURE_TTL);
+        if attempts.failures < LOCKOUT_THRESHOLD {
+            return None;
+        }
+
+        warn!(
+            "Locking logins for {} after {} failed attempts",
+            email, attempts.failures
+        );
+
+        if !self.unlock_by_email {
+            self.store.lock(&key, now + LOCKOUT_DURATION, None);
+            return None;
+        }
+
+        let unlock_token = new_unlock_token();
+        self.store.lock(
+            &key,
+            now + UNLOCK_LINK_TTL,
+            Some(auth::hash_token(&unlock_token)),
+        );
+        Some(unlock_token)
+    }
+
+    /// Forgets the failures of an email after a successful login. Failures
+    /// from the IP address are kept, so one working account cannot be used
+    /// to keep guessing others.
+    pub fn login_succeeded(&self, email: &str) {
+        self.store.clear(&email_key(email));
+    }
+
+    /// Lifts the lock that an emailed unlock token belongs to. Returns false
+    /// if the token is unknown or the lock has expired.
+    pub fn unlock(&self, unlock_token: &str) -> bool {
+        self.unlock_at(unlock_token, Utc::now())
+    }
+
+    fn unlock_at(&self, unlock_token: &str, now: DateTime<Utc>) -> bool {
+        self.store
+            .unlock(&auth::hash_token(unlock_token), now)
+            .is_some()
+    }
+
+    /// Counts a password reset request and refuses it once the email or IP
+    /// address has made too many in the current window
+    pub fn check_password_reset(&self, email: &str, ip: Option<IpAddr>) -> Result<(), Throttled> {
+        let now = Utc::now();
+
+        if let Some(ip) = ip {
+            let (count, ends_at) = self
+                .store
+                .hit(&format!("reset:ip:{}", ip), now, RESET_WINDOW);
+            if count > RESET_IP_LIMIT {
+                return Err(Throttled::RateLimited(ends_at - now));
+            }
+        }
+
+        let (count, ends_at) = self.store.hit(
+            &format!("reset:email:{}", normalize_email(email)),
+            now,
+            RESET_WINDOW,
+        );
+        if count > RESET_EMAIL_LIMIT {
+            return Err(Throttled::RateLimited(ends_at - now));
+        }
+
+        Ok(())
+    }
+
+    /// Counts a request to resend the verification email and refuses it once
+    /// the user has made too many in the current window
+    pub fn check_verification_resend(&self, owner: i32) -> Result<(), Throttled> {
+        let now = Utc::now();
+
+        let (count, ends_at) =
+            self.store
+                .hit(&format!("verify-email:user:{}", owner), now, RESET_WINDOW);
+        if count > VERIFICATION_RESEND_LIMIT {
+            return Err(Throttled::RateLimited(ends_at - now));
+        }
+
+        Ok(())
+    }
+}
+
+/// Lifts a login lockout with the token from the emailed unlock link
+#[post("/unlock")]
+pub async fn unlock_account(
+    guard: web::Data<LoginGuard>,
+    unlock_data: web::Json<models::UnlockAccountRequest>,
+) -> Result<HttpResponse, AppError> {
+    if !guard.unlock(&unlock_data.token) {
+        return Err(AppError::bad_request("Invalid or expired unlock link"));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Account unlocked. You can log in again."
+    })))
+}
+
+/// Refuses an attempt made before the backoff after the last failure is over
+fn check_backoff(
+    attempts: &Attempts,
+    free_failures: u32,
+    now: DateTime<Utc>,
+) -> Result<(), Throttled> {
+    let Some(last_failure) = attempts.last_failure else {
+        return Ok(());
+    };
+    if attempts.failures < free_failures {
+        return Ok(());
+    }
+
+    // Double the wait for each failure past the free ones, up to the maximum
+    let doublings = (attempts.failures - free_failures).min(16);
+    let wait = (BASE_BACKOFF * 2i32.pow(doublings)).min(MAX_BACKOFF);
+    let retry_at = last_failure + wait;
+    if retry_at > now {
+        Err(Throttled::Backoff(retry_at - now))
+    } else {
+        Ok(())
+    }
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn email_key(email: &str) -> String {
+    format!("login:email:{}", normalize_email(email))
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("login:ip:{}", ip)
+}
+
+fn new_unlock_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const EMAIL: &str = "alice@example.com";
+
+    fn start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    fn guard(unlock_by_email: bool) -> LoginGuard {
+        LoginGuard::new(Box::new(MemoryStore::new()), unlock_by_email)
+    }
+
+    fn fail(guard: &LoginGuard, times: u32, now: DateTime<Utc>) -> Option<String> {
+        let mut unlock_token = None;
+        for _ in 0..times {
+            unlock_token = guard.record_login_failure(EMAIL, None, now);
+        }
+        unlock_token
+    }
+
+    fn backoff_after(failures: u32, free_failures: u32) -> Duration {
+        let attempts = Attempts {
+            failures,
+            last_failure: Some(start()),
+            ..Attempts::default()
+        };
+        match check_backoff(&attempts, free_failures, start()) {
+            Err(Throttled::Backoff(wait)) => wait,
+            other => panic!("expected a backoff, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn free_failures_do_not_wait() {
+        let guard = guard(false);
+        fail(&guard, FREE_EMAIL_FAILURES - 1, start());
+
+        assert!(guard.check_login_at(EMAIL, None, start()).is_ok());
+
+        fail(&guard, 1, start());
+        assert!(matches!(
+            guard.check_login_at(EMAIL, None, start()),
+            Err(Throttled::Backoff(wait)) if wait == BASE_BACKOFF
+        ));
+    }
+
+    #[test]
+    fn backoff_doubles_per_failure_up_to_the_maximum() {
+        assert_eq!(
+            backoff_after(FREE_EMAIL_FAILURES, FREE_EMAIL_FAILURES),
+            BASE_BACKOFF
+        );
+        assert_eq!(
+            backoff_after(FREE_EMAIL_FAILURES + 1, FREE_EMAIL_FAILURES),
+            BASE_BACKOFF * 2
+        );
+        assert_eq!(
+            backoff_after(FREE_EMAIL_FAILURES + 2, FREE_EMAIL_FAILURES),
+            BASE_BACKOFF * 4
+        );
+        assert_eq!(
+            backoff_after(FREE_IP_FAILURES + 9, FREE_IP_FAILURES),
+            MAX_BACKOFF
+        );
+        assert_eq!(backoff_after(u32::MAX, 0), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_ends_after_the_wait() {
+        let guard = guard(false);
+        fail(&guard, FREE_EMAIL_FAILURES + 1, start());
+
+        assert!(
+            guard
+                .check_login_at(EMAIL, None, start() + BASE_BACKOFF)
+                .is_err()
+        );
+        assert!(
+            guard
+                .check_login_at(EMAIL, None, start() + BASE_BACKOFF * 2)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn locks_at_the_threshold() {
+        let guard = guard(false);
+        assert_eq!(fail(&guard, LOCKOUT_THRESHOLD - 1, start()), None);
+        let later = start() + MAX_BACKOFF;
+        assert!(guard.check_login_at(EMAIL, None, later).is_ok());
+
+        assert_eq!(fail(&guard, 1, later), None);
+
+        assert!(matches!(
+            guard.check_login_at(EMAIL, None, later),
+            Err(Throttled::Locked(wait)) if wait == LOCKOUT_DURATION
+        ));
+        assert!(matches!(
+            guard.check_login_at(&EMAIL.to_uppercase(), None, later + MAX_BACKOFF),
+            Err(Throttled::Locked(_))
+        ));
+    }
+
+    #[test]
+    fn unlock_link_clears_the_key() {
+        let guard = guard(true);
+        let unlock_token = fail(&guard, LOCKOUT_THRESHOLD, start()).unwrap();
+        assert!(matches!(
+            guard.check_login_at(EMAIL, None, start()),
+            Err(Throttled::Locked(wait)) if wait == UNLOCK_LINK_TTL
+        ));
+
+        assert!(!guard.unlock_at("not-the-token", start()));
+        assert!(guard.unlock_at(&unlock_token, start()));
+
+        assert!(guard.check_login_at(EMAIL, None, start()).is_ok());
+        assert!(!guard.unlock_at(&unlock_token, start()));
+    }
+
+    #[test]
+    fn unlock_link_stops_working_when_the_lock_ends() {
+        let guard = guard(true);
+        let unlock_token = fail(&guard, LOCKOUT_THRESHOLD, start()).unwrap();
+
+        assert!(!guard.unlock_at(&unlock_token, start() + UNLOCK_LINK_TTL));
+    }
+
+    #[test]
+    fn failures_expire_and_start_over() {
+        let store = MemoryStore::new();
+        store.add_failure("key", start(), FAILURE_TTL);
+        store.add_failure("key", start(), FAILURE_TTL);
+
+        assert!(store.get("key", start() + FAILURE_TTL).is_none());
+        let attempts = store.add_failure("key", start() + FAILURE_TTL, FAILURE_TTL);
+        assert_eq!(attempts.failures, 1);
+    }
+
+    #[test]
+    fn hits_are_counted_per_window() {
+        let store = MemoryStore::new();
+        store.hit("key", start(), RESET_WINDOW);
+
+        assert_eq!(
+            store.hit("key", start() + Duration::minutes(59), RESET_WINDOW),
+            (2, start() + RESET_WINDOW)
+        );
+        assert_eq!(
+            store.hit("key", start() + RESET_WINDOW, RESET_WINDOW),
+            (1, start() + RESET_WINDOW * 2)
+        );
+    }
+}
@@ -0,0 +1,497 @@
+use crate::error::AppError;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+use log::warn;
+use mime::Mime;
+use std::io::Cursor;
+
+/// Widest or tallest image accepted, which bounds the memory decoding takes
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+
+/// Quality JPEGs are re-encoded at
+const JPEG_QUALITY: u8 = 90;
+
+/// PDF readers look for their header anywhere in the first kilobyte, and
+/// browsers sniff HTML there too
+const SNIFF_WINDOW: usize = 1024;
+
+/// Size of a ZIP end of central directory record without its comment
+const ZIP_DIRECTORY_LEN: usize = 22;
+
+/// Kinds of file accepted for KYC documents, told apart by their contents
+/// rather than by what the client claims
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Jpeg,
+    Png,
+    Pdf,
+}
+
+impl FileKind {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FileKind::Jpeg => "image/jpeg",
+            FileKind::Png => "image/png",
+            FileKind::Pdf => "application/pdf",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileKind::Jpeg => "jpg",
+            FileKind::Png => "png",
+            FileKind::Pdf => "pdf",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            FileKind::Jpeg => "JPEG",
+            FileKind::Png => "PNG",
+            FileKind::Pdf => "PDF",
+        }
+    }
+
+    /// The kind a file's signature says it is
+    pub fn sniff(contents: &[u8]) -> Option<FileKind> {
+        if contents.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(FileKind::Jpeg)
+        } else if contents.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(FileKind::Png)
+        } else if contents.starts_with(b"%PDF-") {
+            Some(FileKind::Pdf)
+        } else {
+            None
+        }
+    }
+
+    fn from_mime(content_type: &Mime) -> Option<FileKind> {
+        match content_type.essence_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(FileKind::Jpeg),
+            "image/png" => Some(FileKind::Png),
+            "application/pdf" => Some(FileKind::Pdf),
+            _ => None,
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<FileKind> {
+        match extension.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(FileKind::Jpeg),
+            "png" => Some(FileKind::Png),
+            "pdf" => Some(FileKind::Pdf),
+            _ => None,
+        }
+    }
+}
+
+/// Reasons an uploaded file is refused
+#[derive(Debug)]
+pub enum UploadError {
+    /// Over the limit, in bytes
+    TooLarge(usize),
+    Empty,
+    UnsupportedType,
+    /// The type or file name the client sent disagrees with the contents
+    Mismatch {
+        claimed: String,
+        actual: FileKind,
+    },
+    /// The file is also readable as another format
+    Polyglot {
+        kind: FileKind,
+        other: &'static str,
+    },
+    Unreadable(FileKind),
+    ImageTooLarge,
+    Encrypted,
+    TooManyPages {
+        pages: usize,
+        max: usize,
+    },
+}
+
+/// A size in the largest unit that shows it whole
+fn describe_size(bytes: usize) -> String {
+    if bytes.is_multiple_of(1024 * 1024) {
+        format!("{} MB", bytes / (1024 * 1024))
+    } else if bytes.is_multiple_of(1024) {
+        format!("{} KB", bytes / 1024)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+impl From<UploadError> for AppError {
+    fn from(e: UploadError) -> Self {
+        let invalid = |code, message: String| AppError::InvalidFile { code, message };
+        match e {
+            UploadError::TooLarge(max) => {
+                AppError::FileTooLarge(format!("Files must be at most {}", describe_size(max)))
+            }
+            UploadError::Empty => invalid("empty_file", "The file is empty".to_string()),
+            UploadError::UnsupportedType => AppError::UnsupportedFileType(
+                "Invalid file type. Only JPG, PNG, and PDF files are allowed.".to_string(),
+            ),
+            UploadError::Mismatch { claimed, actual } => invalid(
+                "file_type_mismatch",
+                format!(
+                    "The file was sent as {} but is a {}",
+                    claimed,
+                    actual.label()
+                ),
+            ),
+            UploadError::Polyglot { kind, other } => invalid(
+                "polyglot_file",
+                format!("The {} file also contains {}", kind.label(), other),
+            ),
+            UploadError::Unreadable(kind) => invalid(
+                "unreadable_file",
+                format!("The {} file is damaged or cannot be read", kind.label()),
+            ),
+            UploadError::ImageTooLarge => invalid(
+                "image_too_large",
+                format!(
+                    "Images must be at most {} pixels wide and high",
+                    MAX_IMAGE_DIMENSION
+                ),
+            ),
+            UploadError::Encrypted => invalid(
+                "encrypted_pdf",
+                "Password-protected PDFs are not accepted".to_string(),
+            ),
+            UploadError::TooManyPages { pages, max } => invalid(
+                "too_many_pages",
+                format!(
+                    "PDFs must have at most {} pages; this one has {}",
+                    max, pages
+                ),
+            ),
+        }
+    }
+}
+
+/// Refuses a file by the type the client declares before any of it is read.
+/// Clients that send no type, or a generic one, are judged by the contents.
+pub fn check_declared_type(declared: Option<&Mime>) -> Result<(), UploadError> {
+    match declared {
+        Some(content_type)
+            if *content_type != mime::APPLICATION_OCTET_STREAM
+                && FileKind::from_mime(content_type).is_none() =>
+        {
+            Err(UploadError::UnsupportedType)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Whether the file ends with a ZIP directory, which is how archives are
+/// read. The comment length has to reach the end exactly, so stray bytes
+/// in compressed data do not count.
+fn has_zip_directory(contents: &[u8]) -> bool {
+    if contents.len() < ZIP_DIRECTORY_LEN {
+        return false;
+    }
+    let last = contents.len() - ZIP_DIRECTORY_LEN;
+    let first = last.saturating_sub(u16::MAX as usize);
+    (first..=last).rev().any(|at| {
+        contents[at..].starts_with(b"PK\x05\x06")
+            && at
+                + ZIP_DIRECTORY_LEN
+                + u16::from_le_bytes([contents[at + 20], contents[at + 21]]) as usize
+                == contents.len()
+    })
+}
+
+/// Another format a file can also be read as, if any
+fn embedded_format(contents: &[u8], kind: FileKind) -> Option<&'static str> {
+    let head = &contents[..contents.len().min(SNIFF_WINDOW)];
+    if kind != FileKind::Pdf && contains(head, b"%PDF-") {
+        return Some("a PDF");
+    }
+    let head = head.to_ascii_lowercase();
+    if [b"<html".as_slice(), b"<script", b"<svg", b"<!doctype html"]
+        .iter()
+        .any(|marker| contains(&head, marker))
+    {
+        return Some("HTML");
+    }
+    if has_zip_directory(contents) {
+        return Some("a ZIP archive");
+    }
+    None
+}
+
+/// Decodes an image and encodes its pixels again, which drops EXIF and any
+/// other metadata or data hidden in the file
+fn reencode(contents: &[u8], kind: FileKind) -> Result<Vec<u8>, UploadError> {
+    let format = match kind {
+        FileKind::Jpeg => ImageFormat::Jpeg,
+        _ => ImageFormat::Png,
+    };
+    let unreadable = |e: ImageError| match e {
+        ImageError::Limits(_) => UploadError::ImageTooLarge,
+        _ => UploadError::Unreadable(kind),
+    };
+
+    let mut reader = ImageReader::with_format(Cursor::new(contents), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(unreadable)?;
+    // The metadata saying which way up the photo is goes, so it is applied
+    // to the pixels first
+    let orientation = decoder.orientation().map_err(unreadable)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(unreadable)?;
+    image.apply_orientation(orientation);
+
+    let mut output = Vec::new();
+    let written = match kind {
+        FileKind::Jpeg => {
+            // JPEG has no alpha channel or 16-bit samples
+            let image = match image {
+                DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => image,
+                other => DynamicImage::ImageRgb8(other.to_rgb8()),
+            };
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY))
+        }
+        _ => image.write_with_encoder(PngEncoder::new(&mut output)),
+    };
+    written.map_err(|e| {
+        warn!("Failed to re-encode {} upload: {}", kind.label(), e);
+        UploadError::Unreadable(kind)
+    })?;
+    Ok(output)
+}
+
+fn check_pdf(contents: &[u8], max_pages: usize) -> Result<(), UploadError> {
+    let document = lopdf::Document::load_mem(contents).map_err(|e| match e {
+        lopdf::Error::Decryption(_) => UploadError::Encrypted,
+        _ => UploadError::Unreadable(FileKind::Pdf),
+    })?;
+    // Encrypted PDFs that open without a password, such as statements that
+    // only restrict printing, are fine; reviewers cannot open the others
+    if document.is_encrypted() && document.authenticate_password("").is_err() {
+        return Err(UploadError::Encrypted);
+    }
+
+    let pages = document.get_pages().len();
+    if pages == 0 {
+        return Err(UploadError::Unreadable(FileKind::Pdf));
+    }
+    if pages > max_pages {
+        return Err(UploadError::TooManyPages {
+            pages,
+            max: max_pages,
+        });
+    }
+    Ok(())
+}
+
+/// Checks an uploaded file against its declared type and name, and returns
+/// its kind with the contents to store. Images are re-encoded; PDFs are
+/// stored as sent once their pages are counted.
+///
+/// Decoding is CPU-bound, so this belongs in `web::block`.
+pub fn validate(
+    contents: Vec<u8>,
+    declared: Option<&Mime>,
+    file_name: Option<&str>,
+    max_pdf_pages: usize,
+) -> Result<(FileKind, Vec<u8>), UploadError> {
+    if contents.is_empty() {
+        return Err(UploadError::Empty);
+    }
+    let kind = FileKind::sniff(&contents).ok_or(UploadError::UnsupportedType)?;
+
+    check_declared_type(declared)?;
+    if let Some(content_type) = declared
+        && let Some(claimed) = FileKind::from_mime(content_type)
+        && claimed != kind
+    {
+        return Err(UploadError::Mismatch {
+            claimed: content_type.essence_str().to_string(),
+            actual: kind,
+        });
+    }
+    if let Some(extension) = file_name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        && let Some(claimed) = FileKind::from_extension(extension)
+        && claimed != kind
+    {
+        return Err(UploadError::Mismatch {
+            claimed: format!("a .{} file", extension),
+            actual: kind,
+        });
+    }
+
+    if let Some(other) = embedded_format(&contents, kind) {
+        return Err(UploadError::Polyglot { kind, other });
+    }
+
+    match kind {
+        FileKind::Jpeg | FileKind::Png => Ok((kind, reencode(&contents, kind)?)),
+        FileKind::Pdf => {
+            check_pdf(&contents, max_pdf_pages)?;
+            Ok((kind, contents))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Document, Object, Stream, dictionary};
+
+    fn jpeg() -> Vec<u8> {
+        let image = image::RgbImage::from_fn(4, 2, |x, _| image::Rgb([x as u8 * 60, 0, 0]));
+        let mut output = Vec::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut output), ImageFormat::Jpeg)
+            .unwrap();
+        output
+    }
+
+    /// A JPEG with a comment segment holding `comment` right after the
+    /// start of image marker
+    fn jpeg_with_comment(comment: &[u8]) -> Vec<u8> {
+        let mut output = vec![0xFF, 0xD8, 0xFF, 0xFE];
+        output.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+        output.extend_from_slice(comment);
+        output.extend_from_slice(&jpeg()[2..]);
+        output
+    }
+
+    fn pdf(pages: usize) -> Vec<u8> {
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+        let kids: Vec<Object> = (0..pages)
+            .map(|_| {
+                let content = document.add_object(Stream::new(dictionary! {}, b"BT ET".to_vec()));
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "Contents" => content,
+                    })
+                    .into()
+            })
+            .collect();
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => pages as i64,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog);
+
+        let mut output = Vec::new();
+        document.save_to(&mut output).unwrap();
+        output
+    }
+
+    fn mime(value: &str) -> Mime {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn accepts_images_and_pdfs_by_their_contents() {
+        let (kind, contents) =
+            validate(jpeg(), Some(&mime("image/jpeg")), Some("id.JPG"), 10).unwrap();
+        assert_eq!(kind, FileKind::Jpeg);
+        assert_eq!(FileKind::sniff(&contents), Some(FileKind::Jpeg));
+
+        // Generic or missing types are judged by the contents alone
+        let (kind, _) = validate(pdf(2), Some(&mime::APPLICATION_OCTET_STREAM), None, 10).unwrap();
+        assert_eq!(kind, FileKind::Pdf);
+    }
+
+    #[test]
+    fn rejects_empty_files() {
+        assert!(matches!(
+            validate(Vec::new(), Some(&mime("image/png")), Some("id.png"), 10),
+            Err(UploadError::Empty)
+        ));
+    }
+
+    #[test]
+    fn rejects_contents_that_disagree_with_the_declared_type_or_name() {
+        assert!(matches!(
+            validate(jpeg(), Some(&mime("image/png")), None, 10),
+            Err(UploadError::Mismatch {
+                actual: FileKind::Jpeg,
+                ..
+            })
+        ));
+        assert!(matches!(
+            validate(jpeg(), Some(&mime("image/jpeg")), Some("statement.pdf"), 10),
+            Err(UploadError::Mismatch {
+                actual: FileKind::Jpeg,
+                ..
+            })
+        ));
+        assert!(matches!(
+            validate(jpeg(), Some(&mime("text/html")), None, 10),
+            Err(UploadError::UnsupportedType)
+        ));
+        assert!(matches!(
+            validate(b"GIF89a".to_vec(), None, None, 10),
+            Err(UploadError::UnsupportedType)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_jpeg_that_is_also_a_zip_archive() {
+        let mut contents = jpeg();
+        contents.extend_from_slice(b"PK\x03\x04payload");
+        contents.extend_from_slice(b"PK\x05\x06");
+        contents.extend_from_slice(&[0; 18]);
+
+        assert!(matches!(
+            validate(contents, Some(&mime("image/jpeg")), None, 10),
+            Err(UploadError::Polyglot {
+                kind: FileKind::Jpeg,
+                other: "a ZIP archive"
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_html_in_the_first_kilobyte() {
+        let contents = jpeg_with_comment(b"<SCRIPT>alert(1)</SCRIPT>");
+        assert!(matches!(
+            validate(contents, None, None, 10),
+            Err(UploadError::Polyglot { other: "HTML", .. })
+        ));
+
+        // Browsers do not sniff that far in
+        let mut comment = vec![b' '; SNIFF_WINDOW];
+        comment.extend_from_slice(b"<script>");
+        let (kind, _) = validate(jpeg_with_comment(&comment), None, None, 10).unwrap();
+        assert_eq!(kind, FileKind::Jpeg);
+    }
+
+    #[test]
+    fn rejects_pdfs_over_the_page_limit() {
+        assert!(matches!(
+            validate(pdf(3), None, Some("statement.pdf"), 2),
+            Err(UploadError::TooManyPages { pages: 3, max: 2 })
+        ));
+        assert!(validate(pdf(2), None, Some("statement.pdf"), 2).is_ok());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::api_keys::Scope;
+use crate::auth;
+use crate::db;
+use crate::error::AppError;
+use crate::feed::{BalancesChanged, FeedHub};
+use crate::ledger::{self, LedgerError};
+use crate::limits::{self, Activity};
+use crate::mfa;
+use crate::models;
+use crate::roles::Permission;
+use crate::schema;
+use actix::Addr;
+use actix_web::{HttpRequest, HttpResponse, post, put, web};
+use diesel::prelude::*;
+use log::{error, info};
+use rust_decimal::Decimal;
+
+impl From<LedgerError> for AppError {
+    fn from(e: LedgerError) -> Self {
+        match e {
+            LedgerError::InsufficientFunds => AppError::InsufficientFunds,
+            LedgerError::Database(e) => AppError::Database(e),
+            e => {
+                error!("Ledger error: {}", e);
+                AppError::internal("Failed to update balances")
+            }
+        }
+    }
+}
+
+/// Requests a withdrawal. The amount is moved to the user's held balance
+/// until an admin completes or rejects the request. The amount counts towards
+/// the user's withdrawal limits until it is rejected. Users with two-factor
+/// authentication must include a fresh code.
+#[post("/withdrawals")]
+pub async fn request_withdrawal(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    feed: web::Data<Addr<FeedHub>>,
+    withdrawal_data: web::Json<models::WithdrawalRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Extract user_id from JWT token
+    let current_user_id = auth::extract_user_id_for(&req, Scope::Withdraw)?;
+    let withdrawal_data = withdrawal_data.into_inner();
+
+    if !ledger::is_valid_asset(&withdrawal_data.asset) {
+        return Err(AppError::bad_request("Invalid asset"));
+    }
+    if withdrawal_data.amount <= Decimal::ZERO {
+        return Err(AppError::bad_request("Amount must be positive"));
+    }
+    if withdrawal_data.address.trim().is_empty() {
+        return Err(AppError::bad_request("Withdrawal address is required"));
+    }
+
+    mfa::require_fresh_code(&pool, current_user_id, withdrawal_data.totp_code.clone()).await?;
+
+    let mut conn = pool.get()?;
+
+    let withdrawal = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            limits::check(
+                conn,
+                current_user_id,
+                Activity::Withdrawal,
+                &withdrawal_data.asset,
+                withdrawal_data.amount,
+            )?;
+
+            let withdrawal = diesel::insert_into(schema::withdrawals::table)
+                .values(&models::NewWithdrawal {
+                    user_id: current_user_id,
+                    asset: withdrawal_data.asset.clone(),
+                    amount: withdrawal_data.amount,
+                    address: withdrawal_data.address.trim().to_string(),
+                })
+                .get_result::<models::Withdrawal>(conn)?;
+
+            ledger::hold(
+                conn,
+                current_user_id,
+                &withdrawal.asset,
+                withdrawal.amount,
+                &format!("withdrawal:{}", withdrawal.id),
+            )?;
+
+            Ok(withdrawal)
+        })
+    })
+    .await??;
+
+    feed.do_send(BalancesChanged {
+        user_ids: vec![withdrawal.user_id],
+    });
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "Withdrawal requested",
+        "withdrawal": withdrawal
+    })))
+}
+
+/// Credits a deposit that arrived outside the exchange to a user's balance
+/// (admin only). Deposits over the user's limits are refused.
+#[post("/deposits")]
+pub async fn admin_credit_deposit(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    feed: web::Data<Addr<FeedHub>>,
+    deposit_data: web::Json<models::DepositRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FundsDeposit).await?;
+
+    let deposit_data = deposit_data.into_inner();
+    if !ledger::is_valid_asset(&deposit_data.asset) {
+        return Err(AppError::bad_request("Invalid asset"));
+    }
+    if deposit_data.amount <= Decimal::ZERO {
+        return Err(AppError::bad_request("Amount must be positive"));
+    }
+    if deposit_data.reference.trim().is_empty() {
+        return Err(AppError::bad_request("Deposit reference is required"));
+    }
+
+    let mut conn = pool.get()?;
+
+    let depositor = deposit_data.user_id;
+    let entry = web::block(move || {
+        conn.transaction::<_, AppError, _>(|conn| {
+            limits::check(
+                conn,
+                deposit_data.user_id,
+                Activity::Deposit,
+                &deposit_data.asset,
+                deposit_data.amount,
+            )?;
+
+            let entry = ledger::deposit(
+                conn,
+                deposit_data.user_id,
+                &deposit_data.asset,
+                deposit_data.amount,
+                &format!("deposit:{}", deposit_data.reference.trim()),
+            )?;
+            Ok(entry)
+        })
+    })
+    .await??;
+
+    info!("Deposit credited in journal entry {}", entry.id);
+    feed.do_send(BalancesChanged {
+        user_ids: vec![depositor],
+    });
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "message": "Deposit credited",
+        "journal_entry": entry
+    })))
+}
+
+/// Completes or rejects a pending withdrawal (admin only). Completing pays
+/// out the held funds; rejecting returns them to the user's available balance.
+#[put("/withdrawals/{withdrawal_id}")]
+pub async fn admin_update_withdrawal(
+    req: HttpRequest,
+    pool: web::Data<db::DbPool>,
+    feed: web::Data<Addr<FeedHub>>,
+    path: web::Path<i32>,
+    status_update: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, AppError> {
+    // Check if the user has admin access
+    auth::require_permission(&req, &pool, Permission::FundsWithdrawals).await?;
+
+    let withdrawal_id = path.into_inner();
+    let new_status = status_update
+        .get("status")
+        .and_then(|s| s.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if new_status != "completed" && new_status != "rejected" {
+        return Err(AppError::bad_request(
+            "Invalid status. Must be 'completed' or 'rejected'.",
+        ));
+    }
+
+    let mut conn = pool.get()?;
+
+    let withdrawal = web::block(move || {
+        conn.transaction::<_, LedgerError, _>(|conn| {
+            use schema::withdrawals::dsl::*;
+
+            let withdrawal = diesel::update(
+                withdrawals
+                    .filter(id.eq(withdrawal_id))
+                    .filter(status.eq("pending")),
+            )
+            .set((status.eq(&new_status), updated_at.eq(diesel::dsl::now)))
+            .get_result::<models::Withdrawal>(conn)
+            .optional()?;
+
+            let Some(withdrawal) = withdrawal else {
+                return Ok(None);
+            };
+
+            let reference = format!("withdrawal:{}", withdrawal.id);
+            if new_status == "completed" {
+                ledger::withdraw(
+                    conn,
+                    withdrawal.user_id,
+                    &withdrawal.asset,
+                    withdrawal.amount,
+                    &reference,
+                )?;
+            } else {
+                ledger::release(
+                    conn,
+                    withdrawal.user_id,
+                    &withdrawal.asset,
+                    withdrawal.amount,
+                    &reference,
+                )?;
+            }
+
+            Ok(Some(withdrawal))
+        })
+    })
+    .await??
+    .ok_or_else(|| AppError::not_found("Pending withdrawal not found"))?;
+
+    feed.do_send(BalancesChanged {
+        user_ids: vec![withdrawal.user_id],
+    });
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "withdrawal": withdrawal
+    })))
+}